@@ -0,0 +1,589 @@
+use std::{pin::Pin, str::FromStr, time::Instant};
+
+use actix_web::{
+    FromRequest, HttpRequest, HttpResponse, Responder,
+    http::{StatusCode, header::AUTHORIZATION},
+    web::{Data, Json, Path, ServiceConfig, delete, get, post},
+};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tracing::field::Empty;
+use uuid::Uuid;
+
+use crate::{
+    IntoPublic, ObjectId,
+    audit::{self, AuditAction, AuditContext, AuditEvent, AuditSink},
+    crypto::{hash_token, random_token},
+    error::{BoxError, as_source, fmt_with_source},
+    identity::{IdentityBackend, IdentityError},
+    problem::{Problem, ToProblem},
+    session::{SessionError, SessionRes},
+    telemetry::{observe_backend, record_outcome, record_user_id},
+    unix_now,
+};
+
+/// Start of every issued key, so leaked keys are easy to recognize for secret scanners.
+pub const API_KEY_PREFIX: &str = "toro_";
+/// Length of the random part of a key.
+const API_KEY_LENGTH: usize = 40;
+/// How many characters of a key are kept in the clear to tell keys apart in listings.
+const API_KEY_HINT_LENGTH: usize = API_KEY_PREFIX.len() + 6;
+/// `last_used_at` is only written again once it is older than this, in seconds, so
+/// busy scripts don't cause a write per request.
+const LAST_USED_RESOLUTION: u64 = 60;
+
+#[derive(Debug)]
+pub enum ApiKeyError {
+    NotFound,
+    Unauthorized,
+    /// The key requests a scope that isn't one of the configured ones.
+    InvalidScope(String),
+    /// `expires_in` reaches past what can be stored.
+    InvalidExpiresIn,
+    InternalServerError(Option<BoxError>),
+    ServiceUnavailable(Option<BoxError>),
+}
+
+impl std::fmt::Display for ApiKeyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ApiKeyError::NotFound => write!(f, "API key not found"),
+            ApiKeyError::Unauthorized => {
+                write!(f, "not allowed to access the API keys of this identity")
+            }
+            ApiKeyError::InvalidScope(scope) => write!(f, "unknown scope {scope}"),
+            ApiKeyError::InvalidExpiresIn => write!(f, "expires_in is too large"),
+            ApiKeyError::InternalServerError(source) => {
+                fmt_with_source(f, "internal server error", source)
+            }
+            ApiKeyError::ServiceUnavailable(source) => {
+                fmt_with_source(f, "service unavailable", source)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ApiKeyError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ApiKeyError::InternalServerError(source) | ApiKeyError::ServiceUnavailable(source) => {
+                as_source(source)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl ApiKeyError {
+    pub fn internal(source: impl Into<BoxError>) -> Self {
+        ApiKeyError::InternalServerError(Some(source.into()))
+    }
+
+    /// For failures that are likely to go away on retry, like a lost database connection.
+    pub fn unavailable(source: impl Into<BoxError>) -> Self {
+        ApiKeyError::ServiceUnavailable(Some(source.into()))
+    }
+}
+
+impl ToProblem for ApiKeyError {
+    fn to_problem(&self) -> Problem {
+        match self {
+            ApiKeyError::NotFound => Problem::new(
+                StatusCode::NOT_FOUND,
+                "api_key_not_found",
+                "API key not found",
+            ),
+            ApiKeyError::Unauthorized => Problem::new(
+                StatusCode::UNAUTHORIZED,
+                "unauthorized",
+                "Not allowed to access the API keys of this identity",
+            ),
+            ApiKeyError::InvalidScope(scope) => Problem::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_scope",
+                "Unknown API key scope",
+            )
+            .with_detail(scope.clone()),
+            ApiKeyError::InvalidExpiresIn => Problem::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_expires_in",
+                "The API key lifetime is too large",
+            ),
+            ApiKeyError::InternalServerError(_) => Problem::internal_server_error(),
+            ApiKeyError::ServiceUnavailable(_) => Problem::service_unavailable(),
+        }
+    }
+}
+
+impl From<ApiKeyError> for HttpResponse {
+    fn from(value: ApiKeyError) -> Self {
+        value.to_problem().into_response()
+    }
+}
+
+impl From<ApiKeyError> for SessionError {
+    fn from(value: ApiKeyError) -> Self {
+        match value {
+            ApiKeyError::InternalServerError(source) => SessionError::InternalServerError(source),
+            ApiKeyError::ServiceUnavailable(source) => SessionError::ServiceUnavailable(source),
+            _ => SessionError::InvalidOrMissingSession,
+        }
+    }
+}
+
+impl actix_web::error::ResponseError for ApiKeyError {
+    fn status_code(&self) -> StatusCode {
+        self.to_problem().status_code()
+    }
+
+    fn error_response(&self) -> HttpResponse<actix_web::body::BoxBody> {
+        self.to_problem().into_response()
+    }
+}
+
+/// A personal API key as persisted by an [`ApiKeyBackend`]. Only the hash of the key
+/// is stored, the key itself is shown once when it is created.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ApiKey {
+    pub id: String,
+    pub user_id: String,
+    pub name: String,
+    /// Hex encoded SHA-256 digest of the key.
+    pub key_hash: String,
+    /// The first characters of the key, e.g. `toro_a1B2c3`.
+    pub hint: String,
+    pub scopes: Vec<String>,
+    pub expires_at: Option<u64>,
+    pub last_used_at: Option<u64>,
+    pub created_at: u64,
+}
+
+/// An API key as listed to its owner, without the hash.
+#[derive(Serialize, Deserialize)]
+pub struct ApiKeyResponse {
+    pub id: String,
+    pub name: String,
+    pub hint: String,
+    pub scopes: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_used_at: Option<u64>,
+    pub created_at: u64,
+}
+
+impl From<ApiKey> for ApiKeyResponse {
+    fn from(value: ApiKey) -> Self {
+        Self {
+            id: value.id,
+            name: value.name,
+            hint: value.hint,
+            scopes: value.scopes,
+            expires_at: value.expires_at,
+            last_used_at: value.last_used_at,
+            created_at: value.created_at,
+        }
+    }
+}
+
+/// Answer to `POST identity/{id}/api-keys`, the only time the key is shown.
+#[derive(Serialize, Deserialize)]
+pub struct CreatedApiKey {
+    pub key: String,
+    #[serde(flatten)]
+    pub api_key: ApiKeyResponse,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ApiKeyRequest {
+    pub name: String,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    /// Lifetime of the key in seconds. Keys without one don't expire.
+    #[serde(default)]
+    pub expires_in: Option<u64>,
+}
+
+#[derive(Deserialize)]
+pub struct ApiKeysPath {
+    id: String,
+}
+
+#[derive(Deserialize)]
+pub struct ApiKeyPath {
+    id: String,
+    key_id: String,
+}
+
+/// Identity of a request authenticated by an API key, or else by a session or access
+/// token like [`SessionRes`]. Only routes that take this extractor accept keys, so
+/// account management and admin routes can't be reached with one.
+pub struct ApiKeyRes<T> {
+    pub inner: T,
+    /// Scopes of the API key, `None` for sessions and access tokens.
+    pub scopes: Option<Vec<String>>,
+}
+
+impl<T> ApiKeyRes<T> {
+    /// Whether the request may be used for `scope`. Sessions and access tokens are
+    /// allowed everything, API keys only their scopes.
+    pub fn has_scope(&self, scope: &str) -> bool {
+        match &self.scopes {
+            Some(scopes) => scopes.iter().any(|granted| granted == scope),
+            None => true,
+        }
+    }
+}
+
+/// Personal API keys for scripts and CI. Requests authenticate with
+/// `Authorization: ApiKey <key>` or `X-Api-Key: <key>` and are accepted by
+/// [`ApiKeyRes`] with the scopes of the key.
+#[derive(Clone)]
+pub struct ApiKeyProvider<T>
+where
+    T: IntoPublic
+        + ObjectId
+        + Serialize
+        + for<'de> Deserialize<'de>
+        + Clone
+        + Send
+        + Sync
+        + 'static,
+{
+    identity_base_path: String,
+    backend: Data<Box<dyn ApiKeyBackend>>,
+    identity_backend: Data<Box<dyn IdentityBackend<T>>>,
+    scopes: Vec<String>,
+    audit_sink: Option<Data<Box<dyn AuditSink>>>,
+}
+
+impl<
+    T: IntoPublic + ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
+> ApiKeyProvider<T>
+{
+    /// `scopes` are the ones keys may be issued for, their meaning is up to the app.
+    pub fn default_with_backend(
+        backend: Data<Box<dyn ApiKeyBackend>>,
+        identity_backend: Data<Box<dyn IdentityBackend<T>>>,
+        scopes: Vec<String>,
+    ) -> Self {
+        Self {
+            identity_base_path: String::from("identity"),
+            backend,
+            identity_backend,
+            scopes,
+            audit_sink: None,
+        }
+    }
+
+    /// Records created and deleted keys.
+    pub fn with_audit_sink(mut self, sink: Data<Box<dyn AuditSink>>) -> Self {
+        self.audit_sink = Some(sink);
+        self
+    }
+
+    pub fn configure(&self, cfg: &mut ServiceConfig) {
+        let data = Data::new(self.clone());
+        cfg.app_data(data.clone())
+            .route(
+                &format!("{}/{{id}}/api-keys", data.identity_base_path),
+                post().to(create_api_key::<T>),
+            )
+            .route(
+                &format!("{}/{{id}}/api-keys", data.identity_base_path),
+                get().to(get_api_keys::<T>),
+            )
+            .route(
+                &format!("{}/{{id}}/api-keys/{{key_id}}", data.identity_base_path),
+                delete().to(delete_api_key::<T>),
+            );
+    }
+
+    /// Issues a key and returns it together with its stored form.
+    #[tracing::instrument(
+        name = "api_key.create",
+        skip_all,
+        fields(user_id = Empty, outcome = Empty, latency_ms = Empty)
+    )]
+    pub async fn create(
+        &self,
+        user_id: String,
+        request: ApiKeyRequest,
+    ) -> Result<(String, ApiKey), ApiKeyError> {
+        record_user_id(&user_id);
+        let started = Instant::now();
+        let result: Result<(String, ApiKey), ApiKeyError> = async {
+            if let Some(scope) = request
+                .scopes
+                .iter()
+                .find(|scope| !self.scopes.contains(scope))
+            {
+                return Err(ApiKeyError::InvalidScope(scope.clone()));
+            }
+
+            let now = unix_now();
+            // Stores keep timestamps as signed 64 bit integers.
+            let expires_at = match request.expires_in {
+                Some(expires_in) => Some(
+                    now.checked_add(expires_in)
+                        .filter(|expires_at| i64::try_from(*expires_at).is_ok())
+                        .ok_or(ApiKeyError::InvalidExpiresIn)?,
+                ),
+                None => None,
+            };
+
+            let key = format!("{API_KEY_PREFIX}{}", random_token(API_KEY_LENGTH));
+            let api_key = ApiKey {
+                id: Uuid::new_v4().into(),
+                user_id,
+                name: request.name,
+                key_hash: hash_token(&key),
+                hint: key[..API_KEY_HINT_LENGTH].into(),
+                scopes: request.scopes,
+                expires_at,
+                last_used_at: None,
+                created_at: now,
+            };
+
+            observe_backend("save_api_key", self.backend.save_api_key(api_key.clone())).await?;
+            Ok((key, api_key))
+        }
+        .await;
+
+        record_outcome("api_key.create", &result, started);
+        result
+    }
+
+    #[tracing::instrument(
+        name = "api_key.get_all",
+        skip_all,
+        fields(user_id = Empty, outcome = Empty, latency_ms = Empty)
+    )]
+    pub async fn get_all(&self, user_id: String) -> Result<Vec<ApiKey>, ApiKeyError> {
+        record_user_id(&user_id);
+        let started = Instant::now();
+        let result = observe_backend("get_api_keys", self.backend.get_api_keys(user_id)).await;
+
+        record_outcome("api_key.get_all", &result, started);
+        result
+    }
+
+    #[tracing::instrument(
+        name = "api_key.delete",
+        skip_all,
+        fields(user_id = Empty, outcome = Empty, latency_ms = Empty)
+    )]
+    pub async fn delete(&self, user_id: String, key_id: String) -> Result<(), ApiKeyError> {
+        record_user_id(&user_id);
+        let started = Instant::now();
+        let result = match observe_backend(
+            "delete_api_key",
+            self.backend.delete_api_key(user_id, key_id),
+        )
+        .await
+        {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(ApiKeyError::NotFound),
+            Err(e) => Err(e),
+        };
+
+        record_outcome("api_key.delete", &result, started);
+        result
+    }
+
+    /// Returns the owner of a key and the scopes it was issued for.
+    /// Unknown, expired and malformed keys are rejected alike.
+    #[tracing::instrument(
+        name = "api_key.authenticate",
+        level = "debug",
+        skip_all,
+        fields(user_id = Empty, outcome = Empty, latency_ms = Empty)
+    )]
+    pub async fn authenticate(&self, key: String) -> Result<(T, Vec<String>), SessionError> {
+        let started = Instant::now();
+        let result: Result<(T, Vec<String>), SessionError> = async {
+            if !key.starts_with(API_KEY_PREFIX) {
+                return Err(SessionError::InvalidOrMissingSession);
+            }
+
+            let now = unix_now();
+            let api_key = observe_backend(
+                "get_api_key_by_hash",
+                self.backend.get_api_key_by_hash(hash_token(&key)),
+            )
+            .await?
+            .filter(|api_key| api_key.expires_at.is_none_or(|expires_at| expires_at > now))
+            .ok_or(SessionError::InvalidOrMissingSession)?;
+            record_user_id(&api_key.user_id);
+
+            if api_key
+                .last_used_at
+                .is_none_or(|last_used_at| last_used_at + LAST_USED_RESOLUTION <= now)
+                && let Err(e) = observe_backend(
+                    "touch_api_key",
+                    self.backend.touch_api_key(api_key.id.clone(), now),
+                )
+                .await
+            {
+                tracing::error!(error = %e, "failed to record API key use");
+            }
+
+            let identity = match observe_backend(
+                "get_by_id",
+                self.identity_backend.get_by_id(api_key.user_id),
+            )
+            .await
+            {
+                Ok(identity) => identity,
+                Err(IdentityError::NotFound) => {
+                    return Err(SessionError::InvalidOrMissingSession);
+                }
+                Err(e) => return Err(e.into()),
+            };
+            Ok((identity, api_key.scopes))
+        }
+        .await;
+
+        record_outcome("api_key.authenticate", &result, started);
+        result
+    }
+}
+
+/// The key of `Authorization: ApiKey <key>` or `X-Api-Key: <key>`.
+fn api_key(req: &HttpRequest) -> Option<String> {
+    let headers = req.headers();
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok()?.strip_prefix("ApiKey "))
+        .or_else(|| headers.get("X-Api-Key")?.to_str().ok())
+        .map(String::from)
+}
+
+/// Only the owner of an identity may manage its keys. [`SessionRes`] doesn't accept keys,
+/// so a key can't be used to issue further keys.
+fn check_owner<T: ObjectId>(session: &SessionRes<T>, id: &str) -> Result<String, ApiKeyError> {
+    match session.inner.id() {
+        Some(user_id) if Uuid::from_str(id).ok() == Some(user_id) => Ok(user_id.into()),
+        _ => Err(ApiKeyError::Unauthorized),
+    }
+}
+
+async fn create_api_key<
+    T: IntoPublic + ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
+>(
+    req: HttpRequest,
+    api_key_provider: Data<ApiKeyProvider<T>>,
+    path: Path<ApiKeysPath>,
+    session: SessionRes<T>,
+    request: Json<ApiKeyRequest>,
+) -> impl Responder {
+    let result = match check_owner(&session, &path.id) {
+        Ok(user_id) => api_key_provider.create(user_id, request.into_inner()).await,
+        Err(e) => Err(e),
+    };
+
+    audit::record(
+        &api_key_provider.audit_sink,
+        AuditEvent::new(AuditAction::ApiKeyCreate, &AuditContext::from_request(&req))
+            .actor(session.inner.id().map(String::from).unwrap_or_default())
+            .target(path.id.clone())
+            .outcome_of(&result),
+    )
+    .await;
+
+    match result {
+        Ok((key, api_key)) => HttpResponse::Created().json(CreatedApiKey {
+            key,
+            api_key: api_key.into(),
+        }),
+        Err(e) => e.into(),
+    }
+}
+
+async fn get_api_keys<
+    T: IntoPublic + ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
+>(
+    api_key_provider: Data<ApiKeyProvider<T>>,
+    path: Path<ApiKeysPath>,
+    session: SessionRes<T>,
+) -> Result<impl Responder, ApiKeyError> {
+    let user_id = check_owner(&session, &path.id)?;
+    let api_keys = api_key_provider.get_all(user_id).await?;
+
+    Ok(HttpResponse::Ok().json(
+        api_keys
+            .into_iter()
+            .map(ApiKeyResponse::from)
+            .collect::<Vec<_>>(),
+    ))
+}
+
+async fn delete_api_key<
+    T: IntoPublic + ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
+>(
+    req: HttpRequest,
+    api_key_provider: Data<ApiKeyProvider<T>>,
+    path: Path<ApiKeyPath>,
+    session: SessionRes<T>,
+) -> impl Responder {
+    let path = path.into_inner();
+    let result = match check_owner(&session, &path.id) {
+        Ok(user_id) => api_key_provider.delete(user_id, path.key_id).await,
+        Err(e) => Err(e),
+    };
+
+    audit::record(
+        &api_key_provider.audit_sink,
+        AuditEvent::new(AuditAction::ApiKeyDelete, &AuditContext::from_request(&req))
+            .actor(session.inner.id().map(String::from).unwrap_or_default())
+            .target(path.id)
+            .outcome_of(&result),
+    )
+    .await;
+
+    match result {
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(e) => e.into(),
+    }
+}
+
+impl<
+    T: IntoPublic + ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
+> FromRequest for ApiKeyRes<T>
+{
+    type Error = SessionError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &actix_web::HttpRequest, _: &mut actix_web::dev::Payload) -> Self::Future {
+        let req = req.clone();
+        Box::pin(async move {
+            let Some(key) = api_key(&req) else {
+                let session = SessionRes::<T>::extract(&req).await?;
+                return Ok(ApiKeyRes {
+                    inner: session.inner,
+                    scopes: None,
+                });
+            };
+
+            let Some(api_key_provider) = req.app_data::<Data<ApiKeyProvider<T>>>() else {
+                return Err(SessionError::internal("ApiKeyProvider is not configured"));
+            };
+            let (inner, scopes) = api_key_provider.authenticate(key).await?;
+            Ok(ApiKeyRes {
+                inner,
+                scopes: Some(scopes),
+            })
+        })
+    }
+}
+
+#[async_trait]
+pub trait ApiKeyBackend: Send + Sync {
+    async fn save_api_key(&self, api_key: ApiKey) -> Result<(), ApiKeyError>;
+    async fn get_api_keys(&self, user_id: String) -> Result<Vec<ApiKey>, ApiKeyError>;
+    async fn get_api_key_by_hash(&self, key_hash: String) -> Result<Option<ApiKey>, ApiKeyError>;
+    async fn touch_api_key(&self, id: String, last_used_at: u64) -> Result<(), ApiKeyError>;
+    async fn delete_api_key(&self, user_id: String, id: String) -> Result<bool, ApiKeyError>;
+    /// Removes every key of a deleted identity.
+    async fn delete_api_keys(&self, user_id: String) -> Result<(), ApiKeyError>;
+}
@@ -0,0 +1,414 @@
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    net::IpAddr,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use actix_web::{
+    HttpRequest, HttpResponse, Responder,
+    http::{StatusCode, header::USER_AGENT},
+    web::{Data, Query, ServiceConfig, block, get},
+};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    IntoPublic, ObjectId,
+    error::{BoxError, as_source, fmt_with_source},
+    problem::{Problem, ToProblem},
+    session::SessionRes,
+    unix_now,
+};
+
+/// Upper bound for the number of events returned by one query.
+const MAX_QUERY_LIMIT: usize = 1000;
+
+#[derive(Debug)]
+pub enum AuditError {
+    Forbidden,
+    InternalServerError(Option<BoxError>),
+    ServiceUnavailable(Option<BoxError>),
+}
+
+impl std::fmt::Display for AuditError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AuditError::Forbidden => write!(f, "not allowed to read the audit log"),
+            AuditError::InternalServerError(source) => {
+                fmt_with_source(f, "internal server error", source)
+            }
+            AuditError::ServiceUnavailable(source) => {
+                fmt_with_source(f, "service unavailable", source)
+            }
+        }
+    }
+}
+
+impl std::error::Error for AuditError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            AuditError::InternalServerError(source) | AuditError::ServiceUnavailable(source) => {
+                as_source(source)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl AuditError {
+    pub fn internal(source: impl Into<BoxError>) -> Self {
+        AuditError::InternalServerError(Some(source.into()))
+    }
+
+    /// For failures that are likely to go away on retry, like a lost database connection.
+    pub fn unavailable(source: impl Into<BoxError>) -> Self {
+        AuditError::ServiceUnavailable(Some(source.into()))
+    }
+}
+
+impl ToProblem for AuditError {
+    fn to_problem(&self) -> Problem {
+        match self {
+            AuditError::Forbidden => Problem::new(
+                StatusCode::FORBIDDEN,
+                "forbidden",
+                "Not allowed to read the audit log",
+            ),
+            AuditError::InternalServerError(_) => Problem::internal_server_error(),
+            AuditError::ServiceUnavailable(_) => Problem::service_unavailable(),
+        }
+    }
+}
+
+impl From<AuditError> for HttpResponse {
+    fn from(value: AuditError) -> Self {
+        value.to_problem().into_response()
+    }
+}
+
+impl actix_web::error::ResponseError for AuditError {
+    fn status_code(&self) -> StatusCode {
+        self.to_problem().status_code()
+    }
+
+    fn error_response(&self) -> HttpResponse<actix_web::body::BoxBody> {
+        self.to_problem().into_response()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    Login,
+    MfaVerification,
+    MfaDisable,
+    Logout,
+    /// Sessions or tokens ended by a client, an OIDC logout or after a refresh token was
+    /// reused.
+    SessionRevoke,
+    IdentityCreate,
+    IdentityUpdate,
+    PasswordChange,
+    CredentialDelete,
+    ApiKeyCreate,
+    ApiKeyDelete,
+    ServiceAccountCreate,
+    ServiceAccountDelete,
+    IdentityDelete,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditOutcome {
+    Success,
+    Failure,
+}
+
+/// Client details attached to every event of a request.
+#[derive(Clone, Default)]
+pub struct AuditContext {
+    pub ip: Option<IpAddr>,
+    pub user_agent: Option<String>,
+}
+
+impl AuditContext {
+    /// Uses the direct peer as client address. Callers that know the real client
+    /// behind a trusted proxy (like the login throttle) should overwrite `ip`.
+    pub fn from_request(req: &HttpRequest) -> Self {
+        Self {
+            ip: req.peer_addr().map(|addr| addr.ip()),
+            user_agent: req
+                .headers()
+                .get(USER_AGENT)
+                .and_then(|user_agent| user_agent.to_str().ok())
+                .map(String::from),
+        }
+    }
+}
+
+/// One entry of the audit log. `actor` is the identity that acted, `target` the identity
+/// acted on. Failed logins use the submitted username as `target` since no id is known.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AuditEvent {
+    pub id: String,
+    pub timestamp: u64,
+    pub action: AuditAction,
+    pub outcome: AuditOutcome,
+    /// Problem code of failed actions, e.g. `invalid_login`.
+    pub reason: Option<String>,
+    pub actor: Option<String>,
+    pub target: Option<String>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+impl AuditEvent {
+    pub fn new(action: AuditAction, context: &AuditContext) -> Self {
+        Self {
+            id: Uuid::new_v4().into(),
+            timestamp: unix_now(),
+            action,
+            outcome: AuditOutcome::Success,
+            reason: None,
+            actor: None,
+            target: None,
+            ip: context.ip.map(|ip| ip.to_string()),
+            user_agent: context.user_agent.clone(),
+        }
+    }
+
+    pub fn actor(mut self, actor: impl Into<String>) -> Self {
+        self.actor = Some(actor.into());
+        self
+    }
+
+    pub fn target(mut self, target: impl Into<String>) -> Self {
+        self.target = Some(target.into());
+        self
+    }
+
+    /// Marks the event as failed if the action returned an error.
+    pub fn outcome_of<V, E: ToProblem>(mut self, result: &Result<V, E>) -> Self {
+        if let Err(e) = result {
+            self.outcome = AuditOutcome::Failure;
+            self.reason = Some(e.to_problem().code);
+        }
+        self
+    }
+
+    fn matches(&self, query: &AuditQuery) -> bool {
+        query.action.is_none_or(|action| action == self.action)
+            && query.outcome.is_none_or(|outcome| outcome == self.outcome)
+            && query
+                .actor
+                .as_ref()
+                .is_none_or(|actor| self.actor.as_ref() == Some(actor))
+            && query
+                .target
+                .as_ref()
+                .is_none_or(|target| self.target.as_ref() == Some(target))
+            && query.since.is_none_or(|since| self.timestamp >= since)
+            && query.until.is_none_or(|until| self.timestamp < until)
+    }
+}
+
+/// Filters for `GET audit`. Results are ordered newest first.
+#[derive(Clone, Default, Deserialize)]
+pub struct AuditQuery {
+    pub action: Option<AuditAction>,
+    pub outcome: Option<AuditOutcome>,
+    pub actor: Option<String>,
+    pub target: Option<String>,
+    /// Unix timestamp, inclusive.
+    pub since: Option<u64>,
+    /// Unix timestamp, exclusive.
+    pub until: Option<u64>,
+    pub limit: Option<usize>,
+}
+
+impl AuditQuery {
+    /// The requested limit, capped to keep responses bounded.
+    pub fn limit(&self) -> usize {
+        self.limit.unwrap_or(100).min(MAX_QUERY_LIMIT)
+    }
+}
+
+/// Records an event without failing the audited action. Sink errors are logged instead.
+pub(crate) async fn record(sink: &Option<Data<Box<dyn AuditSink>>>, event: AuditEvent) {
+    let Some(sink) = sink else {
+        return;
+    };
+
+    if let Err(e) = sink.record(event).await {
+        tracing::error!(error = %e, "failed to record audit event");
+    }
+}
+
+/// Serves the audit log to identities accepted by `is_admin`.
+#[derive(Clone)]
+pub struct AuditProvider<T>
+where
+    T: IntoPublic
+        + ObjectId
+        + Serialize
+        + for<'de> Deserialize<'de>
+        + Clone
+        + Send
+        + Sync
+        + 'static,
+{
+    audit_path: String,
+    sink: Data<Box<dyn AuditSink>>,
+    is_admin: Arc<dyn Fn(&T) -> bool + Send + Sync>,
+}
+
+impl<
+    T: IntoPublic + ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
+> AuditProvider<T>
+{
+    pub fn default_with_sink(
+        sink: Data<Box<dyn AuditSink>>,
+        is_admin: impl Fn(&T) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            audit_path: String::from("audit"),
+            sink,
+            is_admin: Arc::new(is_admin),
+        }
+    }
+
+    pub fn configure(&self, cfg: &mut ServiceConfig) {
+        let data = Data::new(self.clone());
+        cfg.app_data(data.clone())
+            .route(&data.audit_path, get().to(query_audit_log::<T>));
+    }
+
+    pub async fn query(&self, query: AuditQuery) -> Result<Vec<AuditEvent>, AuditError> {
+        self.sink.query(query).await
+    }
+}
+
+async fn query_audit_log<
+    T: IntoPublic + ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
+>(
+    audit_provider: Data<AuditProvider<T>>,
+    session: SessionRes<T>,
+    query: Query<AuditQuery>,
+) -> Result<impl Responder, AuditError> {
+    if !(audit_provider.is_admin)(&session.inner) {
+        return Err(AuditError::Forbidden);
+    }
+
+    let events = audit_provider.query(query.0).await?;
+    Ok(HttpResponse::Ok().json(events))
+}
+
+/// Keeps events in process memory, meant for tests.
+#[derive(Default)]
+pub struct InMemoryAuditSink {
+    events: Mutex<Vec<AuditEvent>>,
+}
+
+impl InMemoryAuditSink {
+    pub fn events(&self) -> Vec<AuditEvent> {
+        self.events
+            .lock()
+            .map(|events| events.clone())
+            .unwrap_or_default()
+    }
+}
+
+#[async_trait]
+impl AuditSink for InMemoryAuditSink {
+    async fn record(&self, event: AuditEvent) -> Result<(), AuditError> {
+        self.events
+            .lock()
+            .map_err(|e| AuditError::internal(e.to_string()))?
+            .push(event);
+        Ok(())
+    }
+
+    async fn query(&self, query: AuditQuery) -> Result<Vec<AuditEvent>, AuditError> {
+        let events = self
+            .events
+            .lock()
+            .map_err(|e| AuditError::internal(e.to_string()))?;
+        Ok(events
+            .iter()
+            .rev()
+            .filter(|event| event.matches(&query))
+            .take(query.limit())
+            .cloned()
+            .collect())
+    }
+}
+
+/// Appends events as JSON lines to a file. The file is only ever opened for appending,
+/// rotation and retention are left to external tooling. Queries scan the whole file.
+#[derive(Clone)]
+pub struct JsonLinesAuditSink {
+    path: PathBuf,
+    lock: Arc<Mutex<()>>,
+}
+
+impl JsonLinesAuditSink {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            lock: Arc::new(Mutex::new(())),
+        }
+    }
+}
+
+#[async_trait]
+impl AuditSink for JsonLinesAuditSink {
+    async fn record(&self, event: AuditEvent) -> Result<(), AuditError> {
+        let mut line = serde_json::to_vec(&event).map_err(AuditError::internal)?;
+        line.push(b'\n');
+
+        let sink = self.clone();
+        block(move || {
+            // Serializes writers of this process so lines don't interleave.
+            let _guard = sink.lock.lock().map_err(|e| e.to_string())?;
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&sink.path)
+                .and_then(|mut file| file.write_all(&line))
+                .map_err(|e| e.to_string())
+        })
+        .await
+        .map_err(AuditError::internal)?
+        .map_err(AuditError::internal)
+    }
+
+    async fn query(&self, query: AuditQuery) -> Result<Vec<AuditEvent>, AuditError> {
+        let path = self.path.clone();
+        let content = block(move || match std::fs::read_to_string(path) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(String::new()),
+            result => result,
+        })
+        .await
+        .map_err(AuditError::internal)?
+        .map_err(AuditError::internal)?;
+
+        Ok(content
+            .lines()
+            .rev()
+            .filter_map(|line| serde_json::from_str::<AuditEvent>(line).ok())
+            .filter(|event| event.matches(&query))
+            .take(query.limit())
+            .collect())
+    }
+}
+
+/// Append-only store for audit events. Implementations should not offer ways to change
+/// or delete recorded events.
+#[async_trait]
+pub trait AuditSink: Send + Sync {
+    async fn record(&self, event: AuditEvent) -> Result<(), AuditError>;
+    async fn query(&self, query: AuditQuery) -> Result<Vec<AuditEvent>, AuditError>;
+}
@@ -0,0 +1,677 @@
+use std::{marker::PhantomData, net::IpAddr, str::FromStr, time::Instant};
+
+use actix_web::{
+    HttpRequest, HttpResponse, Responder,
+    http::StatusCode,
+    web::{Data, Json, Path, ServiceConfig, delete, get, put},
+};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tracing::field::Empty;
+use uuid::Uuid;
+
+use crate::{
+    IntoPublic, ObjectId,
+    audit::{self, AuditAction, AuditContext, AuditEvent, AuditSink},
+    crypto::{hash_password, verify_password},
+    error::{BoxError, as_source, fmt_with_source},
+    identity::IdentityError,
+    password::{PasswordPolicy, PasswordViolation},
+    problem::{FieldError, Problem, ToProblem},
+    session::{SessionError, SessionProvider, SessionRes, current_session_id},
+    telemetry::{observe_backend, record_outcome, record_user_id},
+    throttle::LoginThrottle,
+    unix_now,
+    webhook::{WebhookDispatcher, WebhookEvent, WebhookEventType},
+};
+
+#[derive(Debug)]
+pub enum CredentialError {
+    NotFound,
+    /// Removing the credential would leave the identity without a way to log in.
+    LastCredential,
+    Unauthorized,
+    /// Replacing a password requires the current one.
+    InvalidCurrentPassword,
+    InvalidPassword(Vec<PasswordViolation>),
+    /// Another identity owns a credential with the same kind, issuer and identifier.
+    AlreadyRegistered,
+    TooManyRequests {
+        retry_after: u64,
+    },
+    InternalServerError(Option<BoxError>),
+    ServiceUnavailable(Option<BoxError>),
+}
+
+impl std::fmt::Display for CredentialError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CredentialError::NotFound => write!(f, "credential not found"),
+            CredentialError::LastCredential => write!(f, "the last credential can't be removed"),
+            CredentialError::Unauthorized => {
+                write!(f, "not allowed to access the credentials of this identity")
+            }
+            CredentialError::InvalidCurrentPassword => {
+                write!(f, "current password is missing or wrong")
+            }
+            CredentialError::InvalidPassword(_) => {
+                write!(f, "password doesn't meet the requirements")
+            }
+            CredentialError::AlreadyRegistered => {
+                write!(f, "credential is registered to another identity")
+            }
+            CredentialError::TooManyRequests { retry_after } => {
+                write!(f, "too many attempts, retry in {retry_after} seconds")
+            }
+            CredentialError::InternalServerError(source) => {
+                fmt_with_source(f, "internal server error", source)
+            }
+            CredentialError::ServiceUnavailable(source) => {
+                fmt_with_source(f, "service unavailable", source)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CredentialError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CredentialError::InternalServerError(source)
+            | CredentialError::ServiceUnavailable(source) => as_source(source),
+            _ => None,
+        }
+    }
+}
+
+impl CredentialError {
+    pub fn internal(source: impl Into<BoxError>) -> Self {
+        CredentialError::InternalServerError(Some(source.into()))
+    }
+
+    /// For failures that are likely to go away on retry, like a lost database connection.
+    pub fn unavailable(source: impl Into<BoxError>) -> Self {
+        CredentialError::ServiceUnavailable(Some(source.into()))
+    }
+}
+
+impl ToProblem for CredentialError {
+    fn to_problem(&self) -> Problem {
+        match self {
+            CredentialError::NotFound => Problem::new(
+                StatusCode::NOT_FOUND,
+                "credential_not_found",
+                "Credential not found",
+            ),
+            CredentialError::LastCredential => Problem::new(
+                StatusCode::CONFLICT,
+                "last_credential",
+                "The last credential of an identity can't be removed",
+            ),
+            CredentialError::Unauthorized => Problem::new(
+                StatusCode::UNAUTHORIZED,
+                "unauthorized",
+                "Not allowed to access the credentials of this identity",
+            ),
+            CredentialError::InvalidCurrentPassword => Problem::new(
+                StatusCode::FORBIDDEN,
+                "invalid_current_password",
+                "The current password is missing or wrong",
+            ),
+            CredentialError::InvalidPassword(violations) => Problem::new(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "invalid_password",
+                "Password doesn't meet the requirements",
+            )
+            .with_errors(
+                violations
+                    .iter()
+                    .map(|violation| FieldError::from_violation("password", violation))
+                    .collect(),
+            ),
+            CredentialError::AlreadyRegistered => Problem::new(
+                StatusCode::CONFLICT,
+                "credential_in_use",
+                "The credential is registered to another identity",
+            ),
+            CredentialError::TooManyRequests { retry_after } => SessionError::TooManyRequests {
+                retry_after: *retry_after,
+            }
+            .to_problem(),
+            CredentialError::InternalServerError(_) => Problem::internal_server_error(),
+            CredentialError::ServiceUnavailable(_) => Problem::service_unavailable(),
+        }
+    }
+}
+
+impl From<CredentialError> for HttpResponse {
+    fn from(value: CredentialError) -> Self {
+        value.to_problem().into_response()
+    }
+}
+
+impl From<IdentityError> for CredentialError {
+    fn from(value: IdentityError) -> Self {
+        match value {
+            IdentityError::InternalServerError(source) => {
+                CredentialError::InternalServerError(source)
+            }
+            IdentityError::ServiceUnavailable(source) => {
+                CredentialError::ServiceUnavailable(source)
+            }
+            IdentityError::InvalidPassword(violations) => {
+                CredentialError::InvalidPassword(violations)
+            }
+            other => CredentialError::internal(other),
+        }
+    }
+}
+
+impl From<SessionError> for CredentialError {
+    fn from(value: SessionError) -> Self {
+        match value {
+            SessionError::InternalServerError(source) => {
+                CredentialError::InternalServerError(source)
+            }
+            SessionError::ServiceUnavailable(source) => CredentialError::ServiceUnavailable(source),
+            SessionError::TooManyRequests { retry_after } => {
+                CredentialError::TooManyRequests { retry_after }
+            }
+            other => CredentialError::internal(other),
+        }
+    }
+}
+
+impl actix_web::error::ResponseError for CredentialError {
+    fn status_code(&self) -> StatusCode {
+        self.to_problem().status_code()
+    }
+
+    fn error_response(&self) -> HttpResponse<actix_web::body::BoxBody> {
+        match self {
+            CredentialError::TooManyRequests { retry_after } => {
+                actix_web::error::ResponseError::error_response(&SessionError::TooManyRequests {
+                    retry_after: *retry_after,
+                })
+            }
+            _ => self.to_problem().into_response(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CredentialKind {
+    Password,
+    Passkey,
+    External,
+}
+
+impl CredentialKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CredentialKind::Password => "password",
+            CredentialKind::Passkey => "passkey",
+            CredentialKind::External => "external",
+        }
+    }
+}
+
+/// A way to log in as an identity, stored apart from the identity itself.
+/// Identities can have any number of credentials, but at most one password.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Credential {
+    pub id: String,
+    pub user_id: String,
+    pub kind: CredentialKind,
+    /// The issuer of an external account.
+    pub issuer: Option<String>,
+    /// Unique per kind and issuer: the user id for passwords, the base64url encoded
+    /// credential id of a passkey and the subject of an external account.
+    pub identifier: String,
+    /// What a login is checked against: the argon2id hash of the password or the JSON
+    /// serialized passkey.
+    pub secret: Option<String>,
+    pub created_at: u64,
+}
+
+impl Credential {
+    pub fn new(user_id: String, kind: CredentialKind, identifier: String) -> Self {
+        Self {
+            id: Uuid::new_v4().into(),
+            user_id,
+            kind,
+            issuer: None,
+            identifier,
+            secret: None,
+            created_at: unix_now(),
+        }
+    }
+
+    /// A password credential, storing the argon2id hash of the password.
+    pub fn password(user_id: String, password: &str) -> Result<Self, CredentialError> {
+        Self::new(user_id.clone(), CredentialKind::Password, user_id).with_password(password)
+    }
+
+    pub fn with_issuer(mut self, issuer: String) -> Self {
+        self.issuer = Some(issuer);
+        self
+    }
+
+    pub fn with_secret(mut self, secret: String) -> Self {
+        self.secret = Some(secret);
+        self
+    }
+
+    /// Replaces the secret with the argon2id hash of the password.
+    pub fn with_password(self, password: &str) -> Result<Self, CredentialError> {
+        let hash = hash_password(password).map_err(CredentialError::internal)?;
+        Ok(self.with_secret(hash))
+    }
+}
+
+/// A credential as listed to its owner, without the secret.
+#[derive(Serialize, Deserialize)]
+pub struct CredentialResponse {
+    pub id: String,
+    pub kind: CredentialKind,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub issuer: Option<String>,
+    pub created_at: u64,
+}
+
+impl From<Credential> for CredentialResponse {
+    fn from(value: Credential) -> Self {
+        Self {
+            id: value.id,
+            kind: value.kind,
+            issuer: value.issuer,
+            created_at: value.created_at,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PasswordRequest {
+    /// Required when the identity already has a password.
+    #[serde(default)]
+    current_password: Option<String>,
+    password: String,
+}
+
+#[derive(Deserialize)]
+pub struct CredentialsPath {
+    id: String,
+}
+
+#[derive(Deserialize)]
+pub struct CredentialPath {
+    id: String,
+    credential_id: String,
+}
+
+/// Lets users list and remove the credentials of their identity and set its password.
+/// Passkeys and external accounts are added through their own login flows.
+#[derive(Clone)]
+pub struct CredentialProvider<T>
+where
+    T: IntoPublic
+        + ObjectId
+        + Serialize
+        + for<'de> Deserialize<'de>
+        + Clone
+        + Send
+        + Sync
+        + 'static,
+{
+    identity_base_path: String,
+    backend: Data<Box<dyn CredentialBackend>>,
+    password_policy: Option<PasswordPolicy>,
+    audit_sink: Option<Data<Box<dyn AuditSink>>>,
+    webhooks: Option<Data<WebhookDispatcher>>,
+    session_provider: Option<Data<SessionProvider<T>>>,
+    throttle: Option<LoginThrottle>,
+    _identity: PhantomData<T>,
+}
+
+impl<
+    T: IntoPublic + ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
+> CredentialProvider<T>
+{
+    pub fn default_with_backend(backend: Data<Box<dyn CredentialBackend>>) -> Self {
+        Self {
+            identity_base_path: String::from("identity"),
+            backend,
+            password_policy: None,
+            audit_sink: None,
+            webhooks: None,
+            session_provider: None,
+            throttle: None,
+            _identity: PhantomData,
+        }
+    }
+
+    pub fn with_password_policy(mut self, policy: PasswordPolicy) -> Self {
+        self.password_policy = Some(policy);
+        self
+    }
+
+    /// Records password changes and removed credentials.
+    pub fn with_audit_sink(mut self, sink: Data<Box<dyn AuditSink>>) -> Self {
+        self.audit_sink = Some(sink);
+        self
+    }
+
+    /// Publishes password changes as webhook events.
+    pub fn with_webhooks(mut self, webhooks: Data<WebhookDispatcher>) -> Self {
+        self.webhooks = Some(webhooks);
+        self
+    }
+
+    /// Logs the identity out everywhere else once its password changed.
+    pub fn with_session_provider(mut self, session_provider: Data<SessionProvider<T>>) -> Self {
+        self.session_provider = Some(session_provider);
+        self
+    }
+
+    /// Counts wrong current passwords against the same account counter as logins.
+    pub fn with_throttle(mut self, throttle: LoginThrottle) -> Self {
+        self.throttle = Some(throttle);
+        self
+    }
+
+    pub fn configure(&self, cfg: &mut ServiceConfig) {
+        let data = Data::new(self.clone());
+        cfg.app_data(data.clone())
+            .route(
+                &format!("{}/{{id}}/credentials", data.identity_base_path),
+                get().to(get_credentials::<T>),
+            )
+            .route(
+                &format!("{}/{{id}}/credentials/password", data.identity_base_path),
+                put().to(set_password::<T>),
+            )
+            .route(
+                &format!(
+                    "{}/{{id}}/credentials/{{credential_id}}",
+                    data.identity_base_path
+                ),
+                delete().to(delete_credential::<T>),
+            );
+    }
+
+    #[tracing::instrument(
+        name = "credential.get_all",
+        skip_all,
+        fields(user_id = Empty, outcome = Empty, latency_ms = Empty)
+    )]
+    pub async fn get_all(&self, user_id: String) -> Result<Vec<Credential>, CredentialError> {
+        record_user_id(&user_id);
+        let started = Instant::now();
+        let result =
+            observe_backend("get_credentials", self.backend.get_credentials(user_id)).await;
+
+        record_outcome("credential.get_all", &result, started);
+        result
+    }
+
+    /// Adds a password to the identity or replaces its current one, which has to be given
+    /// as `current_password`. Afterwards every session of the identity except `keep_session`
+    /// is revoked.
+    #[tracing::instrument(
+        name = "credential.set_password",
+        skip_all,
+        fields(user_id = Empty, outcome = Empty, latency_ms = Empty)
+    )]
+    pub async fn set_password(
+        &self,
+        identity: &T,
+        current_password: Option<String>,
+        password: String,
+        keep_session: Option<String>,
+        client_ip: Option<IpAddr>,
+    ) -> Result<(), CredentialError> {
+        let started = Instant::now();
+        let result: Result<(), CredentialError> = async {
+            let Some(user_id) = identity.id() else {
+                return Err(CredentialError::internal("identity without id"));
+            };
+            let user_id = String::from(user_id);
+            record_user_id(&user_id);
+
+            if let Some(policy) = &self.password_policy {
+                let violations = policy.check(&identity.username(), &password).await?;
+                if !violations.is_empty() {
+                    return Err(CredentialError::InvalidPassword(violations));
+                }
+            }
+
+            let existing = observe_backend(
+                "find_credential",
+                self.backend
+                    .find_credential(CredentialKind::Password, None, user_id.clone()),
+            )
+            .await?;
+            let credential = match existing {
+                Some(existing) => {
+                    let Some(current_password) = current_password else {
+                        return Err(CredentialError::InvalidCurrentPassword);
+                    };
+                    let username = identity.username();
+                    if let Some(throttle) = &self.throttle {
+                        throttle.check(&username, client_ip).await?;
+                    }
+                    if !verify_password(&current_password, existing.secret.as_deref()) {
+                        if let Some(throttle) = &self.throttle {
+                            throttle.record_failure(&username, client_ip).await?;
+                        }
+                        return Err(CredentialError::InvalidCurrentPassword);
+                    }
+                    if let Some(throttle) = &self.throttle {
+                        throttle.record_success(&username).await?;
+                    }
+                    existing.with_password(&password)?
+                }
+                None => Credential::password(user_id.clone(), &password)?,
+            };
+            observe_backend("save_credential", self.backend.save_credential(credential)).await?;
+
+            if let Some(session_provider) = &self.session_provider
+                && let Err(e) = session_provider
+                    .logout_others(user_id.clone(), keep_session)
+                    .await
+            {
+                tracing::error!(error = %e, "failed to revoke sessions after password change");
+            }
+
+            if let Some(webhooks) = &self.webhooks {
+                let identity = serde_json::to_value(identity.clone().into_public()).ok();
+                if let Err(e) = webhooks
+                    .enqueue(WebhookEvent::new(
+                        WebhookEventType::PasswordChanged,
+                        user_id,
+                        identity,
+                    ))
+                    .await
+                {
+                    tracing::error!(error = %e, "failed to enqueue webhook event");
+                }
+            }
+            Ok(())
+        }
+        .await;
+
+        record_outcome("credential.set_password", &result, started);
+        result
+    }
+
+    /// Removes a credential unless it is the last one of the identity.
+    #[tracing::instrument(
+        name = "credential.delete",
+        skip_all,
+        fields(user_id = Empty, outcome = Empty, latency_ms = Empty)
+    )]
+    pub async fn delete(
+        &self,
+        user_id: String,
+        credential_id: String,
+    ) -> Result<(), CredentialError> {
+        record_user_id(&user_id);
+        let started = Instant::now();
+        let result: Result<(), CredentialError> = async {
+            match observe_backend(
+                "delete_credential_unless_last",
+                self.backend
+                    .delete_credential_unless_last(user_id, credential_id),
+            )
+            .await?
+            {
+                true => Ok(()),
+                false => Err(CredentialError::NotFound),
+            }
+        }
+        .await;
+
+        record_outcome("credential.delete", &result, started);
+        result
+    }
+}
+
+/// Only the owner of an identity may touch its credentials.
+fn check_owner<T: ObjectId>(identity: &T, id: &str) -> Result<String, CredentialError> {
+    match identity.id() {
+        Some(user_id) if Uuid::from_str(id).ok() == Some(user_id) => Ok(user_id.into()),
+        _ => Err(CredentialError::Unauthorized),
+    }
+}
+
+async fn get_credentials<
+    T: IntoPublic + ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
+>(
+    credential_provider: Data<CredentialProvider<T>>,
+    path: Path<CredentialsPath>,
+    session: SessionRes<T>,
+) -> Result<impl Responder, CredentialError> {
+    let user_id = check_owner(&session.inner, &path.id)?;
+    let credentials = credential_provider.get_all(user_id).await?;
+
+    Ok(HttpResponse::Ok().json(
+        credentials
+            .into_iter()
+            .map(CredentialResponse::from)
+            .collect::<Vec<_>>(),
+    ))
+}
+
+async fn set_password<
+    T: IntoPublic + ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
+>(
+    req: HttpRequest,
+    credential_provider: Data<CredentialProvider<T>>,
+    path: Path<CredentialsPath>,
+    session: SessionRes<T>,
+    request: Json<PasswordRequest>,
+) -> impl Responder {
+    let request = request.into_inner();
+    let keep_session = credential_provider
+        .session_provider
+        .as_ref()
+        .and_then(|session_provider| current_session_id(&req, session_provider));
+    let client_ip = credential_provider
+        .throttle
+        .as_ref()
+        .and_then(|throttle| throttle.client_ip(&req));
+    let result = match check_owner(&session.inner, &path.id) {
+        Ok(_) => {
+            credential_provider
+                .set_password(
+                    &session.inner,
+                    request.current_password,
+                    request.password,
+                    keep_session,
+                    client_ip,
+                )
+                .await
+        }
+        Err(e) => Err(e),
+    };
+
+    audit::record(
+        &credential_provider.audit_sink,
+        AuditEvent::new(
+            AuditAction::PasswordChange,
+            &AuditContext::from_request(&req),
+        )
+        .actor(session.inner.id().map(String::from).unwrap_or_default())
+        .target(path.id.clone())
+        .outcome_of(&result),
+    )
+    .await;
+
+    match result {
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(e) => e.into(),
+    }
+}
+
+async fn delete_credential<
+    T: IntoPublic + ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
+>(
+    req: HttpRequest,
+    credential_provider: Data<CredentialProvider<T>>,
+    path: Path<CredentialPath>,
+    session: SessionRes<T>,
+) -> impl Responder {
+    let path = path.into_inner();
+    let result = match check_owner(&session.inner, &path.id) {
+        Ok(user_id) => {
+            credential_provider
+                .delete(user_id, path.credential_id)
+                .await
+        }
+        Err(e) => Err(e),
+    };
+
+    audit::record(
+        &credential_provider.audit_sink,
+        AuditEvent::new(
+            AuditAction::CredentialDelete,
+            &AuditContext::from_request(&req),
+        )
+        .actor(session.inner.id().map(String::from).unwrap_or_default())
+        .target(path.id)
+        .outcome_of(&result),
+    )
+    .await;
+
+    match result {
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(e) => e.into(),
+    }
+}
+
+#[async_trait]
+pub trait CredentialBackend: Send + Sync {
+    async fn get_credentials(&self, user_id: String) -> Result<Vec<Credential>, CredentialError>;
+    /// The credential of a kind with the given issuer and identifier, e.g. the owner of a
+    /// passkey or external account.
+    async fn find_credential(
+        &self,
+        kind: CredentialKind,
+        issuer: Option<String>,
+        identifier: String,
+    ) -> Result<Option<Credential>, CredentialError>;
+    /// Inserts a credential or replaces the one with the same id. Fails with
+    /// [`CredentialError::AlreadyRegistered`] if another credential has the same kind, issuer
+    /// and identifier.
+    async fn save_credential(&self, credential: Credential) -> Result<(), CredentialError>;
+    /// Removes a credential of the identity, returns `false` if it doesn't exist. Fails with
+    /// [`CredentialError::LastCredential`] instead of removing the only credential left, also
+    /// when concurrent calls remove the others.
+    async fn delete_credential_unless_last(
+        &self,
+        user_id: String,
+        credential_id: String,
+    ) -> Result<bool, CredentialError>;
+    /// Removes every credential of a deleted identity.
+    async fn delete_credentials(&self, user_id: String) -> Result<(), CredentialError>;
+}
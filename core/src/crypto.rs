@@ -0,0 +1,75 @@
+use std::sync::LazyLock;
+
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier, password_hash::SaltString};
+use rand::{Rng, RngCore, distr::Alphanumeric};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+
+use crate::error::BoxError;
+
+/// Compared against when an account doesn't exist, so unknown users cost the same work.
+const DUMMY_SECRET: &str = "toro-auth-dummy-secret";
+
+/// Argon2id hash of [`DUMMY_SECRET`], checked when there is no password to check against.
+static DUMMY_PASSWORD_HASH: LazyLock<String> =
+    LazyLock::new(|| hash_password(DUMMY_SECRET).unwrap_or_default());
+
+/// Generates a random alphanumeric token of the given length.
+pub(crate) fn random_token(len: usize) -> String {
+    rand::rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
+/// Hex encoded SHA-256 digest of a token.
+/// Only meant for high entropy values, never for user chosen passwords.
+pub(crate) fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+/// Compares a provided secret with the stored one in constant time.
+/// Backends pass `None` for unknown accounts: the comparison is still carried out
+/// against a dummy, so the response time doesn't reveal whether the account exists.
+pub fn verify_secret(provided: &str, stored: Option<&str>) -> bool {
+    // Digests have a fixed length, so the comparison doesn't leak the secret length either.
+    let provided = Sha256::digest(provided.as_bytes());
+    let expected = Sha256::digest(stored.unwrap_or(DUMMY_SECRET).as_bytes());
+    let matches: bool = provided.ct_eq(&expected).into();
+    matches && stored.is_some()
+}
+
+/// Argon2id hash of a password in PHC string format, what password credentials store.
+pub fn hash_password(password: &str) -> Result<String, BoxError> {
+    let mut salt = [0u8; 16];
+    rand::rng().fill_bytes(&mut salt);
+    let salt = SaltString::encode_b64(&salt)?;
+    Ok(Argon2::default()
+        .hash_password(password.as_bytes(), &salt)?
+        .to_string())
+}
+
+/// Whether a stored password is a hash of [`hash_password`]. Earlier versions stored
+/// passwords in the clear, backends rehash those on the next successful login.
+pub fn is_password_hash(stored: &str) -> bool {
+    stored.starts_with("$argon2")
+}
+
+/// Checks a password against the stored hash, or against a password stored in the clear
+/// by earlier versions. Like [`verify_secret`], backends pass `None` for unknown accounts
+/// and the password is still hashed, so the response time doesn't reveal the account.
+pub fn verify_password(provided: &str, stored: Option<&str>) -> bool {
+    match stored {
+        Some(stored) if !is_password_hash(stored) => verify_secret(provided, Some(stored)),
+        _ => {
+            let hash = stored.unwrap_or(DUMMY_PASSWORD_HASH.as_str());
+            let matches = PasswordHash::new(hash).is_ok_and(|hash| {
+                Argon2::default()
+                    .verify_password(provided.as_bytes(), &hash)
+                    .is_ok()
+            });
+            matches && stored.is_some()
+        }
+    }
+}
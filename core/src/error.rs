@@ -0,0 +1,22 @@
+use std::fmt::{Formatter, Result};
+
+/// Underlying cause attached to `InternalServerError` and `ServiceUnavailable` variants.
+/// Sources are never sent to clients, they are meant for logs and retry decisions.
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+pub(crate) fn fmt_with_source(
+    f: &mut Formatter<'_>,
+    message: &str,
+    source: &Option<BoxError>,
+) -> Result {
+    match source {
+        Some(source) => write!(f, "{message}: {source}"),
+        None => write!(f, "{message}"),
+    }
+}
+
+pub(crate) fn as_source(source: &Option<BoxError>) -> Option<&(dyn std::error::Error + 'static)> {
+    source
+        .as_ref()
+        .map(|source| source.as_ref() as &(dyn std::error::Error + 'static))
+}
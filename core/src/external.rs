@@ -0,0 +1,1171 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+use actix_web::{
+    HttpRequest, HttpResponse,
+    cookie::{Cookie, SameSite, time::Duration as CookieDuration},
+    http::{StatusCode, header::LOCATION},
+    web::{Data, Path, Query, ServiceConfig, get},
+};
+use async_trait::async_trait;
+use base64::{Engine, prelude::BASE64_URL_SAFE_NO_PAD};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation, decode_header, jwk::JwkSet};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use tracing::field::Empty;
+
+use crate::{
+    IntoPublic, ObjectId,
+    audit::{self, AuditAction, AuditContext, AuditEvent, AuditSink},
+    credential::{Credential, CredentialBackend, CredentialError, CredentialKind},
+    crypto::{hash_token, random_token, verify_secret},
+    error::{BoxError, as_source, fmt_with_source},
+    hooks::{AuthHooks, HookError},
+    identity::{IdentityBackend, IdentityError, IdentityKind},
+    oauth::with_query,
+    problem::{Problem, ToProblem},
+    session::{SessionError, SessionKind, SessionProvider, SessionRes, session_cookie},
+    telemetry::{observe_backend, record_outcome, record_user_id},
+    unix_now,
+    username::UsernamePolicy,
+    webhook::{WebhookDispatcher, WebhookEvent, WebhookEventType},
+};
+
+/// How long a login started at an external issuer can be finished, in seconds.
+const LOGIN_STATE_LIFETIME: u64 = 10 * 60;
+/// Holds the hash of the `state` a login was started with, so only the browser that started
+/// it can finish it.
+const LOGIN_STATE_COOKIE: &str = "externalLoginState";
+/// How long the discovery document of an issuer is cached, in seconds.
+const METADATA_MAX_AGE: u64 = 60 * 60;
+/// ID tokens signed with an unknown key refetch the key set at most this often, in seconds.
+const JWKS_REFRESH_INTERVAL: u64 = 60;
+
+#[derive(Debug)]
+pub enum ExternalLoginError {
+    UnknownIssuer,
+    InvalidState,
+    InvalidReturnTo,
+    /// The issuer answered with this OAuth error code, e.g. `access_denied`.
+    Denied(String),
+    InvalidIdToken,
+    /// A new identity would take a username that is already in use.
+    IdentityConflict,
+    /// The external account is already linked to another identity.
+    AccountInUse,
+    /// Several identities have the verified email of the external account.
+    AmbiguousEmail,
+    /// Vetoed by the `before_create` hook of [`AuthHooks`].
+    Rejected(HookError),
+    InternalServerError(Option<BoxError>),
+    ServiceUnavailable(Option<BoxError>),
+}
+
+impl std::fmt::Display for ExternalLoginError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExternalLoginError::UnknownIssuer => write!(f, "unknown external issuer"),
+            ExternalLoginError::InvalidState => write!(f, "unknown or expired external login"),
+            ExternalLoginError::InvalidReturnTo => write!(f, "return_to is not a local path"),
+            ExternalLoginError::Denied(error) => {
+                write!(f, "external issuer denied the login: {error}")
+            }
+            ExternalLoginError::InvalidIdToken => write!(f, "invalid ID token"),
+            ExternalLoginError::IdentityConflict => write!(f, "username already in use"),
+            ExternalLoginError::AccountInUse => {
+                write!(f, "external account linked to another identity")
+            }
+            ExternalLoginError::AmbiguousEmail => {
+                write!(
+                    f,
+                    "several identities use the email of the external account"
+                )
+            }
+            ExternalLoginError::Rejected(e) => write!(f, "{e}"),
+            ExternalLoginError::InternalServerError(source) => {
+                fmt_with_source(f, "internal server error", source)
+            }
+            ExternalLoginError::ServiceUnavailable(source) => {
+                fmt_with_source(f, "service unavailable", source)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ExternalLoginError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ExternalLoginError::InternalServerError(source)
+            | ExternalLoginError::ServiceUnavailable(source) => as_source(source),
+            ExternalLoginError::Rejected(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl ExternalLoginError {
+    pub fn internal(source: impl Into<BoxError>) -> Self {
+        ExternalLoginError::InternalServerError(Some(source.into()))
+    }
+
+    /// For failures that are likely to go away on retry, like a lost database connection
+    /// or an issuer that can't be reached.
+    pub fn unavailable(source: impl Into<BoxError>) -> Self {
+        ExternalLoginError::ServiceUnavailable(Some(source.into()))
+    }
+}
+
+impl ToProblem for ExternalLoginError {
+    fn to_problem(&self) -> Problem {
+        match self {
+            ExternalLoginError::UnknownIssuer => Problem::new(
+                StatusCode::NOT_FOUND,
+                "unknown_issuer",
+                "Unknown external issuer",
+            ),
+            ExternalLoginError::InvalidState => Problem::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_external_login",
+                "Unknown or expired external login",
+            ),
+            ExternalLoginError::InvalidReturnTo => Problem::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_return_to",
+                "return_to has to be a path on this site",
+            ),
+            ExternalLoginError::Denied(error) => Problem::new(
+                StatusCode::UNAUTHORIZED,
+                "external_login_denied",
+                "The external issuer denied the login",
+            )
+            .with_detail(error.clone()),
+            ExternalLoginError::InvalidIdToken => Problem::new(
+                StatusCode::UNAUTHORIZED,
+                "invalid_id_token",
+                "Invalid ID token from the external issuer",
+            ),
+            ExternalLoginError::IdentityConflict => Problem::new(
+                StatusCode::CONFLICT,
+                "username_already_in_use",
+                "Username already in use",
+            ),
+            ExternalLoginError::AccountInUse => Problem::new(
+                StatusCode::CONFLICT,
+                "external_account_in_use",
+                "The external account is linked to another identity",
+            ),
+            ExternalLoginError::AmbiguousEmail => Problem::new(
+                StatusCode::CONFLICT,
+                "ambiguous_email",
+                "Several identities use this email, log in and link the account instead",
+            ),
+            ExternalLoginError::Rejected(e) => e.to_problem(),
+            ExternalLoginError::InternalServerError(_) => Problem::internal_server_error(),
+            ExternalLoginError::ServiceUnavailable(_) => Problem::service_unavailable(),
+        }
+    }
+}
+
+impl From<ExternalLoginError> for HttpResponse {
+    fn from(value: ExternalLoginError) -> Self {
+        value.to_problem().into_response()
+    }
+}
+
+impl From<SessionError> for ExternalLoginError {
+    fn from(value: SessionError) -> Self {
+        match value {
+            SessionError::InternalServerError(source) => {
+                ExternalLoginError::InternalServerError(source)
+            }
+            SessionError::ServiceUnavailable(source) => {
+                ExternalLoginError::ServiceUnavailable(source)
+            }
+            other => ExternalLoginError::internal(other),
+        }
+    }
+}
+
+impl From<IdentityError> for ExternalLoginError {
+    fn from(value: IdentityError) -> Self {
+        match value {
+            IdentityError::InternalServerError(source) => {
+                ExternalLoginError::InternalServerError(source)
+            }
+            IdentityError::ServiceUnavailable(source) => {
+                ExternalLoginError::ServiceUnavailable(source)
+            }
+            IdentityError::UsernameAlreadyInUse => ExternalLoginError::IdentityConflict,
+            IdentityError::Rejected(e) => ExternalLoginError::Rejected(e),
+            other => ExternalLoginError::internal(other),
+        }
+    }
+}
+
+impl From<CredentialError> for ExternalLoginError {
+    fn from(value: CredentialError) -> Self {
+        match value {
+            CredentialError::InternalServerError(source) => {
+                ExternalLoginError::InternalServerError(source)
+            }
+            CredentialError::ServiceUnavailable(source) => {
+                ExternalLoginError::ServiceUnavailable(source)
+            }
+            other => ExternalLoginError::internal(other),
+        }
+    }
+}
+
+impl actix_web::error::ResponseError for ExternalLoginError {
+    fn status_code(&self) -> StatusCode {
+        self.to_problem().status_code()
+    }
+
+    fn error_response(&self) -> HttpResponse<actix_web::body::BoxBody> {
+        self.to_problem().into_response()
+    }
+}
+
+/// An OpenID Connect provider users can sign in with, e.g. Google or a corporate IdP.
+/// Its endpoints and keys are taken from the discovery document below `issuer`.
+#[derive(Clone)]
+pub struct ExternalIssuer {
+    name: String,
+    issuer: String,
+    client_id: String,
+    client_secret: Option<String>,
+    redirect_uri: String,
+    scopes: Vec<String>,
+    link_by_email: bool,
+}
+
+impl ExternalIssuer {
+    /// `name` is the path segment the issuer is reached under, `redirect_uri` the
+    /// URL of `session/external/{name}/callback` as registered at the issuer.
+    pub fn new(name: String, issuer: String, client_id: String, redirect_uri: String) -> Self {
+        Self {
+            name,
+            issuer,
+            client_id,
+            client_secret: None,
+            redirect_uri,
+            scopes: vec![
+                String::from("openid"),
+                String::from("email"),
+                String::from("profile"),
+            ],
+            link_by_email: false,
+        }
+    }
+
+    /// Authenticates at the token endpoint with HTTP Basic, public clients only use PKCE.
+    pub fn with_client_secret(mut self, client_secret: String) -> Self {
+        self.client_secret = Some(client_secret);
+        self
+    }
+
+    /// Replaces the default `openid email profile` scopes.
+    pub fn with_scopes(mut self, scopes: Vec<String>) -> Self {
+        self.scopes = scopes;
+        self
+    }
+
+    /// Links the first login of an account to the identity with the same email, if both
+    /// the issuer and [`ExternalLoginBackend::get_by_verified_email`] report it verified.
+    /// Only enable it for issuers that own the addresses they verify, otherwise accounts
+    /// have to be linked with `session/external/{name}/link`.
+    pub fn with_email_linking(mut self) -> Self {
+        self.link_by_email = true;
+        self
+    }
+}
+
+/// Server side state of a login between the redirect to the issuer and its callback.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ExternalLoginState {
+    /// Sent to the issuer as `state`.
+    pub id: String,
+    /// Name of the [`ExternalIssuer`].
+    pub issuer: String,
+    pub nonce: String,
+    pub code_verifier: String,
+    pub return_to: Option<String>,
+    /// The identity the account gets linked to, for links started by a logged in user.
+    #[serde(default)]
+    pub user_id: Option<String>,
+    pub expires_at: u64,
+}
+
+/// Where to send the browser to start a login, and the `state` to bind the login to it.
+pub struct ExternalAuthorization {
+    pub url: String,
+    pub state: String,
+}
+
+/// The result of a callback.
+pub struct ExternalLoginOutcome {
+    pub user_id: String,
+    /// The `return_to` path the login was started with.
+    pub return_to: Option<String>,
+    /// Whether the account was linked to the logged in identity instead of logged in with.
+    pub linked: bool,
+}
+
+/// The verified claims of an external account, handed to the app to create its identity.
+pub struct ExternalProfile {
+    pub issuer: String,
+    pub subject: String,
+    pub email: Option<String>,
+    pub email_verified: bool,
+    /// All claims of the ID token, e.g. `name` or `preferred_username`.
+    pub claims: Map<String, Value>,
+}
+
+#[derive(Deserialize)]
+pub struct ExternalLoginQuery {
+    return_to: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ExternalCallback {
+    pub code: Option<String>,
+    pub state: Option<String>,
+    pub error: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ExternalIssuerPath {
+    issuer: String,
+}
+
+/// The parts of an issuer's discovery document the login needs.
+#[derive(Clone, Deserialize)]
+struct IssuerMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Deserialize)]
+struct IssuerTokenResponse {
+    id_token: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct IssuerErrorResponse {
+    error: String,
+}
+
+#[derive(Deserialize)]
+struct ExternalClaims {
+    iss: String,
+    sub: String,
+    nonce: Option<String>,
+    #[serde(flatten)]
+    claims: Map<String, Value>,
+}
+
+/// Discovery documents and key sets of the issuers, with the time they were fetched.
+#[derive(Default)]
+struct IssuerCache {
+    metadata: RwLock<HashMap<String, (IssuerMetadata, u64)>>,
+    keys: RwLock<HashMap<String, (JwkSet, u64)>>,
+}
+
+/// Login with accounts of external OpenID Connect issuers. The first login of an account
+/// creates a new identity, or links it to the identity with the same verified email for
+/// issuers with [`ExternalIssuer::with_email_linking`].
+/// Logged in users can also link further accounts to their identity themselves.
+/// Links are stored as [`Credential`]s of kind [`CredentialKind::External`].
+#[derive(Clone)]
+pub struct ExternalLoginProvider<T>
+where
+    T: IntoPublic
+        + ObjectId
+        + Serialize
+        + for<'de> Deserialize<'de>
+        + Clone
+        + Send
+        + Sync
+        + 'static,
+{
+    external_path: String,
+    issuers: Arc<HashMap<String, ExternalIssuer>>,
+    backend: Data<Box<dyn ExternalLoginBackend<T>>>,
+    credential_backend: Data<Box<dyn CredentialBackend>>,
+    identity_backend: Data<Box<dyn IdentityBackend<T>>>,
+    new_identity: Arc<dyn Fn(&ExternalProfile) -> T + Send + Sync>,
+    username_policy: Option<UsernamePolicy>,
+    hooks: Option<Data<Box<dyn AuthHooks<T>>>>,
+    webhooks: Option<Data<WebhookDispatcher>>,
+    audit_sink: Option<Data<Box<dyn AuditSink>>>,
+    cache: Data<IssuerCache>,
+    client: reqwest::Client,
+}
+
+impl<
+    T: IntoPublic + ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
+> ExternalLoginProvider<T>
+{
+    /// `new_identity` builds the identity stored on the first login of an account that
+    /// isn't linked yet. It gets no password, so it logs in with the external account
+    /// until its user sets one.
+    pub fn default_with_backend(
+        backend: Data<Box<dyn ExternalLoginBackend<T>>>,
+        credential_backend: Data<Box<dyn CredentialBackend>>,
+        identity_backend: Data<Box<dyn IdentityBackend<T>>>,
+        issuers: Vec<ExternalIssuer>,
+        new_identity: impl Fn(&ExternalProfile) -> T + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            external_path: String::from("session/external"),
+            issuers: Arc::new(
+                issuers
+                    .into_iter()
+                    .map(|issuer| (issuer.name.clone(), issuer))
+                    .collect(),
+            ),
+            backend,
+            credential_backend,
+            identity_backend,
+            new_identity: Arc::new(new_identity),
+            username_policy: None,
+            hooks: None,
+            webhooks: None,
+            audit_sink: None,
+            cache: Data::new(IssuerCache::default()),
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(10))
+                .build()
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Normalizes the usernames of created identities.
+    pub fn with_username_policy(mut self, policy: UsernamePolicy) -> Self {
+        self.username_policy = Some(policy);
+        self
+    }
+
+    /// Runs the create hooks for identities created on a first login.
+    pub fn with_hooks(mut self, hooks: Data<Box<dyn AuthHooks<T>>>) -> Self {
+        self.hooks = Some(hooks);
+        self
+    }
+
+    /// Publishes identities created on a first login as webhook events.
+    pub fn with_webhooks(mut self, webhooks: Data<WebhookDispatcher>) -> Self {
+        self.webhooks = Some(webhooks);
+        self
+    }
+
+    /// Records logins via external accounts and failed callbacks.
+    pub fn with_audit_sink(mut self, sink: Data<Box<dyn AuditSink>>) -> Self {
+        self.audit_sink = Some(sink);
+        self
+    }
+
+    pub fn configure(&self, cfg: &mut ServiceConfig) {
+        let data = Data::new(self.clone());
+        cfg.app_data(data.clone())
+            .route(
+                &format!("{}/{{issuer}}", data.external_path),
+                get().to(start::<T>),
+            )
+            .route(
+                &format!("{}/{{issuer}}/link", data.external_path),
+                get().to(link::<T>),
+            )
+            .route(
+                &format!("{}/{{issuer}}/callback", data.external_path),
+                get().to(callback::<T>),
+            );
+    }
+
+    /// Starts a login and returns the authorization URL of the issuer to send the browser to.
+    /// `return_to` is where the browser ends up after the callback and has to be a local path.
+    /// The callback is only accepted together with the returned `state`, see
+    /// [`ExternalLoginProvider::finish`].
+    #[tracing::instrument(
+        name = "external.start",
+        skip_all,
+        fields(outcome = Empty, latency_ms = Empty)
+    )]
+    pub async fn start(
+        &self,
+        issuer: &str,
+        return_to: Option<String>,
+    ) -> Result<ExternalAuthorization, ExternalLoginError> {
+        let started = Instant::now();
+        let result = self.authorization_url(issuer, return_to, None).await;
+
+        record_outcome("external.start", &result, started);
+        result
+    }
+
+    /// Starts linking an account at the issuer to the identity `user_id` and returns the
+    /// authorization URL of the issuer. The callback has to arrive with the same session.
+    #[tracing::instrument(
+        name = "external.link",
+        skip_all,
+        fields(user_id = Empty, outcome = Empty, latency_ms = Empty)
+    )]
+    pub async fn link(
+        &self,
+        user_id: String,
+        issuer: &str,
+        return_to: Option<String>,
+    ) -> Result<ExternalAuthorization, ExternalLoginError> {
+        let started = Instant::now();
+        record_user_id(&user_id);
+        let result = self
+            .authorization_url(issuer, return_to, Some(user_id))
+            .await;
+
+        record_outcome("external.link", &result, started);
+        result
+    }
+
+    /// Finishes a login or link from the issuer's callback. `started_state` is the `state`
+    /// the browser started the login with, which has to match the callback's so nobody can
+    /// log a victim into their own account. `current_user` is the identity of the session
+    /// the callback arrived with, if any.
+    #[tracing::instrument(
+        name = "external.callback",
+        skip_all,
+        fields(user_id = Empty, outcome = Empty, latency_ms = Empty)
+    )]
+    pub async fn finish(
+        &self,
+        issuer: &str,
+        callback: ExternalCallback,
+        started_state: Option<String>,
+        current_user: Option<String>,
+    ) -> Result<ExternalLoginOutcome, ExternalLoginError> {
+        let started = Instant::now();
+        let result: Result<ExternalLoginOutcome, ExternalLoginError> = async {
+            let issuer = self
+                .issuers
+                .get(issuer)
+                .ok_or(ExternalLoginError::UnknownIssuer)?;
+            let Some(state_id) = callback.state else {
+                return Err(ExternalLoginError::InvalidState);
+            };
+            if started_state.is_none_or(|started_state| {
+                !verify_secret(&hash_token(&state_id), Some(&started_state))
+            }) {
+                return Err(ExternalLoginError::InvalidState);
+            }
+            let state =
+                observe_backend("take_login_state", self.backend.take_login_state(state_id))
+                    .await?
+                    .filter(|state| state.issuer == issuer.name && state.expires_at > unix_now())
+                    .ok_or(ExternalLoginError::InvalidState)?;
+            // A link finished in another browser would attach that browser's account
+            // to the identity that started it.
+            if state.user_id.is_some() && state.user_id != current_user {
+                return Err(ExternalLoginError::InvalidState);
+            }
+
+            if let Some(error) = callback.error {
+                return Err(ExternalLoginError::Denied(error));
+            }
+            let Some(code) = callback.code else {
+                return Err(ExternalLoginError::Denied(String::from("invalid_request")));
+            };
+
+            let metadata = self.metadata(issuer).await?;
+            let id_token = self.exchange_code(issuer, &metadata, &code, &state).await?;
+            let profile = self
+                .verify_id_token(issuer, &metadata, &id_token, &state)
+                .await?;
+
+            let (user_id, linked) = match state.user_id {
+                Some(user_id) => (self.link_account(profile, user_id).await?, true),
+                None => (
+                    self.resolve_identity(profile, issuer.link_by_email).await?,
+                    false,
+                ),
+            };
+            record_user_id(&user_id);
+            Ok(ExternalLoginOutcome {
+                user_id,
+                return_to: state.return_to,
+                linked,
+            })
+        }
+        .await;
+
+        record_outcome("external.callback", &result, started);
+        result
+    }
+
+    async fn authorization_url(
+        &self,
+        issuer: &str,
+        return_to: Option<String>,
+        user_id: Option<String>,
+    ) -> Result<ExternalAuthorization, ExternalLoginError> {
+        let issuer = self
+            .issuers
+            .get(issuer)
+            .ok_or(ExternalLoginError::UnknownIssuer)?;
+        if return_to
+            .as_deref()
+            .is_some_and(|path| !is_local_path(path))
+        {
+            return Err(ExternalLoginError::InvalidReturnTo);
+        }
+        let metadata = self.metadata(issuer).await?;
+
+        let state = ExternalLoginState {
+            id: random_token(32),
+            issuer: issuer.name.clone(),
+            nonce: random_token(32),
+            code_verifier: random_token(64),
+            return_to,
+            user_id,
+            expires_at: unix_now() + LOGIN_STATE_LIFETIME,
+        };
+        let code_challenge =
+            BASE64_URL_SAFE_NO_PAD.encode(Sha256::digest(state.code_verifier.as_bytes()));
+        let url = with_query(
+            &metadata.authorization_endpoint,
+            &[
+                ("response_type", "code"),
+                ("client_id", &issuer.client_id),
+                ("redirect_uri", &issuer.redirect_uri),
+                ("scope", &issuer.scopes.join(" ")),
+                ("state", &state.id),
+                ("nonce", &state.nonce),
+                ("code_challenge", &code_challenge),
+                ("code_challenge_method", "S256"),
+            ],
+        );
+
+        let state_id = state.id.clone();
+        observe_backend("save_login_state", self.backend.save_login_state(state)).await?;
+        Ok(ExternalAuthorization {
+            url,
+            state: state_id,
+        })
+    }
+
+    /// The discovery document of the issuer, fetched again once it is older than
+    /// [`METADATA_MAX_AGE`].
+    async fn metadata(
+        &self,
+        issuer: &ExternalIssuer,
+    ) -> Result<IssuerMetadata, ExternalLoginError> {
+        let cached = self
+            .cache
+            .metadata
+            .read()
+            .ok()
+            .and_then(|metadata| metadata.get(&issuer.name).cloned());
+        if let Some((metadata, fetched_at)) = cached
+            && fetched_at + METADATA_MAX_AGE > unix_now()
+        {
+            return Ok(metadata);
+        }
+
+        let url = format!(
+            "{}/.well-known/openid-configuration",
+            issuer.issuer.trim_end_matches('/')
+        );
+        let metadata: IssuerMetadata = self.fetch_json(&url).await?;
+        if metadata.issuer != issuer.issuer {
+            return Err(ExternalLoginError::internal(format!(
+                "discovery document of {} names the issuer {}",
+                issuer.issuer, metadata.issuer
+            )));
+        }
+
+        if let Ok(mut cache) = self.cache.metadata.write() {
+            cache.insert(issuer.name.clone(), (metadata.clone(), unix_now()));
+        }
+        Ok(metadata)
+    }
+
+    /// The key set of the issuer. It is fetched again if it doesn't contain `kid`, which
+    /// happens after the issuer rotated its keys, at most every [`JWKS_REFRESH_INTERVAL`].
+    async fn keys(
+        &self,
+        issuer: &ExternalIssuer,
+        metadata: &IssuerMetadata,
+        kid: Option<&str>,
+    ) -> Result<JwkSet, ExternalLoginError> {
+        let cached = self
+            .cache
+            .keys
+            .read()
+            .ok()
+            .and_then(|keys| keys.get(&issuer.name).cloned());
+        if let Some((keys, fetched_at)) = cached {
+            let known = kid.is_none_or(|kid| keys.find(kid).is_some());
+            if known || fetched_at + JWKS_REFRESH_INTERVAL > unix_now() {
+                return Ok(keys);
+            }
+        }
+
+        let keys: JwkSet = self.fetch_json(&metadata.jwks_uri).await?;
+        if let Ok(mut cache) = self.cache.keys.write() {
+            cache.insert(issuer.name.clone(), (keys.clone(), unix_now()));
+        }
+        Ok(keys)
+    }
+
+    async fn fetch_json<V: for<'de> Deserialize<'de>>(
+        &self,
+        url: &str,
+    ) -> Result<V, ExternalLoginError> {
+        let response = self
+            .client
+            .get(url)
+            .send()
+            .await
+            .map_err(ExternalLoginError::unavailable)?;
+        if !response.status().is_success() {
+            return Err(ExternalLoginError::unavailable(format!(
+                "{url} answered with {}",
+                response.status()
+            )));
+        }
+        response.json().await.map_err(ExternalLoginError::internal)
+    }
+
+    /// Redeems the authorization code at the issuer's token endpoint for an ID token.
+    async fn exchange_code(
+        &self,
+        issuer: &ExternalIssuer,
+        metadata: &IssuerMetadata,
+        code: &str,
+        state: &ExternalLoginState,
+    ) -> Result<String, ExternalLoginError> {
+        let mut request = self.client.post(&metadata.token_endpoint).form(&[
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", &issuer.redirect_uri),
+            ("code_verifier", &state.code_verifier),
+            ("client_id", &issuer.client_id),
+        ]);
+        if let Some(client_secret) = &issuer.client_secret {
+            // RFC 6749 section 2.3.1 form-encodes both parts before they are joined.
+            request = request.basic_auth(
+                url::form_urlencoded::byte_serialize(issuer.client_id.as_bytes())
+                    .collect::<String>(),
+                Some(
+                    url::form_urlencoded::byte_serialize(client_secret.as_bytes())
+                        .collect::<String>(),
+                ),
+            );
+        }
+
+        let response = request
+            .send()
+            .await
+            .map_err(ExternalLoginError::unavailable)?;
+        let status = response.status();
+        if status.is_client_error() {
+            let error = response
+                .json::<IssuerErrorResponse>()
+                .await
+                .map(|response| response.error)
+                .unwrap_or_else(|_| String::from("invalid_grant"));
+            return Err(ExternalLoginError::Denied(error));
+        }
+        if !status.is_success() {
+            return Err(ExternalLoginError::unavailable(format!(
+                "token endpoint answered with {status}"
+            )));
+        }
+
+        response
+            .json::<IssuerTokenResponse>()
+            .await
+            .map_err(ExternalLoginError::internal)?
+            .id_token
+            .ok_or(ExternalLoginError::InvalidIdToken)
+    }
+
+    /// Checks signature, issuer, audience, expiry and nonce of an ID token.
+    async fn verify_id_token(
+        &self,
+        issuer: &ExternalIssuer,
+        metadata: &IssuerMetadata,
+        id_token: &str,
+        state: &ExternalLoginState,
+    ) -> Result<ExternalProfile, ExternalLoginError> {
+        let header = decode_header(id_token).map_err(|_| ExternalLoginError::InvalidIdToken)?;
+        // Keys come from the issuer's key set, so shared secrets are never valid here.
+        if matches!(
+            header.alg,
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512
+        ) {
+            return Err(ExternalLoginError::InvalidIdToken);
+        }
+
+        let keys = self.keys(issuer, metadata, header.kid.as_deref()).await?;
+        let jwk = match &header.kid {
+            Some(kid) => keys.find(kid),
+            None if keys.keys.len() == 1 => keys.keys.first(),
+            None => None,
+        }
+        .ok_or(ExternalLoginError::InvalidIdToken)?;
+        let key = DecodingKey::from_jwk(jwk).map_err(|_| ExternalLoginError::InvalidIdToken)?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&metadata.issuer]);
+        validation.set_audience(&[&issuer.client_id]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+        let claims = jsonwebtoken::decode::<ExternalClaims>(id_token, &key, &validation)
+            .map_err(|_| ExternalLoginError::InvalidIdToken)?
+            .claims;
+
+        if !verify_secret(
+            claims.nonce.as_deref().unwrap_or_default(),
+            Some(&state.nonce),
+        ) {
+            return Err(ExternalLoginError::InvalidIdToken);
+        }
+
+        let email = claims
+            .claims
+            .get("email")
+            .and_then(Value::as_str)
+            .map(String::from);
+        // Some issuers send the flag as a string.
+        let email_verified = matches!(claims.claims.get("email_verified"), Some(Value::Bool(true)))
+            || matches!(claims.claims.get("email_verified"), Some(Value::String(verified)) if verified == "true");
+
+        Ok(ExternalProfile {
+            issuer: claims.iss,
+            subject: claims.sub,
+            email,
+            email_verified,
+            claims: claims.claims,
+        })
+    }
+
+    /// The local identity of an external account: the linked one, the one with the same
+    /// email verified on both sides if the issuer links by email, or else a newly created one.
+    async fn resolve_identity(
+        &self,
+        profile: ExternalProfile,
+        link_by_email: bool,
+    ) -> Result<String, ExternalLoginError> {
+        if let Some(linked) = self.find_link(&profile).await? {
+            return Ok(linked.user_id);
+        }
+
+        let existing = match (&profile.email, profile.email_verified && link_by_email) {
+            (Some(email), true) => {
+                let mut matches = observe_backend(
+                    "get_by_verified_email",
+                    self.backend.get_by_verified_email(email.to_lowercase()),
+                )
+                .await?
+                .into_iter()
+                .filter(|identity| identity.kind() == IdentityKind::Human);
+                let existing = matches.next();
+                if matches.next().is_some() {
+                    return Err(ExternalLoginError::AmbiguousEmail);
+                }
+                existing
+            }
+            _ => None,
+        };
+        let user_id = match existing {
+            Some(identity) => identity
+                .id()
+                .ok_or(ExternalLoginError::internal("identity without id"))?
+                .into(),
+            None => self.create_identity(&profile).await?,
+        };
+
+        self.save_link(profile, user_id.clone()).await?;
+        Ok(user_id)
+    }
+
+    /// Links the external account to `user_id`, unless another identity has it already.
+    async fn link_account(
+        &self,
+        profile: ExternalProfile,
+        user_id: String,
+    ) -> Result<String, ExternalLoginError> {
+        match self.find_link(&profile).await? {
+            Some(linked) if linked.user_id == user_id => {}
+            Some(_) => return Err(ExternalLoginError::AccountInUse),
+            None => self.save_link(profile, user_id.clone()).await?,
+        }
+        Ok(user_id)
+    }
+
+    async fn find_link(
+        &self,
+        profile: &ExternalProfile,
+    ) -> Result<Option<Credential>, ExternalLoginError> {
+        Ok(observe_backend(
+            "find_credential",
+            self.credential_backend.find_credential(
+                CredentialKind::External,
+                Some(profile.issuer.clone()),
+                profile.subject.clone(),
+            ),
+        )
+        .await?)
+    }
+
+    /// Stores the link under the `iss` of the ID tokens, which stays the same if the
+    /// issuer is renamed.
+    async fn save_link(
+        &self,
+        profile: ExternalProfile,
+        user_id: String,
+    ) -> Result<(), ExternalLoginError> {
+        let credential = Credential::new(user_id, CredentialKind::External, profile.subject)
+            .with_issuer(profile.issuer);
+        Ok(observe_backend(
+            "save_credential",
+            self.credential_backend.save_credential(credential),
+        )
+        .await?)
+    }
+
+    async fn create_identity(
+        &self,
+        profile: &ExternalProfile,
+    ) -> Result<String, ExternalLoginError> {
+        let mut identity = (self.new_identity)(profile);
+        if let Some(policy) = &self.username_policy {
+            let username = policy.normalize(&identity.username());
+            identity.set_username(username.clone());
+            if identity.username() != username {
+                return Err(ExternalLoginError::internal(
+                    "identity doesn't store the normalized username",
+                ));
+            }
+        }
+
+        let username = identity.username();
+        let taken = observe_backend(
+            "get_by_username",
+            self.identity_backend.get_by_username(username.clone()),
+        )
+        .await?;
+        if taken.is_some() {
+            return Err(ExternalLoginError::IdentityConflict);
+        }
+
+        if let Some(hooks) = &self.hooks {
+            hooks
+                .before_create(&identity)
+                .await
+                .map_err(ExternalLoginError::Rejected)?;
+        }
+        observe_backend("create", self.identity_backend.create(identity)).await?;
+
+        // Backends assign the id, so it is only known once the identity is read back.
+        let created = observe_backend(
+            "get_by_username",
+            self.identity_backend.get_by_username(username),
+        )
+        .await?
+        .ok_or(ExternalLoginError::internal("created identity not found"))?;
+        let user_id: String = created
+            .id()
+            .ok_or(ExternalLoginError::internal("identity without id"))?
+            .into();
+
+        if let Some(hooks) = &self.hooks {
+            hooks.after_create(&created).await;
+        }
+        if let Some(webhooks) = &self.webhooks {
+            let identity = serde_json::to_value(created.into_public()).ok();
+            if let Err(e) = webhooks
+                .enqueue(WebhookEvent::new(
+                    WebhookEventType::IdentityCreated,
+                    user_id.clone(),
+                    identity,
+                ))
+                .await
+            {
+                tracing::error!(error = %e, "failed to enqueue webhook event");
+            }
+        }
+        Ok(user_id)
+    }
+}
+
+/// Only paths on this site are accepted as `return_to`, so logins can't be used to
+/// redirect elsewhere. Browsers drop tabs and line breaks from URLs and read `\\` as `/`,
+/// so the path is resolved like they do and has to stay on the same origin.
+fn is_local_path(path: &str) -> bool {
+    if !path.starts_with('/') || path.chars().any(|c| c.is_control() || c.is_whitespace()) {
+        return false;
+    }
+
+    let Ok(base) = url::Url::parse("https://return-to.invalid/") else {
+        return false;
+    };
+    base.join(path)
+        .is_ok_and(|resolved| resolved.origin() == base.origin())
+}
+
+/// Sends the browser to the issuer, remembering the hash of the login's `state` in a cookie.
+fn authorization_redirect(authorization: ExternalAuthorization) -> HttpResponse {
+    let cookie = Cookie::build(LOGIN_STATE_COOKIE, hash_token(&authorization.state))
+        .path("/")
+        .http_only(true)
+        .secure(true)
+        .same_site(SameSite::Lax)
+        .max_age(CookieDuration::seconds(
+            i64::try_from(LOGIN_STATE_LIFETIME).unwrap_or(i64::MAX),
+        ))
+        .finish();
+
+    HttpResponse::Found()
+        .cookie(cookie)
+        .insert_header((LOCATION, authorization.url))
+        .finish()
+}
+
+async fn start<
+    T: IntoPublic + ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
+>(
+    external_login_provider: Data<ExternalLoginProvider<T>>,
+    path: Path<ExternalIssuerPath>,
+    query: Query<ExternalLoginQuery>,
+) -> Result<HttpResponse, ExternalLoginError> {
+    let authorization = external_login_provider
+        .start(&path.issuer, query.into_inner().return_to)
+        .await?;
+
+    Ok(authorization_redirect(authorization))
+}
+
+async fn link<
+    T: IntoPublic + ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
+>(
+    external_login_provider: Data<ExternalLoginProvider<T>>,
+    session: SessionRes<T>,
+    path: Path<ExternalIssuerPath>,
+    query: Query<ExternalLoginQuery>,
+) -> Result<HttpResponse, ExternalLoginError> {
+    let Some(user_id) = session.inner.id() else {
+        return Err(ExternalLoginError::internal("identity without id"));
+    };
+
+    let authorization = external_login_provider
+        .link(user_id.into(), &path.issuer, query.into_inner().return_to)
+        .await?;
+
+    Ok(authorization_redirect(authorization))
+}
+
+async fn callback<
+    T: IntoPublic + ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
+>(
+    req: HttpRequest,
+    external_login_provider: Data<ExternalLoginProvider<T>>,
+    session_provider: Data<SessionProvider<T>>,
+    session: Result<SessionRes<T>, SessionError>,
+    path: Path<ExternalIssuerPath>,
+    query: Query<ExternalCallback>,
+) -> Result<HttpResponse, ExternalLoginError> {
+    let current_user = session
+        .ok()
+        .and_then(|session| session.inner.id().map(String::from));
+    let started_state = req
+        .cookie(LOGIN_STATE_COOKIE)
+        .map(|cookie| cookie.value().to_string());
+    let result = external_login_provider
+        .finish(
+            &path.issuer,
+            query.into_inner(),
+            started_state,
+            current_user,
+        )
+        .await;
+
+    // Linking an account isn't a login.
+    if !result.as_ref().is_ok_and(|outcome| outcome.linked) {
+        let event = AuditEvent::new(AuditAction::Login, &AuditContext::from_request(&req))
+            .outcome_of(&result);
+        let event = match &result {
+            Ok(outcome) => event
+                .actor(outcome.user_id.clone())
+                .target(outcome.user_id.clone()),
+            Err(_) => event,
+        };
+        audit::record(&external_login_provider.audit_sink, event).await;
+    }
+
+    let outcome = result?;
+
+    let mut response = callback_response(&session_provider, outcome).await?;
+    let mut removal = Cookie::build(LOGIN_STATE_COOKIE, "").path("/").finish();
+    removal.make_removal();
+    response
+        .add_cookie(&removal)
+        .map_err(ExternalLoginError::internal)?;
+    Ok(response)
+}
+
+async fn callback_response<
+    T: IntoPublic + ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
+>(
+    session_provider: &SessionProvider<T>,
+    outcome: ExternalLoginOutcome,
+) -> Result<HttpResponse, ExternalLoginError> {
+    if outcome.linked {
+        return Ok(match outcome.return_to {
+            Some(return_to) => HttpResponse::Found()
+                .insert_header((LOCATION, return_to))
+                .finish(),
+            None => HttpResponse::NoContent().finish(),
+        });
+    }
+
+    let session = session_provider
+        .login_without_password(outcome.user_id)
+        .await?;
+    match outcome.return_to {
+        // Identities enrolled in MFA still have to send their code to `session/mfa`.
+        Some(return_to) if session.kind == SessionKind::MfaPending => Ok(HttpResponse::Found()
+            .cookie(session_cookie(session))
+            .insert_header((
+                LOCATION,
+                with_query(&return_to, &[("mfa_required", "true")]),
+            ))
+            .finish()),
+        // Tokens of the stateless mode can't travel along a redirect, so they are
+        // always answered as JSON.
+        Some(return_to) if session.kind != SessionKind::TokenFamily => Ok(HttpResponse::Found()
+            .cookie(session_cookie(session))
+            .insert_header((LOCATION, return_to))
+            .finish()),
+        _ => Ok(session_provider.grant(session).await?),
+    }
+}
+
+#[async_trait]
+pub trait ExternalLoginBackend<T: ObjectId + Serialize + for<'de> Deserialize<'de>>:
+    Send + Sync
+{
+    async fn save_login_state(&self, state: ExternalLoginState) -> Result<(), ExternalLoginError>;
+    /// Returns and removes a login state, so each callback can only be used once.
+    async fn take_login_state(
+        &self,
+        id: String,
+    ) -> Result<Option<ExternalLoginState>, ExternalLoginError>;
+    /// The identities whose email address is `email`, compared case-insensitively, and has
+    /// been verified by the app. External accounts of issuers with
+    /// [`ExternalIssuer::with_email_linking`] are linked to a single match.
+    async fn get_by_verified_email(&self, email: String) -> Result<Vec<T>, ExternalLoginError>;
+}
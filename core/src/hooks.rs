@@ -0,0 +1,100 @@
+use actix_web::{HttpResponse, http::StatusCode};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    ObjectId,
+    problem::{Problem, ToProblem},
+    session::Session,
+};
+
+/// Veto of a `before_*` hook. It is answered with its problem as is, so the
+/// status and code are up to the app.
+#[derive(Debug)]
+pub struct HookError {
+    problem: Problem,
+}
+
+impl HookError {
+    pub fn new(status: StatusCode, code: &str, title: &str) -> Self {
+        Self {
+            problem: Problem::new(status, code, title),
+        }
+    }
+
+    pub fn with_detail(mut self, detail: String) -> Self {
+        self.problem = self.problem.with_detail(detail);
+        self
+    }
+}
+
+impl From<Problem> for HookError {
+    fn from(problem: Problem) -> Self {
+        Self { problem }
+    }
+}
+
+impl std::fmt::Display for HookError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "rejected by hook: {}", self.problem.title)
+    }
+}
+
+impl std::error::Error for HookError {}
+
+impl ToProblem for HookError {
+    fn to_problem(&self) -> Problem {
+        self.problem.clone()
+    }
+}
+
+impl From<HookError> for HttpResponse {
+    fn from(value: HookError) -> Self {
+        value.to_problem().into_response()
+    }
+}
+
+impl actix_web::error::ResponseError for HookError {
+    fn status_code(&self) -> StatusCode {
+        self.to_problem().status_code()
+    }
+
+    fn error_response(&self) -> HttpResponse<actix_web::body::BoxBody> {
+        self.to_problem().into_response()
+    }
+}
+
+/// App code run around identity and session changes, e.g. to provision a workspace
+/// on sign up or clean up data on deletion. Every method defaults to doing nothing.
+///
+/// `before_*` hooks run right before the change is written and can veto it.
+/// `after_*` hooks run once the change is stored and are awaited before the response
+/// is sent, spawn a task for slow work. They can't fail the action, so they have to
+/// handle their own errors.
+#[async_trait]
+pub trait AuthHooks<T>: Send + Sync
+where
+    T: ObjectId + Serialize + for<'de> Deserialize<'de> + Send + Sync,
+{
+    /// Runs for registrations that passed the username and password policies, and for
+    /// identities created on the first external login of an account.
+    async fn before_create(&self, _identity: &T) -> Result<(), HookError> {
+        Ok(())
+    }
+
+    /// Receives the identity as stored, including its id.
+    async fn after_create(&self, _identity: &T) {}
+
+    async fn before_update(&self, _current: &T, _updated: &T) -> Result<(), HookError> {
+        Ok(())
+    }
+
+    async fn after_delete(&self, _identity: &T) {}
+
+    /// Runs for every full session issued, whether by password, second factor,
+    /// passkey, magic link or external issuer. Logins still waiting for a second factor
+    /// don't count.
+    async fn after_login(&self, _identity: &T, _session: &Session<T>) {}
+
+    async fn after_logout(&self, _session: &Session<T>) {}
+}
@@ -1,33 +1,294 @@
-use std::str::FromStr;
+use std::{str::FromStr, sync::Arc, time::Instant};
 
 use actix_web::{
-    HttpResponse, Responder,
+    HttpRequest, HttpResponse, Responder,
+    http::StatusCode,
     web::{Data, Json, Path, ServiceConfig, delete, get, post, put},
 };
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
+use tracing::field::Empty;
 use uuid::Uuid;
 
-use crate::{IntoPublic, ObjectId, session::SessionRes};
+use crate::{
+    IntoPublic, ObjectId,
+    api_key::{ApiKeyBackend, ApiKeyError},
+    audit::{self, AuditAction, AuditContext, AuditEvent, AuditSink},
+    credential::{Credential, CredentialBackend, CredentialError},
+    crypto::{hash_password, hash_token, random_token},
+    error::{BoxError, as_source, fmt_with_source},
+    hooks::{AuthHooks, HookError},
+    mfa::{MfaBackend, MfaError},
+    notifier::{Notification, Notifier},
+    oauth::{ClientBackend, OAuthClient, OAuthError, is_public_key},
+    password::{PasswordPolicy, PasswordViolation},
+    problem::{FieldError, Problem, ToProblem},
+    session::{SessionBackend, SessionError, SessionRes},
+    telemetry::{observe_backend, record_outcome, record_user_id},
+    unix_now,
+    username::{UsernamePolicy, UsernameViolation},
+    webhook::{WebhookDispatcher, WebhookEvent, WebhookEventType},
+};
 
+#[derive(Debug)]
 pub enum IdentityError {
     NotFound,
-    InternalServerError,
-    ServiceUnavailable,
+    InternalServerError(Option<BoxError>),
+    ServiceUnavailable(Option<BoxError>),
     Unauthorized,
     InvalidId,
     UsernameAlreadyInUse,
+    InvalidUsername(Vec<UsernameViolation>),
+    InvalidPassword(Vec<PasswordViolation>),
+    /// Vetoed by a `before_*` hook of [`AuthHooks`].
+    Rejected(HookError),
+    /// Not allowed to manage service accounts.
+    Forbidden,
+    /// The public key of a service account isn't an RSA, EC or Ed25519 key in PEM format.
+    InvalidPublicKey,
+}
+
+impl std::fmt::Display for IdentityError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IdentityError::NotFound => write!(f, "identity not found"),
+            IdentityError::InternalServerError(source) => {
+                fmt_with_source(f, "internal server error", source)
+            }
+            IdentityError::ServiceUnavailable(source) => {
+                fmt_with_source(f, "service unavailable", source)
+            }
+            IdentityError::Unauthorized => write!(f, "not allowed to access this identity"),
+            IdentityError::InvalidId => write!(f, "invalid identity id"),
+            IdentityError::UsernameAlreadyInUse => write!(f, "username already in use"),
+            IdentityError::InvalidUsername(_) => {
+                write!(f, "username doesn't meet the requirements")
+            }
+            IdentityError::InvalidPassword(_) => {
+                write!(f, "password doesn't meet the requirements")
+            }
+            IdentityError::Rejected(e) => write!(f, "{e}"),
+            IdentityError::Forbidden => write!(f, "not allowed to manage service accounts"),
+            IdentityError::InvalidPublicKey => write!(f, "invalid public key"),
+        }
+    }
+}
+
+impl std::error::Error for IdentityError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            IdentityError::InternalServerError(source)
+            | IdentityError::ServiceUnavailable(source) => as_source(source),
+            IdentityError::Rejected(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl IdentityError {
+    pub fn internal(source: impl Into<BoxError>) -> Self {
+        IdentityError::InternalServerError(Some(source.into()))
+    }
+
+    /// For failures that are likely to go away on retry, like a lost database connection.
+    pub fn unavailable(source: impl Into<BoxError>) -> Self {
+        IdentityError::ServiceUnavailable(Some(source.into()))
+    }
+}
+
+impl ToProblem for IdentityError {
+    fn to_problem(&self) -> Problem {
+        match self {
+            IdentityError::InternalServerError(_) => Problem::internal_server_error(),
+            IdentityError::ServiceUnavailable(_) => Problem::service_unavailable(),
+            IdentityError::NotFound => Problem::new(
+                StatusCode::NOT_FOUND,
+                "identity_not_found",
+                "Identity not found",
+            ),
+            IdentityError::Unauthorized => Problem::new(
+                StatusCode::UNAUTHORIZED,
+                "unauthorized",
+                "Not allowed to access this identity",
+            ),
+            IdentityError::InvalidId => {
+                Problem::new(StatusCode::BAD_REQUEST, "invalid_id", "Invalid identity id")
+            }
+            IdentityError::UsernameAlreadyInUse => Problem::new(
+                StatusCode::CONFLICT,
+                "username_already_in_use",
+                "Username already in use",
+            )
+            .with_errors(vec![FieldError::new("username", "already_in_use")]),
+            IdentityError::InvalidUsername(violations) => Problem::new(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "invalid_username",
+                "Username doesn't meet the requirements",
+            )
+            .with_errors(
+                violations
+                    .iter()
+                    .map(|violation| FieldError::from_violation("username", violation))
+                    .collect(),
+            ),
+            IdentityError::InvalidPassword(violations) => Problem::new(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "invalid_password",
+                "Password doesn't meet the requirements",
+            )
+            .with_errors(
+                violations
+                    .iter()
+                    .map(|violation| FieldError::from_violation("password", violation))
+                    .collect(),
+            ),
+            IdentityError::Rejected(e) => e.to_problem(),
+            IdentityError::Forbidden => Problem::new(
+                StatusCode::FORBIDDEN,
+                "forbidden",
+                "Not allowed to manage service accounts",
+            ),
+            IdentityError::InvalidPublicKey => Problem::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_public_key",
+                "Public key has to be an RSA, EC or Ed25519 key in PEM format",
+            ),
+        }
+    }
 }
 
 impl From<IdentityError> for HttpResponse {
     fn from(value: IdentityError) -> Self {
+        value.to_problem().into_response()
+    }
+}
+
+impl From<ApiKeyError> for IdentityError {
+    fn from(value: ApiKeyError) -> Self {
         match value {
-            IdentityError::InternalServerError => HttpResponse::InternalServerError().finish(),
-            IdentityError::NotFound => HttpResponse::NotFound().finish(),
-            IdentityError::ServiceUnavailable => HttpResponse::ServiceUnavailable().finish(),
-            IdentityError::Unauthorized => HttpResponse::Unauthorized().finish(),
-            IdentityError::InvalidId => HttpResponse::BadRequest().finish(),
-            IdentityError::UsernameAlreadyInUse => HttpResponse::Conflict().finish(),
+            ApiKeyError::InternalServerError(source) => IdentityError::InternalServerError(source),
+            ApiKeyError::ServiceUnavailable(source) => IdentityError::ServiceUnavailable(source),
+            other => IdentityError::internal(other),
+        }
+    }
+}
+
+impl From<CredentialError> for IdentityError {
+    fn from(value: CredentialError) -> Self {
+        match value {
+            CredentialError::InternalServerError(source) => {
+                IdentityError::InternalServerError(source)
+            }
+            CredentialError::ServiceUnavailable(source) => {
+                IdentityError::ServiceUnavailable(source)
+            }
+            CredentialError::InvalidPassword(violations) => {
+                IdentityError::InvalidPassword(violations)
+            }
+            other => IdentityError::internal(other),
+        }
+    }
+}
+
+impl From<SessionError> for IdentityError {
+    fn from(value: SessionError) -> Self {
+        match value {
+            SessionError::InternalServerError(source) => IdentityError::InternalServerError(source),
+            SessionError::ServiceUnavailable(source) => IdentityError::ServiceUnavailable(source),
+            other => IdentityError::internal(other),
+        }
+    }
+}
+
+impl From<MfaError> for IdentityError {
+    fn from(value: MfaError) -> Self {
+        match value {
+            MfaError::InternalServerError(source) => IdentityError::InternalServerError(source),
+            MfaError::ServiceUnavailable(source) => IdentityError::ServiceUnavailable(source),
+            other => IdentityError::internal(other),
+        }
+    }
+}
+
+impl From<OAuthError> for IdentityError {
+    fn from(value: OAuthError) -> Self {
+        match value {
+            OAuthError::InternalServerError(source) => IdentityError::InternalServerError(source),
+            OAuthError::ServiceUnavailable(source) => IdentityError::ServiceUnavailable(source),
+            other => IdentityError::internal(other),
+        }
+    }
+}
+
+impl actix_web::error::ResponseError for IdentityError {
+    fn status_code(&self) -> StatusCode {
+        self.to_problem().status_code()
+    }
+
+    fn error_response(&self) -> HttpResponse<actix_web::body::BoxBody> {
+        self.to_problem().into_response()
+    }
+}
+
+/// Body of `POST identity`: the fields of the identity and the password it logs in with,
+/// which is stored as a [`Credential`].
+#[derive(Deserialize)]
+pub struct Registration<T> {
+    #[serde(flatten)]
+    pub identity: T,
+    pub password: String,
+}
+
+/// Whether an identity is a person or a service account. Service accounts have no
+/// password, can't log in and authenticate as OAuth clients instead.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum IdentityKind {
+    #[default]
+    Human,
+    Service,
+}
+
+/// Body of `POST service-accounts`.
+#[derive(Deserialize)]
+pub struct ServiceAccountRegistration<T> {
+    #[serde(flatten)]
+    pub identity: T,
+    /// Scopes the account may request with the `client_credentials` grant.
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    /// PEM public key for signed JWT assertions. Without one the account gets a secret.
+    #[serde(default)]
+    pub public_key: Option<String>,
+}
+
+/// A service account. Its id is the client id it authenticates with at `oauth/token`.
+#[derive(Serialize, Deserialize)]
+pub struct ServiceAccountResponse {
+    pub id: String,
+    pub username: String,
+    pub scopes: Vec<String>,
+    /// `client_secret_basic` or `private_key_jwt`.
+    pub token_endpoint_auth_method: String,
+    /// Only returned on creation, only its hash is stored.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_secret: Option<String>,
+    pub created_at: u64,
+}
+
+impl From<OAuthClient> for ServiceAccountResponse {
+    fn from(client: OAuthClient) -> Self {
+        let token_endpoint_auth_method = match client.public_key {
+            Some(_) => "private_key_jwt",
+            None => "client_secret_basic",
+        };
+        Self {
+            id: client.client_id,
+            username: client.name,
+            scopes: client.scopes,
+            token_endpoint_auth_method: token_endpoint_auth_method.into(),
+            client_secret: None,
+            created_at: client.created_at,
         }
     }
 }
@@ -37,6 +298,13 @@ pub struct IdentityGetPath {
     id: String,
 }
 
+/// What managing service accounts needs, set by [`IdentityProvider::with_service_accounts`].
+#[derive(Clone)]
+struct ServiceAccounts<T> {
+    client_backend: Data<Box<dyn ClientBackend>>,
+    is_admin: Arc<dyn Fn(&T) -> bool + Send + Sync>,
+}
+
 #[derive(Clone)]
 pub struct IdentityProvider<T>
 where
@@ -50,20 +318,115 @@ where
         + 'static,
 {
     identity_base_path: String,
+    service_account_path: String,
     backend: Data<Box<dyn IdentityBackend<T>>>,
+    credential_backend: Data<Box<dyn CredentialBackend>>,
+    api_key_backend: Option<Data<Box<dyn ApiKeyBackend>>>,
+    session_backend: Option<Data<Box<dyn SessionBackend<T>>>>,
+    mfa_backend: Option<Data<Box<dyn MfaBackend<T>>>>,
+    duplicate_notifier: Option<Data<Box<dyn Notifier<T>>>>,
+    password_policy: Option<PasswordPolicy>,
+    username_policy: Option<UsernamePolicy>,
+    audit_sink: Option<Data<Box<dyn AuditSink>>>,
+    hooks: Option<Data<Box<dyn AuthHooks<T>>>>,
+    webhooks: Option<Data<WebhookDispatcher>>,
+    service_accounts: Option<ServiceAccounts<T>>,
 }
 
 impl<
     T: IntoPublic + ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
 > IdentityProvider<T>
 {
-    pub fn default_with_backend(backend: Data<Box<dyn IdentityBackend<T>>>) -> Self {
+    pub fn default_with_backend(
+        backend: Data<Box<dyn IdentityBackend<T>>>,
+        credential_backend: Data<Box<dyn CredentialBackend>>,
+    ) -> Self {
         Self {
             identity_base_path: String::from("identity"),
+            service_account_path: String::from("service-accounts"),
             backend,
+            credential_backend,
+            api_key_backend: None,
+            session_backend: None,
+            mfa_backend: None,
+            duplicate_notifier: None,
+            password_policy: None,
+            username_policy: None,
+            audit_sink: None,
+            hooks: None,
+            webhooks: None,
+            service_accounts: None,
         }
     }
 
+    /// Stops `create` from revealing taken usernames. Registrations for an existing
+    /// username are answered like successful ones and the owner is notified instead.
+    pub fn with_duplicate_notifier(mut self, notifier: Data<Box<dyn Notifier<T>>>) -> Self {
+        self.duplicate_notifier = Some(notifier);
+        self
+    }
+
+    pub fn with_password_policy(mut self, policy: PasswordPolicy) -> Self {
+        self.password_policy = Some(policy);
+        self
+    }
+
+    /// Normalizes usernames before they are stored and rejects ones that break the policy.
+    pub fn with_username_policy(mut self, policy: UsernamePolicy) -> Self {
+        self.username_policy = Some(policy);
+        self
+    }
+
+    /// Records identity creations, updates, password changes and deletions.
+    pub fn with_audit_sink(mut self, sink: Data<Box<dyn AuditSink>>) -> Self {
+        self.audit_sink = Some(sink);
+        self
+    }
+
+    /// Removes the API keys of deleted identities.
+    pub fn with_api_key_backend(mut self, backend: Data<Box<dyn ApiKeyBackend>>) -> Self {
+        self.api_key_backend = Some(backend);
+        self
+    }
+
+    /// Logs deleted identities out everywhere.
+    pub fn with_session_backend(mut self, backend: Data<Box<dyn SessionBackend<T>>>) -> Self {
+        self.session_backend = Some(backend);
+        self
+    }
+
+    /// Removes the second factor and recovery codes of deleted identities.
+    pub fn with_mfa_backend(mut self, backend: Data<Box<dyn MfaBackend<T>>>) -> Self {
+        self.mfa_backend = Some(backend);
+        self
+    }
+
+    /// Runs the create, update and delete hooks.
+    pub fn with_hooks(mut self, hooks: Data<Box<dyn AuthHooks<T>>>) -> Self {
+        self.hooks = Some(hooks);
+        self
+    }
+
+    /// Publishes identity creations and deletions as webhook events.
+    pub fn with_webhooks(mut self, webhooks: Data<WebhookDispatcher>) -> Self {
+        self.webhooks = Some(webhooks);
+        self
+    }
+
+    /// Lets identities accepted by `is_admin` manage service accounts at `service-accounts`,
+    /// each stored with an OAuth client of the same id.
+    pub fn with_service_accounts(
+        mut self,
+        client_backend: Data<Box<dyn ClientBackend>>,
+        is_admin: impl Fn(&T) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.service_accounts = Some(ServiceAccounts {
+            client_backend,
+            is_admin: Arc::new(is_admin),
+        });
+        self
+    }
+
     pub fn configure(&self, cfg: &mut ServiceConfig) {
         let data = Data::new(self.clone());
         cfg.app_data(data.clone())
@@ -81,31 +444,417 @@ impl<
                 &format!("{}/{{id}}", data.identity_base_path),
                 delete().to(delete_by_id::<T>),
             );
+
+        if data.service_accounts.is_some() {
+            cfg.route(
+                &data.service_account_path,
+                post().to(create_service_account::<T>),
+            )
+            .route(
+                &data.service_account_path,
+                get().to(get_service_accounts::<T>),
+            )
+            .route(
+                &format!("{}/{{id}}", data.service_account_path),
+                delete().to(delete_service_account::<T>),
+            );
+        }
     }
 
+    /// All human identities, service accounts are listed by [`Self::get_service_accounts`].
+    #[tracing::instrument(
+        name = "identity.get_all",
+        skip_all,
+        fields(outcome = Empty, latency_ms = Empty)
+    )]
     pub async fn get_all(&self) -> Result<Vec<T>, IdentityError> {
-        self.backend.get_all().await
+        let started = Instant::now();
+        let result = observe_backend("get_all", self.backend.get_all())
+            .await
+            .map(|identities| {
+                identities
+                    .into_iter()
+                    .filter(|identity| identity.kind() == IdentityKind::Human)
+                    .collect()
+            });
+
+        record_outcome("identity.get_all", &result, started);
+        result
     }
 
-    pub async fn create(&self, identity: T) -> Result<(), IdentityError> {
-        let by_username = self.backend.get_by_username(identity.username()).await?;
-        if by_username.is_some() {
-            return Err(IdentityError::UsernameAlreadyInUse);
+    #[tracing::instrument(
+        name = "identity.create",
+        skip_all,
+        fields(outcome = Empty, latency_ms = Empty)
+    )]
+    pub async fn create(&self, identity: T, password: String) -> Result<(), IdentityError> {
+        let started = Instant::now();
+        let result: Result<(), IdentityError> = async {
+            let mut identity = self
+                .check_username(identity)
+                .map_err(IdentityError::InvalidUsername)?;
+            // Sign ups are people, service accounts are created by admins.
+            identity.set_kind(IdentityKind::Human);
+            self.check_password(&identity.username(), &password).await?;
+
+            let by_username = observe_backend(
+                "get_by_username",
+                self.backend.get_by_username(identity.username()),
+            )
+            .await?;
+            if let Some(existing) = by_username {
+                let Some(notifier) = &self.duplicate_notifier else {
+                    return Err(IdentityError::UsernameAlreadyInUse);
+                };
+
+                // Hash the password like a new account would, so the response time doesn't
+                // reveal that the username is taken either.
+                let _ = hash_password(&password);
+
+                // Delivery failures can't be reported without revealing that the username is taken.
+                let _ = notifier
+                    .notify(existing, Notification::DuplicateRegistration)
+                    .await;
+                return Ok(());
+            }
+
+            if let Some(hooks) = &self.hooks {
+                hooks
+                    .before_create(&identity)
+                    .await
+                    .map_err(IdentityError::Rejected)?;
+            }
+
+            let username = identity.username();
+            observe_backend("create", self.backend.create(identity)).await?;
+
+            // Backends assign the id, so the password and hooks need the identity as stored.
+            let created =
+                observe_backend("get_by_username", self.backend.get_by_username(username))
+                    .await?
+                    .ok_or(IdentityError::internal("created identity not found"))?;
+            let Some(id) = created.id().map(String::from) else {
+                return Err(IdentityError::internal("identity without id"));
+            };
+
+            let saved = match Credential::password(id.clone(), &password) {
+                Ok(credential) => {
+                    observe_backend(
+                        "save_credential",
+                        self.credential_backend.save_credential(credential),
+                    )
+                    .await
+                }
+                Err(e) => Err(e),
+            };
+            if let Err(e) = saved {
+                // Without its password nobody could log in as the identity or register it again.
+                if let Err(e) = observe_backend("delete_by_id", self.backend.delete_by_id(id)).await
+                {
+                    tracing::error!(error = %e, "failed to remove identity without password");
+                }
+                return Err(e.into());
+            }
+
+            if let Some(hooks) = &self.hooks {
+                hooks.after_create(&created).await;
+            }
+            self.publish(WebhookEventType::IdentityCreated, id, &created)
+                .await;
+            Ok(())
         }
+        .await;
 
-        self.backend.create(identity).await
+        record_outcome("identity.create", &result, started);
+        result
     }
 
+    #[tracing::instrument(
+        name = "identity.get_by_id",
+        skip_all,
+        fields(user_id = Empty, outcome = Empty, latency_ms = Empty)
+    )]
     pub async fn get_by_id(&self, id: String) -> Result<T, IdentityError> {
-        self.backend.get_by_id(id).await
+        record_user_id(&id);
+        let started = Instant::now();
+        let result = observe_backend("get_by_id", self.backend.get_by_id(id)).await;
+
+        record_outcome("identity.get_by_id", &result, started);
+        result
     }
 
+    #[tracing::instrument(
+        name = "identity.update",
+        skip_all,
+        fields(user_id = Empty, outcome = Empty, latency_ms = Empty)
+    )]
     pub async fn update(&self, id: String, identity: T) -> Result<(), IdentityError> {
-        self.backend.update_by_id(id, identity).await
+        record_user_id(&id);
+        let started = Instant::now();
+        let result: Result<(), IdentityError> = async {
+            let mut identity = self
+                .check_username(identity)
+                .map_err(IdentityError::InvalidUsername)?;
+            let current = observe_backend("get_by_id", self.backend.get_by_id(id.clone())).await?;
+            identity.set_kind(current.kind());
+
+            // Backends should still enforce uniqueness, this check can race with concurrent writes.
+            let by_username = observe_backend(
+                "get_by_username",
+                self.backend.get_by_username(identity.username()),
+            )
+            .await?;
+            if let Some(existing) = by_username
+                && existing.id() != Uuid::from_str(&id).ok()
+            {
+                return Err(IdentityError::UsernameAlreadyInUse);
+            }
+
+            if let Some(hooks) = &self.hooks {
+                hooks
+                    .before_update(&current, &identity)
+                    .await
+                    .map_err(IdentityError::Rejected)?;
+            }
+
+            observe_backend("update_by_id", self.backend.update_by_id(id, identity)).await
+        }
+        .await;
+
+        record_outcome("identity.update", &result, started);
+        result
     }
 
+    #[tracing::instrument(
+        name = "identity.delete",
+        skip_all,
+        fields(user_id = Empty, outcome = Empty, latency_ms = Empty)
+    )]
     pub async fn delete(&self, id: String) -> Result<(), IdentityError> {
-        self.backend.delete_by_id(id).await
+        record_user_id(&id);
+        let started = Instant::now();
+        let result: Result<(), IdentityError> = async {
+            let deleted = match self.hooks.is_some()
+                || self.webhooks.is_some()
+                || self.service_accounts.is_some()
+            {
+                true => {
+                    Some(observe_backend("get_by_id", self.backend.get_by_id(id.clone())).await?)
+                }
+                false => None,
+            };
+            observe_backend("delete_by_id", self.backend.delete_by_id(id.clone())).await?;
+            observe_backend(
+                "delete_credentials",
+                self.credential_backend.delete_credentials(id.clone()),
+            )
+            .await?;
+            if let Some(api_key_backend) = &self.api_key_backend {
+                observe_backend(
+                    "delete_api_keys",
+                    api_key_backend.delete_api_keys(id.clone()),
+                )
+                .await?;
+            }
+            if let Some(mfa_backend) = &self.mfa_backend {
+                observe_backend("delete_mfa", mfa_backend.delete_mfa(id.clone())).await?;
+            }
+            if let Some(session_backend) = &self.session_backend {
+                observe_backend(
+                    "delete_sessions",
+                    session_backend.delete_sessions(id.clone(), None),
+                )
+                .await?;
+            }
+            if let (Some(service_accounts), Some(deleted)) = (&self.service_accounts, &deleted)
+                && deleted.kind() == IdentityKind::Service
+            {
+                observe_backend(
+                    "delete_client",
+                    service_accounts.client_backend.delete_client(id.clone()),
+                )
+                .await?;
+            }
+
+            if let Some(deleted) = deleted {
+                if let Some(hooks) = &self.hooks {
+                    hooks.after_delete(&deleted).await;
+                }
+                self.publish(WebhookEventType::IdentityDeleted, id, &deleted)
+                    .await;
+            }
+            Ok(())
+        }
+        .await;
+
+        record_outcome("identity.delete", &result, started);
+        result
+    }
+
+    /// Creates a service account and the OAuth client it authenticates with. The secret,
+    /// issued unless the account has a public key, is only part of the response.
+    #[tracing::instrument(
+        name = "identity.create_service_account",
+        skip_all,
+        fields(user_id = Empty, outcome = Empty, latency_ms = Empty)
+    )]
+    pub async fn create_service_account(
+        &self,
+        registration: ServiceAccountRegistration<T>,
+    ) -> Result<ServiceAccountResponse, IdentityError> {
+        let started = Instant::now();
+        let result: Result<ServiceAccountResponse, IdentityError> = async {
+            let Some(service_accounts) = &self.service_accounts else {
+                return Err(IdentityError::internal("service accounts are not enabled"));
+            };
+            let mut identity = self
+                .check_username(registration.identity)
+                .map_err(IdentityError::InvalidUsername)?;
+            identity.set_kind(IdentityKind::Service);
+            if identity.kind() != IdentityKind::Service {
+                return Err(IdentityError::internal(
+                    "identity type doesn't store its kind",
+                ));
+            }
+            if let Some(public_key) = &registration.public_key
+                && !is_public_key(public_key)
+            {
+                return Err(IdentityError::InvalidPublicKey);
+            }
+
+            let username = identity.username();
+            if observe_backend(
+                "get_by_username",
+                self.backend.get_by_username(username.clone()),
+            )
+            .await?
+            .is_some()
+            {
+                return Err(IdentityError::UsernameAlreadyInUse);
+            }
+            observe_backend("create", self.backend.create(identity)).await?;
+
+            let created = observe_backend(
+                "get_by_username",
+                self.backend.get_by_username(username.clone()),
+            )
+            .await?
+            .ok_or(IdentityError::internal("created identity not found"))?;
+            let Some(id) = created.id().map(String::from) else {
+                return Err(IdentityError::internal("identity without id"));
+            };
+            record_user_id(&id);
+
+            let client_secret = registration.public_key.is_none().then(|| random_token(48));
+            let client = OAuthClient {
+                client_id: id.clone(),
+                name: username,
+                secret_hash: client_secret.as_deref().map(hash_token),
+                redirect_uris: Vec::new(),
+                scopes: registration.scopes,
+                first_party: false,
+                post_logout_redirect_uris: Vec::new(),
+                service_account: true,
+                public_key: registration.public_key,
+                created_at: unix_now(),
+            };
+            if let Err(e) = observe_backend(
+                "create_client",
+                service_accounts
+                    .client_backend
+                    .create_client(client.clone()),
+            )
+            .await
+            {
+                // Without its client the account couldn't authenticate or be created again.
+                if let Err(e) = observe_backend("delete_by_id", self.backend.delete_by_id(id)).await
+                {
+                    tracing::error!(error = %e, "failed to remove service account without client");
+                }
+                return Err(e.into());
+            }
+
+            Ok(ServiceAccountResponse {
+                client_secret,
+                ..client.into()
+            })
+        }
+        .await;
+
+        record_outcome("identity.create_service_account", &result, started);
+        result
+    }
+
+    pub async fn get_service_accounts(&self) -> Result<Vec<ServiceAccountResponse>, IdentityError> {
+        let Some(service_accounts) = &self.service_accounts else {
+            return Err(IdentityError::internal("service accounts are not enabled"));
+        };
+
+        let clients =
+            observe_backend("get_clients", service_accounts.client_backend.get_clients()).await?;
+        Ok(clients
+            .into_iter()
+            .filter(|client| client.service_account)
+            .map(ServiceAccountResponse::from)
+            .collect())
+    }
+
+    /// Deletes a service account along with its client. Access tokens it holds stay valid
+    /// until they expire.
+    pub async fn delete_service_account(&self, id: String) -> Result<(), IdentityError> {
+        let identity = observe_backend("get_by_id", self.backend.get_by_id(id.clone())).await?;
+        if identity.kind() != IdentityKind::Service {
+            return Err(IdentityError::NotFound);
+        }
+
+        self.delete(id).await
+    }
+
+    fn is_admin(&self, identity: &T) -> bool {
+        self.service_accounts
+            .as_ref()
+            .is_some_and(|service_accounts| (service_accounts.is_admin)(identity))
+    }
+
+    /// Enqueues a webhook event once the change is stored. The outbox isn't written in the
+    /// same transaction, so like `after_*` hooks failures don't fail the change, the event
+    /// is logged and dropped instead.
+    async fn publish(&self, event_type: WebhookEventType, user_id: String, identity: &T) {
+        let Some(webhooks) = &self.webhooks else {
+            return;
+        };
+
+        let identity = serde_json::to_value(identity.clone().into_public()).ok();
+        if let Err(e) = webhooks
+            .enqueue(WebhookEvent::new(event_type, user_id, identity))
+            .await
+        {
+            tracing::error!(error = %e, "failed to enqueue webhook event");
+        }
+    }
+
+    /// Normalizes the username and checks it against the policy.
+    fn check_username(&self, mut identity: T) -> Result<T, Vec<UsernameViolation>> {
+        if let Some(policy) = &self.username_policy {
+            let username = policy.validate(&identity.username())?;
+            identity.set_username(username.clone());
+            if identity.username() != username {
+                return Err(vec![UsernameViolation::NotNormalized]);
+            }
+        }
+
+        Ok(identity)
+    }
+
+    async fn check_password(&self, username: &str, password: &str) -> Result<(), IdentityError> {
+        if let Some(policy) = &self.password_policy {
+            let violations = policy.check(username, password).await?;
+            if !violations.is_empty() {
+                return Err(IdentityError::InvalidPassword(violations));
+            }
+        }
+
+        Ok(())
     }
 }
 
@@ -128,10 +877,30 @@ async fn get_all<
 async fn create<
     T: IntoPublic + ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
 >(
+    req: HttpRequest,
     identity_provider: Data<IdentityProvider<T>>,
-    identity: Json<T>,
+    registration: Json<Registration<T>>,
 ) -> impl Responder {
-    match identity_provider.create(identity.0).await {
+    let registration = registration.into_inner();
+    let username = registration.identity.username();
+    let result = identity_provider
+        .create(registration.identity, registration.password)
+        .await;
+    audit::record(
+        &identity_provider.audit_sink,
+        AuditEvent::new(
+            AuditAction::IdentityCreate,
+            &AuditContext::from_request(&req),
+        )
+        .target(username)
+        .outcome_of(&result),
+    )
+    .await;
+
+    match result {
+        Ok(_) if identity_provider.duplicate_notifier.is_some() => {
+            HttpResponse::Accepted().finish()
+        }
         Ok(_) => HttpResponse::Created().finish(),
         Err(e) => e.into(),
     }
@@ -152,16 +921,31 @@ async fn get_by_id<
 async fn update_by_id<
     T: IntoPublic + ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
 >(
+    req: HttpRequest,
     identity_provider: Data<IdentityProvider<T>>,
     path: Path<IdentityGetPath>,
     identity: Json<T>,
     session: SessionRes<T>,
 ) -> impl Responder {
-    if session.inner.id() != Uuid::from_str(&path.id).ok() {
-        return HttpResponse::Unauthorized().finish();
-    }
+    let context = AuditContext::from_request(&req);
+    let actor = session.inner.id().map(String::from).unwrap_or_default();
+
+    let result = if session.inner.id() != Uuid::from_str(&path.id).ok() {
+        Err(IdentityError::Unauthorized)
+    } else {
+        identity_provider.update(path.id.clone(), identity.0).await
+    };
+
+    audit::record(
+        &identity_provider.audit_sink,
+        AuditEvent::new(AuditAction::IdentityUpdate, &context)
+            .actor(actor)
+            .target(path.id.clone())
+            .outcome_of(&result),
+    )
+    .await;
 
-    match identity_provider.update(path.id.clone(), identity.0).await {
+    match result {
         Ok(_) => HttpResponse::Ok().finish(),
         Err(e) => e.into(),
     }
@@ -170,15 +954,115 @@ async fn update_by_id<
 async fn delete_by_id<
     T: IntoPublic + ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
 >(
+    req: HttpRequest,
     identity_provider: Data<IdentityProvider<T>>,
     path: Path<IdentityGetPath>,
     session: SessionRes<T>,
 ) -> impl Responder {
-    if session.inner.id() != Uuid::from_str(&path.id).ok() {
-        return HttpResponse::Unauthorized().finish();
+    let result = if session.inner.id() != Uuid::from_str(&path.id).ok() {
+        Err(IdentityError::Unauthorized)
+    } else {
+        identity_provider.delete(path.id.clone()).await
+    };
+
+    audit::record(
+        &identity_provider.audit_sink,
+        AuditEvent::new(
+            AuditAction::IdentityDelete,
+            &AuditContext::from_request(&req),
+        )
+        .actor(session.inner.id().map(String::from).unwrap_or_default())
+        .target(path.id.clone())
+        .outcome_of(&result),
+    )
+    .await;
+
+    match result {
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(e) => e.into(),
     }
+}
+
+async fn create_service_account<
+    T: IntoPublic + ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
+>(
+    req: HttpRequest,
+    identity_provider: Data<IdentityProvider<T>>,
+    session: SessionRes<T>,
+    registration: Json<ServiceAccountRegistration<T>>,
+) -> impl Responder {
+    let registration = registration.into_inner();
+    let username = registration.identity.username();
+    let result = match identity_provider.is_admin(&session.inner) {
+        true => identity_provider.create_service_account(registration).await,
+        false => Err(IdentityError::Forbidden),
+    };
+
+    audit::record(
+        &identity_provider.audit_sink,
+        AuditEvent::new(
+            AuditAction::ServiceAccountCreate,
+            &AuditContext::from_request(&req),
+        )
+        .actor(session.inner.id().map(String::from).unwrap_or_default())
+        .target(username)
+        .outcome_of(&result),
+    )
+    .await;
+
+    match result {
+        Ok(account) => HttpResponse::Created().json(account),
+        Err(e) => e.into(),
+    }
+}
+
+async fn get_service_accounts<
+    T: IntoPublic + ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
+>(
+    identity_provider: Data<IdentityProvider<T>>,
+    session: SessionRes<T>,
+) -> impl Responder {
+    let result = match identity_provider.is_admin(&session.inner) {
+        true => identity_provider.get_service_accounts().await,
+        false => Err(IdentityError::Forbidden),
+    };
+
+    match result {
+        Ok(accounts) => HttpResponse::Ok().json(accounts),
+        Err(e) => e.into(),
+    }
+}
+
+async fn delete_service_account<
+    T: IntoPublic + ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
+>(
+    req: HttpRequest,
+    identity_provider: Data<IdentityProvider<T>>,
+    path: Path<IdentityGetPath>,
+    session: SessionRes<T>,
+) -> impl Responder {
+    let result = match identity_provider.is_admin(&session.inner) {
+        true => {
+            identity_provider
+                .delete_service_account(path.id.clone())
+                .await
+        }
+        false => Err(IdentityError::Forbidden),
+    };
+
+    audit::record(
+        &identity_provider.audit_sink,
+        AuditEvent::new(
+            AuditAction::ServiceAccountDelete,
+            &AuditContext::from_request(&req),
+        )
+        .actor(session.inner.id().map(String::from).unwrap_or_default())
+        .target(path.id.clone())
+        .outcome_of(&result),
+    )
+    .await;
 
-    match identity_provider.delete(path.id.clone()).await {
+    match result {
         Ok(_) => HttpResponse::NoContent().finish(),
         Err(e) => e.into(),
     }
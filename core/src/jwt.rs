@@ -0,0 +1,382 @@
+use std::{marker::PhantomData, pin::Pin, time::Instant};
+
+use actix_web::{
+    FromRequest, HttpRequest,
+    http::header::AUTHORIZATION,
+    web::{Data, Json},
+};
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use tracing::{Instrument, field::Empty};
+use uuid::Uuid;
+
+use crate::{
+    IntoPublic, ObjectId,
+    audit::AuditContext,
+    keys::{KeyError, KeyRing, LoadedKey},
+    oauth::OAuthProvider,
+    session::{SessionError, SessionProvider},
+    telemetry::{record_outcome, record_user_id},
+    unix_now,
+};
+
+/// Signing setup of the stateless mode, see [`crate::provider::AuthProviderBuilder::with_jwt`].
+#[derive(Clone)]
+pub struct JwtConfig {
+    keys: KeyRing,
+    issuer: String,
+    audience: Option<String>,
+    access_token_lifetime: u64,
+    refresh_token_lifetime: u64,
+    session_lifetime: u64,
+}
+
+impl JwtConfig {
+    /// Signs with a shared secret. Every service validating the tokens needs the secret,
+    /// prefer [`JwtConfig::rs256`] or [`JwtConfig::ed_dsa`] if they shouldn't be able to sign.
+    pub fn hs256(secret: &[u8], issuer: String) -> Self {
+        Self::rotating(KeyRing::fixed(LoadedKey::secret(secret)), issuer)
+    }
+
+    /// Signs with an RSA key pair given as PEM. The public key is published in the JWKS.
+    pub fn rs256(private_pem: &[u8], public_pem: &[u8], issuer: String) -> Result<Self, KeyError> {
+        let key = LoadedKey::fixed(
+            Algorithm::RS256,
+            EncodingKey::from_rsa_pem(private_pem).map_err(KeyError::internal)?,
+            DecodingKey::from_rsa_pem(public_pem).map_err(KeyError::internal)?,
+        )?;
+        Ok(Self::rotating(KeyRing::fixed(key), issuer))
+    }
+
+    /// Signs with an Ed25519 key pair given as PEM. The public key is published in the JWKS.
+    pub fn ed_dsa(private_pem: &[u8], public_pem: &[u8], issuer: String) -> Result<Self, KeyError> {
+        let key = LoadedKey::fixed(
+            Algorithm::EdDSA,
+            EncodingKey::from_ed_pem(private_pem).map_err(KeyError::internal)?,
+            DecodingKey::from_ed_pem(public_pem).map_err(KeyError::internal)?,
+        )?;
+        Ok(Self::rotating(KeyRing::fixed(key), issuer))
+    }
+
+    /// Signs with the keys of a [`KeyRing`] loaded from a store, rotating them on schedule.
+    pub fn rotating(keys: KeyRing, issuer: String) -> Self {
+        Self {
+            keys,
+            issuer,
+            audience: None,
+            access_token_lifetime: 15 * 60,
+            refresh_token_lifetime: 30 * 24 * 60 * 60,
+            session_lifetime: 90 * 24 * 60 * 60,
+        }
+    }
+
+    /// Sets `aud` on issued tokens and requires it on validated ones.
+    pub fn with_audience(mut self, audience: String) -> Self {
+        self.audience = Some(audience);
+        self
+    }
+
+    /// In seconds. Access tokens can't be revoked, so keep this short.
+    pub fn with_access_token_lifetime(mut self, lifetime: u64) -> Self {
+        self.access_token_lifetime = lifetime;
+        self
+    }
+
+    /// In seconds, counted from the last refresh.
+    pub fn with_refresh_token_lifetime(mut self, lifetime: u64) -> Self {
+        self.refresh_token_lifetime = lifetime;
+        self
+    }
+
+    /// In seconds, counted from the login. Refreshing doesn't extend it, afterwards the
+    /// user has to log in again.
+    pub fn with_session_lifetime(mut self, lifetime: u64) -> Self {
+        self.session_lifetime = lifetime;
+        self
+    }
+
+    pub fn access_token_lifetime(&self) -> u64 {
+        self.access_token_lifetime
+    }
+
+    pub fn refresh_token_lifetime(&self) -> u64 {
+        self.refresh_token_lifetime
+    }
+
+    pub fn session_lifetime(&self) -> u64 {
+        self.session_lifetime
+    }
+
+    pub fn issuer(&self) -> &str {
+        &self.issuer
+    }
+
+    pub fn key_ring(&self) -> &KeyRing {
+        &self.keys
+    }
+
+    /// Signs an access token for the identity. `sid` is the token family the token belongs to.
+    pub fn issue<T: ObjectId>(&self, identity: &T, sid: String) -> Result<String, SessionError> {
+        self.issue_scoped(identity, sid, None, None)
+    }
+
+    /// Signs an access token for an OAuth client, limited to the granted scopes.
+    pub(crate) fn issue_scoped<T: ObjectId>(
+        &self,
+        identity: &T,
+        sid: String,
+        client_id: Option<String>,
+        scope: Option<String>,
+    ) -> Result<String, SessionError> {
+        let Some(user_id) = identity.id() else {
+            return Err(SessionError::internal("identity without id"));
+        };
+
+        let now = unix_now();
+        let claims = JwtClaims::<T> {
+            sub: user_id.into(),
+            username: identity.username(),
+            iss: self.issuer.clone(),
+            aud: self.audience.clone(),
+            iat: now,
+            exp: now + self.access_token_lifetime,
+            jti: Uuid::new_v4().into(),
+            sid,
+            client_id,
+            scope,
+            _mapped: PhantomData,
+        };
+
+        self.sign(&claims)
+    }
+
+    /// Checks signature, issuer, audience and expiry. Doesn't notice revoked token families.
+    pub fn verify<T>(&self, token: &str) -> Result<JwtClaims<T>, SessionError> {
+        self.decode(token, |validation| match &self.audience {
+            Some(audience) => {
+                validation.set_audience(&[audience]);
+                validation.set_required_spec_claims(&["exp", "iss", "sub", "aud"]);
+            }
+            None => validation.set_required_spec_claims(&["exp", "iss", "sub"]),
+        })
+    }
+
+    /// Signs any claims with the current signing key, naming it in the `kid` header.
+    pub(crate) fn sign(&self, claims: &impl Serialize) -> Result<String, SessionError> {
+        let key = self.keys.signing_key().map_err(SessionError::internal)?;
+        let mut header = Header::new(key.algorithm);
+        header.kid = key.kid;
+        jsonwebtoken::encode(&header, claims, &key.encoding_key).map_err(SessionError::internal)
+    }
+
+    /// Checks the signature and issuer of a token signed by [`JwtConfig::sign`].
+    /// `validate` adjusts the remaining checks.
+    pub(crate) fn decode<C: DeserializeOwned>(
+        &self,
+        token: &str,
+        validate: impl FnOnce(&mut Validation),
+    ) -> Result<C, SessionError> {
+        let header = jsonwebtoken::decode_header(token)
+            .map_err(|_| SessionError::InvalidOrMissingSession)?;
+        let Some(key) = self.keys.verifying_key(header.kid.as_deref()) else {
+            return Err(SessionError::InvalidOrMissingSession);
+        };
+
+        let mut validation = Validation::new(key.algorithm);
+        validation.leeway = 0;
+        validation.set_issuer(&[&self.issuer]);
+        validate(&mut validation);
+
+        jsonwebtoken::decode::<C>(token, &key.decoding_key, &validation)
+            .map(|data| data.claims)
+            .map_err(|_| SessionError::InvalidOrMissingSession)
+    }
+}
+
+/// Claims of an access token. As extractor it validates the `Authorization: Bearer`
+/// token without a backend round-trip, so a token stays valid until it expires even
+/// if its family was revoked in the meantime. Use [`crate::session::SessionRes`] where
+/// revocation has to take effect immediately. Tokens of OAuth clients are rejected,
+/// [`ClientClaims`] accepts them instead.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct JwtClaims<T> {
+    pub sub: String,
+    pub username: String,
+    pub iss: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aud: Option<String>,
+    pub iat: u64,
+    pub exp: u64,
+    pub jti: String,
+    /// Token family, i.e. the login the token was issued for.
+    pub sid: String,
+    /// OAuth client the token was issued to. Absent for first-party logins.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+    /// Space separated scopes granted to the OAuth client.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    #[serde(skip)]
+    _mapped: PhantomData<T>,
+}
+
+impl<T> JwtClaims<T> {
+    /// Whether the token may be used for `scope`. First-party tokens carry no scopes
+    /// and are allowed everything.
+    pub fn has_scope(&self, scope: &str) -> bool {
+        match (&self.client_id, &self.scope) {
+            (None, _) => true,
+            (Some(_), Some(granted)) => granted.split(' ').any(|granted| granted == scope),
+            (Some(_), None) => false,
+        }
+    }
+}
+
+/// Returned by logins and `session/refresh` in stateless mode, and by `oauth/token`.
+#[derive(Serialize, Deserialize)]
+pub struct TokenResponse {
+    pub access_token: String,
+    pub token_type: String,
+    /// Lifetime of the access token in seconds.
+    pub expires_in: u64,
+    /// Absent for the `client_credentials` grant, service accounts authenticate again instead.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub refresh_token: Option<String>,
+    /// OpenID Connect ID token, issued for the `openid` scope.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id_token: Option<String>,
+    /// Scopes granted to an OAuth client.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RefreshRequest {
+    refresh_token: String,
+}
+
+pub(crate) fn bearer_token(req: &HttpRequest) -> Option<String> {
+    req.headers()
+        .get(AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+        .map(String::from)
+}
+
+pub(crate) async fn refresh<
+    T: IntoPublic + ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
+>(
+    req: HttpRequest,
+    session_provider: Data<SessionProvider<T>>,
+    request: Json<RefreshRequest>,
+) -> Result<Json<TokenResponse>, SessionError> {
+    let context = AuditContext::from_request(&req);
+    Ok(Json(
+        session_provider
+            .refresh(request.0.refresh_token, &context)
+            .await?,
+    ))
+}
+
+/// Verifies the `Authorization: Bearer` token of a request with the key ring of the stateless
+/// mode or of the OAuth provider.
+fn verify_bearer<
+    T: IntoPublic + ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
+>(
+    req: &HttpRequest,
+) -> Result<JwtClaims<T>, SessionError> {
+    let Some(token) = bearer_token(req) else {
+        return Err(SessionError::InvalidOrMissingSession);
+    };
+
+    // Tokens of OAuth clients are signed by the OAuth provider, which may
+    // run without the stateless mode.
+    let jwt = req
+        .app_data::<Data<SessionProvider<T>>>()
+        .and_then(|session_provider| session_provider.jwt())
+        .or_else(|| {
+            req.app_data::<Data<OAuthProvider<T>>>()
+                .map(|oauth_provider| oauth_provider.jwt())
+        });
+    let Some(jwt) = jwt else {
+        return Err(SessionError::internal(
+            "neither stateless mode nor OAuth is enabled",
+        ));
+    };
+
+    let claims = jwt.verify::<T>(&token)?;
+    record_user_id(&claims.sub);
+    Ok(claims)
+}
+
+impl<
+    T: IntoPublic + ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
+> FromRequest for JwtClaims<T>
+{
+    type Error = SessionError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &actix_web::HttpRequest, _: &mut actix_web::dev::Payload) -> Self::Future {
+        let req = req.clone();
+        let span = tracing::debug_span!(
+            "jwt.extract",
+            user_id = Empty,
+            outcome = Empty,
+            latency_ms = Empty
+        );
+        Box::pin(
+            async move {
+                let started = Instant::now();
+                // Tokens of OAuth clients are limited to their scopes and only accepted
+                // as `ClientClaims`.
+                let result = verify_bearer::<T>(&req).and_then(|claims| match claims.client_id {
+                    Some(_) => Err(SessionError::InvalidOrMissingSession),
+                    None => Ok(claims),
+                });
+
+                record_outcome("jwt.extract", &result, started);
+                result
+            }
+            .instrument(span),
+        )
+    }
+}
+
+/// Claims of an access token issued to an OAuth client, validated like [`JwtClaims`].
+/// Check the granted scopes with [`JwtClaims::has_scope`].
+#[derive(Clone, Debug)]
+pub struct ClientClaims<T> {
+    pub inner: JwtClaims<T>,
+}
+
+impl<
+    T: IntoPublic + ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
+> FromRequest for ClientClaims<T>
+{
+    type Error = SessionError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &actix_web::HttpRequest, _: &mut actix_web::dev::Payload) -> Self::Future {
+        let req = req.clone();
+        let span = tracing::debug_span!(
+            "jwt.extract_client",
+            user_id = Empty,
+            outcome = Empty,
+            latency_ms = Empty
+        );
+        Box::pin(
+            async move {
+                let started = Instant::now();
+                let result = verify_bearer::<T>(&req).and_then(|claims| match claims.client_id {
+                    Some(_) => Ok(ClientClaims { inner: claims }),
+                    None => Err(SessionError::InvalidOrMissingSession),
+                });
+
+                record_outcome("jwt.extract_client", &result, started);
+                result
+            }
+            .instrument(span),
+        )
+    }
+}
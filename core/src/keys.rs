@@ -0,0 +1,563 @@
+use std::{
+    io::Write,
+    path::PathBuf,
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
+};
+
+use actix_web::{
+    HttpResponse, Responder,
+    http::{StatusCode, header::CACHE_CONTROL},
+    web::{Data, block},
+};
+use async_trait::async_trait;
+use jsonwebtoken::{
+    Algorithm, DecodingKey, EncodingKey,
+    jwk::{Jwk, JwkSet, PublicKeyUse, ThumbprintHash},
+};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinHandle;
+
+use crate::{
+    error::{BoxError, as_source, fmt_with_source},
+    problem::{Problem, ToProblem},
+    unix_now,
+};
+
+/// How long JWKS consumers may cache the key set, in seconds. Keys are published
+/// [`KeyRotation::with_prepublish`] before they sign, which has to be longer than this.
+const JWKS_MAX_AGE: u64 = 5 * 60;
+
+#[derive(Debug)]
+pub enum KeyError {
+    /// No stored key can sign tokens right now.
+    NoSigningKey,
+    /// A shared secret where relying parties need a published public key, like for
+    /// OpenID Connect.
+    SharedSecret,
+    InternalServerError(Option<BoxError>),
+    ServiceUnavailable(Option<BoxError>),
+}
+
+impl std::fmt::Display for KeyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KeyError::NoSigningKey => write!(f, "no active signing key"),
+            KeyError::SharedSecret => write!(f, "shared secrets can't be published in the JWKS"),
+            KeyError::InternalServerError(source) => {
+                fmt_with_source(f, "internal server error", source)
+            }
+            KeyError::ServiceUnavailable(source) => {
+                fmt_with_source(f, "service unavailable", source)
+            }
+        }
+    }
+}
+
+impl std::error::Error for KeyError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            KeyError::InternalServerError(source) | KeyError::ServiceUnavailable(source) => {
+                as_source(source)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl KeyError {
+    pub fn internal(source: impl Into<BoxError>) -> Self {
+        KeyError::InternalServerError(Some(source.into()))
+    }
+
+    /// For failures that are likely to go away on retry, like a lost database connection.
+    pub fn unavailable(source: impl Into<BoxError>) -> Self {
+        KeyError::ServiceUnavailable(Some(source.into()))
+    }
+}
+
+impl ToProblem for KeyError {
+    fn to_problem(&self) -> Problem {
+        match self {
+            KeyError::NoSigningKey | KeyError::SharedSecret | KeyError::InternalServerError(_) => {
+                Problem::internal_server_error()
+            }
+            KeyError::ServiceUnavailable(_) => Problem::service_unavailable(),
+        }
+    }
+}
+
+impl From<KeyError> for HttpResponse {
+    fn from(value: KeyError) -> Self {
+        value.to_problem().into_response()
+    }
+}
+
+impl actix_web::error::ResponseError for KeyError {
+    fn status_code(&self) -> StatusCode {
+        self.to_problem().status_code()
+    }
+
+    fn error_response(&self) -> HttpResponse<actix_web::body::BoxBody> {
+        self.to_problem().into_response()
+    }
+}
+
+/// A persisted signing key.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct StoredKey {
+    pub kid: String,
+    pub algorithm: Algorithm,
+    /// PKCS#8 PEM of the private key. Key stores hold secrets, protect them accordingly.
+    pub private_pem: String,
+    /// Unix timestamp from which the key signs new tokens. It is published before that.
+    pub active_from: u64,
+    /// Unix timestamp after which tokens signed by the key are no longer accepted.
+    pub expires_at: u64,
+}
+
+/// When new signing keys are generated. With the defaults a new Ed25519 key is published
+/// a day before it takes over signing every 30 days, and the retired key keeps verifying
+/// for another day so tokens it signed stay valid until they expire.
+#[derive(Clone)]
+pub struct KeyRotation {
+    algorithm: Algorithm,
+    interval: u64,
+    prepublish: u64,
+    overlap: u64,
+    check_interval: Duration,
+}
+
+impl Default for KeyRotation {
+    fn default() -> Self {
+        Self {
+            algorithm: Algorithm::EdDSA,
+            interval: 30 * 24 * 60 * 60,
+            prepublish: 24 * 60 * 60,
+            overlap: 24 * 60 * 60,
+            check_interval: Duration::from_secs(10 * 60),
+        }
+    }
+}
+
+impl KeyRotation {
+    /// `EdDSA` (Ed25519) or `RS256` (2048 bit RSA).
+    pub fn with_algorithm(mut self, algorithm: Algorithm) -> Self {
+        self.algorithm = algorithm;
+        self
+    }
+
+    /// How long each key signs, in seconds.
+    pub fn with_interval(mut self, interval: u64) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// How long a key is published before it signs, in seconds. Has to exceed the time
+    /// consumers cache the JWKS and the check interval of every instance.
+    pub fn with_prepublish(mut self, prepublish: u64) -> Self {
+        self.prepublish = prepublish;
+        self
+    }
+
+    /// How long a retired key still verifies, in seconds. Has to exceed the access
+    /// token lifetime.
+    pub fn with_overlap(mut self, overlap: u64) -> Self {
+        self.overlap = overlap;
+        self
+    }
+
+    /// How often [`KeyRing::spawn`] rotates and picks up keys rotated by other instances.
+    pub fn with_check_interval(mut self, check_interval: Duration) -> Self {
+        self.check_interval = check_interval;
+        self
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct LoadedKey {
+    pub(crate) kid: Option<String>,
+    pub(crate) algorithm: Algorithm,
+    pub(crate) encoding_key: EncodingKey,
+    pub(crate) decoding_key: DecodingKey,
+    /// Public key as published in the JWKS. `None` for shared secrets.
+    jwk: Option<Jwk>,
+    active_from: u64,
+    expires_at: Option<u64>,
+}
+
+impl LoadedKey {
+    /// A shared secret that signs and verifies forever. It is never published.
+    pub(crate) fn secret(secret: &[u8]) -> Self {
+        Self {
+            kid: None,
+            algorithm: Algorithm::HS256,
+            encoding_key: EncodingKey::from_secret(secret),
+            decoding_key: DecodingKey::from_secret(secret),
+            jwk: None,
+            active_from: 0,
+            expires_at: None,
+        }
+    }
+
+    /// A key pair that signs and verifies forever, given directly instead of via a store.
+    /// Its `kid` is the RFC 7638 thumbprint of the public key.
+    pub(crate) fn fixed(
+        algorithm: Algorithm,
+        encoding_key: EncodingKey,
+        decoding_key: DecodingKey,
+    ) -> Result<Self, KeyError> {
+        let mut jwk =
+            Jwk::from_encoding_key(&encoding_key, algorithm).map_err(KeyError::internal)?;
+        let kid = jwk
+            .thumbprint(ThumbprintHash::SHA256)
+            .map_err(KeyError::internal)?;
+        jwk.common.key_id = Some(kid.clone());
+        jwk.common.public_key_use = Some(PublicKeyUse::Signature);
+
+        Ok(Self {
+            kid: Some(kid),
+            algorithm,
+            encoding_key,
+            decoding_key,
+            jwk: Some(jwk),
+            active_from: 0,
+            expires_at: None,
+        })
+    }
+
+    fn from_stored(key: &StoredKey) -> Result<Self, KeyError> {
+        let encoding_key = match key.algorithm {
+            Algorithm::EdDSA => EncodingKey::from_ed_pem(key.private_pem.as_bytes()),
+            Algorithm::RS256 => EncodingKey::from_rsa_pem(key.private_pem.as_bytes()),
+            _ => return Err(KeyError::internal("unsupported key algorithm")),
+        }
+        .map_err(KeyError::internal)?;
+
+        let mut jwk =
+            Jwk::from_encoding_key(&encoding_key, key.algorithm).map_err(KeyError::internal)?;
+        jwk.common.key_id = Some(key.kid.clone());
+        jwk.common.public_key_use = Some(PublicKeyUse::Signature);
+        let decoding_key = DecodingKey::from_jwk(&jwk).map_err(KeyError::internal)?;
+
+        Ok(Self {
+            kid: Some(key.kid.clone()),
+            algorithm: key.algorithm,
+            encoding_key,
+            decoding_key,
+            jwk: Some(jwk),
+            active_from: key.active_from,
+            expires_at: Some(key.expires_at),
+        })
+    }
+
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+/// The keys tokens are signed and verified with. Keys loaded from a [`KeyStore`] are
+/// rotated on schedule, every instance sharing the store signs with the same key.
+#[derive(Clone)]
+pub struct KeyRing {
+    keys: Arc<RwLock<Vec<LoadedKey>>>,
+    store: Option<Arc<dyn KeyStore>>,
+    rotation: KeyRotation,
+}
+
+impl KeyRing {
+    pub(crate) fn fixed(key: LoadedKey) -> Self {
+        Self {
+            keys: Arc::new(RwLock::new(vec![key])),
+            store: None,
+            rotation: KeyRotation::default(),
+        }
+    }
+
+    /// Loads the keys of the store, generating the first one if the store is empty.
+    pub async fn load(
+        store: impl KeyStore + 'static,
+        rotation: KeyRotation,
+    ) -> Result<Self, KeyError> {
+        let ring = Self {
+            keys: Arc::new(RwLock::new(Vec::new())),
+            store: Some(Arc::new(store)),
+            rotation,
+        };
+        ring.rotate().await?;
+        Ok(ring)
+    }
+
+    /// Generates the next key once the current one is due to be replaced, deletes
+    /// expired keys and reloads the ring from the store.
+    pub async fn rotate(&self) -> Result<(), KeyError> {
+        let Some(store) = &self.store else {
+            return Ok(());
+        };
+
+        let now = unix_now();
+        let stored = store.get_keys().await?;
+        let next_active_from = stored
+            .iter()
+            .map(|key| key.active_from)
+            .max()
+            .map(|latest| latest + self.rotation.interval);
+
+        if next_active_from.is_none_or(|active_from| active_from <= now + self.rotation.prepublish)
+        {
+            // Every instance computes the same slot and `kid`, so only the first one to
+            // insert its key wins and the others load that key below.
+            let active_from = match next_active_from {
+                Some(active_from) if active_from > now => active_from,
+                _ => now - now % self.rotation.interval.max(1),
+            };
+            let algorithm = self.rotation.algorithm;
+            let private_pem = block(move || generate_key(algorithm))
+                .await
+                .map_err(KeyError::internal)??;
+
+            let inserted = store
+                .insert_key(StoredKey {
+                    kid: format!("{algorithm:?}-{active_from}"),
+                    algorithm,
+                    private_pem,
+                    active_from,
+                    expires_at: active_from + self.rotation.interval + self.rotation.overlap,
+                })
+                .await?;
+            if inserted {
+                tracing::info!(active_from, "generated signing key");
+            }
+        }
+
+        let mut loaded = Vec::new();
+        for key in store.get_keys().await? {
+            if key.expires_at <= now {
+                store.delete_key(key.kid).await?;
+                continue;
+            }
+            loaded.push(LoadedKey::from_stored(&key)?);
+        }
+
+        *self
+            .keys
+            .write()
+            .map_err(|e| KeyError::internal(e.to_string()))? = loaded;
+        Ok(())
+    }
+
+    /// Runs [`KeyRing::rotate`] every check interval on the Tokio runtime.
+    pub fn spawn(&self) -> JoinHandle<()> {
+        let ring = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(ring.rotation.check_interval);
+            loop {
+                interval.tick().await;
+                if let Err(e) = ring.rotate().await {
+                    tracing::error!(error = %e, "failed to rotate signing keys");
+                }
+            }
+        })
+    }
+
+    /// The newest key that is already active.
+    pub(crate) fn signing_key(&self) -> Result<LoadedKey, KeyError> {
+        let now = unix_now();
+        self.keys
+            .read()
+            .map_err(|e| KeyError::internal(e.to_string()))?
+            .iter()
+            .filter(|key| key.active_from <= now && !key.is_expired(now))
+            .max_by_key(|key| key.active_from)
+            .cloned()
+            .ok_or(KeyError::NoSigningKey)
+    }
+
+    /// The key a token names in its `kid` header. Tokens without `kid` are only accepted
+    /// if the ring holds a single key.
+    pub(crate) fn verifying_key(&self, kid: Option<&str>) -> Option<LoadedKey> {
+        let now = unix_now();
+        let keys = self.keys.read().ok()?;
+        let mut candidates = keys.iter().filter(|key| !key.is_expired(now));
+        match kid {
+            Some(kid) => candidates
+                .find(|key| key.kid.as_deref() == Some(kid))
+                .cloned(),
+            None if keys.len() == 1 => candidates.next().cloned(),
+            None => None,
+        }
+    }
+
+    /// Whether the ring signs with a shared secret, which relying parties can't verify
+    /// without knowing it.
+    pub fn has_shared_secret(&self) -> bool {
+        self.keys
+            .read()
+            .map(|keys| keys.iter().any(|key| key.jwk.is_none()))
+            .unwrap_or(false)
+    }
+
+    /// Public keys of every key that is published, including the next one.
+    pub fn jwks(&self) -> JwkSet {
+        let now = unix_now();
+        JwkSet {
+            keys: self
+                .keys
+                .read()
+                .map(|keys| {
+                    keys.iter()
+                        .filter(|key| !key.is_expired(now))
+                        .filter_map(|key| key.jwk.clone())
+                        .collect()
+                })
+                .unwrap_or_default(),
+        }
+    }
+}
+
+fn generate_key(algorithm: Algorithm) -> Result<String, KeyError> {
+    match algorithm {
+        Algorithm::EdDSA => {
+            use ed25519_dalek::pkcs8::{
+                EncodePrivateKey, KeypairBytes, spki::der::pem::LineEnding,
+            };
+
+            // Without the public key, which is what `openssl genpkey` writes as well.
+            let keypair = KeypairBytes {
+                secret_key: rand::rng().random(),
+                public_key: None,
+            };
+            keypair
+                .to_pkcs8_pem(LineEnding::LF)
+                .map(|pem| pem.to_string())
+                .map_err(KeyError::internal)
+        }
+        Algorithm::RS256 => {
+            use rsa::pkcs8::{EncodePrivateKey, LineEnding};
+
+            rsa::RsaPrivateKey::new(&mut rsa::rand_core::OsRng, 2048)
+                .map_err(KeyError::internal)?
+                .to_pkcs8_pem(LineEnding::LF)
+                .map(|pem| pem.to_string())
+                .map_err(KeyError::internal)
+        }
+        _ => Err(KeyError::internal("unsupported key algorithm")),
+    }
+}
+
+pub(crate) async fn jwks(key_ring: Data<KeyRing>) -> impl Responder {
+    jwks_response(&key_ring)
+}
+
+pub(crate) fn jwks_response(key_ring: &KeyRing) -> HttpResponse {
+    HttpResponse::Ok()
+        .insert_header((CACHE_CONTROL, format!("public, max-age={JWKS_MAX_AGE}")))
+        .json(key_ring.jwks())
+}
+
+/// Keeps keys in a JSON file. Writes replace the file atomically, the file holds private
+/// keys and should only be readable by the app.
+#[derive(Clone)]
+pub struct FileKeyStore {
+    path: PathBuf,
+    lock: Arc<Mutex<()>>,
+}
+
+impl FileKeyStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            lock: Arc::new(Mutex::new(())),
+        }
+    }
+
+    /// Applies a change to the stored keys. The file is only rewritten if `change`
+    /// returns `true`, which is passed on.
+    async fn modify(
+        &self,
+        change: impl FnOnce(&mut Vec<StoredKey>) -> bool + Send + 'static,
+    ) -> Result<bool, KeyError> {
+        let store = self.clone();
+        block(move || {
+            // Serializes writers of this process so no change is lost.
+            let _guard = store
+                .lock
+                .lock()
+                .map_err(|e| KeyError::internal(e.to_string()))?;
+            let mut keys = read_keys(&store.path)?;
+            if !change(&mut keys) {
+                return Ok(false);
+            }
+
+            let content = serde_json::to_vec_pretty(&keys).map_err(KeyError::internal)?;
+            let temporary = store.path.with_extension("tmp");
+            // Created afresh so the private keys are never readable by others, not even
+            // before the rename.
+            match std::fs::remove_file(&temporary) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(KeyError::internal(e)),
+            }
+            let mut options = std::fs::OpenOptions::new();
+            options.write(true).create_new(true);
+            #[cfg(unix)]
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+            let mut file = options.open(&temporary).map_err(KeyError::internal)?;
+            file.write_all(&content).map_err(KeyError::internal)?;
+            file.sync_all().map_err(KeyError::internal)?;
+            std::fs::rename(&temporary, &store.path).map_err(KeyError::internal)?;
+            Ok(true)
+        })
+        .await
+        .map_err(KeyError::internal)?
+    }
+}
+
+fn read_keys(path: &PathBuf) -> Result<Vec<StoredKey>, KeyError> {
+    match std::fs::read(path) {
+        Ok(content) => serde_json::from_slice(&content).map_err(KeyError::internal),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(KeyError::internal(e)),
+    }
+}
+
+#[async_trait]
+impl KeyStore for FileKeyStore {
+    async fn get_keys(&self) -> Result<Vec<StoredKey>, KeyError> {
+        let path = self.path.clone();
+        block(move || read_keys(&path))
+            .await
+            .map_err(KeyError::internal)?
+    }
+
+    async fn insert_key(&self, key: StoredKey) -> Result<bool, KeyError> {
+        self.modify(move |keys| {
+            if keys.iter().any(|stored| stored.kid == key.kid) {
+                return false;
+            }
+            keys.push(key);
+            true
+        })
+        .await
+    }
+
+    async fn delete_key(&self, kid: String) -> Result<(), KeyError> {
+        self.modify(move |keys| {
+            keys.retain(|stored| stored.kid != kid);
+            true
+        })
+        .await
+        .map(|_| ())
+    }
+}
+
+/// Persists signing keys, shared by every instance that signs tokens.
+#[async_trait]
+pub trait KeyStore: Send + Sync {
+    async fn get_keys(&self) -> Result<Vec<StoredKey>, KeyError>;
+    /// Inserts a key unless one with the same `kid` exists, atomically. Returns whether it
+    /// was inserted, instances rotating at the same time insert the same `kid`.
+    async fn insert_key(&self, key: StoredKey) -> Result<bool, KeyError>;
+    async fn delete_key(&self, kid: String) -> Result<(), KeyError>;
+}
@@ -1,17 +1,62 @@
+pub mod api_key;
+pub mod audit;
+pub mod credential;
+pub mod crypto;
+pub mod error;
+pub mod external;
+pub mod hooks;
 pub mod identity;
+pub mod jwt;
+pub mod keys;
+pub mod magic_link;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+pub mod mfa;
+pub mod notifier;
+pub mod oauth;
+pub mod oidc;
+pub mod passkey;
+pub mod password;
+pub mod problem;
 pub mod provider;
 pub mod session;
+mod telemetry;
+pub mod throttle;
+pub mod username;
+pub mod webhook;
+
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use serde::Serialize;
 use uuid::Uuid;
 
+use crate::identity::IdentityKind;
+
 pub trait ObjectId {
     fn id(&self) -> Option<Uuid>;
     fn set_id(&mut self, id: Uuid);
     fn username(&self) -> String;
+
+    /// Apps with a `UsernamePolicy` have to store the normalized username, by default only
+    /// usernames that are normalized already are accepted.
+    fn set_username(&mut self, _username: String) {}
+
+    /// Apps with service accounts have to store the kind, by default every identity is human.
+    fn kind(&self) -> IdentityKind {
+        IdentityKind::Human
+    }
+
+    fn set_kind(&mut self, _kind: IdentityKind) {}
 }
 
 pub trait IntoPublic {
     type Public: Serialize;
     fn into_public(self) -> Self::Public;
 }
+
+pub(crate) fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
@@ -0,0 +1,250 @@
+use std::{collections::HashMap, net::IpAddr, sync::Mutex};
+
+use actix_web::{
+    HttpRequest, HttpResponse, Responder,
+    web::{Data, Json, Path, ServiceConfig, get, post},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    IntoPublic, ObjectId,
+    audit::{self, AuditAction, AuditContext, AuditEvent},
+    crypto::{hash_token, random_token},
+    identity::{IdentityBackend, IdentityKind},
+    notifier::{Notification, Notifier},
+    session::{Session, SessionBackend, SessionError, SessionKind, SessionProvider},
+    throttle::LoginThrottle,
+    unix_now,
+    username::UsernamePolicy,
+};
+
+/// How long an emailed link can be redeemed, in seconds.
+const MAGIC_LINK_LIFETIME: u64 = 15 * 60;
+/// Window in which at most [`MAGIC_LINK_MAX_REQUESTS`] links are sent to the same address.
+const MAGIC_LINK_RATE_WINDOW: u64 = 15 * 60;
+const MAGIC_LINK_MAX_REQUESTS: usize = 3;
+
+#[derive(Serialize, Deserialize)]
+pub struct MagicLinkRequest {
+    username: String,
+}
+
+#[derive(Deserialize)]
+pub struct MagicLinkPath {
+    token: String,
+}
+
+/// Remembers when links were requested per address.
+#[derive(Default)]
+struct AddressRateLimiter {
+    requests: Mutex<HashMap<String, Vec<u64>>>,
+}
+
+impl AddressRateLimiter {
+    /// Registers a request, or returns the seconds until the next one is allowed.
+    fn allow(&self, address: &str) -> Result<(), u64> {
+        let now = unix_now();
+        let Ok(mut requests) = self.requests.lock() else {
+            return Err(MAGIC_LINK_RATE_WINDOW);
+        };
+
+        requests.retain(|_, timestamps| {
+            timestamps.retain(|timestamp| timestamp + MAGIC_LINK_RATE_WINDOW > now);
+            !timestamps.is_empty()
+        });
+
+        let timestamps = requests.entry(address.to_string()).or_default();
+        if timestamps.len() >= MAGIC_LINK_MAX_REQUESTS {
+            let oldest = timestamps.iter().min().copied().unwrap_or(now);
+            return Err(oldest + MAGIC_LINK_RATE_WINDOW - now);
+        }
+
+        timestamps.push(now);
+        Ok(())
+    }
+}
+
+/// Passwordless login via single-use links. Unredeemed links are stored as
+/// [`SessionKind::MagicLink`] sessions keyed by the token hash, so any [`SessionBackend`] works.
+#[derive(Clone)]
+pub struct MagicLinkProvider<T>
+where
+    T: IntoPublic
+        + ObjectId
+        + Serialize
+        + for<'de> Deserialize<'de>
+        + Clone
+        + Send
+        + Sync
+        + 'static,
+{
+    magic_link_path: String,
+    session_backend: Data<Box<dyn SessionBackend<T>>>,
+    identity_backend: Data<Box<dyn IdentityBackend<T>>>,
+    notifier: Data<Box<dyn Notifier<T>>>,
+    rate_limiter: Data<AddressRateLimiter>,
+    username_policy: Option<UsernamePolicy>,
+    throttle: Option<LoginThrottle>,
+}
+
+impl<
+    T: IntoPublic + ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
+> MagicLinkProvider<T>
+{
+    pub fn default_with_backend(
+        session_backend: Data<Box<dyn SessionBackend<T>>>,
+        identity_backend: Data<Box<dyn IdentityBackend<T>>>,
+        notifier: Data<Box<dyn Notifier<T>>>,
+    ) -> Self {
+        Self {
+            magic_link_path: String::from("session/magic-link"),
+            session_backend,
+            identity_backend,
+            notifier,
+            rate_limiter: Data::new(AddressRateLimiter::default()),
+            username_policy: None,
+            throttle: None,
+        }
+    }
+
+    /// Looks up identities by the normalized username.
+    pub fn with_username_policy(mut self, policy: UsernamePolicy) -> Self {
+        self.username_policy = Some(policy);
+        self
+    }
+
+    /// Throttles clients redeeming unknown or expired links.
+    pub fn with_throttle(mut self, throttle: LoginThrottle) -> Self {
+        self.throttle = Some(throttle);
+        self
+    }
+
+    pub fn configure(&self, cfg: &mut ServiceConfig) {
+        let data = Data::new(self.clone());
+        cfg.app_data(data.clone())
+            .route(&data.magic_link_path, post().to(request_magic_link::<T>))
+            .route(
+                &format!("{}/{{token}}", data.magic_link_path),
+                get().to(redeem_magic_link::<T>),
+            );
+    }
+
+    /// Sends a login link to the identity with the given username.
+    /// Unknown usernames are silently ignored so the endpoint doesn't reveal which accounts exist.
+    pub async fn request(&self, username: String) -> Result<(), SessionError> {
+        let username = match &self.username_policy {
+            Some(policy) => policy.normalize(&username),
+            None => username,
+        };
+
+        self.rate_limiter
+            .allow(&username)
+            .map_err(|retry_after| SessionError::TooManyRequests { retry_after })?;
+
+        let identity = self.identity_backend.get_by_username(username).await?;
+        // Service accounts can't log in, they are treated like unknown usernames.
+        let Some(identity) = identity.filter(|identity| identity.kind() == IdentityKind::Human)
+        else {
+            return Ok(());
+        };
+        let Some(user_id) = identity.id() else {
+            return Err(SessionError::internal("identity without id"));
+        };
+
+        let token = random_token(48);
+        let expires_at = unix_now() + MAGIC_LINK_LIFETIME;
+        self.session_backend
+            .create_session(Session::short_lived(
+                hash_token(&token),
+                user_id.into(),
+                SessionKind::MagicLink,
+                expires_at,
+            ))
+            .await?;
+
+        self.notifier
+            .notify(identity, Notification::MagicLink { token, expires_at })
+            .await
+            .map_err(SessionError::unavailable)
+    }
+
+    /// Consumes a link and returns the id of the user it was issued for.
+    pub async fn redeem(
+        &self,
+        token: String,
+        client_ip: Option<IpAddr>,
+    ) -> Result<String, SessionError> {
+        if let Some(throttle) = &self.throttle {
+            throttle.check_client(client_ip).await?;
+        }
+
+        let result = self.take_link(token).await;
+        if let (Err(SessionError::InvalidOrMissingSession), Some(throttle)) =
+            (&result, &self.throttle)
+        {
+            throttle.record_client_failure(client_ip).await?;
+        }
+        result
+    }
+
+    async fn take_link(&self, token: String) -> Result<String, SessionError> {
+        let Some(link) = self.session_backend.get_session(hash_token(&token)).await? else {
+            return Err(SessionError::InvalidOrMissingSession);
+        };
+
+        if link.kind != SessionKind::MagicLink || link.is_expired() {
+            return Err(SessionError::InvalidOrMissingSession);
+        }
+
+        if !self.session_backend.delete_session(link.id).await? {
+            return Err(SessionError::InvalidOrMissingSession);
+        }
+
+        Ok(link.user_id)
+    }
+}
+
+async fn request_magic_link<
+    T: IntoPublic + ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
+>(
+    magic_link_provider: Data<MagicLinkProvider<T>>,
+    request: Json<MagicLinkRequest>,
+) -> Result<impl Responder, SessionError> {
+    magic_link_provider.request(request.0.username).await?;
+
+    Ok(HttpResponse::Accepted().finish())
+}
+
+async fn redeem_magic_link<
+    T: IntoPublic + ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
+>(
+    req: HttpRequest,
+    magic_link_provider: Data<MagicLinkProvider<T>>,
+    session_provider: Data<SessionProvider<T>>,
+    path: Path<MagicLinkPath>,
+) -> Result<impl Responder, SessionError> {
+    let client_ip = magic_link_provider
+        .throttle
+        .as_ref()
+        .and_then(|throttle| throttle.client_ip(&req));
+    let mut context = AuditContext::from_request(&req);
+    if client_ip.is_some() {
+        context.ip = client_ip;
+    }
+
+    let result = magic_link_provider
+        .redeem(path.into_inner().token, client_ip)
+        .await;
+
+    let event = AuditEvent::new(AuditAction::Login, &context).outcome_of(&result);
+    let event = match &result {
+        Ok(user_id) => event.actor(user_id.clone()).target(user_id.clone()),
+        Err(_) => event,
+    };
+    audit::record(&session_provider.audit_sink, event).await;
+
+    let user_id = result?;
+
+    let session = session_provider.login_without_password(user_id).await?;
+    session_provider.grant(session).await
+}
@@ -0,0 +1,62 @@
+use actix_web::{
+    HttpResponse, Responder,
+    web::{Data, ServiceConfig, get},
+};
+use metrics_exporter_prometheus::{BuildError, Matcher, PrometheusBuilder, PrometheusHandle};
+
+/// Counter of provider operations, labeled with `operation` and `outcome`
+/// (`ok` or the problem code, e.g. `invalid_login`).
+pub const OPERATIONS_TOTAL: &str = "toro_auth_operations_total";
+/// Histogram of provider operation latency, labeled with `operation` and `outcome`.
+pub const OPERATION_DURATION_SECONDS: &str = "toro_auth_operation_duration_seconds";
+/// Histogram of backend call latency, labeled with `operation` and `outcome` (`ok` or `error`).
+pub const BACKEND_DURATION_SECONDS: &str = "toro_auth_backend_duration_seconds";
+/// Counter of logins rejected by throttling, labeled with `scope` (`account` or `client`).
+pub const LOCKOUTS_TOTAL: &str = "toro_auth_lockouts_total";
+
+const DURATION_BUCKETS: &[f64] = &[
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+];
+
+/// Installs a global Prometheus recorder with latency buckets for the histograms above.
+/// Apps that already use another `metrics` recorder don't need this, the providers
+/// record through the `metrics` facade either way.
+pub fn install_prometheus_recorder() -> Result<PrometheusHandle, BuildError> {
+    PrometheusBuilder::new()
+        .set_buckets_for_metric(
+            Matcher::Suffix(String::from("_duration_seconds")),
+            DURATION_BUCKETS,
+        )?
+        .install_recorder()
+}
+
+/// Serves the Prometheus text format. The route is unauthenticated,
+/// restrict access to it in your proxy if the numbers shouldn't be public.
+#[derive(Clone)]
+pub struct MetricsProvider {
+    metrics_path: String,
+    handle: PrometheusHandle,
+}
+
+impl MetricsProvider {
+    pub fn default_with_handle(handle: PrometheusHandle) -> Self {
+        Self {
+            metrics_path: String::from("metrics"),
+            handle,
+        }
+    }
+
+    pub fn configure(&self, cfg: &mut ServiceConfig) {
+        let data = Data::new(self.clone());
+        cfg.app_data(data.clone())
+            .route(&data.metrics_path, get().to(render_metrics));
+    }
+}
+
+async fn render_metrics(metrics_provider: Data<MetricsProvider>) -> impl Responder {
+    // Without the HTTP listener of the exporter nobody else drains the histograms.
+    metrics_provider.handle.run_upkeep();
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics_provider.handle.render())
+}
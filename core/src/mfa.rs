@@ -0,0 +1,630 @@
+use std::{io::Cursor, net::IpAddr};
+
+use actix_web::{
+    HttpRequest, HttpResponse, Responder,
+    http::StatusCode,
+    web::{Data, Json, ServiceConfig, delete, get, post},
+};
+use async_trait::async_trait;
+use image::{ImageFormat, Luma};
+use qrcode::{QrCode, render::svg};
+use serde::{Deserialize, Serialize};
+use totp_rs::{Builder, Secret, Totp};
+
+use crate::{
+    IntoPublic, ObjectId,
+    audit::{self, AuditAction, AuditContext, AuditEvent, AuditSink},
+    crypto::{hash_token, random_token},
+    error::{BoxError, as_source, fmt_with_source},
+    problem::{Problem, ToProblem},
+    session::{SessionError, SessionRes},
+    throttle::LoginThrottle,
+};
+
+const RECOVERY_CODE_COUNT: usize = 10;
+
+#[derive(Debug)]
+pub enum MfaError {
+    NotEnrolled,
+    AlreadyEnrolled,
+    InvalidCode,
+    TooManyRequests { retry_after: u64 },
+    InternalServerError(Option<BoxError>),
+    ServiceUnavailable(Option<BoxError>),
+}
+
+impl std::fmt::Display for MfaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MfaError::NotEnrolled => write!(f, "no second factor enrolled"),
+            MfaError::AlreadyEnrolled => write!(f, "a second factor is already enrolled"),
+            MfaError::InvalidCode => write!(f, "invalid or already used code"),
+            MfaError::TooManyRequests { retry_after } => {
+                write!(f, "too many attempts, retry in {retry_after} seconds")
+            }
+            MfaError::InternalServerError(source) => {
+                fmt_with_source(f, "internal server error", source)
+            }
+            MfaError::ServiceUnavailable(source) => {
+                fmt_with_source(f, "service unavailable", source)
+            }
+        }
+    }
+}
+
+impl std::error::Error for MfaError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MfaError::InternalServerError(source) | MfaError::ServiceUnavailable(source) => {
+                as_source(source)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl MfaError {
+    pub fn internal(source: impl Into<BoxError>) -> Self {
+        MfaError::InternalServerError(Some(source.into()))
+    }
+
+    /// For failures that are likely to go away on retry, like a lost database connection.
+    pub fn unavailable(source: impl Into<BoxError>) -> Self {
+        MfaError::ServiceUnavailable(Some(source.into()))
+    }
+}
+
+impl ToProblem for MfaError {
+    fn to_problem(&self) -> Problem {
+        match self {
+            MfaError::NotEnrolled => Problem::new(
+                StatusCode::NOT_FOUND,
+                "mfa_not_enrolled",
+                "No second factor enrolled",
+            ),
+            MfaError::AlreadyEnrolled => Problem::new(
+                StatusCode::CONFLICT,
+                "mfa_already_enrolled",
+                "A second factor is already enrolled",
+            ),
+            MfaError::InvalidCode => Problem::new(
+                StatusCode::UNAUTHORIZED,
+                "invalid_mfa_code",
+                "Invalid or already used code",
+            ),
+            MfaError::TooManyRequests { retry_after } => SessionError::TooManyRequests {
+                retry_after: *retry_after,
+            }
+            .to_problem(),
+            MfaError::InternalServerError(_) => Problem::internal_server_error(),
+            MfaError::ServiceUnavailable(_) => Problem::service_unavailable(),
+        }
+    }
+}
+
+impl From<MfaError> for HttpResponse {
+    fn from(value: MfaError) -> Self {
+        value.to_problem().into_response()
+    }
+}
+
+impl From<MfaError> for SessionError {
+    fn from(value: MfaError) -> Self {
+        match value {
+            MfaError::NotEnrolled | MfaError::InvalidCode => SessionError::InvalidLogin,
+            MfaError::TooManyRequests { retry_after } => {
+                SessionError::TooManyRequests { retry_after }
+            }
+            MfaError::InternalServerError(source) => SessionError::InternalServerError(source),
+            MfaError::ServiceUnavailable(source) => SessionError::ServiceUnavailable(source),
+            other @ MfaError::AlreadyEnrolled => SessionError::internal(other),
+        }
+    }
+}
+
+impl From<SessionError> for MfaError {
+    fn from(value: SessionError) -> Self {
+        match value {
+            SessionError::InternalServerError(source) => MfaError::InternalServerError(source),
+            SessionError::ServiceUnavailable(source) => MfaError::ServiceUnavailable(source),
+            SessionError::TooManyRequests { retry_after } => {
+                MfaError::TooManyRequests { retry_after }
+            }
+            other => MfaError::internal(other),
+        }
+    }
+}
+
+impl actix_web::error::ResponseError for MfaError {
+    fn status_code(&self) -> StatusCode {
+        self.to_problem().status_code()
+    }
+
+    fn error_response(&self) -> HttpResponse<actix_web::body::BoxBody> {
+        match self {
+            MfaError::TooManyRequests { retry_after } => {
+                actix_web::error::ResponseError::error_response(&SessionError::TooManyRequests {
+                    retry_after: *retry_after,
+                })
+            }
+            _ => self.to_problem().into_response(),
+        }
+    }
+}
+
+/// A TOTP secret as persisted by an [`MfaBackend`].
+/// Secrets start out unconfirmed and only count as an enrolled second factor
+/// once the user proved possession by submitting a first valid code.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TotpSecret {
+    pub user_id: String,
+    /// Base32 encoded shared secret.
+    pub secret: String,
+    pub confirmed: bool,
+    /// Last accepted time step, used to reject replayed codes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_used_step: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TotpEnrollment {
+    secret: String,
+    otpauth_uri: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TotpCodeRequest {
+    code: String,
+}
+
+/// Plaintext recovery codes. They are only ever shown in this response, the backend keeps their hashes.
+#[derive(Serialize, Deserialize)]
+pub struct RecoveryCodes {
+    recovery_codes: Vec<String>,
+}
+
+#[derive(Clone)]
+pub struct MfaProvider<T>
+where
+    T: IntoPublic
+        + ObjectId
+        + Serialize
+        + for<'de> Deserialize<'de>
+        + Clone
+        + Send
+        + Sync
+        + 'static,
+{
+    totp_path: String,
+    recovery_codes_path: String,
+    issuer: String,
+    backend: Data<Box<dyn MfaBackend<T>>>,
+    throttle: Option<LoginThrottle>,
+    audit_sink: Option<Data<Box<dyn AuditSink>>>,
+}
+
+impl<
+    T: IntoPublic + ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
+> MfaProvider<T>
+{
+    pub fn default_with_backend(backend: Data<Box<dyn MfaBackend<T>>>, issuer: String) -> Self {
+        Self {
+            totp_path: String::from("mfa/totp"),
+            recovery_codes_path: String::from("mfa/recovery-codes"),
+            issuer,
+            backend,
+            throttle: None,
+            audit_sink: None,
+        }
+    }
+
+    /// Records attempts to disable the second factor.
+    pub fn with_audit_sink(mut self, sink: Data<Box<dyn AuditSink>>) -> Self {
+        self.audit_sink = Some(sink);
+        self
+    }
+
+    /// Throttles wrong codes sent to disable the second factor or regenerate recovery
+    /// codes, counted together with those of `session/mfa`.
+    pub fn with_throttle(mut self, throttle: LoginThrottle) -> Self {
+        self.throttle = Some(throttle);
+        self
+    }
+
+    pub fn configure(&self, cfg: &mut ServiceConfig) {
+        let data = Data::new(self.clone());
+        cfg.app_data(data.clone())
+            .route(&data.totp_path, post().to(enroll_totp::<T>))
+            .route(&data.totp_path, delete().to(disable_totp::<T>))
+            .route(
+                &format!("{}/confirm", data.totp_path),
+                post().to(confirm_totp::<T>),
+            )
+            .route(
+                &format!("{}/qr.png", data.totp_path),
+                get().to(totp_qr_png::<T>),
+            )
+            .route(
+                &format!("{}/qr.svg", data.totp_path),
+                get().to(totp_qr_svg::<T>),
+            )
+            .route(
+                &data.recovery_codes_path,
+                post().to(regenerate_recovery_codes::<T>),
+            );
+    }
+
+    pub async fn is_enrolled(&self, user_id: String) -> Result<bool, MfaError> {
+        let totp = self.backend.get_totp(user_id).await?;
+        Ok(totp.is_some_and(|totp| totp.confirmed))
+    }
+
+    /// Generates a new unconfirmed secret, replacing any previous unconfirmed one.
+    pub async fn enroll(
+        &self,
+        user_id: String,
+        username: String,
+    ) -> Result<TotpEnrollment, MfaError> {
+        if self.is_enrolled(user_id.clone()).await? {
+            return Err(MfaError::AlreadyEnrolled);
+        }
+
+        let secret = Secret::generate().to_base32();
+        let otpauth_uri = self
+            .totp(&secret, username)?
+            .to_url()
+            .map_err(MfaError::internal)?;
+
+        self.backend
+            .save_totp(TotpSecret {
+                user_id,
+                secret: secret.clone(),
+                confirmed: false,
+                last_used_step: None,
+            })
+            .await?;
+
+        Ok(TotpEnrollment {
+            secret,
+            otpauth_uri,
+        })
+    }
+
+    /// Returns the `otpauth://` URI of an enrollment that has not been confirmed yet.
+    pub async fn pending_uri(&self, user_id: String, username: String) -> Result<String, MfaError> {
+        let Some(totp) = self.backend.get_totp(user_id).await? else {
+            return Err(MfaError::NotEnrolled);
+        };
+
+        if totp.confirmed {
+            return Err(MfaError::AlreadyEnrolled);
+        }
+
+        self.totp(&totp.secret, username)?
+            .to_url()
+            .map_err(MfaError::internal)
+    }
+
+    /// Activates a pending enrollment and returns the initial set of recovery codes.
+    pub async fn confirm(&self, user_id: String, code: String) -> Result<Vec<String>, MfaError> {
+        let Some(mut totp) = self.backend.get_totp(user_id.clone()).await? else {
+            return Err(MfaError::NotEnrolled);
+        };
+
+        if totp.confirmed {
+            return Err(MfaError::AlreadyEnrolled);
+        }
+
+        let step = self.check(&totp, code)?;
+        totp.confirmed = true;
+        totp.last_used_step = Some(step);
+        self.backend.save_totp(totp).await?;
+
+        self.generate_recovery_codes(user_id).await
+    }
+
+    /// Checks a code against a confirmed secret. Each time step is only accepted once.
+    /// Codes that don't match the secret are tried as single-use recovery codes.
+    pub async fn verify(&self, user_id: String, code: String) -> Result<(), MfaError> {
+        let Some(mut totp) = self.backend.get_totp(user_id.clone()).await? else {
+            return Err(MfaError::NotEnrolled);
+        };
+
+        if !totp.confirmed {
+            return Err(MfaError::NotEnrolled);
+        }
+
+        match self.check(&totp, code.clone()) {
+            Ok(step) => {
+                totp.last_used_step = Some(step);
+                self.backend.save_totp(totp).await
+            }
+            Err(MfaError::InvalidCode) => {
+                let code_hash = hash_token(&normalize_recovery_code(&code));
+                match self
+                    .backend
+                    .consume_recovery_code(user_id, code_hash)
+                    .await?
+                {
+                    true => Ok(()),
+                    false => Err(MfaError::InvalidCode),
+                }
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Removes the second factor. A confirmed one has to be proven with a fresh code or a
+    /// recovery code first, an enrollment that was never confirmed can be dropped without.
+    pub async fn disable(
+        &self,
+        user_id: String,
+        code: Option<String>,
+        client_ip: Option<IpAddr>,
+    ) -> Result<(), MfaError> {
+        if self.is_enrolled(user_id.clone()).await? {
+            let Some(code) = code else {
+                return Err(MfaError::InvalidCode);
+            };
+            self.verify_throttled(user_id.clone(), code, client_ip)
+                .await?;
+        }
+
+        self.backend.delete_totp(user_id.clone()).await?;
+        self.backend.save_recovery_codes(user_id, Vec::new()).await
+    }
+
+    /// Replaces all recovery codes of an enrolled user with a fresh set, once the user
+    /// proved the second factor with a fresh code or a recovery code.
+    pub async fn regenerate_recovery_codes(
+        &self,
+        user_id: String,
+        code: String,
+        client_ip: Option<IpAddr>,
+    ) -> Result<Vec<String>, MfaError> {
+        self.verify_throttled(user_id.clone(), code, client_ip)
+            .await?;
+
+        self.generate_recovery_codes(user_id).await
+    }
+
+    /// [`MfaProvider::verify`] limited by the throttle, under the same key as `session/mfa`.
+    async fn verify_throttled(
+        &self,
+        user_id: String,
+        code: String,
+        client_ip: Option<IpAddr>,
+    ) -> Result<(), MfaError> {
+        let Some(throttle) = &self.throttle else {
+            return self.verify(user_id, code).await;
+        };
+
+        let account = format!("mfa:{user_id}");
+        throttle.check(&account, client_ip).await?;
+        match self.verify(user_id, code).await {
+            Ok(()) => {
+                throttle.record_success(&account).await?;
+                Ok(())
+            }
+            Err(MfaError::InvalidCode) => {
+                throttle.record_failure(&account, client_ip).await?;
+                Err(MfaError::InvalidCode)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Number of unused recovery codes, or `None` if the user has no second factor.
+    pub async fn remaining_recovery_codes(
+        &self,
+        user_id: String,
+    ) -> Result<Option<usize>, MfaError> {
+        if !self.is_enrolled(user_id.clone()).await? {
+            return Ok(None);
+        }
+
+        let code_hashes = self.backend.get_recovery_codes(user_id).await?;
+        Ok(Some(code_hashes.len()))
+    }
+
+    async fn generate_recovery_codes(&self, user_id: String) -> Result<Vec<String>, MfaError> {
+        let codes = (0..RECOVERY_CODE_COUNT)
+            .map(|_| {
+                let code = random_token(10).to_ascii_lowercase();
+                format!("{}-{}", &code[..5], &code[5..])
+            })
+            .collect::<Vec<String>>();
+
+        let code_hashes = codes
+            .iter()
+            .map(|code| hash_token(&normalize_recovery_code(code)))
+            .collect();
+        self.backend
+            .save_recovery_codes(user_id, code_hashes)
+            .await?;
+
+        Ok(codes)
+    }
+
+    fn check(&self, totp: &TotpSecret, code: String) -> Result<u64, MfaError> {
+        let Some(step) = self.totp(&totp.secret, String::new())?.check_current(&code) else {
+            return Err(MfaError::InvalidCode);
+        };
+
+        if totp
+            .last_used_step
+            .is_some_and(|last_used| step <= last_used)
+        {
+            return Err(MfaError::InvalidCode);
+        }
+
+        Ok(step)
+    }
+
+    fn totp(&self, secret: &str, username: String) -> Result<Totp, MfaError> {
+        let secret = Secret::try_from_base32(secret).map_err(MfaError::internal)?;
+        Builder::new()
+            .with_secret(secret)
+            .with_issuer(Some(self.issuer.clone()))
+            .with_account_name(username)
+            .build()
+            .map_err(MfaError::internal)
+    }
+}
+
+fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+fn session_user_id<T: ObjectId>(session: &SessionRes<T>) -> Result<String, MfaError> {
+    session
+        .inner
+        .id()
+        .map(|id| id.into())
+        .ok_or_else(|| MfaError::internal("identity without id"))
+}
+
+async fn enroll_totp<
+    T: IntoPublic + ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
+>(
+    mfa_provider: Data<MfaProvider<T>>,
+    session: SessionRes<T>,
+) -> Result<impl Responder, MfaError> {
+    let enrollment = mfa_provider
+        .enroll(session_user_id(&session)?, session.inner.username())
+        .await?;
+
+    Ok(HttpResponse::Created().json(enrollment))
+}
+
+async fn confirm_totp<
+    T: IntoPublic + ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
+>(
+    mfa_provider: Data<MfaProvider<T>>,
+    session: SessionRes<T>,
+    request: Json<TotpCodeRequest>,
+) -> Result<impl Responder, MfaError> {
+    let recovery_codes = mfa_provider
+        .confirm(session_user_id(&session)?, request.0.code)
+        .await?;
+
+    Ok(HttpResponse::Ok().json(RecoveryCodes { recovery_codes }))
+}
+
+async fn disable_totp<
+    T: IntoPublic + ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
+>(
+    req: HttpRequest,
+    mfa_provider: Data<MfaProvider<T>>,
+    session: SessionRes<T>,
+    request: Option<Json<TotpCodeRequest>>,
+) -> Result<impl Responder, MfaError> {
+    let client_ip = mfa_provider
+        .throttle
+        .as_ref()
+        .and_then(|throttle| throttle.client_ip(&req));
+    let mut context = AuditContext::from_request(&req);
+    if client_ip.is_some() {
+        context.ip = client_ip;
+    }
+
+    let user_id = session_user_id(&session)?;
+    let result = mfa_provider
+        .disable(
+            user_id.clone(),
+            request.map(|request| request.into_inner().code),
+            client_ip,
+        )
+        .await;
+    audit::record(
+        &mfa_provider.audit_sink,
+        AuditEvent::new(AuditAction::MfaDisable, &context)
+            .outcome_of(&result)
+            .actor(user_id.clone())
+            .target(user_id),
+    )
+    .await;
+    result?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+async fn regenerate_recovery_codes<
+    T: IntoPublic + ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
+>(
+    req: HttpRequest,
+    mfa_provider: Data<MfaProvider<T>>,
+    session: SessionRes<T>,
+    request: Json<TotpCodeRequest>,
+) -> Result<impl Responder, MfaError> {
+    let client_ip = mfa_provider
+        .throttle
+        .as_ref()
+        .and_then(|throttle| throttle.client_ip(&req));
+    let recovery_codes = mfa_provider
+        .regenerate_recovery_codes(session_user_id(&session)?, request.0.code, client_ip)
+        .await?;
+
+    Ok(HttpResponse::Ok().json(RecoveryCodes { recovery_codes }))
+}
+
+async fn totp_qr_png<
+    T: IntoPublic + ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
+>(
+    mfa_provider: Data<MfaProvider<T>>,
+    session: SessionRes<T>,
+) -> Result<impl Responder, MfaError> {
+    let uri = mfa_provider
+        .pending_uri(session_user_id(&session)?, session.inner.username())
+        .await?;
+
+    let code = QrCode::new(uri.as_bytes()).map_err(MfaError::internal)?;
+    let mut png = Vec::new();
+    code.render::<Luma<u8>>()
+        .build()
+        .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+        .map_err(MfaError::internal)?;
+
+    Ok(HttpResponse::Ok().content_type("image/png").body(png))
+}
+
+async fn totp_qr_svg<
+    T: IntoPublic + ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
+>(
+    mfa_provider: Data<MfaProvider<T>>,
+    session: SessionRes<T>,
+) -> Result<impl Responder, MfaError> {
+    let uri = mfa_provider
+        .pending_uri(session_user_id(&session)?, session.inner.username())
+        .await?;
+
+    let code = QrCode::new(uri.as_bytes()).map_err(MfaError::internal)?;
+    let svg = code.render::<svg::Color>().min_dimensions(200, 200).build();
+
+    Ok(HttpResponse::Ok().content_type("image/svg+xml").body(svg))
+}
+
+#[async_trait]
+pub trait MfaBackend<T: ObjectId + Serialize + for<'de> Deserialize<'de>>: Send + Sync {
+    async fn get_totp(&self, user_id: String) -> Result<Option<TotpSecret>, MfaError>;
+    async fn save_totp(&self, totp: TotpSecret) -> Result<(), MfaError>;
+    async fn delete_totp(&self, user_id: String) -> Result<(), MfaError>;
+    async fn get_recovery_codes(&self, user_id: String) -> Result<Vec<String>, MfaError>;
+    async fn save_recovery_codes(
+        &self,
+        user_id: String,
+        code_hashes: Vec<String>,
+    ) -> Result<(), MfaError>;
+    /// Removes a recovery code hash and reports whether it was present.
+    /// Has to be atomic so a code can't be redeemed twice.
+    async fn consume_recovery_code(
+        &self,
+        user_id: String,
+        code_hash: String,
+    ) -> Result<bool, MfaError>;
+    /// Removes the second factor and recovery codes of a deleted identity.
+    async fn delete_mfa(&self, user_id: String) -> Result<(), MfaError>;
+}
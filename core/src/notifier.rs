@@ -0,0 +1,28 @@
+use async_trait::async_trait;
+
+/// Messages that have to reach a user outside of the HTTP response, e.g. by email.
+pub enum Notification {
+    /// A login link was requested. `token` has to end up in `GET session/magic-link/{token}`.
+    MagicLink { token: String, expires_at: u64 },
+    /// Someone tried to register the username of this identity.
+    DuplicateRegistration,
+}
+
+#[derive(Debug)]
+pub enum NotifierError {
+    DeliveryFailed,
+}
+
+impl std::fmt::Display for NotifierError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:#?}", self)
+    }
+}
+
+impl std::error::Error for NotifierError {}
+
+/// Delivers [`Notification`]s to an identity. Implementations decide which address of `T` to use.
+#[async_trait]
+pub trait Notifier<T>: Send + Sync {
+    async fn notify(&self, identity: T, notification: Notification) -> Result<(), NotifierError>;
+}
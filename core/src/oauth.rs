@@ -0,0 +1,2041 @@
+use std::{sync::Arc, time::Instant};
+
+use actix_web::{
+    HttpRequest, HttpResponse, Responder,
+    http::{
+        StatusCode,
+        header::{AUTHORIZATION, CACHE_CONTROL, HeaderValue, LOCATION, PRAGMA, WWW_AUTHENTICATE},
+    },
+    web::{Data, Form, Json, Path, Query, ServiceConfig, delete, get, post},
+};
+use async_trait::async_trait;
+use base64::{
+    Engine,
+    prelude::{BASE64_STANDARD, BASE64_URL_SAFE_NO_PAD},
+};
+use jsonwebtoken::{Algorithm, AlgorithmFamily, DecodingKey, Validation};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::field::Empty;
+use url::Url;
+use uuid::Uuid;
+
+use crate::{
+    IntoPublic, ObjectId,
+    audit::{self, AuditAction, AuditContext, AuditEvent},
+    crypto::{hash_token, random_token, verify_secret},
+    error::{BoxError, as_source, fmt_with_source},
+    identity::IdentityKind,
+    jwt::{JwtClaims, JwtConfig, TokenResponse},
+    keys, oidc,
+    problem::{Problem, ToProblem},
+    session::{Session, SessionBackend, SessionError, SessionProvider, SessionRes},
+    telemetry::{observe_backend, record_outcome, record_user_id},
+    unix_now,
+};
+
+/// How long an authorization code can be exchanged, in seconds.
+const AUTHORIZATION_CODE_LIFETIME: u64 = 60;
+/// How long an authorization request waits for the user's consent, in seconds.
+const CONSENT_LIFETIME: u64 = 10 * 60;
+/// Longest a client assertion may be valid for, in seconds. Used ones are remembered
+/// until they expire.
+const MAX_ASSERTION_LIFETIME: u64 = 5 * 60;
+/// `client_assertion_type` of RFC 7523 `private_key_jwt` client authentication.
+const JWT_BEARER_ASSERTION: &str = "urn:ietf:params:oauth:client-assertion-type:jwt-bearer";
+
+#[derive(Debug)]
+pub enum OAuthError {
+    /// Malformed request, the detail names what is wrong.
+    InvalidRequest(String),
+    /// Unknown client or failed client authentication.
+    InvalidClient,
+    /// Unknown, expired or already used code or refresh token, or a failed PKCE check.
+    InvalidGrant,
+    UnsupportedGrantType,
+    /// The client isn't allowed to use the grant type.
+    UnauthorizedClient,
+    UnsupportedResponseType,
+    /// Scopes the client isn't registered for.
+    InvalidScope,
+    /// The user declined the authorization request.
+    AccessDenied,
+    /// Not allowed to manage clients.
+    Forbidden,
+    ClientNotFound,
+    /// Unknown or expired consent request, or one of another user.
+    ConsentNotFound,
+    /// Missing, invalid, expired or revoked bearer token.
+    InvalidToken,
+    /// The bearer token lacks the scope the endpoint requires.
+    InsufficientScope,
+    InternalServerError(Option<BoxError>),
+    ServiceUnavailable(Option<BoxError>),
+}
+
+impl std::fmt::Display for OAuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OAuthError::InvalidRequest(detail) => write!(f, "invalid request: {detail}"),
+            OAuthError::InvalidClient => write!(f, "unknown client or invalid client credentials"),
+            OAuthError::InvalidGrant => write!(f, "invalid, expired or used grant"),
+            OAuthError::UnsupportedGrantType => write!(f, "unsupported grant type"),
+            OAuthError::UnauthorizedClient => {
+                write!(f, "client not allowed to use the grant type")
+            }
+            OAuthError::UnsupportedResponseType => write!(f, "unsupported response type"),
+            OAuthError::InvalidScope => write!(f, "scope not allowed for the client"),
+            OAuthError::AccessDenied => write!(f, "authorization denied by the user"),
+            OAuthError::Forbidden => write!(f, "not allowed to manage OAuth clients"),
+            OAuthError::ClientNotFound => write!(f, "OAuth client not found"),
+            OAuthError::ConsentNotFound => write!(f, "consent request not found"),
+            OAuthError::InvalidToken => write!(f, "missing, invalid or expired access token"),
+            OAuthError::InsufficientScope => write!(f, "access token lacks the required scope"),
+            OAuthError::InternalServerError(source) => {
+                fmt_with_source(f, "internal server error", source)
+            }
+            OAuthError::ServiceUnavailable(source) => {
+                fmt_with_source(f, "service unavailable", source)
+            }
+        }
+    }
+}
+
+impl std::error::Error for OAuthError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            OAuthError::InternalServerError(source) | OAuthError::ServiceUnavailable(source) => {
+                as_source(source)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl OAuthError {
+    pub fn internal(source: impl Into<BoxError>) -> Self {
+        OAuthError::InternalServerError(Some(source.into()))
+    }
+
+    /// For failures that are likely to go away on retry, like a lost database connection.
+    pub fn unavailable(source: impl Into<BoxError>) -> Self {
+        OAuthError::ServiceUnavailable(Some(source.into()))
+    }
+
+    /// The RFC 6749 error code, used in redirects and token endpoint responses.
+    fn error_code(&self) -> &'static str {
+        match self {
+            OAuthError::InvalidRequest(_) => "invalid_request",
+            OAuthError::InvalidClient => "invalid_client",
+            OAuthError::InvalidGrant => "invalid_grant",
+            OAuthError::UnsupportedGrantType => "unsupported_grant_type",
+            OAuthError::UnauthorizedClient => "unauthorized_client",
+            OAuthError::UnsupportedResponseType => "unsupported_response_type",
+            OAuthError::InvalidScope => "invalid_scope",
+            OAuthError::AccessDenied => "access_denied",
+            OAuthError::InvalidToken => "invalid_token",
+            OAuthError::InsufficientScope => "insufficient_scope",
+            OAuthError::ServiceUnavailable(_) => "temporarily_unavailable",
+            _ => "server_error",
+        }
+    }
+
+    /// Answers a token request in the RFC 6749 format OAuth clients expect, instead of
+    /// as problem.
+    fn token_response(&self) -> HttpResponse {
+        let problem = self.to_problem();
+        let mut response = HttpResponse::build(problem.status_code());
+        if let OAuthError::InvalidClient = self {
+            response.insert_header((WWW_AUTHENTICATE, "Basic realm=\"oauth\""));
+        }
+
+        response
+            .insert_header((CACHE_CONTROL, "no-store"))
+            .json(TokenErrorResponse {
+                error: self.error_code(),
+                error_description: problem.detail.unwrap_or(problem.title),
+            })
+    }
+}
+
+impl From<SessionError> for OAuthError {
+    fn from(value: SessionError) -> Self {
+        match value {
+            SessionError::InternalServerError(source) => OAuthError::InternalServerError(source),
+            SessionError::ServiceUnavailable(source) => OAuthError::ServiceUnavailable(source),
+            other => OAuthError::internal(other),
+        }
+    }
+}
+
+impl ToProblem for OAuthError {
+    fn to_problem(&self) -> Problem {
+        match self {
+            OAuthError::InvalidRequest(detail) => Problem::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_request",
+                "Invalid authorization request",
+            )
+            .with_detail(detail.clone()),
+            OAuthError::InvalidClient => Problem::new(
+                StatusCode::UNAUTHORIZED,
+                "invalid_client",
+                "Unknown client or invalid client credentials",
+            ),
+            OAuthError::InvalidGrant => Problem::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_grant",
+                "Invalid, expired or already used grant",
+            ),
+            OAuthError::UnsupportedGrantType => Problem::new(
+                StatusCode::BAD_REQUEST,
+                "unsupported_grant_type",
+                "Unsupported grant type",
+            ),
+            OAuthError::UnauthorizedClient => Problem::new(
+                StatusCode::BAD_REQUEST,
+                "unauthorized_client",
+                "Client not allowed to use this grant type",
+            ),
+            OAuthError::UnsupportedResponseType => Problem::new(
+                StatusCode::BAD_REQUEST,
+                "unsupported_response_type",
+                "Unsupported response type",
+            ),
+            OAuthError::InvalidScope => Problem::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_scope",
+                "Scope not allowed for the client",
+            ),
+            OAuthError::AccessDenied => Problem::new(
+                StatusCode::FORBIDDEN,
+                "access_denied",
+                "Authorization denied",
+            ),
+            OAuthError::Forbidden => Problem::new(
+                StatusCode::FORBIDDEN,
+                "forbidden",
+                "Not allowed to manage OAuth clients",
+            ),
+            OAuthError::ClientNotFound => Problem::new(
+                StatusCode::NOT_FOUND,
+                "client_not_found",
+                "OAuth client not found",
+            ),
+            OAuthError::ConsentNotFound => Problem::new(
+                StatusCode::NOT_FOUND,
+                "consent_not_found",
+                "Consent request not found or expired",
+            ),
+            OAuthError::InvalidToken => Problem::new(
+                StatusCode::UNAUTHORIZED,
+                "invalid_token",
+                "Missing, invalid or expired access token",
+            ),
+            OAuthError::InsufficientScope => Problem::new(
+                StatusCode::FORBIDDEN,
+                "insufficient_scope",
+                "Access token lacks the required scope",
+            ),
+            OAuthError::InternalServerError(_) => Problem::internal_server_error(),
+            OAuthError::ServiceUnavailable(_) => Problem::service_unavailable(),
+        }
+    }
+}
+
+impl From<OAuthError> for HttpResponse {
+    fn from(value: OAuthError) -> Self {
+        value.to_problem().into_response()
+    }
+}
+
+impl actix_web::error::ResponseError for OAuthError {
+    fn status_code(&self) -> StatusCode {
+        self.to_problem().status_code()
+    }
+
+    fn error_response(&self) -> HttpResponse<actix_web::body::BoxBody> {
+        let mut response = self.to_problem().into_response();
+        // RFC 6750 challenge for endpoints protected by bearer tokens.
+        if let OAuthError::InvalidToken | OAuthError::InsufficientScope = self
+            && let Ok(challenge) =
+                HeaderValue::from_str(&format!("Bearer error=\"{}\"", self.error_code()))
+        {
+            response.headers_mut().insert(WWW_AUTHENTICATE, challenge);
+        }
+        response
+    }
+}
+
+/// A registered client application.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct OAuthClient {
+    pub client_id: String,
+    pub name: String,
+    /// Hash of the secret of confidential clients. Public clients like SPAs and mobile
+    /// apps can't keep a secret and are authenticated by PKCE alone.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secret_hash: Option<String>,
+    /// Allowed redirect targets, compared exactly.
+    pub redirect_uris: Vec<String>,
+    /// Scopes the client may request. Requests without `scope` get all of them.
+    pub scopes: Vec<String>,
+    /// Trusted clients of the app itself, they never ask for consent.
+    #[serde(default)]
+    pub first_party: bool,
+    /// Where `oauth/end-session` may send the browser after logging out.
+    #[serde(default)]
+    pub post_logout_redirect_uris: Vec<String>,
+    /// Client of a service account, with the account's id as client id. Only these may
+    /// use the `client_credentials` grant.
+    #[serde(default)]
+    pub service_account: bool,
+    /// PEM public key of clients that authenticate with signed JWT assertions instead of
+    /// a secret (RFC 7523 `private_key_jwt`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub public_key: Option<String>,
+    pub created_at: u64,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ClientRegistration {
+    pub name: String,
+    pub redirect_uris: Vec<String>,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    /// Issues a client secret. Leave unset for SPAs and mobile apps.
+    #[serde(default)]
+    pub confidential: bool,
+    #[serde(default)]
+    pub first_party: bool,
+    #[serde(default)]
+    pub post_logout_redirect_uris: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ClientResponse {
+    pub client_id: String,
+    pub name: String,
+    pub redirect_uris: Vec<String>,
+    pub scopes: Vec<String>,
+    pub first_party: bool,
+    pub post_logout_redirect_uris: Vec<String>,
+    pub confidential: bool,
+    pub service_account: bool,
+    /// Only returned on registration, only its hash is stored.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_secret: Option<String>,
+}
+
+impl From<OAuthClient> for ClientResponse {
+    fn from(client: OAuthClient) -> Self {
+        Self {
+            confidential: client.secret_hash.is_some() || client.public_key.is_some(),
+            service_account: client.service_account,
+            client_id: client.client_id,
+            name: client.name,
+            redirect_uris: client.redirect_uris,
+            scopes: client.scopes,
+            first_party: client.first_party,
+            post_logout_redirect_uris: client.post_logout_redirect_uris,
+            client_secret: None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum GrantKind {
+    /// Authorization request waiting for the user's consent, stored under a random id.
+    ConsentPending,
+    /// Unredeemed authorization code, stored under the hash of the code.
+    AuthorizationCode,
+    /// Scopes a user agreed to for a client. Later requests within them skip the consent screen.
+    Consent,
+    /// Tokens issued for one authorization code or `client_credentials` request. Its id is
+    /// the `sid` of the access tokens, deleting it revokes every refresh token of the family.
+    TokenFamily,
+    /// Refresh token, stored under the hash of the token with its family in `family`.
+    RefreshToken,
+    /// Refresh token that was already exchanged. Kept until it expires to detect reuse.
+    RotatedRefreshToken,
+    /// Client assertion that was already used, stored under its client and `jti` until it
+    /// expires to reject replays.
+    ClientAssertion,
+}
+
+/// Something a user granted a client, or a step towards it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Grant {
+    pub id: String,
+    pub kind: GrantKind,
+    pub client_id: String,
+    pub user_id: String,
+    pub scopes: Vec<String>,
+    /// Unix timestamp (seconds) after which the grant is no longer accepted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+    /// Id of the [`GrantKind::TokenFamily`] a refresh token belongs to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub family: Option<String>,
+    /// The request a pending consent or an authorization code was issued for.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request: Option<AuthorizationRequest>,
+}
+
+impl Grant {
+    pub fn is_expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| expires_at <= unix_now())
+    }
+}
+
+/// Query of `oauth/authorize`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AuthorizationRequest {
+    pub response_type: String,
+    pub client_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub redirect_uri: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub state: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code_challenge: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code_challenge_method: Option<String>,
+    /// OpenID Connect nonce, echoed in the ID token.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<String>,
+}
+
+/// What the consent screen shows, also served at `GET oauth/consent/{id}`.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ConsentRequest {
+    pub id: String,
+    pub client_id: String,
+    pub client_name: String,
+    pub scopes: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ConsentDecision {
+    approve: bool,
+}
+
+/// Where the consent screen sends the browser next, back to the client either way.
+#[derive(Serialize, Deserialize)]
+pub struct ConsentResponse {
+    redirect_to: String,
+}
+
+/// Outcome of an authorization request of a logged in user.
+pub enum Authorization {
+    /// Redirect back to the client with a code.
+    Granted { redirect_to: String },
+    /// The user has to agree first.
+    ConsentRequired(ConsentRequest),
+}
+
+/// Client authentication in the form of `oauth/token`, `oauth/introspect` and `oauth/revoke`.
+#[derive(Default, Serialize, Deserialize)]
+pub struct ClientAuthentication {
+    client_id: Option<String>,
+    client_secret: Option<String>,
+    client_assertion_type: Option<String>,
+    client_assertion: Option<String>,
+}
+
+/// How a client authenticates, taken from HTTP Basic authentication or the form.
+/// Clients with a public key send `client_assertion` instead of a secret.
+pub struct ClientCredentials {
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    pub client_assertion: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TokenRequest {
+    grant_type: String,
+    code: Option<String>,
+    redirect_uri: Option<String>,
+    code_verifier: Option<String>,
+    refresh_token: Option<String>,
+    scope: Option<String>,
+    #[serde(flatten)]
+    client: ClientAuthentication,
+}
+
+/// Form of `oauth/introspect` and `oauth/revoke`. `token_type_hint` is accepted but not
+/// needed, the kind of token is detected.
+#[derive(Serialize, Deserialize)]
+pub struct TokenLookupRequest {
+    token: String,
+    #[serde(flatten)]
+    client: ClientAuthentication,
+}
+
+/// RFC 7662 introspection response. Inactive tokens only carry `active: false`.
+#[derive(Default, Serialize, Deserialize)]
+pub struct Introspection {
+    pub active: bool,
+    /// Space separated scopes of client tokens.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    /// `session`, `Bearer` or `refresh_token`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_type: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exp: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iat: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sub: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aud: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iss: Option<String>,
+}
+
+/// An active token found by `oauth/introspect` or `oauth/revoke`.
+enum ActiveToken<T> {
+    Session(Session<T>, T),
+    /// Access token of the stateless mode.
+    SessionAccessToken(JwtClaims<T>),
+    ClientAccessToken(JwtClaims<T>),
+    RefreshToken(Grant),
+}
+
+impl<T> ActiveToken<T> {
+    /// Whether the client may introspect or revoke the token: first-party clients the
+    /// sessions and access tokens of first-party logins, every client the tokens issued to it.
+    fn accessible_by(&self, client: &OAuthClient) -> bool {
+        match self {
+            ActiveToken::Session(..) | ActiveToken::SessionAccessToken(_) => client.first_party,
+            ActiveToken::ClientAccessToken(claims) => {
+                claims.client_id.as_ref() == Some(&client.client_id)
+            }
+            ActiveToken::RefreshToken(grant) => grant.client_id == client.client_id,
+        }
+    }
+}
+
+/// Claims of an RFC 7523 client assertion, besides the validated `iss` and `aud`.
+#[derive(Deserialize)]
+struct ClientAssertion {
+    sub: String,
+    jti: String,
+    exp: u64,
+}
+
+#[derive(Serialize)]
+struct TokenErrorResponse {
+    error: &'static str,
+    error_description: String,
+}
+
+#[derive(Deserialize)]
+pub struct ConsentPath {
+    id: String,
+}
+
+#[derive(Deserialize)]
+pub struct ClientPath {
+    client_id: String,
+}
+
+/// Asks the user whether a client may act on their behalf. The screen belongs to the app,
+/// it submits the decision to `POST oauth/consent/{id}`.
+#[async_trait]
+pub trait ConsentScreen<T>: Send + Sync
+where
+    T: ObjectId + Serialize + for<'de> Deserialize<'de> + Send + Sync,
+{
+    async fn render(&self, identity: &T, request: &ConsentRequest) -> HttpResponse;
+}
+
+/// Redirects to the app's consent page with the request id in the `consent` parameter.
+pub struct ConsentRedirect {
+    url: String,
+}
+
+impl ConsentRedirect {
+    pub fn new(url: String) -> Self {
+        Self { url }
+    }
+}
+
+#[async_trait]
+impl<T> ConsentScreen<T> for ConsentRedirect
+where
+    T: ObjectId + Serialize + for<'de> Deserialize<'de> + Send + Sync,
+{
+    async fn render(&self, _identity: &T, request: &ConsentRequest) -> HttpResponse {
+        redirect(with_query(&self.url, &[("consent", &request.id)]))
+    }
+}
+
+/// OAuth 2.0 authorization server for the authorization code flow with PKCE. Users
+/// authorize clients with their regular session, clients get signed access tokens and
+/// rotating refresh tokens.
+#[derive(Clone)]
+pub struct OAuthProvider<T>
+where
+    T: IntoPublic
+        + ObjectId
+        + Serialize
+        + for<'de> Deserialize<'de>
+        + Clone
+        + Send
+        + Sync
+        + 'static,
+{
+    authorize_path: String,
+    token_path: String,
+    consent_path: String,
+    clients_path: String,
+    jwks_path: String,
+    session_backend: Data<Box<dyn SessionBackend<T>>>,
+    client_backend: Data<Box<dyn ClientBackend>>,
+    grant_backend: Data<Box<dyn GrantBackend>>,
+    consent_screen: Data<Box<dyn ConsentScreen<T>>>,
+    login_url: String,
+    is_admin: Arc<dyn Fn(&T) -> bool + Send + Sync>,
+    jwt: JwtConfig,
+    oidc: bool,
+    introspect_path: String,
+    revoke_path: String,
+    session_provider: Option<Data<SessionProvider<T>>>,
+}
+
+impl<
+    T: IntoPublic + ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
+> OAuthProvider<T>
+{
+    /// Access tokens are signed with `jwt`, which may be the config of the stateless mode.
+    /// Users without a session are redirected to `login_url` with the authorization
+    /// request to continue with in its `return_to` parameter.
+    pub fn default_with_backend(
+        jwt: JwtConfig,
+        session_backend: Data<Box<dyn SessionBackend<T>>>,
+        client_backend: Data<Box<dyn ClientBackend>>,
+        grant_backend: Data<Box<dyn GrantBackend>>,
+        consent_screen: Data<Box<dyn ConsentScreen<T>>>,
+        login_url: String,
+        is_admin: impl Fn(&T) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            authorize_path: String::from("oauth/authorize"),
+            token_path: String::from("oauth/token"),
+            consent_path: String::from("oauth/consent"),
+            clients_path: String::from("oauth/clients"),
+            jwks_path: String::from("oauth/jwks.json"),
+            session_backend,
+            client_backend,
+            grant_backend,
+            consent_screen,
+            login_url,
+            is_admin: Arc::new(is_admin),
+            jwt,
+            oidc: false,
+            introspect_path: String::from("oauth/introspect"),
+            revoke_path: String::from("oauth/revoke"),
+            session_provider: None,
+        }
+    }
+
+    /// Issues ID tokens for the `openid` scope, see [`crate::oidc`].
+    pub fn with_oidc(mut self) -> Self {
+        self.oidc = true;
+        self
+    }
+
+    /// Lets `oauth/introspect` and `oauth/revoke` handle the sessions and stateless access
+    /// tokens of the provider as well.
+    pub fn with_session_provider(mut self, session_provider: Data<SessionProvider<T>>) -> Self {
+        self.session_provider = Some(session_provider);
+        self
+    }
+
+    pub(crate) fn authorize_path(&self) -> &str {
+        &self.authorize_path
+    }
+
+    pub(crate) fn token_path(&self) -> &str {
+        &self.token_path
+    }
+
+    pub(crate) fn jwks_path(&self) -> &str {
+        &self.jwks_path
+    }
+
+    pub(crate) fn introspect_path(&self) -> &str {
+        &self.introspect_path
+    }
+
+    pub(crate) fn revoke_path(&self) -> &str {
+        &self.revoke_path
+    }
+
+    pub(crate) fn jwt(&self) -> &JwtConfig {
+        &self.jwt
+    }
+
+    pub fn configure(&self, cfg: &mut ServiceConfig) {
+        let data = Data::new(self.clone());
+        cfg.app_data(data.clone())
+            .route(&data.authorize_path, get().to(authorize::<T>))
+            .route(&data.token_path, post().to(token::<T>))
+            .route(&data.introspect_path, post().to(introspect::<T>))
+            .route(&data.revoke_path, post().to(revoke::<T>))
+            .route(&data.jwks_path, get().to(jwks::<T>))
+            .route(
+                &format!("{}/{{id}}", data.consent_path),
+                get().to(get_consent::<T>),
+            )
+            .route(
+                &format!("{}/{{id}}", data.consent_path),
+                post().to(decide_consent::<T>),
+            )
+            .route(&data.clients_path, post().to(register_client::<T>))
+            .route(&data.clients_path, get().to(get_clients::<T>))
+            .route(
+                &format!("{}/{{client_id}}", data.clients_path),
+                delete().to(delete_client::<T>),
+            );
+    }
+
+    /// Registers a client. The secret of confidential clients is only part of the response.
+    pub async fn register_client(
+        &self,
+        registration: ClientRegistration,
+    ) -> Result<ClientResponse, OAuthError> {
+        if registration.redirect_uris.is_empty() {
+            return Err(OAuthError::InvalidRequest(String::from(
+                "at least one redirect_uri is required",
+            )));
+        }
+        for redirect_uri in registration
+            .redirect_uris
+            .iter()
+            .chain(&registration.post_logout_redirect_uris)
+        {
+            match Url::parse(redirect_uri) {
+                Ok(url) if url.fragment().is_none() => {}
+                _ => {
+                    return Err(OAuthError::InvalidRequest(format!(
+                        "{redirect_uri} is not an absolute URL without fragment"
+                    )));
+                }
+            }
+        }
+
+        let client_secret = registration.confidential.then(|| random_token(48));
+        let client = OAuthClient {
+            client_id: Uuid::new_v4().into(),
+            name: registration.name,
+            secret_hash: client_secret.as_deref().map(hash_token),
+            redirect_uris: registration.redirect_uris,
+            scopes: registration.scopes,
+            first_party: registration.first_party,
+            post_logout_redirect_uris: registration.post_logout_redirect_uris,
+            service_account: false,
+            public_key: None,
+            created_at: unix_now(),
+        };
+
+        observe_backend(
+            "create_client",
+            self.client_backend.create_client(client.clone()),
+        )
+        .await?;
+
+        Ok(ClientResponse {
+            client_secret,
+            ..client.into()
+        })
+    }
+
+    /// The registered clients, without those of service accounts. They are managed through
+    /// `service-accounts` together with their identity.
+    pub async fn clients(&self) -> Result<Vec<ClientResponse>, OAuthError> {
+        let clients = observe_backend("get_clients", self.client_backend.get_clients()).await?;
+        Ok(clients
+            .into_iter()
+            .filter(|client| !client.service_account)
+            .map(ClientResponse::from)
+            .collect())
+    }
+
+    /// Removes a client. Access tokens it holds stay valid until they expire, its
+    /// refresh tokens are rejected right away. Clients of service accounts are treated as
+    /// unknown, deleting the service account removes them.
+    pub async fn delete_client(&self, client_id: String) -> Result<(), OAuthError> {
+        let client = observe_backend(
+            "get_client",
+            self.client_backend.get_client(client_id.clone()),
+        )
+        .await?;
+        if client.is_none_or(|client| client.service_account) {
+            return Err(OAuthError::ClientNotFound);
+        }
+
+        match observe_backend(
+            "delete_client",
+            self.client_backend.delete_client(client_id),
+        )
+        .await?
+        {
+            true => Ok(()),
+            false => Err(OAuthError::ClientNotFound),
+        }
+    }
+
+    /// Looks up the client of an authorization request and the redirect target to answer
+    /// it with. Until both are known errors can't be sent back to the client.
+    pub async fn resolve_client(
+        &self,
+        request: &AuthorizationRequest,
+    ) -> Result<(OAuthClient, String), OAuthError> {
+        let Some(client) = observe_backend(
+            "get_client",
+            self.client_backend.get_client(request.client_id.clone()),
+        )
+        .await?
+        else {
+            return Err(OAuthError::InvalidClient);
+        };
+
+        let redirect_uri = redirect_uri(&client, request)?;
+        Ok((client, redirect_uri))
+    }
+
+    /// Authorizes a request of a logged in user. Requests within scopes the user already
+    /// agreed to, and those of first-party clients, are granted right away.
+    #[tracing::instrument(
+        name = "oauth.authorize",
+        skip_all,
+        fields(user_id = Empty, outcome = Empty, latency_ms = Empty)
+    )]
+    pub async fn authorize(
+        &self,
+        identity: &T,
+        client: &OAuthClient,
+        redirect_uri: String,
+        request: AuthorizationRequest,
+    ) -> Result<Authorization, OAuthError> {
+        let started = Instant::now();
+        let result: Result<Authorization, OAuthError> = async {
+            let Some(user_id) = identity.id() else {
+                return Err(OAuthError::internal("identity without id"));
+            };
+            let user_id = String::from(user_id);
+            record_user_id(&user_id);
+
+            if request.response_type != "code" {
+                return Err(OAuthError::UnsupportedResponseType);
+            }
+            match (
+                &request.code_challenge,
+                request.code_challenge_method.as_deref(),
+            ) {
+                (Some(_), Some("S256")) => {}
+                (Some(_), _) => {
+                    return Err(OAuthError::InvalidRequest(String::from(
+                        "code_challenge_method has to be S256",
+                    )));
+                }
+                (None, _) => {
+                    return Err(OAuthError::InvalidRequest(String::from(
+                        "code_challenge is required",
+                    )));
+                }
+            }
+            let scopes = requested_scopes(client, request.scope.as_deref())?;
+
+            let consent = observe_backend(
+                "get_grant",
+                self.grant_backend
+                    .get_grant(consent_id(&client.client_id, &user_id)),
+            )
+            .await?;
+            let consented = consent.is_some_and(|consent| {
+                consent.kind == GrantKind::Consent
+                    && scopes.iter().all(|scope| consent.scopes.contains(scope))
+            });
+
+            if client.first_party || consented {
+                let redirect_to = self
+                    .issue_code(user_id, client, redirect_uri, request, scopes)
+                    .await?;
+                return Ok(Authorization::Granted { redirect_to });
+            }
+
+            let pending = Grant {
+                id: random_token(32),
+                kind: GrantKind::ConsentPending,
+                client_id: client.client_id.clone(),
+                user_id,
+                scopes: scopes.clone(),
+                expires_at: Some(unix_now() + CONSENT_LIFETIME),
+                family: None,
+                request: Some(request),
+            };
+            observe_backend("save_grant", self.grant_backend.save_grant(pending.clone())).await?;
+
+            Ok(Authorization::ConsentRequired(ConsentRequest {
+                id: pending.id,
+                client_id: client.client_id.clone(),
+                client_name: client.name.clone(),
+                scopes,
+            }))
+        }
+        .await;
+
+        record_outcome("oauth.authorize", &result, started);
+        result
+    }
+
+    /// The pending authorization request with the given id, if it belongs to the user.
+    pub async fn consent_request(
+        &self,
+        user_id: String,
+        id: String,
+    ) -> Result<ConsentRequest, OAuthError> {
+        let pending = self.pending_consent(&user_id, id).await?;
+        let client = self.consent_client(&pending).await?;
+
+        Ok(ConsentRequest {
+            id: pending.id,
+            client_id: client.client_id,
+            client_name: client.name,
+            scopes: pending.scopes,
+        })
+    }
+
+    /// Answers a pending authorization request and returns where to send the browser.
+    /// Approvals are remembered, so the client doesn't ask again for the same scopes.
+    #[tracing::instrument(
+        name = "oauth.consent",
+        skip_all,
+        fields(user_id = Empty, outcome = Empty, latency_ms = Empty)
+    )]
+    pub async fn decide_consent(
+        &self,
+        user_id: String,
+        id: String,
+        approve: bool,
+    ) -> Result<String, OAuthError> {
+        let started = Instant::now();
+        let result: Result<String, OAuthError> = async {
+            record_user_id(&user_id);
+            let pending = self.pending_consent(&user_id, id).await?;
+            let client = self.consent_client(&pending).await?;
+            let Some(request) = pending.request.clone() else {
+                return Err(OAuthError::internal("consent request without request"));
+            };
+            let redirect_uri = redirect_uri(&client, &request)?;
+
+            if !observe_backend("delete_grant", self.grant_backend.delete_grant(pending.id)).await?
+            {
+                return Err(OAuthError::ConsentNotFound);
+            }
+
+            if !approve {
+                return Ok(error_redirect(
+                    &redirect_uri,
+                    &OAuthError::AccessDenied,
+                    request.state,
+                ));
+            }
+
+            let id = consent_id(&client.client_id, &user_id);
+            let mut scopes =
+                match observe_backend("get_grant", self.grant_backend.get_grant(id.clone())).await?
+                {
+                    Some(consent) if consent.kind == GrantKind::Consent => consent.scopes,
+                    _ => Vec::new(),
+                };
+            for scope in &pending.scopes {
+                if !scopes.contains(scope) {
+                    scopes.push(scope.clone());
+                }
+            }
+            let consent = Grant {
+                id,
+                kind: GrantKind::Consent,
+                client_id: client.client_id.clone(),
+                user_id: user_id.clone(),
+                scopes,
+                expires_at: None,
+                family: None,
+                request: None,
+            };
+            observe_backend("save_grant", self.grant_backend.save_grant(consent)).await?;
+
+            self.issue_code(user_id, &client, redirect_uri, request, pending.scopes)
+                .await
+        }
+        .await;
+
+        record_outcome("oauth.consent", &result, started);
+        result
+    }
+
+    /// Handles `oauth/token`.
+    #[tracing::instrument(
+        name = "oauth.token",
+        skip_all,
+        fields(user_id = Empty, client_id = Empty, grant_type = Empty, outcome = Empty, latency_ms = Empty)
+    )]
+    pub async fn token(
+        &self,
+        credentials: ClientCredentials,
+        request: TokenRequest,
+        context: &AuditContext,
+    ) -> Result<TokenResponse, OAuthError> {
+        let started = Instant::now();
+        let span = tracing::Span::current();
+        span.record("client_id", credentials.client_id.as_str());
+        span.record("grant_type", request.grant_type.as_str());
+
+        let result: Result<TokenResponse, OAuthError> = async {
+            let client = self.authenticate_client(credentials).await?;
+
+            match request.grant_type.as_str() {
+                "authorization_code" => self.exchange_code(&client, request).await,
+                "refresh_token" => self.refresh(&client, request, context).await,
+                "client_credentials" => self.client_credentials_grant(&client, request).await,
+                _ => Err(OAuthError::UnsupportedGrantType),
+            }
+        }
+        .await;
+
+        record_outcome("oauth.token", &result, started);
+        result
+    }
+
+    /// RFC 7662 introspection for resource servers and gateways, which have to be
+    /// confidential clients. Answers whether a session id, access token or refresh token
+    /// is active and whom it belongs to. Tokens the client couldn't revoke are reported
+    /// as inactive.
+    #[tracing::instrument(
+        name = "oauth.introspect",
+        skip_all,
+        fields(user_id = Empty, client_id = Empty, outcome = Empty, latency_ms = Empty)
+    )]
+    pub async fn introspect(
+        &self,
+        credentials: ClientCredentials,
+        token: String,
+    ) -> Result<Introspection, OAuthError> {
+        let started = Instant::now();
+        tracing::Span::current().record("client_id", credentials.client_id.as_str());
+
+        let result: Result<Introspection, OAuthError> = async {
+            let client = self.authenticate_client(credentials).await?;
+            if client.secret_hash.is_none() && client.public_key.is_none() {
+                return Err(OAuthError::UnauthorizedClient);
+            }
+
+            let token = self
+                .find_token(token)
+                .await?
+                .filter(|token| token.accessible_by(&client));
+            let introspection = match token {
+                Some(ActiveToken::Session(session, identity)) => Introspection {
+                    active: true,
+                    username: Some(identity.username()),
+                    token_type: Some(String::from("session")),
+                    exp: session.expires_at,
+                    sub: Some(session.user_id),
+                    ..Default::default()
+                },
+                Some(
+                    ActiveToken::SessionAccessToken(claims)
+                    | ActiveToken::ClientAccessToken(claims),
+                ) => Introspection {
+                    active: true,
+                    scope: claims.scope,
+                    client_id: claims.client_id,
+                    username: Some(claims.username),
+                    token_type: Some(String::from("Bearer")),
+                    exp: Some(claims.exp),
+                    iat: Some(claims.iat),
+                    sub: Some(claims.sub),
+                    aud: claims.aud,
+                    iss: Some(claims.iss),
+                },
+                Some(ActiveToken::RefreshToken(grant)) => {
+                    let identity = observe_backend(
+                        "get_identity",
+                        self.session_backend.get_identity(grant.user_id.clone()),
+                    )
+                    .await?;
+                    Introspection {
+                        active: true,
+                        scope: Some(grant.scopes.join(" ")),
+                        client_id: Some(grant.client_id),
+                        username: Some(identity.username()),
+                        token_type: Some(String::from("refresh_token")),
+                        exp: grant.expires_at,
+                        sub: Some(grant.user_id),
+                        iss: Some(self.jwt.issuer().into()),
+                        ..Default::default()
+                    }
+                }
+                None => Introspection::default(),
+            };
+            if let Some(sub) = &introspection.sub {
+                record_user_id(sub);
+            }
+            Ok(introspection)
+        }
+        .await;
+
+        record_outcome("oauth.introspect", &result, started);
+        result
+    }
+
+    /// RFC 7009 revocation. Clients revoke the tokens issued to them along with the rest of
+    /// their grant, first-party clients also sessions and stateless access tokens. Unknown
+    /// tokens and those of others are ignored, as the RFC asks. Revoked tokens are audited
+    /// with `context`.
+    #[tracing::instrument(
+        name = "oauth.revoke",
+        skip_all,
+        fields(user_id = Empty, client_id = Empty, outcome = Empty, latency_ms = Empty)
+    )]
+    pub async fn revoke(
+        &self,
+        credentials: ClientCredentials,
+        token: String,
+        context: &AuditContext,
+    ) -> Result<(), OAuthError> {
+        let started = Instant::now();
+        tracing::Span::current().record("client_id", credentials.client_id.as_str());
+
+        let result: Result<(), OAuthError> = async {
+            let client = self.authenticate_client(credentials).await?;
+
+            let token = self
+                .find_token(token)
+                .await?
+                .filter(|token| token.accessible_by(&client));
+            let user_id = match token {
+                Some(ActiveToken::Session(session, _)) => {
+                    self.end_session(session.id).await?;
+                    session.user_id
+                }
+                Some(ActiveToken::SessionAccessToken(claims)) => {
+                    self.end_session(claims.sid).await?;
+                    claims.sub
+                }
+                Some(ActiveToken::ClientAccessToken(claims)) => {
+                    self.revoke_family(claims.sid).await?;
+                    claims.sub
+                }
+                Some(ActiveToken::RefreshToken(grant)) => {
+                    if let Some(family_id) = grant.family {
+                        self.revoke_family(family_id).await?;
+                    }
+                    grant.user_id
+                }
+                None => return Ok(()),
+            };
+            record_user_id(&user_id);
+            self.audit(
+                AuditEvent::new(AuditAction::SessionRevoke, context)
+                    .actor(user_id.clone())
+                    .target(user_id),
+            )
+            .await;
+            Ok(())
+        }
+        .await;
+
+        record_outcome("oauth.revoke", &result, started);
+        result
+    }
+
+    /// Looks a token up as access token, refresh token of a client or session id, in that
+    /// order. Tokens that are invalid, expired or revoked aren't found.
+    async fn find_token(&self, token: String) -> Result<Option<ActiveToken<T>>, OAuthError> {
+        if let Ok(claims) = self.jwt.verify::<T>(&token)
+            && claims.client_id.is_some()
+        {
+            let family = observe_backend(
+                "get_grant",
+                self.grant_backend.get_grant(claims.sid.clone()),
+            )
+            .await?;
+            let active = family.is_some_and(|family| {
+                family.kind == GrantKind::TokenFamily && !family.is_expired()
+            });
+            return Ok(active.then_some(ActiveToken::ClientAccessToken(claims)));
+        }
+
+        let session_provider = self.session_provider.as_deref();
+        if let Some(session_provider) = session_provider
+            && let Some(jwt) = session_provider.jwt()
+            && let Ok(claims) = jwt.verify::<T>(&token)
+        {
+            return match session_provider.validate_access_token(token).await {
+                Ok(_) => Ok(Some(ActiveToken::SessionAccessToken(claims))),
+                Err(SessionError::InvalidOrMissingSession) => Ok(None),
+                Err(e) => Err(e.into()),
+            };
+        }
+
+        if let Some(grant) = observe_backend(
+            "get_grant",
+            self.grant_backend.get_grant(hash_token(&token)),
+        )
+        .await?
+        {
+            if grant.kind != GrantKind::RefreshToken || grant.is_expired() {
+                return Ok(None);
+            }
+            let Some(family_id) = grant.family.clone() else {
+                return Ok(None);
+            };
+            let family =
+                observe_backend("get_grant", self.grant_backend.get_grant(family_id)).await?;
+            let active = family.is_some_and(|family| family.kind == GrantKind::TokenFamily);
+            return Ok(active.then_some(ActiveToken::RefreshToken(grant)));
+        }
+
+        let Some(session_provider) = session_provider else {
+            return Ok(None);
+        };
+        match session_provider.active_session(token).await {
+            Ok((session, identity)) => Ok(Some(ActiveToken::Session(session, identity))),
+            Err(SessionError::InvalidOrMissingSession) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Logs out a session or revokes a token family of the stateless mode, whose id is
+    /// the `sid` of its access tokens.
+    /// Records through the audit sink of the session provider.
+    async fn audit(&self, event: AuditEvent) {
+        if let Some(session_provider) = &self.session_provider {
+            audit::record(&session_provider.audit_sink, event).await;
+        }
+    }
+
+    async fn end_session(&self, session_id: String) -> Result<(), OAuthError> {
+        let Some(session_provider) = &self.session_provider else {
+            return Ok(());
+        };
+        match session_provider.logout(session_id).await {
+            Ok(_) | Err(SessionError::InvalidOrMissingSession) => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn authenticate_client(
+        &self,
+        credentials: ClientCredentials,
+    ) -> Result<OAuthClient, OAuthError> {
+        let client = observe_backend(
+            "get_client",
+            self.client_backend.get_client(credentials.client_id),
+        )
+        .await?
+        .ok_or(OAuthError::InvalidClient)?;
+
+        let authenticated = match (
+            &client.secret_hash,
+            &client.public_key,
+            credentials.client_secret,
+            credentials.client_assertion,
+        ) {
+            (Some(secret_hash), _, Some(secret), None) => {
+                verify_secret(&hash_token(&secret), Some(secret_hash))
+            }
+            (_, Some(public_key), None, Some(assertion)) => {
+                self.verify_client_assertion(&client, public_key, &assertion)
+                    .await?
+            }
+            (None, None, None, None) => true,
+            _ => false,
+        };
+
+        match authenticated {
+            true => Ok(client),
+            false => Err(OAuthError::InvalidClient),
+        }
+    }
+
+    /// Checks an RFC 7523 assertion: signed with the client's key, issued by the client
+    /// about itself for this server, and not presented before.
+    async fn verify_client_assertion(
+        &self,
+        client: &OAuthClient,
+        public_key: &str,
+        assertion: &str,
+    ) -> Result<bool, OAuthError> {
+        let Ok(header) = jsonwebtoken::decode_header(assertion) else {
+            return Ok(false);
+        };
+        let Some(key) = assertion_key(public_key, header.alg) else {
+            return Ok(false);
+        };
+
+        let token_endpoint = format!(
+            "{}/{}",
+            self.jwt.issuer().trim_end_matches('/'),
+            self.token_path
+        );
+        let mut validation = Validation::new(header.alg);
+        validation.leeway = 0;
+        validation.set_issuer(&[&client.client_id]);
+        validation.sub = Some(client.client_id.clone());
+        validation.set_audience(&[self.jwt.issuer(), token_endpoint.as_str()]);
+        validation.set_required_spec_claims(&["exp", "iss", "sub", "aud"]);
+        let Ok(claims) = jsonwebtoken::decode::<ClientAssertion>(assertion, &key, &validation)
+            .map(|data| data.claims)
+        else {
+            return Ok(false);
+        };
+        if claims.exp > unix_now() + MAX_ASSERTION_LIFETIME {
+            return Ok(false);
+        }
+
+        let used = Grant {
+            id: format!("assertion:{}:{}", client.client_id, claims.jti),
+            kind: GrantKind::ClientAssertion,
+            client_id: client.client_id.clone(),
+            user_id: client.client_id.clone(),
+            scopes: Vec::new(),
+            expires_at: Some(claims.exp),
+            family: None,
+            request: None,
+        };
+        // Recording the `jti` fails if it was used before, also for concurrent requests.
+        if !observe_backend("insert_grant", self.grant_backend.insert_grant(used)).await? {
+            tracing::warn!("client assertion replayed");
+            return Ok(false);
+        }
+
+        Ok(true)
+    }
+
+    async fn exchange_code(
+        &self,
+        client: &OAuthClient,
+        request: TokenRequest,
+    ) -> Result<TokenResponse, OAuthError> {
+        let Some(code) = request.code else {
+            return Err(OAuthError::InvalidRequest(String::from("code is required")));
+        };
+        let Some(code_verifier) = request.code_verifier else {
+            return Err(OAuthError::InvalidRequest(String::from(
+                "code_verifier is required",
+            )));
+        };
+
+        let Some(grant) =
+            observe_backend("get_grant", self.grant_backend.get_grant(hash_token(&code))).await?
+        else {
+            return Err(OAuthError::InvalidGrant);
+        };
+        if grant.kind != GrantKind::AuthorizationCode
+            || grant.is_expired()
+            || grant.client_id != client.client_id
+        {
+            return Err(OAuthError::InvalidGrant);
+        }
+        record_user_id(&grant.user_id);
+
+        // Burned before the checks, so a code can't be guessed against repeatedly.
+        if !observe_backend(
+            "delete_grant",
+            self.grant_backend.delete_grant(grant.id.clone()),
+        )
+        .await?
+        {
+            return Err(OAuthError::InvalidGrant);
+        }
+
+        let Some(authorization) = &grant.request else {
+            return Err(OAuthError::internal("authorization code without request"));
+        };
+        if authorization.redirect_uri != request.redirect_uri {
+            return Err(OAuthError::InvalidGrant);
+        }
+        let Some(code_challenge) = &authorization.code_challenge else {
+            return Err(OAuthError::internal("authorization code without challenge"));
+        };
+        if !verify_pkce(&code_verifier, code_challenge) {
+            return Err(OAuthError::InvalidGrant);
+        }
+
+        let family = Grant {
+            id: Uuid::new_v4().into(),
+            kind: GrantKind::TokenFamily,
+            client_id: client.client_id.clone(),
+            user_id: grant.user_id.clone(),
+            scopes: grant.scopes.clone(),
+            expires_at: None,
+            family: None,
+            request: None,
+        };
+        observe_backend("save_grant", self.grant_backend.save_grant(family.clone())).await?;
+
+        self.issue_tokens(&family, &family.scopes, Some(authorization))
+            .await
+    }
+
+    /// Same rotation and reuse detection as the refresh tokens of the stateless mode.
+    /// A narrower `scope` only applies to the new access token.
+    async fn refresh(
+        &self,
+        client: &OAuthClient,
+        request: TokenRequest,
+        context: &AuditContext,
+    ) -> Result<TokenResponse, OAuthError> {
+        let Some(refresh_token) = request.refresh_token else {
+            return Err(OAuthError::InvalidRequest(String::from(
+                "refresh_token is required",
+            )));
+        };
+
+        let Some(current) = observe_backend(
+            "get_grant",
+            self.grant_backend.get_grant(hash_token(&refresh_token)),
+        )
+        .await?
+        else {
+            return Err(OAuthError::InvalidGrant);
+        };
+        let Some(family_id) = current.family.clone() else {
+            return Err(OAuthError::InvalidGrant);
+        };
+        if current.client_id != client.client_id {
+            return Err(OAuthError::InvalidGrant);
+        }
+        record_user_id(&current.user_id);
+
+        match current.kind {
+            GrantKind::RefreshToken if !current.is_expired() => {}
+            GrantKind::RotatedRefreshToken => {
+                tracing::warn!("refresh token reused, revoking its family");
+                self.revoke_reused_family(family_id, current.user_id, context)
+                    .await?;
+                return Err(OAuthError::InvalidGrant);
+            }
+            _ => return Err(OAuthError::InvalidGrant),
+        }
+
+        let Some(family) =
+            observe_backend("get_grant", self.grant_backend.get_grant(family_id.clone())).await?
+        else {
+            return Err(OAuthError::InvalidGrant);
+        };
+        if family.kind != GrantKind::TokenFamily {
+            return Err(OAuthError::InvalidGrant);
+        }
+
+        let scopes = match request.scope.as_deref() {
+            Some(scope) => {
+                let scopes = split_scope(scope);
+                if !scopes.iter().all(|scope| family.scopes.contains(scope)) {
+                    return Err(OAuthError::InvalidScope);
+                }
+                scopes
+            }
+            None => family.scopes.clone(),
+        };
+
+        if !observe_backend(
+            "delete_grant",
+            self.grant_backend.delete_grant(current.id.clone()),
+        )
+        .await?
+        {
+            // A concurrent request exchanged the same token first.
+            tracing::warn!("refresh token reused concurrently, revoking its family");
+            self.revoke_reused_family(family_id, current.user_id, context)
+                .await?;
+            return Err(OAuthError::InvalidGrant);
+        }
+
+        let rotated = Grant {
+            kind: GrantKind::RotatedRefreshToken,
+            ..current
+        };
+        observe_backend("save_grant", self.grant_backend.save_grant(rotated)).await?;
+
+        self.issue_tokens(&family, &scopes, None).await
+    }
+
+    /// RFC 6749 section 4.4 for service accounts, which act as themselves. Answers with an
+    /// access token only, the client authenticates again once it expires.
+    async fn client_credentials_grant(
+        &self,
+        client: &OAuthClient,
+        request: TokenRequest,
+    ) -> Result<TokenResponse, OAuthError> {
+        if !client.service_account {
+            return Err(OAuthError::UnauthorizedClient);
+        }
+        let scopes = requested_scopes(client, request.scope.as_deref())?;
+        record_user_id(&client.client_id);
+        let jwt = &self.jwt;
+
+        let identity = observe_backend(
+            "get_identity",
+            self.session_backend.get_identity(client.client_id.clone()),
+        )
+        .await?;
+        if identity.kind() != IdentityKind::Service {
+            return Err(OAuthError::UnauthorizedClient);
+        }
+
+        // A family per token, so it can be revoked like the tokens of users.
+        let family = Grant {
+            id: Uuid::new_v4().into(),
+            kind: GrantKind::TokenFamily,
+            client_id: client.client_id.clone(),
+            user_id: client.client_id.clone(),
+            scopes: scopes.clone(),
+            expires_at: Some(unix_now() + jwt.access_token_lifetime()),
+            family: None,
+            request: None,
+        };
+        observe_backend("save_grant", self.grant_backend.save_grant(family.clone())).await?;
+
+        let scope = scopes.join(" ");
+        Ok(TokenResponse {
+            access_token: jwt.issue_scoped(
+                &identity,
+                family.id,
+                Some(client.client_id.clone()),
+                Some(scope.clone()),
+            )?,
+            token_type: String::from("Bearer"),
+            expires_in: jwt.access_token_lifetime(),
+            refresh_token: None,
+            id_token: None,
+            scope: Some(scope),
+        })
+    }
+
+    async fn issue_code(
+        &self,
+        user_id: String,
+        client: &OAuthClient,
+        redirect_uri: String,
+        request: AuthorizationRequest,
+        scopes: Vec<String>,
+    ) -> Result<String, OAuthError> {
+        let code = random_token(48);
+        let state = request.state.clone();
+        let grant = Grant {
+            id: hash_token(&code),
+            kind: GrantKind::AuthorizationCode,
+            client_id: client.client_id.clone(),
+            user_id,
+            scopes,
+            expires_at: Some(unix_now() + AUTHORIZATION_CODE_LIFETIME),
+            family: None,
+            request: Some(request),
+        };
+        observe_backend("save_grant", self.grant_backend.save_grant(grant)).await?;
+
+        let mut params = vec![("code", code.as_str())];
+        if let Some(state) = &state {
+            params.push(("state", state));
+        }
+        Ok(with_query(&redirect_uri, &params))
+    }
+
+    /// `authorization` is the request of an exchanged code. ID tokens are only issued
+    /// along with it, so refreshed tokens come without.
+    async fn issue_tokens(
+        &self,
+        family: &Grant,
+        scopes: &[String],
+        authorization: Option<&AuthorizationRequest>,
+    ) -> Result<TokenResponse, OAuthError> {
+        let jwt = &self.jwt;
+
+        let identity = observe_backend(
+            "get_identity",
+            self.session_backend.get_identity(family.user_id.clone()),
+        )
+        .await?;
+
+        let refresh_token = random_token(48);
+        let stored = Grant {
+            id: hash_token(&refresh_token),
+            kind: GrantKind::RefreshToken,
+            client_id: family.client_id.clone(),
+            user_id: family.user_id.clone(),
+            scopes: family.scopes.clone(),
+            expires_at: Some(unix_now() + jwt.refresh_token_lifetime()),
+            family: Some(family.id.clone()),
+            request: None,
+        };
+        observe_backend("save_grant", self.grant_backend.save_grant(stored)).await?;
+
+        let id_token = match authorization {
+            Some(authorization) if self.oidc && scopes.iter().any(|scope| scope == "openid") => {
+                Some(oidc::id_token(
+                    jwt,
+                    &identity,
+                    &family.client_id,
+                    &family.id,
+                    authorization.nonce.clone(),
+                    scopes,
+                )?)
+            }
+            _ => None,
+        };
+
+        let scope = scopes.join(" ");
+        Ok(TokenResponse {
+            access_token: jwt.issue_scoped(
+                &identity,
+                family.id.clone(),
+                Some(family.client_id.clone()),
+                Some(scope.clone()),
+            )?,
+            token_type: String::from("Bearer"),
+            expires_in: jwt.access_token_lifetime(),
+            refresh_token: Some(refresh_token),
+            id_token,
+            scope: Some(scope),
+        })
+    }
+
+    async fn revoke_family(&self, family_id: String) -> Result<(), OAuthError> {
+        observe_backend("delete_grant", self.grant_backend.delete_grant(family_id)).await?;
+        Ok(())
+    }
+
+    async fn revoke_reused_family(
+        &self,
+        family_id: String,
+        user_id: String,
+        context: &AuditContext,
+    ) -> Result<(), OAuthError> {
+        self.revoke_family(family_id).await?;
+        self.audit(AuditEvent::new(AuditAction::SessionRevoke, context).target(user_id))
+            .await;
+        Ok(())
+    }
+
+    async fn pending_consent(&self, user_id: &str, id: String) -> Result<Grant, OAuthError> {
+        match observe_backend("get_grant", self.grant_backend.get_grant(id)).await? {
+            Some(pending)
+                if pending.kind == GrantKind::ConsentPending
+                    && pending.user_id == user_id
+                    && !pending.is_expired() =>
+            {
+                Ok(pending)
+            }
+            _ => Err(OAuthError::ConsentNotFound),
+        }
+    }
+
+    async fn consent_client(&self, pending: &Grant) -> Result<OAuthClient, OAuthError> {
+        observe_backend(
+            "get_client",
+            self.client_backend.get_client(pending.client_id.clone()),
+        )
+        .await?
+        .ok_or(OAuthError::ConsentNotFound)
+    }
+
+    fn check_admin(&self, identity: &T) -> Result<(), OAuthError> {
+        match (self.is_admin)(identity) {
+            true => Ok(()),
+            false => Err(OAuthError::Forbidden),
+        }
+    }
+}
+
+/// Id of the [`GrantKind::Consent`] of a user for a client.
+fn consent_id(client_id: &str, user_id: &str) -> String {
+    format!("consent:{client_id}:{user_id}")
+}
+
+/// The registered redirect target a request names, or the only one the client has.
+fn redirect_uri(
+    client: &OAuthClient,
+    request: &AuthorizationRequest,
+) -> Result<String, OAuthError> {
+    match &request.redirect_uri {
+        Some(redirect_uri) if client.redirect_uris.contains(redirect_uri) => {
+            Ok(redirect_uri.clone())
+        }
+        Some(_) => Err(OAuthError::InvalidRequest(String::from(
+            "redirect_uri is not registered for the client",
+        ))),
+        None => match client.redirect_uris.as_slice() {
+            [redirect_uri] => Ok(redirect_uri.clone()),
+            _ => Err(OAuthError::InvalidRequest(String::from(
+                "redirect_uri is required",
+            ))),
+        },
+    }
+}
+
+fn split_scope(scope: &str) -> Vec<String> {
+    let mut scopes: Vec<String> = Vec::new();
+    for scope in scope.split(' ').filter(|scope| !scope.is_empty()) {
+        if !scopes.iter().any(|known| known == scope) {
+            scopes.push(scope.into());
+        }
+    }
+    scopes
+}
+
+fn requested_scopes(client: &OAuthClient, scope: Option<&str>) -> Result<Vec<String>, OAuthError> {
+    let Some(scope) = scope else {
+        return Ok(client.scopes.clone());
+    };
+
+    let scopes = split_scope(scope);
+    match scopes.iter().all(|scope| client.scopes.contains(scope)) {
+        true => Ok(scopes),
+        false => Err(OAuthError::InvalidScope),
+    }
+}
+
+/// Checks an RFC 7636 `S256` code verifier against the challenge of the authorization request.
+fn verify_pkce(code_verifier: &str, code_challenge: &str) -> bool {
+    let valid_length = (43..=128).contains(&code_verifier.len());
+    let computed = BASE64_URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()));
+    verify_secret(&computed, Some(code_challenge)) && valid_length
+}
+
+/// Decoding key for an assertion signed with `algorithm`, if the public key fits it.
+fn assertion_key(public_key: &str, algorithm: Algorithm) -> Option<DecodingKey> {
+    let pem = public_key.as_bytes();
+    match algorithm.family() {
+        AlgorithmFamily::Rsa => DecodingKey::from_rsa_pem(pem).ok(),
+        AlgorithmFamily::Ec => DecodingKey::from_ec_pem(pem).ok(),
+        AlgorithmFamily::Ed => DecodingKey::from_ed_pem(pem).ok(),
+        AlgorithmFamily::Hmac => None,
+    }
+}
+
+/// Whether `public_key` is an RSA, EC or Ed25519 public key in PEM format.
+pub(crate) fn is_public_key(public_key: &str) -> bool {
+    [Algorithm::RS256, Algorithm::ES256, Algorithm::EdDSA]
+        .into_iter()
+        .any(|algorithm| assertion_key(public_key, algorithm).is_some())
+}
+
+/// Appends query parameters to a URL that may already have some.
+pub(crate) fn with_query(url: &str, params: &[(&str, &str)]) -> String {
+    let query = url::form_urlencoded::Serializer::new(String::new())
+        .extend_pairs(params)
+        .finish();
+    let separator = if url.contains('?') { '&' } else { '?' };
+    format!("{url}{separator}{query}")
+}
+
+pub(crate) fn redirect(location: String) -> HttpResponse {
+    HttpResponse::Found()
+        .insert_header((LOCATION, location))
+        .finish()
+}
+
+/// Reports an error of an authorization request back to the client.
+fn error_redirect(redirect_uri: &str, error: &OAuthError, state: Option<String>) -> String {
+    let mut params = vec![("error", error.error_code())];
+    if let Some(state) = &state {
+        params.push(("state", state));
+    }
+    with_query(redirect_uri, &params)
+}
+
+/// Client id and secret from HTTP Basic authentication, or else from the form. Clients
+/// sending an assertion may leave out `client_id`, the assertion's subject names them.
+fn client_credentials(
+    req: &HttpRequest,
+    client: ClientAuthentication,
+) -> Result<ClientCredentials, OAuthError> {
+    let basic = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|header| header.to_str().ok())
+        .and_then(|header| header.strip_prefix("Basic "));
+
+    if let Some(basic) = basic {
+        let decoded = BASE64_STANDARD
+            .decode(basic)
+            .ok()
+            .and_then(|decoded| String::from_utf8(decoded).ok())
+            .ok_or(OAuthError::InvalidClient)?;
+        let Some((client_id, client_secret)) = decoded.split_once(':') else {
+            return Err(OAuthError::InvalidClient);
+        };
+        return Ok(ClientCredentials {
+            client_id: client_id.into(),
+            client_secret: Some(client_secret.into()),
+            client_assertion: None,
+        });
+    }
+
+    if let Some(assertion) = client.client_assertion {
+        if client.client_assertion_type.as_deref() != Some(JWT_BEARER_ASSERTION) {
+            return Err(OAuthError::InvalidRequest(format!(
+                "client_assertion_type has to be {JWT_BEARER_ASSERTION}"
+            )));
+        }
+        let client_id = match client.client_id {
+            Some(client_id) => client_id,
+            None => {
+                jsonwebtoken::dangerous::insecure_decode_claims::<ClientAssertion>(&assertion)
+                    .map_err(|_| OAuthError::InvalidClient)?
+                    .sub
+            }
+        };
+        return Ok(ClientCredentials {
+            client_id,
+            client_secret: None,
+            client_assertion: Some(assertion),
+        });
+    }
+
+    match client.client_id {
+        Some(client_id) => Ok(ClientCredentials {
+            client_id,
+            client_secret: client.client_secret,
+            client_assertion: None,
+        }),
+        None => Err(OAuthError::InvalidClient),
+    }
+}
+
+async fn authorize<
+    T: IntoPublic + ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
+>(
+    req: HttpRequest,
+    oauth_provider: Data<OAuthProvider<T>>,
+    session: Result<SessionRes<T>, SessionError>,
+    request: Query<AuthorizationRequest>,
+) -> Result<HttpResponse, OAuthError> {
+    let request = request.into_inner();
+    let (client, redirect_uri) = oauth_provider.resolve_client(&request).await?;
+
+    let identity = match session {
+        Ok(session) => session.inner,
+        Err(SessionError::InvalidOrMissingSession) => {
+            let return_to = req
+                .uri()
+                .path_and_query()
+                .map(|path| path.as_str())
+                .unwrap_or_default();
+            return Ok(redirect(with_query(
+                &oauth_provider.login_url,
+                &[("return_to", return_to)],
+            )));
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    let state = request.state.clone();
+    match oauth_provider
+        .authorize(&identity, &client, redirect_uri.clone(), request)
+        .await
+    {
+        Ok(Authorization::Granted { redirect_to }) => Ok(redirect(redirect_to)),
+        Ok(Authorization::ConsentRequired(consent)) => Ok(oauth_provider
+            .consent_screen
+            .render(&identity, &consent)
+            .await),
+        Err(e @ (OAuthError::InternalServerError(_) | OAuthError::ServiceUnavailable(_))) => Err(e),
+        Err(e) => Ok(redirect(error_redirect(&redirect_uri, &e, state))),
+    }
+}
+
+async fn jwks<
+    T: IntoPublic + ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
+>(
+    oauth_provider: Data<OAuthProvider<T>>,
+) -> impl Responder {
+    keys::jwks_response(oauth_provider.jwt.key_ring())
+}
+
+async fn get_consent<
+    T: IntoPublic + ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
+>(
+    oauth_provider: Data<OAuthProvider<T>>,
+    session: SessionRes<T>,
+    path: Path<ConsentPath>,
+) -> Result<impl Responder, OAuthError> {
+    let Some(user_id) = session.inner.id() else {
+        return Err(OAuthError::internal("identity without id"));
+    };
+
+    Ok(Json(
+        oauth_provider
+            .consent_request(user_id.into(), path.into_inner().id)
+            .await?,
+    ))
+}
+
+async fn decide_consent<
+    T: IntoPublic + ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
+>(
+    oauth_provider: Data<OAuthProvider<T>>,
+    session: SessionRes<T>,
+    path: Path<ConsentPath>,
+    decision: Json<ConsentDecision>,
+) -> Result<impl Responder, OAuthError> {
+    let Some(user_id) = session.inner.id() else {
+        return Err(OAuthError::internal("identity without id"));
+    };
+
+    let redirect_to = oauth_provider
+        .decide_consent(user_id.into(), path.into_inner().id, decision.approve)
+        .await?;
+    Ok(Json(ConsentResponse { redirect_to }))
+}
+
+async fn token<
+    T: IntoPublic + ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
+>(
+    req: HttpRequest,
+    oauth_provider: Data<OAuthProvider<T>>,
+    request: Form<TokenRequest>,
+) -> HttpResponse {
+    let mut request = request.into_inner();
+    let client = std::mem::take(&mut request.client);
+    let result = match client_credentials(&req, client) {
+        Ok(credentials) => {
+            oauth_provider
+                .token(credentials, request, &AuditContext::from_request(&req))
+                .await
+        }
+        Err(e) => Err(e),
+    };
+
+    match result {
+        Ok(tokens) => HttpResponse::Ok()
+            .insert_header((CACHE_CONTROL, "no-store"))
+            .insert_header((PRAGMA, "no-cache"))
+            .json(tokens),
+        Err(e) => e.token_response(),
+    }
+}
+
+async fn introspect<
+    T: IntoPublic + ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
+>(
+    req: HttpRequest,
+    oauth_provider: Data<OAuthProvider<T>>,
+    request: Form<TokenLookupRequest>,
+) -> HttpResponse {
+    let request = request.into_inner();
+    let result = match client_credentials(&req, request.client) {
+        Ok(credentials) => oauth_provider.introspect(credentials, request.token).await,
+        Err(e) => Err(e),
+    };
+
+    match result {
+        Ok(introspection) => HttpResponse::Ok()
+            .insert_header((CACHE_CONTROL, "no-store"))
+            .json(introspection),
+        Err(e) => e.token_response(),
+    }
+}
+
+async fn revoke<
+    T: IntoPublic + ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
+>(
+    req: HttpRequest,
+    oauth_provider: Data<OAuthProvider<T>>,
+    request: Form<TokenLookupRequest>,
+) -> HttpResponse {
+    let request = request.into_inner();
+    let result = match client_credentials(&req, request.client) {
+        Ok(credentials) => {
+            oauth_provider
+                .revoke(
+                    credentials,
+                    request.token,
+                    &AuditContext::from_request(&req),
+                )
+                .await
+        }
+        Err(e) => Err(e),
+    };
+
+    match result {
+        Ok(()) => HttpResponse::Ok().finish(),
+        Err(e) => e.token_response(),
+    }
+}
+
+async fn register_client<
+    T: IntoPublic + ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
+>(
+    oauth_provider: Data<OAuthProvider<T>>,
+    session: SessionRes<T>,
+    registration: Json<ClientRegistration>,
+) -> Result<impl Responder, OAuthError> {
+    oauth_provider.check_admin(&session.inner)?;
+
+    let client = oauth_provider.register_client(registration.0).await?;
+    Ok(HttpResponse::Created().json(client))
+}
+
+async fn get_clients<
+    T: IntoPublic + ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
+>(
+    oauth_provider: Data<OAuthProvider<T>>,
+    session: SessionRes<T>,
+) -> Result<impl Responder, OAuthError> {
+    oauth_provider.check_admin(&session.inner)?;
+
+    Ok(Json(oauth_provider.clients().await?))
+}
+
+async fn delete_client<
+    T: IntoPublic + ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
+>(
+    oauth_provider: Data<OAuthProvider<T>>,
+    session: SessionRes<T>,
+    path: Path<ClientPath>,
+) -> Result<impl Responder, OAuthError> {
+    oauth_provider.check_admin(&session.inner)?;
+
+    oauth_provider
+        .delete_client(path.into_inner().client_id)
+        .await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[async_trait]
+pub trait ClientBackend: Send + Sync {
+    async fn create_client(&self, client: OAuthClient) -> Result<(), OAuthError>;
+    async fn get_client(&self, client_id: String) -> Result<Option<OAuthClient>, OAuthError>;
+    async fn get_clients(&self) -> Result<Vec<OAuthClient>, OAuthError>;
+    /// Returns whether a client was deleted.
+    async fn delete_client(&self, client_id: String) -> Result<bool, OAuthError>;
+}
+
+#[async_trait]
+pub trait GrantBackend: Send + Sync {
+    /// Inserts a grant or replaces the one with the same id.
+    async fn save_grant(&self, grant: Grant) -> Result<(), OAuthError>;
+    /// Inserts a grant unless one with the same id exists, atomically. Returns whether it was
+    /// inserted, so callers can record single-use values like assertion ids safely.
+    async fn insert_grant(&self, grant: Grant) -> Result<bool, OAuthError>;
+    async fn get_grant(&self, id: String) -> Result<Option<Grant>, OAuthError>;
+    /// Returns whether a grant was deleted, so callers can redeem single-use grants safely.
+    async fn delete_grant(&self, id: String) -> Result<bool, OAuthError>;
+}
@@ -0,0 +1,589 @@
+use std::time::Instant;
+
+use actix_web::{
+    HttpRequest, HttpResponse, Responder,
+    cookie::Cookie,
+    http::header::{CACHE_CONTROL, CONTENT_SECURITY_POLICY},
+    web::{Data, Form, Json, Query, ServiceConfig, get, post},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use tracing::field::Empty;
+
+use crate::{
+    IntoPublic, ObjectId,
+    audit::{self, AuditAction, AuditContext, AuditEvent},
+    crypto::{hash_token, verify_secret},
+    jwt::{JwtConfig, bearer_token},
+    keys::KeyError,
+    oauth::{
+        ClientBackend, GrantBackend, GrantKind, OAuthError, OAuthProvider, redirect, with_query,
+    },
+    session::{SessionBackend, SessionError, SessionProvider},
+    telemetry::{observe_backend, record_outcome, record_user_id},
+    unix_now,
+};
+
+/// How long an ID token is valid, in seconds.
+const ID_TOKEN_LIFETIME: u64 = 60 * 60;
+/// How long relying parties may cache the discovery document, in seconds.
+const DISCOVERY_MAX_AGE: u64 = 5 * 60;
+
+/// Standard claims released per scope, see OpenID Connect Core 1.0 section 5.4.
+const SCOPE_CLAIMS: [(&str, &[&str]); 4] = [
+    (
+        "profile",
+        &[
+            "name",
+            "family_name",
+            "given_name",
+            "middle_name",
+            "nickname",
+            "preferred_username",
+            "profile",
+            "picture",
+            "website",
+            "gender",
+            "birthdate",
+            "zoneinfo",
+            "locale",
+            "updated_at",
+        ],
+    ),
+    ("email", &["email", "email_verified"]),
+    ("address", &["address"]),
+    ("phone", &["phone_number", "phone_number_verified"]),
+];
+
+/// Claims of an ID token. Besides the registered claims it carries the standard claims
+/// released by the granted scopes.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct IdTokenClaims {
+    pub iss: String,
+    pub sub: String,
+    /// The client the token was issued to.
+    pub aud: String,
+    pub iat: u64,
+    pub exp: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<String>,
+    /// The grant the token was issued for, revoked by `oauth/end-session`.
+    pub sid: String,
+    #[serde(flatten)]
+    pub claims: Map<String, Value>,
+}
+
+/// OpenID Connect Discovery 1.0 provider metadata.
+#[derive(Serialize, Deserialize)]
+pub struct DiscoveryDocument {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub userinfo_endpoint: String,
+    pub jwks_uri: String,
+    pub end_session_endpoint: String,
+    pub introspection_endpoint: String,
+    pub revocation_endpoint: String,
+    pub response_types_supported: Vec<String>,
+    pub grant_types_supported: Vec<String>,
+    pub subject_types_supported: Vec<String>,
+    pub id_token_signing_alg_values_supported: Vec<String>,
+    pub scopes_supported: Vec<String>,
+    pub claims_supported: Vec<String>,
+    pub code_challenge_methods_supported: Vec<String>,
+    pub token_endpoint_auth_methods_supported: Vec<String>,
+}
+
+/// Query or form of `oauth/end-session`, OpenID Connect RP-Initiated Logout 1.0.
+#[derive(Serialize, Deserialize)]
+pub struct EndSessionRequest {
+    id_token_hint: Option<String>,
+    post_logout_redirect_uri: Option<String>,
+    client_id: Option<String>,
+    state: Option<String>,
+    /// Sent by the confirmation page, proves the user confirmed the logout in this browser.
+    confirm: Option<String>,
+}
+
+/// The standard claims released by the granted scopes, taken from the public
+/// representation of the identity. Fields without a standard name are never released,
+/// `preferred_username` falls back to the username.
+pub fn standard_claims<T: IntoPublic + ObjectId + Clone>(
+    identity: &T,
+    scopes: &[String],
+) -> Map<String, Value> {
+    let username = identity.username();
+    let public = match serde_json::to_value(identity.clone().into_public()) {
+        Ok(Value::Object(public)) => public,
+        _ => Map::new(),
+    };
+
+    let mut claims = Map::new();
+    for (scope, names) in SCOPE_CLAIMS {
+        if !scopes.iter().any(|granted| granted == scope) {
+            continue;
+        }
+
+        for name in names {
+            if let Some(value) = public.get(*name) {
+                claims.insert(String::from(*name), value.clone());
+            }
+        }
+    }
+
+    if scopes.iter().any(|granted| granted == "profile") {
+        claims
+            .entry("preferred_username")
+            .or_insert(Value::String(username));
+    }
+    claims
+}
+
+/// Signs the ID token of an authorization code exchange.
+pub(crate) fn id_token<T: IntoPublic + ObjectId + Clone>(
+    jwt: &JwtConfig,
+    identity: &T,
+    client_id: &str,
+    sid: &str,
+    nonce: Option<String>,
+    scopes: &[String],
+) -> Result<String, OAuthError> {
+    let Some(user_id) = identity.id() else {
+        return Err(OAuthError::internal("identity without id"));
+    };
+
+    let now = unix_now();
+    let claims = IdTokenClaims {
+        iss: jwt.issuer().into(),
+        sub: user_id.into(),
+        aud: client_id.into(),
+        iat: now,
+        exp: now + ID_TOKEN_LIFETIME,
+        nonce,
+        sid: sid.into(),
+        claims: standard_claims(identity, scopes),
+    };
+    Ok(jwt.sign(&claims)?)
+}
+
+/// OpenID Connect on top of [`OAuthProvider`]: discovery, userinfo and logout. ID tokens
+/// are issued by the token endpoint once [`OAuthProvider::with_oidc`] is set.
+#[derive(Clone)]
+pub struct OidcProvider<T>
+where
+    T: IntoPublic
+        + ObjectId
+        + Serialize
+        + for<'de> Deserialize<'de>
+        + Clone
+        + Send
+        + Sync
+        + 'static,
+{
+    discovery_path: String,
+    userinfo_path: String,
+    end_session_path: String,
+    session_backend: Data<Box<dyn SessionBackend<T>>>,
+    client_backend: Data<Box<dyn ClientBackend>>,
+    grant_backend: Data<Box<dyn GrantBackend>>,
+    jwt: Option<JwtConfig>,
+}
+
+impl<
+    T: IntoPublic + ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
+> OidcProvider<T>
+{
+    pub fn default_with_backend(
+        session_backend: Data<Box<dyn SessionBackend<T>>>,
+        client_backend: Data<Box<dyn ClientBackend>>,
+        grant_backend: Data<Box<dyn GrantBackend>>,
+    ) -> Self {
+        Self {
+            discovery_path: String::from(".well-known/openid-configuration"),
+            userinfo_path: String::from("oauth/userinfo"),
+            end_session_path: String::from("oauth/end-session"),
+            session_backend,
+            client_backend,
+            grant_backend,
+            jwt: None,
+        }
+    }
+
+    /// Verifies tokens with the config of the [`OAuthProvider`]. Its issuer has to be the
+    /// URL the routes are served under, endpoints in the discovery document are
+    /// relative to it.
+    pub fn with_jwt(mut self, jwt: JwtConfig) -> Self {
+        self.jwt = Some(jwt);
+        self
+    }
+
+    pub fn configure(&self, cfg: &mut ServiceConfig) {
+        let data = Data::new(self.clone());
+        cfg.app_data(data.clone())
+            .route(&data.discovery_path, get().to(discovery::<T>))
+            .route(&data.userinfo_path, get().to(userinfo::<T>))
+            .route(&data.userinfo_path, post().to(userinfo::<T>))
+            .route(&data.end_session_path, get().to(end_session::<T>))
+            .route(&data.end_session_path, post().to(confirm_end_session::<T>));
+    }
+
+    pub fn discovery(
+        &self,
+        oauth_provider: &OAuthProvider<T>,
+    ) -> Result<DiscoveryDocument, OAuthError> {
+        let jwt = self.jwt()?;
+        if jwt.key_ring().has_shared_secret() {
+            return Err(OAuthError::internal(KeyError::SharedSecret));
+        }
+        let algorithm = jwt
+            .key_ring()
+            .signing_key()
+            .map_err(OAuthError::internal)?
+            .algorithm;
+        let endpoint = |path: &str| format!("{}/{path}", jwt.issuer().trim_end_matches('/'));
+
+        let mut scopes_supported = vec![String::from("openid")];
+        let mut claims_supported: Vec<String> = ["iss", "sub", "aud", "iat", "exp", "nonce", "sid"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        for (scope, names) in SCOPE_CLAIMS {
+            scopes_supported.push(scope.into());
+            claims_supported.extend(names.iter().map(|name| String::from(*name)));
+        }
+
+        Ok(DiscoveryDocument {
+            issuer: jwt.issuer().into(),
+            authorization_endpoint: endpoint(oauth_provider.authorize_path()),
+            token_endpoint: endpoint(oauth_provider.token_path()),
+            userinfo_endpoint: endpoint(&self.userinfo_path),
+            jwks_uri: endpoint(oauth_provider.jwks_path()),
+            end_session_endpoint: endpoint(&self.end_session_path),
+            introspection_endpoint: endpoint(oauth_provider.introspect_path()),
+            revocation_endpoint: endpoint(oauth_provider.revoke_path()),
+            response_types_supported: vec![String::from("code")],
+            grant_types_supported: vec![
+                String::from("authorization_code"),
+                String::from("refresh_token"),
+                String::from("client_credentials"),
+            ],
+            subject_types_supported: vec![String::from("public")],
+            id_token_signing_alg_values_supported: vec![format!("{algorithm:?}")],
+            scopes_supported,
+            claims_supported,
+            code_challenge_methods_supported: vec![String::from("S256")],
+            token_endpoint_auth_methods_supported: vec![
+                String::from("client_secret_basic"),
+                String::from("client_secret_post"),
+                String::from("private_key_jwt"),
+                String::from("none"),
+            ],
+        })
+    }
+
+    /// Claims about the user an access token was issued for, limited by its scopes.
+    /// Requires the `openid` scope and a grant that wasn't revoked.
+    #[tracing::instrument(
+        name = "oidc.userinfo",
+        level = "debug",
+        skip_all,
+        fields(user_id = Empty, outcome = Empty, latency_ms = Empty)
+    )]
+    pub async fn userinfo(&self, token: String) -> Result<Map<String, Value>, OAuthError> {
+        let started = Instant::now();
+        let result: Result<Map<String, Value>, OAuthError> = async {
+            let claims = self
+                .jwt()?
+                .verify::<T>(&token)
+                .map_err(|_| OAuthError::InvalidToken)?;
+            record_user_id(&claims.sub);
+
+            if claims.client_id.is_none() {
+                return Err(OAuthError::InvalidToken);
+            }
+            if !claims.has_scope("openid") {
+                return Err(OAuthError::InsufficientScope);
+            }
+
+            let family =
+                observe_backend("get_grant", self.grant_backend.get_grant(claims.sid)).await?;
+            if !family.is_some_and(|family| family.kind == GrantKind::TokenFamily) {
+                return Err(OAuthError::InvalidToken);
+            }
+
+            let identity = observe_backend(
+                "get_identity",
+                self.session_backend.get_identity(claims.sub.clone()),
+            )
+            .await?;
+            let scopes: Vec<String> = claims
+                .scope
+                .unwrap_or_default()
+                .split(' ')
+                .map(String::from)
+                .collect();
+
+            let mut userinfo = standard_claims(&identity, &scopes);
+            userinfo.insert(String::from("sub"), Value::String(claims.sub));
+            Ok(userinfo)
+        }
+        .await;
+
+        record_outcome("oidc.userinfo", &result, started);
+        result
+    }
+
+    /// Revokes the grant named by the `id_token_hint` and returns where to send the
+    /// browser, if the client registered the `post_logout_redirect_uri`.
+    pub async fn end_session(
+        &self,
+        request: EndSessionRequest,
+    ) -> Result<Option<String>, OAuthError> {
+        let hint = self.id_token_hint(&request)?;
+
+        if let Some(hint) = &hint {
+            let family =
+                observe_backend("get_grant", self.grant_backend.get_grant(hint.sid.clone()))
+                    .await?;
+            if let Some(family) = family
+                && family.kind == GrantKind::TokenFamily
+                && family.user_id == hint.sub
+            {
+                observe_backend("delete_grant", self.grant_backend.delete_grant(family.id)).await?;
+            }
+        }
+
+        let Some(post_logout_redirect_uri) = request.post_logout_redirect_uri else {
+            return Ok(None);
+        };
+        let client_id = match (hint.map(|hint| hint.aud), request.client_id) {
+            (Some(audience), Some(client_id)) if audience != client_id => {
+                return Err(OAuthError::InvalidRequest(String::from(
+                    "client_id doesn't match the id_token_hint",
+                )));
+            }
+            (Some(client_id), _) | (None, Some(client_id)) => client_id,
+            (None, None) => {
+                return Err(OAuthError::InvalidRequest(String::from(
+                    "post_logout_redirect_uri requires id_token_hint or client_id",
+                )));
+            }
+        };
+
+        let client = observe_backend("get_client", self.client_backend.get_client(client_id))
+            .await?
+            .ok_or(OAuthError::InvalidClient)?;
+        if !client
+            .post_logout_redirect_uris
+            .contains(&post_logout_redirect_uri)
+        {
+            return Err(OAuthError::InvalidRequest(String::from(
+                "post_logout_redirect_uri is not registered for the client",
+            )));
+        }
+
+        Ok(Some(match &request.state {
+            Some(state) => with_query(&post_logout_redirect_uri, &[("state", state)]),
+            None => post_logout_redirect_uri,
+        }))
+    }
+
+    /// The verified claims of the `id_token_hint`. The hint may be expired, its signature
+    /// is still checked.
+    pub fn id_token_hint(
+        &self,
+        request: &EndSessionRequest,
+    ) -> Result<Option<IdTokenClaims>, OAuthError> {
+        let Some(hint) = &request.id_token_hint else {
+            return Ok(None);
+        };
+
+        self.jwt()?
+            .decode::<IdTokenClaims>(hint, |validation| {
+                validation.validate_exp = false;
+                validation.validate_aud = false;
+                validation.set_required_spec_claims(&["iss", "sub", "aud"]);
+            })
+            .map(Some)
+            .map_err(|_| OAuthError::InvalidRequest(String::from("invalid id_token_hint")))
+    }
+
+    fn jwt(&self) -> Result<&JwtConfig, OAuthError> {
+        self.jwt.as_ref().ok_or_else(|| {
+            OAuthError::internal("OpenID Connect requires the JwtConfig of the OAuth provider")
+        })
+    }
+}
+
+async fn discovery<
+    T: IntoPublic + ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
+>(
+    oidc_provider: Data<OidcProvider<T>>,
+    oauth_provider: Data<OAuthProvider<T>>,
+) -> Result<impl Responder, OAuthError> {
+    let document = oidc_provider.discovery(&oauth_provider)?;
+
+    Ok(HttpResponse::Ok()
+        .insert_header((
+            CACHE_CONTROL,
+            format!("public, max-age={DISCOVERY_MAX_AGE}"),
+        ))
+        .json(document))
+}
+
+async fn userinfo<
+    T: IntoPublic + ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
+>(
+    req: HttpRequest,
+    oidc_provider: Data<OidcProvider<T>>,
+) -> Result<impl Responder, OAuthError> {
+    let Some(token) = bearer_token(&req) else {
+        return Err(OAuthError::InvalidToken);
+    };
+
+    Ok(Json(oidc_provider.userinfo(token).await?))
+}
+
+async fn end_session<
+    T: IntoPublic + ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
+>(
+    req: HttpRequest,
+    oidc_provider: Data<OidcProvider<T>>,
+    session_provider: Data<SessionProvider<T>>,
+    request: Query<EndSessionRequest>,
+) -> Result<impl Responder, OAuthError> {
+    finish_end_session(req, oidc_provider, session_provider, request.into_inner()).await
+}
+
+async fn confirm_end_session<
+    T: IntoPublic + ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
+>(
+    req: HttpRequest,
+    oidc_provider: Data<OidcProvider<T>>,
+    session_provider: Data<SessionProvider<T>>,
+    request: Form<EndSessionRequest>,
+) -> Result<impl Responder, OAuthError> {
+    finish_end_session(req, oidc_provider, session_provider, request.into_inner()).await
+}
+
+/// Ends the cookie session only if the `id_token_hint` names its user or the user
+/// confirmed the logout, so other sites can't log users out with a plain link.
+async fn finish_end_session<
+    T: IntoPublic + ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
+>(
+    req: HttpRequest,
+    oidc_provider: Data<OidcProvider<T>>,
+    session_provider: Data<SessionProvider<T>>,
+    request: EndSessionRequest,
+) -> Result<HttpResponse, OAuthError> {
+    let session = match req.cookie("sessionId") {
+        Some(session_id) => match session_provider
+            .active_session(session_id.value().into())
+            .await
+        {
+            Ok((session, _)) => Some(session),
+            Err(SessionError::InvalidOrMissingSession) => None,
+            Err(e) => return Err(e.into()),
+        },
+        None => None,
+    };
+
+    if let Some(session) = session {
+        let hint = oidc_provider.id_token_hint(&request)?;
+        // The confirmation carries a hash of the session id, which other sites can't know.
+        let confirmed = hint.is_some_and(|hint| hint.sub == session.user_id)
+            || request
+                .confirm
+                .as_deref()
+                .is_some_and(|confirm| verify_secret(&hash_token(&session.id), Some(confirm)));
+        if !confirmed {
+            return confirmation_page(&oidc_provider, &request, &session.id);
+        }
+
+        match session_provider.logout(session.id).await {
+            Ok(_) => {
+                audit::record(
+                    &session_provider.audit_sink,
+                    AuditEvent::new(
+                        AuditAction::SessionRevoke,
+                        &AuditContext::from_request(&req),
+                    )
+                    .actor(session.user_id.clone())
+                    .target(session.user_id),
+                )
+                .await;
+            }
+            Err(SessionError::InvalidOrMissingSession) => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    let redirect_to = oidc_provider.end_session(request).await?;
+
+    let mut response = match redirect_to {
+        Some(redirect_to) => redirect(redirect_to),
+        None => HttpResponse::NoContent().finish(),
+    };
+    response
+        .add_removal_cookie(&Cookie::build("sessionId", "").path("/").finish())
+        .map_err(OAuthError::internal)?;
+    Ok(response)
+}
+
+/// Asks the user to confirm a logout, posting the request back to `oauth/end-session`.
+fn confirmation_page<
+    T: IntoPublic + ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
+>(
+    oidc_provider: &OidcProvider<T>,
+    request: &EndSessionRequest,
+    session_id: &str,
+) -> Result<HttpResponse, OAuthError> {
+    let action = format!(
+        "{}/{}",
+        oidc_provider.jwt()?.issuer().trim_end_matches('/'),
+        oidc_provider.end_session_path
+    );
+    let confirm = hash_token(session_id);
+    let fields = [
+        ("id_token_hint", request.id_token_hint.as_deref()),
+        (
+            "post_logout_redirect_uri",
+            request.post_logout_redirect_uri.as_deref(),
+        ),
+        ("client_id", request.client_id.as_deref()),
+        ("state", request.state.as_deref()),
+        ("confirm", Some(confirm.as_str())),
+    ];
+    let inputs: String = fields
+        .into_iter()
+        .filter_map(|(name, value)| {
+            value.map(|value| {
+                format!(
+                    r#"<input type="hidden" name="{name}" value="{}">"#,
+                    escape_html(value)
+                )
+            })
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok()
+        .insert_header((CACHE_CONTROL, "no-store"))
+        .insert_header((
+            CONTENT_SECURITY_POLICY,
+            "default-src 'none'; frame-ancestors 'none'",
+        ))
+        .content_type("text/html; charset=utf-8")
+        .body(format!(
+            r#"<!DOCTYPE html><html><head><meta charset="utf-8"><title>Log out</title></head><body><form method="post" action="{}"><p>Do you want to log out?</p>{inputs}<button type="submit">Log out</button></form></body></html>"#,
+            escape_html(&action)
+        )))
+}
+
+fn escape_html(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
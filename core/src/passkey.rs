@@ -0,0 +1,656 @@
+use std::net::IpAddr;
+
+use actix_web::{
+    HttpRequest, HttpResponse, Responder,
+    http::StatusCode,
+    web::{Data, Json, ServiceConfig, post},
+};
+use async_trait::async_trait;
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use webauthn_rs::{
+    DEFAULT_AUTHENTICATOR_TIMEOUT,
+    fake::{FakePasskeyDistribution, WebauthnFakeCredentialGenerator},
+    prelude::{
+        CreationChallengeResponse, Passkey, PasskeyAuthentication, PasskeyRegistration,
+        PublicKeyCredential, RegisterPublicKeyCredential, RequestChallengeResponse, Url, Webauthn,
+        WebauthnBuilder,
+    },
+};
+use webauthn_rs_proto::{
+    AllowCredentials, PublicKeyCredentialRequestOptions, UserVerificationPolicy,
+};
+
+use crate::{
+    IntoPublic, ObjectId,
+    audit::{self, AuditAction, AuditContext, AuditEvent},
+    credential::{Credential, CredentialBackend, CredentialError, CredentialKind},
+    error::{BoxError, as_source, fmt_with_source},
+    identity::IdentityBackend,
+    problem::{Problem, ToProblem},
+    session::{SessionError, SessionProvider, SessionRes},
+    throttle::LoginThrottle,
+    unix_now,
+    username::UsernamePolicy,
+};
+
+/// How long a started registration or login ceremony can be finished, in seconds.
+const CEREMONY_LIFETIME: u64 = 5 * 60;
+
+#[derive(Debug)]
+pub enum PasskeyError {
+    InvalidConfiguration,
+    InvalidCeremony,
+    VerificationFailed,
+    /// The authenticator's credential is already registered, possibly to another identity.
+    AlreadyRegistered,
+    TooManyRequests {
+        retry_after: u64,
+    },
+    InternalServerError(Option<BoxError>),
+    ServiceUnavailable(Option<BoxError>),
+}
+
+impl std::fmt::Display for PasskeyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PasskeyError::InvalidConfiguration => write!(f, "invalid relying party configuration"),
+            PasskeyError::InvalidCeremony => write!(f, "unknown or expired passkey ceremony"),
+            PasskeyError::VerificationFailed => write!(f, "passkey verification failed"),
+            PasskeyError::AlreadyRegistered => write!(f, "passkey is already registered"),
+            PasskeyError::TooManyRequests { retry_after } => {
+                write!(f, "too many attempts, retry in {retry_after} seconds")
+            }
+            PasskeyError::InternalServerError(source) => {
+                fmt_with_source(f, "internal server error", source)
+            }
+            PasskeyError::ServiceUnavailable(source) => {
+                fmt_with_source(f, "service unavailable", source)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PasskeyError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PasskeyError::InternalServerError(source)
+            | PasskeyError::ServiceUnavailable(source) => as_source(source),
+            _ => None,
+        }
+    }
+}
+
+impl PasskeyError {
+    pub fn internal(source: impl Into<BoxError>) -> Self {
+        PasskeyError::InternalServerError(Some(source.into()))
+    }
+
+    /// For failures that are likely to go away on retry, like a lost database connection.
+    pub fn unavailable(source: impl Into<BoxError>) -> Self {
+        PasskeyError::ServiceUnavailable(Some(source.into()))
+    }
+}
+
+impl ToProblem for PasskeyError {
+    fn to_problem(&self) -> Problem {
+        match self {
+            PasskeyError::InvalidCeremony => Problem::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_passkey_ceremony",
+                "Unknown or expired passkey ceremony",
+            ),
+            PasskeyError::VerificationFailed => Problem::new(
+                StatusCode::UNAUTHORIZED,
+                "passkey_verification_failed",
+                "Passkey verification failed",
+            ),
+            PasskeyError::AlreadyRegistered => Problem::new(
+                StatusCode::CONFLICT,
+                "passkey_in_use",
+                "The passkey is already registered",
+            ),
+            PasskeyError::TooManyRequests { retry_after } => SessionError::TooManyRequests {
+                retry_after: *retry_after,
+            }
+            .to_problem(),
+            PasskeyError::InvalidConfiguration | PasskeyError::InternalServerError(_) => {
+                Problem::internal_server_error()
+            }
+            PasskeyError::ServiceUnavailable(_) => Problem::service_unavailable(),
+        }
+    }
+}
+
+impl From<PasskeyError> for HttpResponse {
+    fn from(value: PasskeyError) -> Self {
+        actix_web::error::ResponseError::error_response(&value)
+    }
+}
+
+impl From<SessionError> for PasskeyError {
+    fn from(value: SessionError) -> Self {
+        match value {
+            SessionError::InternalServerError(source) => PasskeyError::InternalServerError(source),
+            SessionError::ServiceUnavailable(source) => PasskeyError::ServiceUnavailable(source),
+            SessionError::TooManyRequests { retry_after } => {
+                PasskeyError::TooManyRequests { retry_after }
+            }
+            other => PasskeyError::internal(other),
+        }
+    }
+}
+
+impl From<CredentialError> for PasskeyError {
+    fn from(value: CredentialError) -> Self {
+        match value {
+            CredentialError::InternalServerError(source) => {
+                PasskeyError::InternalServerError(source)
+            }
+            CredentialError::ServiceUnavailable(source) => PasskeyError::ServiceUnavailable(source),
+            CredentialError::AlreadyRegistered => PasskeyError::AlreadyRegistered,
+            other => PasskeyError::internal(other),
+        }
+    }
+}
+
+impl actix_web::error::ResponseError for PasskeyError {
+    fn status_code(&self) -> StatusCode {
+        self.to_problem().status_code()
+    }
+
+    fn error_response(&self) -> HttpResponse<actix_web::body::BoxBody> {
+        match self {
+            PasskeyError::TooManyRequests { retry_after } => {
+                actix_web::error::ResponseError::error_response(&SessionError::TooManyRequests {
+                    retry_after: *retry_after,
+                })
+            }
+            _ => self.to_problem().into_response(),
+        }
+    }
+}
+
+/// Server side state of a registration or login ceremony between its start and finish request.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PasskeyCeremony {
+    pub id: String,
+    pub user_id: String,
+    /// JSON serialized `PasskeyRegistration` or `PasskeyAuthentication`.
+    pub state: String,
+    pub expires_at: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CeremonyStart<O> {
+    ceremony_id: String,
+    options: O,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RegisterFinishRequest {
+    ceremony_id: String,
+    credential: RegisterPublicKeyCredential,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct LoginStartRequest {
+    username: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct LoginFinishRequest {
+    ceremony_id: String,
+    credential: PublicKeyCredential,
+}
+
+#[derive(Clone)]
+pub struct PasskeyProvider<T>
+where
+    T: IntoPublic
+        + ObjectId
+        + Serialize
+        + for<'de> Deserialize<'de>
+        + Clone
+        + Send
+        + Sync
+        + 'static,
+{
+    passkey_path: String,
+    rp_id: String,
+    webauthn: Data<Webauthn>,
+    fake_credentials: Data<WebauthnFakeCredentialGenerator<FakePasskeyDistribution>>,
+    backend: Data<Box<dyn PasskeyBackend<T>>>,
+    credential_backend: Data<Box<dyn CredentialBackend>>,
+    identity_backend: Data<Box<dyn IdentityBackend<T>>>,
+    username_policy: Option<UsernamePolicy>,
+    throttle: Option<LoginThrottle>,
+}
+
+impl<
+    T: IntoPublic + ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
+> PasskeyProvider<T>
+{
+    /// `rp_id` is the domain passkeys are bound to, `rp_origin` the full origin the browser talks to.
+    pub fn default_with_backend(
+        backend: Data<Box<dyn PasskeyBackend<T>>>,
+        credential_backend: Data<Box<dyn CredentialBackend>>,
+        identity_backend: Data<Box<dyn IdentityBackend<T>>>,
+        rp_id: String,
+        rp_origin: String,
+    ) -> Result<Self, PasskeyError> {
+        let rp_origin = Url::parse(&rp_origin).map_err(|_| PasskeyError::InvalidConfiguration)?;
+        let webauthn = WebauthnBuilder::new(&rp_id, &rp_origin)
+            .and_then(|builder| builder.build())
+            .map_err(|_| PasskeyError::InvalidConfiguration)?;
+        let fake_credentials =
+            WebauthnFakeCredentialGenerator::<FakePasskeyDistribution>::new_hmac_key()
+                .and_then(|key| WebauthnFakeCredentialGenerator::new(&key))
+                .map_err(PasskeyError::internal)?;
+
+        Ok(Self {
+            passkey_path: String::from("passkey"),
+            rp_id,
+            webauthn: Data::new(webauthn),
+            fake_credentials: Data::new(fake_credentials),
+            backend,
+            credential_backend,
+            identity_backend,
+            username_policy: None,
+            throttle: None,
+        })
+    }
+
+    /// Looks up identities by the normalized username.
+    pub fn with_username_policy(mut self, policy: UsernamePolicy) -> Self {
+        self.username_policy = Some(policy);
+        self
+    }
+
+    /// Secret the made-up credentials of unknown usernames are derived from. Defaults to a
+    /// random key, set the same one on every instance so they answer alike across restarts.
+    pub fn with_fake_credential_key(mut self, key: &[u8]) -> Result<Self, PasskeyError> {
+        let fake_credentials =
+            WebauthnFakeCredentialGenerator::new(key).map_err(PasskeyError::internal)?;
+        self.fake_credentials = Data::new(fake_credentials);
+        Ok(self)
+    }
+
+    /// Throttles failed passkey logins per identity and client address.
+    pub fn with_throttle(mut self, throttle: LoginThrottle) -> Self {
+        self.throttle = Some(throttle);
+        self
+    }
+
+    pub fn configure(&self, cfg: &mut ServiceConfig) {
+        let data = Data::new(self.clone());
+        cfg.app_data(data.clone())
+            .route(
+                &format!("{}/register/start", data.passkey_path),
+                post().to(start_registration::<T>),
+            )
+            .route(
+                &format!("{}/register/finish", data.passkey_path),
+                post().to(finish_registration::<T>),
+            )
+            .route(
+                &format!("{}/login/start", data.passkey_path),
+                post().to(start_login::<T>),
+            )
+            .route(
+                &format!("{}/login/finish", data.passkey_path),
+                post().to(finish_login::<T>),
+            );
+    }
+
+    pub async fn start_registration(
+        &self,
+        identity: &T,
+    ) -> Result<CeremonyStart<CreationChallengeResponse>, PasskeyError> {
+        let Some(user_id) = identity.id() else {
+            return Err(PasskeyError::internal("identity without id"));
+        };
+
+        let exclude_credentials = self
+            .get_passkeys(user_id.into())
+            .await?
+            .into_iter()
+            .map(|(_, passkey)| passkey.cred_id().clone())
+            .collect();
+
+        let username = identity.username();
+        let (options, state) = self
+            .webauthn
+            .start_passkey_registration(user_id, &username, &username, Some(exclude_credentials))
+            .map_err(PasskeyError::internal)?;
+
+        let ceremony_id = self.save_ceremony(user_id.into(), &state).await?;
+        Ok(CeremonyStart {
+            ceremony_id,
+            options,
+        })
+    }
+
+    pub async fn finish_registration(
+        &self,
+        user_id: String,
+        ceremony_id: String,
+        credential: RegisterPublicKeyCredential,
+    ) -> Result<(), PasskeyError> {
+        let (ceremony_user_id, state) = self
+            .take_ceremony::<PasskeyRegistration>(ceremony_id)
+            .await?;
+        if ceremony_user_id != user_id {
+            return Err(PasskeyError::InvalidCeremony);
+        }
+
+        let passkey = self
+            .webauthn
+            .finish_passkey_registration(&credential, &state)
+            .map_err(|_| PasskeyError::VerificationFailed)?;
+
+        // A credential id belongs to one identity, registering it again must not take it over.
+        let identifier = URL_SAFE_NO_PAD.encode(passkey.cred_id());
+        if self
+            .credential_backend
+            .find_credential(CredentialKind::Passkey, None, identifier.clone())
+            .await?
+            .is_some()
+        {
+            return Err(PasskeyError::AlreadyRegistered);
+        }
+
+        let credential = Credential::new(user_id, CredentialKind::Passkey, identifier);
+        self.save_passkey(credential, &passkey).await
+    }
+
+    pub async fn start_login(
+        &self,
+        username: String,
+    ) -> Result<CeremonyStart<RequestChallengeResponse>, PasskeyError> {
+        let username = match &self.username_policy {
+            Some(policy) => policy.normalize(&username),
+            None => username,
+        };
+
+        let identity = self
+            .identity_backend
+            .get_by_username(username.clone())
+            .await
+            .map_err(SessionError::from)?;
+        let Some(user_id) = identity.and_then(|identity| identity.id()) else {
+            return self.start_fake_login(&username).await;
+        };
+
+        let passkeys = self
+            .get_passkeys(user_id.into())
+            .await?
+            .into_iter()
+            .map(|(_, passkey)| passkey)
+            .collect::<Vec<Passkey>>();
+        if passkeys.is_empty() {
+            return self.start_fake_login(&username).await;
+        }
+
+        let (options, state) = self
+            .webauthn
+            .start_passkey_authentication(&passkeys)
+            .map_err(PasskeyError::internal)?;
+
+        let ceremony_id = self.save_ceremony(user_id.into(), &state).await?;
+        Ok(CeremonyStart {
+            ceremony_id,
+            options,
+        })
+    }
+
+    /// Verifies a login assertion and returns the id of the authenticated user.
+    pub async fn finish_login(
+        &self,
+        ceremony_id: String,
+        credential: PublicKeyCredential,
+        client_ip: Option<IpAddr>,
+    ) -> Result<String, PasskeyError> {
+        let (user_id, state) = self
+            .take_ceremony::<Option<PasskeyAuthentication>>(ceremony_id)
+            .await?;
+        let Some(state) = state else {
+            if let Some(throttle) = &self.throttle {
+                throttle.check_client(client_ip).await?;
+                throttle.record_client_failure(client_ip).await?;
+            }
+            return Err(PasskeyError::VerificationFailed);
+        };
+
+        // Counted apart from password failures, which are keyed by username.
+        let account = format!("passkey:{user_id}");
+        if let Some(throttle) = &self.throttle {
+            throttle.check(&account, client_ip).await?;
+        }
+
+        let result = match self
+            .webauthn
+            .finish_passkey_authentication(&credential, &state)
+        {
+            Ok(result) => result,
+            Err(_) => {
+                if let Some(throttle) = &self.throttle {
+                    throttle.record_failure(&account, client_ip).await?;
+                }
+                return Err(PasskeyError::VerificationFailed);
+            }
+        };
+        if let Some(throttle) = &self.throttle {
+            throttle.record_success(&account).await?;
+        }
+
+        // Keep the signature counter in sync so cloned authenticators can be detected.
+        for (credential, mut passkey) in self.get_passkeys(user_id.clone()).await? {
+            if passkey.update_credential(&result) == Some(true) {
+                self.save_passkey(credential, &passkey).await?;
+            }
+        }
+
+        Ok(user_id)
+    }
+
+    /// Loads the user's passkey credentials along with the passkey stored in their secret.
+    /// Answers like [`PasskeyProvider::start_login`] does for an identity with passkeys, so the
+    /// response doesn't reveal whether the username exists. The credential ids are derived from
+    /// the username and the ceremony can't be finished.
+    async fn start_fake_login(
+        &self,
+        username: &str,
+    ) -> Result<CeremonyStart<RequestChallengeResponse>, PasskeyError> {
+        let allow_credentials = self
+            .fake_credentials
+            .generate(username.as_bytes())
+            .map_err(PasskeyError::internal)?
+            .into_iter()
+            .map(|credential_id| AllowCredentials {
+                type_: String::from("public-key"),
+                id: credential_id.as_ref().into(),
+                transports: None,
+            })
+            .collect();
+
+        let mut challenge = vec![0; 32];
+        rand::rng().fill_bytes(&mut challenge);
+        let options = RequestChallengeResponse {
+            public_key: PublicKeyCredentialRequestOptions {
+                challenge: challenge.into(),
+                timeout: Some(DEFAULT_AUTHENTICATOR_TIMEOUT.as_millis() as u32),
+                rp_id: self.rp_id.clone(),
+                allow_credentials,
+                user_verification: UserVerificationPolicy::Required,
+                hints: None,
+                extensions: None,
+            },
+            mediation: None,
+        };
+
+        let ceremony_id = self
+            .save_ceremony(String::new(), &None::<PasskeyAuthentication>)
+            .await?;
+        Ok(CeremonyStart {
+            ceremony_id,
+            options,
+        })
+    }
+
+    async fn get_passkeys(
+        &self,
+        user_id: String,
+    ) -> Result<Vec<(Credential, Passkey)>, PasskeyError> {
+        let credentials = self.credential_backend.get_credentials(user_id).await?;
+
+        let mut passkeys = Vec::new();
+        for credential in credentials {
+            if credential.kind != CredentialKind::Passkey {
+                continue;
+            }
+            let Some(secret) = &credential.secret else {
+                return Err(PasskeyError::internal("passkey credential without secret"));
+            };
+            let passkey = serde_json::from_str(secret).map_err(PasskeyError::internal)?;
+            passkeys.push((credential, passkey));
+        }
+        Ok(passkeys)
+    }
+
+    async fn save_passkey(
+        &self,
+        credential: Credential,
+        passkey: &Passkey,
+    ) -> Result<(), PasskeyError> {
+        let secret = serde_json::to_string(passkey).map_err(PasskeyError::internal)?;
+        self.credential_backend
+            .save_credential(credential.with_secret(secret))
+            .await?;
+        Ok(())
+    }
+
+    async fn save_ceremony<S: Serialize>(
+        &self,
+        user_id: String,
+        state: &S,
+    ) -> Result<String, PasskeyError> {
+        let ceremony = PasskeyCeremony {
+            id: Uuid::new_v4().into(),
+            user_id,
+            state: serde_json::to_string(state).map_err(PasskeyError::internal)?,
+            expires_at: unix_now() + CEREMONY_LIFETIME,
+        };
+
+        let ceremony_id = ceremony.id.clone();
+        self.backend.save_ceremony(ceremony).await?;
+        Ok(ceremony_id)
+    }
+
+    async fn take_ceremony<S: for<'de> Deserialize<'de>>(
+        &self,
+        ceremony_id: String,
+    ) -> Result<(String, S), PasskeyError> {
+        let Some(ceremony) = self.backend.take_ceremony(ceremony_id).await? else {
+            return Err(PasskeyError::InvalidCeremony);
+        };
+
+        if ceremony.expires_at <= unix_now() {
+            return Err(PasskeyError::InvalidCeremony);
+        }
+
+        let state =
+            serde_json::from_str(&ceremony.state).map_err(|_| PasskeyError::InvalidCeremony)?;
+        Ok((ceremony.user_id, state))
+    }
+}
+
+async fn start_registration<
+    T: IntoPublic + ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
+>(
+    passkey_provider: Data<PasskeyProvider<T>>,
+    session: SessionRes<T>,
+) -> Result<impl Responder, PasskeyError> {
+    let start = passkey_provider.start_registration(&session.inner).await?;
+
+    Ok(HttpResponse::Ok().json(start))
+}
+
+async fn finish_registration<
+    T: IntoPublic + ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
+>(
+    passkey_provider: Data<PasskeyProvider<T>>,
+    session: SessionRes<T>,
+    request: Json<RegisterFinishRequest>,
+) -> Result<impl Responder, PasskeyError> {
+    let Some(user_id) = session.inner.id() else {
+        return Err(PasskeyError::internal("identity without id"));
+    };
+
+    let request = request.0;
+    passkey_provider
+        .finish_registration(user_id.into(), request.ceremony_id, request.credential)
+        .await?;
+
+    Ok(HttpResponse::Created().finish())
+}
+
+async fn start_login<
+    T: IntoPublic + ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
+>(
+    passkey_provider: Data<PasskeyProvider<T>>,
+    request: Json<LoginStartRequest>,
+) -> Result<impl Responder, PasskeyError> {
+    let start = passkey_provider.start_login(request.0.username).await?;
+
+    Ok(HttpResponse::Ok().json(start))
+}
+
+async fn finish_login<
+    T: IntoPublic + ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
+>(
+    req: HttpRequest,
+    passkey_provider: Data<PasskeyProvider<T>>,
+    session_provider: Data<SessionProvider<T>>,
+    request: Json<LoginFinishRequest>,
+) -> Result<impl Responder, PasskeyError> {
+    let request = request.0;
+    let client_ip = passkey_provider
+        .throttle
+        .as_ref()
+        .and_then(|throttle| throttle.client_ip(&req));
+    let mut context = AuditContext::from_request(&req);
+    if client_ip.is_some() {
+        context.ip = client_ip;
+    }
+
+    let result = passkey_provider
+        .finish_login(request.ceremony_id, request.credential, client_ip)
+        .await;
+
+    let event = AuditEvent::new(AuditAction::Login, &context).outcome_of(&result);
+    let event = match &result {
+        Ok(user_id) => event.actor(user_id.clone()).target(user_id.clone()),
+        Err(_) => event,
+    };
+    audit::record(&session_provider.audit_sink, event).await;
+
+    let user_id = result?;
+
+    let session = session_provider.create_session(user_id).await?;
+    Ok(session_provider.grant(session).await?)
+}
+
+/// Stores ceremonies in flight. The passkeys themselves are [`Credential`]s of kind
+/// [`CredentialKind::Passkey`], kept by the [`CredentialBackend`].
+#[async_trait]
+pub trait PasskeyBackend<T: ObjectId + Serialize + for<'de> Deserialize<'de>>: Send + Sync {
+    async fn save_ceremony(&self, ceremony: PasskeyCeremony) -> Result<(), PasskeyError>;
+    /// Returns and removes a ceremony, so each one can only be finished once.
+    async fn take_ceremony(
+        &self,
+        ceremony_id: String,
+    ) -> Result<Option<PasskeyCeremony>, PasskeyError>;
+}
@@ -0,0 +1,167 @@
+use std::path::PathBuf;
+
+use actix_web::web::block;
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+
+use crate::identity::IdentityError;
+
+/// Why a password was rejected by a [`PasswordPolicy`].
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "reason", rename_all = "snake_case")]
+pub enum PasswordViolation {
+    TooShort {
+        min_length: usize,
+    },
+    TooLong {
+        max_length: usize,
+    },
+    MissingLowercase,
+    MissingUppercase,
+    MissingDigit,
+    MissingSymbol,
+    /// zxcvbn score (0 - 4) below the required minimum.
+    TooWeak {
+        score: u8,
+        min_score: u8,
+    },
+    ContainsUsername,
+    /// The password appears in a known breach `occurrences` times.
+    Breached {
+        occurrences: u64,
+    },
+}
+
+/// Requirements passwords have to meet on `IdentityProvider::create` and
+/// `CredentialProvider::set_password`.
+/// Lengths are counted in characters, not bytes.
+#[derive(Clone)]
+pub struct PasswordPolicy {
+    pub min_length: usize,
+    pub max_length: usize,
+    pub require_lowercase: bool,
+    pub require_uppercase: bool,
+    pub require_digit: bool,
+    pub require_symbol: bool,
+    /// Minimum zxcvbn score (0 - 4), `None` skips the strength estimation.
+    pub min_score: Option<u8>,
+    pub disallow_username: bool,
+    pub breached_passwords: Option<BreachedPasswords>,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        Self {
+            min_length: 8,
+            max_length: 128,
+            require_lowercase: false,
+            require_uppercase: false,
+            require_digit: false,
+            require_symbol: false,
+            min_score: Some(2),
+            disallow_username: true,
+            breached_passwords: None,
+        }
+    }
+}
+
+impl PasswordPolicy {
+    /// Returns every rule the password violates, an empty list means it is accepted.
+    pub async fn check(
+        &self,
+        username: &str,
+        password: &str,
+    ) -> Result<Vec<PasswordViolation>, IdentityError> {
+        let mut violations = Vec::new();
+
+        let length = password.chars().count();
+        if length < self.min_length {
+            violations.push(PasswordViolation::TooShort {
+                min_length: self.min_length,
+            });
+        }
+        if length > self.max_length {
+            // Long inputs are rejected before any expensive checks run on them.
+            violations.push(PasswordViolation::TooLong {
+                max_length: self.max_length,
+            });
+            return Ok(violations);
+        }
+
+        if self.require_lowercase && !password.chars().any(char::is_lowercase) {
+            violations.push(PasswordViolation::MissingLowercase);
+        }
+        if self.require_uppercase && !password.chars().any(char::is_uppercase) {
+            violations.push(PasswordViolation::MissingUppercase);
+        }
+        if self.require_digit && !password.chars().any(|c| c.is_ascii_digit()) {
+            violations.push(PasswordViolation::MissingDigit);
+        }
+        if self.require_symbol && password.chars().all(char::is_alphanumeric) {
+            violations.push(PasswordViolation::MissingSymbol);
+        }
+
+        if self.disallow_username
+            && !username.is_empty()
+            && password.to_lowercase().contains(&username.to_lowercase())
+        {
+            violations.push(PasswordViolation::ContainsUsername);
+        }
+
+        if let Some(min_score) = self.min_score {
+            let score = u8::from(zxcvbn::zxcvbn(password, &[username]).score());
+            if score < min_score {
+                violations.push(PasswordViolation::TooWeak { score, min_score });
+            }
+        }
+
+        if let Some(breached_passwords) = &self.breached_passwords {
+            let occurrences = breached_passwords.occurrences(password).await?;
+            if occurrences > 0 {
+                violations.push(PasswordViolation::Breached { occurrences });
+            }
+        }
+
+        Ok(violations)
+    }
+}
+
+/// Offline lookup in a Have I Been Pwned SHA-1 dump split by hash prefix.
+/// The directory holds one `<PREFIX>.txt` per 5 character upper-case hex prefix with
+/// `<SUFFIX>:<COUNT>` lines, the layout of the k-anonymity range API and its downloader.
+#[derive(Clone)]
+pub struct BreachedPasswords {
+    dir: PathBuf,
+}
+
+impl BreachedPasswords {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// How often the password appears in the dump, `0` if it doesn't.
+    pub async fn occurrences(&self, password: &str) -> Result<u64, IdentityError> {
+        let hash = format!("{:X}", Sha1::digest(password.as_bytes()));
+        let (prefix, suffix) = hash.split_at(5);
+        let path = self.dir.join(format!("{prefix}.txt"));
+
+        // The dump leaves out prefixes no breached password has.
+        let range = match block(move || std::fs::read_to_string(path))
+            .await
+            .map_err(IdentityError::internal)?
+        {
+            Ok(range) => range,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(IdentityError::internal(e)),
+        };
+
+        let occurrences = range
+            .lines()
+            .filter_map(|line| line.trim().split_once(':'))
+            .find(|(candidate, _)| candidate.eq_ignore_ascii_case(suffix))
+            .and_then(|(_, count)| count.trim().parse::<u64>().ok())
+            .unwrap_or(0);
+
+        Ok(occurrences)
+    }
+}
@@ -0,0 +1,180 @@
+use actix_web::{
+    HttpRequest, HttpResponse,
+    body::{BoxBody, MessageBody, to_bytes},
+    dev::{ServiceRequest, ServiceResponse},
+    http::{StatusCode, header::CONTENT_TYPE},
+    middleware::Next,
+    web::Data,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+/// Prefix of the `type` of every problem, followed by its `code`.
+pub const PROBLEM_TYPE_PREFIX: &str = "urn:toro-auth:problem:";
+const PROBLEM_CONTENT_TYPE: &str = "application/problem+json";
+
+/// An RFC 7807 `application/problem+json` error body.
+/// `code` is stable and meant for programs, `title` and `detail` are meant for people.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    pub problem_type: String,
+    pub code: String,
+    pub title: String,
+    pub status: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<FieldError>,
+}
+
+/// A problem with a single request field, e.g. a password that is too short.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FieldError {
+    pub field: String,
+    pub code: String,
+    /// Parameters of the rule that was broken, e.g. `min_length`.
+    #[serde(flatten)]
+    pub params: Map<String, Value>,
+}
+
+impl FieldError {
+    pub fn new(field: &str, code: &str) -> Self {
+        Self {
+            field: field.into(),
+            code: code.into(),
+            params: Map::new(),
+        }
+    }
+
+    /// Builds a field error from a violation serialized with a `reason` tag,
+    /// like [`crate::password::PasswordViolation`].
+    pub fn from_violation(field: &str, violation: &impl Serialize) -> Self {
+        let mut params = match serde_json::to_value(violation) {
+            Ok(Value::Object(params)) => params,
+            _ => Map::new(),
+        };
+        let code = match params.remove("reason") {
+            Some(Value::String(code)) => code,
+            _ => String::from("invalid"),
+        };
+
+        Self {
+            field: field.into(),
+            code,
+            params,
+        }
+    }
+}
+
+impl Problem {
+    pub fn new(status: StatusCode, code: &str, title: &str) -> Self {
+        Self {
+            problem_type: format!("{PROBLEM_TYPE_PREFIX}{code}"),
+            code: code.into(),
+            title: title.into(),
+            status: status.as_u16(),
+            detail: None,
+            errors: Vec::new(),
+        }
+    }
+
+    pub fn internal_server_error() -> Self {
+        Self::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "internal_server_error",
+            "Internal server error",
+        )
+    }
+
+    pub fn service_unavailable() -> Self {
+        Self::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "service_unavailable",
+            "Service temporarily unavailable",
+        )
+    }
+
+    pub fn with_detail(mut self, detail: String) -> Self {
+        self.detail = Some(detail);
+        self
+    }
+
+    pub fn with_errors(mut self, errors: Vec<FieldError>) -> Self {
+        self.errors = errors;
+        self
+    }
+
+    pub fn status_code(&self) -> StatusCode {
+        StatusCode::from_u16(self.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+
+    /// Renders the problem, [`customize_problems`] passes it through the app's hook.
+    pub fn into_response(self) -> HttpResponse {
+        HttpResponse::build(self.status_code())
+            .content_type(PROBLEM_CONTENT_TYPE)
+            .json(self)
+    }
+}
+
+/// Errors that are answered with a [`Problem`].
+pub trait ToProblem {
+    fn to_problem(&self) -> Problem;
+}
+
+/// Customizes problems before they are sent, e.g. to translate titles by the request's
+/// `Accept-Language` or point `type` to documentation. Changing `code` breaks clients that
+/// rely on the documented codes.
+pub trait ProblemHook: Send + Sync {
+    fn customize(&self, problem: Problem, req: &HttpRequest) -> Problem;
+}
+
+impl<F> ProblemHook for F
+where
+    F: Fn(Problem, &HttpRequest) -> Problem + Send + Sync,
+{
+    fn customize(&self, problem: Problem, req: &HttpRequest) -> Problem {
+        self(problem, req)
+    }
+}
+
+/// Middleware passing every problem response through the [`ProblemHook`] of the app, see
+/// [`crate::provider::AuthProviderBuilder::with_problem_hook`]. Error responses are rendered
+/// without access to the request, so the hook runs once they are complete:
+/// `App::new().wrap(middleware::from_fn(customize_problems))`.
+pub async fn customize_problems(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    let hook = req.app_data::<Data<Box<dyn ProblemHook>>>().cloned();
+    let response = next.call(req).await?.map_into_boxed_body();
+
+    let is_problem = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .is_some_and(|content_type| content_type == PROBLEM_CONTENT_TYPE);
+    let Some(hook) = hook.filter(|_| is_problem) else {
+        return Ok(response);
+    };
+
+    let (req, response) = response.into_parts();
+    let (response, body) = response.into_parts();
+    let Ok(body) = to_bytes(body).await else {
+        return Ok(ServiceResponse::new(
+            req,
+            Problem::internal_server_error().into_response(),
+        ));
+    };
+
+    let body = match serde_json::from_slice::<Problem>(&body) {
+        Ok(problem) => match serde_json::to_vec(&hook.customize(problem, &req)) {
+            Ok(customized) => customized.into(),
+            Err(_) => body,
+        },
+        Err(_) => body,
+    };
+    Ok(ServiceResponse::new(
+        req,
+        response.set_body(BoxBody::new(body)),
+    ))
+}
@@ -1,10 +1,32 @@
 use actix_web::web::{Data, ServiceConfig};
+#[cfg(feature = "metrics")]
+use metrics_exporter_prometheus::PrometheusHandle;
 use serde::{Deserialize, Serialize};
 
+#[cfg(feature = "metrics")]
+use crate::metrics::MetricsProvider;
 use crate::{
     IntoPublic, ObjectId,
+    api_key::{ApiKeyBackend, ApiKeyProvider},
+    audit::{AuditProvider, AuditSink},
+    credential::{CredentialBackend, CredentialProvider},
+    external::{ExternalIssuer, ExternalLoginBackend, ExternalLoginProvider, ExternalProfile},
+    hooks::AuthHooks,
     identity::{IdentityBackend, IdentityProvider},
+    jwt::JwtConfig,
+    keys::KeyError,
+    magic_link::MagicLinkProvider,
+    mfa::{MfaBackend, MfaProvider},
+    notifier::Notifier,
+    oauth::{ClientBackend, ConsentScreen, GrantBackend, OAuthProvider},
+    oidc::OidcProvider,
+    passkey::{PasskeyBackend, PasskeyError, PasskeyProvider},
+    password::PasswordPolicy,
+    problem::ProblemHook,
     session::{SessionBackend, SessionError, SessionProvider},
+    throttle::LoginThrottle,
+    username::UsernamePolicy,
+    webhook::WebhookDispatcher,
 };
 
 #[derive(Clone)]
@@ -18,16 +40,33 @@ where
         + Send
         + Sync
         + 'static,
-    J: SessionBackend<T> + IdentityBackend<T> + Clone + Send + Sync + 'static,
+    J: SessionBackend<T> + IdentityBackend<T> + CredentialBackend + Clone + Send + Sync + 'static,
 {
     pub session_provider: Data<SessionProvider<T>>,
     pub identity_provider: Data<IdentityProvider<T>>,
+    pub credential_provider: Data<CredentialProvider<T>>,
+    pub mfa_provider: Option<Data<MfaProvider<T>>>,
+    pub passkey_provider: Option<Data<PasskeyProvider<T>>>,
+    pub magic_link_provider: Option<Data<MagicLinkProvider<T>>>,
+    pub audit_provider: Option<Data<AuditProvider<T>>>,
+    pub oauth_provider: Option<Data<OAuthProvider<T>>>,
+    pub oidc_provider: Option<Data<OidcProvider<T>>>,
+    pub external_login_provider: Option<Data<ExternalLoginProvider<T>>>,
+    pub api_key_provider: Option<Data<ApiKeyProvider<T>>>,
+    /// Set by [`AuthProviderBuilder::with_webhooks`], start its delivery task with
+    /// [`WebhookDispatcher::spawn`].
+    pub webhook_dispatcher: Option<Data<WebhookDispatcher>>,
+    /// Registered as app data by [`AuthProvider::configure`], applied by the
+    /// [`crate::problem::customize_problems`] middleware.
+    pub problem_hook: Option<Data<Box<dyn ProblemHook>>>,
+    #[cfg(feature = "metrics")]
+    pub metrics_provider: Option<Data<MetricsProvider>>,
     _backend: Data<J>,
 }
 
 impl<
     T: IntoPublic + ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
-    J: SessionBackend<T> + IdentityBackend<T> + Clone + Send + Sync + 'static,
+    J: SessionBackend<T> + IdentityBackend<T> + CredentialBackend + Clone + Send + Sync + 'static,
 > AuthProvider<T, J>
 {
     pub fn builder(backend: J) -> AuthProviderBuilder<T, J> {
@@ -42,7 +81,49 @@ impl<
         let data = Data::new(self);
         cfg.app_data(data.clone())
             .configure(|cfg| data.clone().identity_provider.configure(cfg))
+            .configure(|cfg| data.clone().credential_provider.configure(cfg))
             .configure(|cfg| data.clone().session_provider.configure(cfg));
+
+        if let Some(mfa_provider) = &data.mfa_provider {
+            cfg.configure(|cfg| mfa_provider.configure(cfg));
+        }
+
+        if let Some(passkey_provider) = &data.passkey_provider {
+            cfg.configure(|cfg| passkey_provider.configure(cfg));
+        }
+
+        if let Some(magic_link_provider) = &data.magic_link_provider {
+            cfg.configure(|cfg| magic_link_provider.configure(cfg));
+        }
+
+        if let Some(audit_provider) = &data.audit_provider {
+            cfg.configure(|cfg| audit_provider.configure(cfg));
+        }
+
+        if let Some(oauth_provider) = &data.oauth_provider {
+            cfg.configure(|cfg| oauth_provider.configure(cfg));
+        }
+
+        if let Some(oidc_provider) = &data.oidc_provider {
+            cfg.configure(|cfg| oidc_provider.configure(cfg));
+        }
+
+        if let Some(external_login_provider) = &data.external_login_provider {
+            cfg.configure(|cfg| external_login_provider.configure(cfg));
+        }
+
+        if let Some(api_key_provider) = &data.api_key_provider {
+            cfg.configure(|cfg| api_key_provider.configure(cfg));
+        }
+
+        if let Some(problem_hook) = &data.problem_hook {
+            cfg.app_data(problem_hook.clone());
+        }
+
+        #[cfg(feature = "metrics")]
+        if let Some(metrics_provider) = &data.metrics_provider {
+            cfg.configure(|cfg| metrics_provider.configure(cfg));
+        }
     }
 
     pub async fn validate_session(&self, session_id: String) -> Result<T, SessionError> {
@@ -60,16 +141,33 @@ where
         + Send
         + Sync
         + 'static,
-    J: SessionBackend<T> + IdentityBackend<T> + Send + Sync + 'static,
+    J: SessionBackend<T> + IdentityBackend<T> + CredentialBackend + Send + Sync + 'static,
 {
     session_provider: SessionProvider<T>,
     identity_provider: IdentityProvider<T>,
+    credential_provider: CredentialProvider<T>,
+    mfa_provider: Option<Data<MfaProvider<T>>>,
+    passkey_provider: Option<PasskeyProvider<T>>,
+    magic_link_provider: Option<MagicLinkProvider<T>>,
+    username_policy: Option<UsernamePolicy>,
+    login_throttle: Option<LoginThrottle>,
+    audit_provider: Option<Data<AuditProvider<T>>>,
+    oauth_provider: Option<OAuthProvider<T>>,
+    oidc_provider: Option<OidcProvider<T>>,
+    external_login_provider: Option<ExternalLoginProvider<T>>,
+    api_key_provider: Option<ApiKeyProvider<T>>,
+    audit_sink: Option<Data<Box<dyn AuditSink>>>,
+    hooks: Option<Data<Box<dyn AuthHooks<T>>>>,
+    webhook_dispatcher: Option<Data<WebhookDispatcher>>,
+    problem_hook: Option<Data<Box<dyn ProblemHook>>>,
+    #[cfg(feature = "metrics")]
+    metrics_provider: Option<Data<MetricsProvider>>,
     backend: J,
 }
 
 impl<
     T: IntoPublic + ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
-    J: SessionBackend<T> + IdentityBackend<T> + Clone + Send + Sync + 'static,
+    J: SessionBackend<T> + IdentityBackend<T> + CredentialBackend + Clone + Send + Sync + 'static,
 > AuthProviderBuilder<T, J>
 {
     pub fn default_with_backend(backend: J) -> Self {
@@ -77,18 +175,389 @@ impl<
             session_provider: SessionProvider::<T>::default_with_backend(Data::new(Box::new(
                 backend.clone(),
             ))),
-            identity_provider: IdentityProvider::<T>::default_with_backend(Data::new(Box::new(
-                backend.clone(),
-            ))),
+            identity_provider: IdentityProvider::<T>::default_with_backend(
+                Data::new(Box::new(backend.clone())),
+                Data::new(Box::new(backend.clone())),
+            )
+            .with_session_backend(Data::new(Box::new(backend.clone()))),
+            credential_provider: CredentialProvider::<T>::default_with_backend(Data::new(
+                Box::new(backend.clone()),
+            )),
+            mfa_provider: None,
+            passkey_provider: None,
+            magic_link_provider: None,
+            username_policy: None,
+            login_throttle: None,
+            audit_provider: None,
+            oauth_provider: None,
+            oidc_provider: None,
+            external_login_provider: None,
+            api_key_provider: None,
+            audit_sink: None,
+            hooks: None,
+            webhook_dispatcher: None,
+            problem_hook: None,
+            #[cfg(feature = "metrics")]
+            metrics_provider: None,
             backend,
         }
     }
 
-    pub fn build(self) -> AuthProvider<T, J> {
+    /// Enables TOTP second factors. Users who confirmed an enrollment have to
+    /// complete `session/mfa` after the password login.
+    pub fn with_mfa(mut self, issuer: String) -> Self
+    where
+        J: MfaBackend<T>,
+    {
+        let mfa_provider = Data::new(MfaProvider::<T>::default_with_backend(
+            Data::new(Box::new(self.backend.clone())),
+            issuer,
+        ));
+        self.session_provider = self
+            .session_provider
+            .with_mfa_provider(mfa_provider.clone());
+        self.mfa_provider = Some(mfa_provider);
+        self.identity_provider = self
+            .identity_provider
+            .with_mfa_backend(Data::new(Box::new(self.backend.clone())));
+        self
+    }
+
+    /// Enables passkey registration and passwordless login.
+    /// `rp_id` is the domain passkeys are bound to (e.g. `example.com`) and
+    /// `rp_origin` the origin the frontend is served from (e.g. `https://example.com`).
+    pub fn with_passkeys(mut self, rp_id: String, rp_origin: String) -> Result<Self, PasskeyError>
+    where
+        J: PasskeyBackend<T>,
+    {
+        self.passkey_provider = Some(PasskeyProvider::<T>::default_with_backend(
+            Data::new(Box::new(self.backend.clone())),
+            Data::new(Box::new(self.backend.clone())),
+            Data::new(Box::new(self.backend.clone())),
+            rp_id,
+            rp_origin,
+        )?);
+        Ok(self)
+    }
+
+    /// Sets the secret unknown usernames get their made-up passkeys from on
+    /// `passkey/login/start`. Requires [`AuthProviderBuilder::with_passkeys`] first.
+    pub fn with_passkey_fake_credential_key(mut self, key: &[u8]) -> Result<Self, PasskeyError> {
+        let Some(passkey_provider) = self.passkey_provider else {
+            return Err(PasskeyError::InvalidConfiguration);
+        };
+        self.passkey_provider = Some(passkey_provider.with_fake_credential_key(key)?);
+        Ok(self)
+    }
+
+    /// Answers registrations for taken usernames like successful ones and notifies the
+    /// existing owner instead, so `POST identity` can't be used to probe for accounts.
+    pub fn with_private_registration(mut self, notifier: impl Notifier<T> + 'static) -> Self {
+        self.identity_provider = self
+            .identity_provider
+            .with_duplicate_notifier(Data::new(Box::new(notifier)));
+        self
+    }
+
+    /// Rejects passwords on registration and password changes that don't meet the policy.
+    pub fn with_password_policy(mut self, policy: PasswordPolicy) -> Self {
+        self.identity_provider = self.identity_provider.with_password_policy(policy.clone());
+        self.credential_provider = self.credential_provider.with_password_policy(policy);
+        self
+    }
+
+    /// Switches logins to the stateless mode: instead of a session cookie they answer with
+    /// a short-lived signed access token and a refresh token for `POST session/refresh`.
+    pub fn with_jwt(mut self, jwt: JwtConfig) -> Self {
+        self.session_provider = self.session_provider.with_jwt(jwt);
+        self
+    }
+
+    /// Throttles failed password, second factor, passkey and magic link logins per account
+    /// and client address.
+    pub fn with_login_throttle(mut self, throttle: LoginThrottle) -> Self {
+        self.login_throttle = Some(throttle);
+        self
+    }
+
+    /// Enables passwordless login via links that are delivered by the given notifier.
+    pub fn with_magic_links(mut self, notifier: impl Notifier<T> + 'static) -> Self {
+        self.magic_link_provider = Some(MagicLinkProvider::<T>::default_with_backend(
+            Data::new(Box::new(self.backend.clone())),
+            Data::new(Box::new(self.backend.clone())),
+            Data::new(Box::new(notifier)),
+        ));
+        self
+    }
+
+    /// Normalizes usernames on registration, updates and every username lookup.
+    pub fn with_username_policy(mut self, policy: UsernamePolicy) -> Self {
+        self.username_policy = Some(policy);
+        self
+    }
+
+    /// Records security relevant events to the sink and serves them at `GET audit`
+    /// to identities accepted by `is_admin`.
+    pub fn with_audit_log(
+        mut self,
+        sink: impl AuditSink + 'static,
+        is_admin: impl Fn(&T) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        let sink: Data<Box<dyn AuditSink>> = Data::new(Box::new(sink));
+        self.session_provider = self.session_provider.with_audit_sink(sink.clone());
+        self.identity_provider = self.identity_provider.with_audit_sink(sink.clone());
+        self.credential_provider = self.credential_provider.with_audit_sink(sink.clone());
+        self.audit_sink = Some(sink.clone());
+        self.audit_provider = Some(Data::new(AuditProvider::default_with_sink(sink, is_admin)));
+        self
+    }
+
+    /// Turns the app into an OAuth 2.0 authorization server for the clients that identities
+    /// accepted by `is_admin` register at `oauth/clients`. Users without a session are sent
+    /// to `login_url`, third-party clients ask for consent via `consent_screen`.
+    /// Access tokens are signed with `jwt`, pass the same config to [`Self::with_jwt`]
+    /// if the stateless mode is used as well. Clients check tokens and session ids at
+    /// `oauth/introspect` and revoke them at `oauth/revoke`.
+    pub fn with_oauth(
+        mut self,
+        jwt: JwtConfig,
+        login_url: String,
+        consent_screen: impl ConsentScreen<T> + 'static,
+        is_admin: impl Fn(&T) -> bool + Send + Sync + 'static,
+    ) -> Self
+    where
+        J: ClientBackend + GrantBackend,
+    {
+        self.oauth_provider = Some(OAuthProvider::<T>::default_with_backend(
+            jwt,
+            Data::new(Box::new(self.backend.clone())),
+            Data::new(Box::new(self.backend.clone())),
+            Data::new(Box::new(self.backend.clone())),
+            Data::new(Box::new(consent_screen)),
+            login_url,
+            is_admin,
+        ));
+        self
+    }
+
+    /// Adds OpenID Connect to [`Self::with_oauth`], which is required and has to come first:
+    /// ID tokens for the `openid` scope, the discovery document, `oauth/userinfo` and
+    /// `oauth/end-session`. The issuer of the OAuth [`JwtConfig`] has to be the URL the app
+    /// is served under. Clients verify ID tokens with the published keys, so
+    /// [`JwtConfig::hs256`] is refused with [`KeyError::SharedSecret`].
+    pub fn with_oidc(mut self) -> Result<Self, KeyError>
+    where
+        J: ClientBackend + GrantBackend,
+    {
+        let Some(oauth_provider) = &self.oauth_provider else {
+            return Err(KeyError::internal("OpenID Connect requires with_oauth"));
+        };
+        if oauth_provider.jwt().key_ring().has_shared_secret() {
+            return Err(KeyError::SharedSecret);
+        }
+
+        self.oidc_provider = Some(OidcProvider::<T>::default_with_backend(
+            Data::new(Box::new(self.backend.clone())),
+            Data::new(Box::new(self.backend.clone())),
+            Data::new(Box::new(self.backend.clone())),
+        ));
+        Ok(self)
+    }
+
+    /// Lets identities accepted by `is_admin` manage service accounts at `service-accounts`.
+    /// They can't log in and get access tokens from the `client_credentials` grant of
+    /// [`Self::with_oauth`], which is required, with a client secret or a signed JWT
+    /// assertion. The identity type has to store its
+    /// [`IdentityKind`](crate::identity::IdentityKind).
+    pub fn with_service_accounts(
+        mut self,
+        is_admin: impl Fn(&T) -> bool + Send + Sync + 'static,
+    ) -> Self
+    where
+        J: ClientBackend,
+    {
+        self.identity_provider = self
+            .identity_provider
+            .with_service_accounts(Data::new(Box::new(self.backend.clone())), is_admin);
+        self
+    }
+
+    /// Enables login with accounts of external OpenID Connect issuers at
+    /// `session/external/{name}`. The first login of an account links it to the identity
+    /// with the same verified email, or else stores the identity built by `new_identity`.
+    /// Logged in users link further accounts at `session/external/{name}/link`.
+    pub fn with_external_login(
+        mut self,
+        issuers: Vec<ExternalIssuer>,
+        new_identity: impl Fn(&ExternalProfile) -> T + Send + Sync + 'static,
+    ) -> Self
+    where
+        J: ExternalLoginBackend<T>,
+    {
+        self.external_login_provider = Some(ExternalLoginProvider::<T>::default_with_backend(
+            Data::new(Box::new(self.backend.clone())),
+            Data::new(Box::new(self.backend.clone())),
+            Data::new(Box::new(self.backend.clone())),
+            issuers,
+            new_identity,
+        ));
+        self
+    }
+
+    /// Enables personal API keys at `identity/{id}/api-keys`. Routes that take [`ApiKeyRes`]
+    /// accept them from `Authorization: ApiKey <key>` and `X-Api-Key` headers, all others
+    /// don't. Keys can be issued for the given `scopes`, check them with
+    /// [`ApiKeyRes::has_scope`].
+    ///
+    /// [`ApiKeyRes`]: crate::api_key::ApiKeyRes
+    /// [`ApiKeyRes::has_scope`]: crate::api_key::ApiKeyRes::has_scope
+    pub fn with_api_keys(mut self, scopes: Vec<String>) -> Self
+    where
+        J: ApiKeyBackend,
+    {
+        self.api_key_provider = Some(ApiKeyProvider::<T>::default_with_backend(
+            Data::new(Box::new(self.backend.clone())),
+            Data::new(Box::new(self.backend.clone())),
+            scopes,
+        ));
+        self.identity_provider = self
+            .identity_provider
+            .with_api_key_backend(Data::new(Box::new(self.backend.clone())));
+        self
+    }
+
+    /// Runs app code around sign ups, updates, deletions, logins and logouts.
+    /// `before_*` hooks can veto the change with a [`crate::hooks::HookError`].
+    pub fn with_hooks(mut self, hooks: impl AuthHooks<T> + 'static) -> Self {
+        let hooks: Data<Box<dyn AuthHooks<T>>> = Data::new(Box::new(hooks));
+        self.session_provider = self.session_provider.with_hooks(hooks.clone());
+        self.identity_provider = self.identity_provider.with_hooks(hooks.clone());
+        self.hooks = Some(hooks);
+        self
+    }
+
+    /// Publishes identity creations, deletions and password changes to the
+    /// dispatcher's endpoints.
+    pub fn with_webhooks(mut self, dispatcher: WebhookDispatcher) -> Self {
+        let dispatcher = Data::new(dispatcher);
+        self.identity_provider = self.identity_provider.with_webhooks(dispatcher.clone());
+        self.credential_provider = self.credential_provider.with_webhooks(dispatcher.clone());
+        self.webhook_dispatcher = Some(dispatcher);
+        self
+    }
+
+    /// Serves the metrics recorded by the providers at `GET metrics`.
+    /// Get the handle from [`crate::metrics::install_prometheus_recorder`].
+    #[cfg(feature = "metrics")]
+    pub fn with_metrics(mut self, handle: PrometheusHandle) -> Self {
+        self.metrics_provider = Some(Data::new(MetricsProvider::default_with_handle(handle)));
+        self
+    }
+
+    /// Customizes or localizes the problem+json bodies of error responses. Only applies
+    /// to apps wrapped in the [`crate::problem::customize_problems`] middleware.
+    pub fn with_problem_hook(mut self, hook: impl ProblemHook + 'static) -> Self {
+        self.problem_hook = Some(Data::new(Box::new(hook)));
+        self
+    }
+
+    pub fn build(mut self) -> AuthProvider<T, J> {
+        if let Some(policy) = self.username_policy {
+            self.session_provider = self.session_provider.with_username_policy(policy.clone());
+            self.identity_provider = self.identity_provider.with_username_policy(policy.clone());
+            self.passkey_provider = self
+                .passkey_provider
+                .map(|provider| provider.with_username_policy(policy.clone()));
+            self.magic_link_provider = self
+                .magic_link_provider
+                .map(|provider| provider.with_username_policy(policy.clone()));
+            self.external_login_provider = self
+                .external_login_provider
+                .map(|provider| provider.with_username_policy(policy));
+        }
+
+        if let Some(throttle) = self.login_throttle {
+            if let Some(mfa_provider) = self.mfa_provider.take() {
+                let mfa_provider = Data::new(
+                    mfa_provider
+                        .as_ref()
+                        .clone()
+                        .with_throttle(throttle.clone()),
+                );
+                self.session_provider = self
+                    .session_provider
+                    .with_mfa_provider(mfa_provider.clone());
+                self.mfa_provider = Some(mfa_provider);
+            }
+            self.session_provider = self.session_provider.with_throttle(throttle.clone());
+            self.credential_provider = self.credential_provider.with_throttle(throttle.clone());
+            self.passkey_provider = self
+                .passkey_provider
+                .map(|provider| provider.with_throttle(throttle.clone()));
+            self.magic_link_provider = self
+                .magic_link_provider
+                .map(|provider| provider.with_throttle(throttle));
+        }
+
+        if let Some(mut provider) = self.external_login_provider.take() {
+            if let Some(hooks) = &self.hooks {
+                provider = provider.with_hooks(hooks.clone());
+            }
+            if let Some(webhooks) = &self.webhook_dispatcher {
+                provider = provider.with_webhooks(webhooks.clone());
+            }
+            if let Some(sink) = &self.audit_sink {
+                provider = provider.with_audit_sink(sink.clone());
+            }
+            self.external_login_provider = Some(provider);
+        }
+
+        if let Some(sink) = &self.audit_sink {
+            self.api_key_provider = self
+                .api_key_provider
+                .map(|provider| provider.with_audit_sink(sink.clone()));
+            if let Some(mfa_provider) = self.mfa_provider.take() {
+                let mfa_provider =
+                    Data::new(mfa_provider.as_ref().clone().with_audit_sink(sink.clone()));
+                self.session_provider = self
+                    .session_provider
+                    .with_mfa_provider(mfa_provider.clone());
+                self.mfa_provider = Some(mfa_provider);
+            }
+        }
+
+        let session_provider = Data::new(self.session_provider);
+        if let Some(oauth_provider) = self.oauth_provider.take() {
+            let jwt = oauth_provider.jwt().clone();
+            self.oidc_provider = self.oidc_provider.map(|provider| provider.with_jwt(jwt));
+            let oauth_provider = oauth_provider.with_session_provider(session_provider.clone());
+            self.oauth_provider = Some(match self.oidc_provider {
+                Some(_) => oauth_provider.with_oidc(),
+                None => oauth_provider,
+            });
+        }
+
+        let credential_provider = self
+            .credential_provider
+            .with_session_provider(session_provider.clone());
+
         AuthProvider {
             _backend: Data::new(self.backend),
-            session_provider: Data::new(self.session_provider),
+            session_provider,
             identity_provider: Data::new(self.identity_provider),
+            credential_provider: Data::new(credential_provider),
+            mfa_provider: self.mfa_provider,
+            passkey_provider: self.passkey_provider.map(Data::new),
+            magic_link_provider: self.magic_link_provider.map(Data::new),
+            audit_provider: self.audit_provider,
+            oauth_provider: self.oauth_provider.map(Data::new),
+            oidc_provider: self.oidc_provider.map(Data::new),
+            external_login_provider: self.external_login_provider.map(Data::new),
+            api_key_provider: self.api_key_provider.map(Data::new),
+            webhook_dispatcher: self.webhook_dispatcher,
+            problem_hook: self.problem_hook,
+            #[cfg(feature = "metrics")]
+            metrics_provider: self.metrics_provider,
         }
     }
 }
@@ -1,22 +1,80 @@
 use actix_web::{
-    FromRequest, HttpResponse, Responder,
+    FromRequest, HttpRequest, HttpResponse, Responder,
     cookie::{
         Cookie,
         time::{Duration, OffsetDateTime},
     },
-    http::StatusCode,
+    http::{
+        StatusCode,
+        header::{HeaderValue, RETRY_AFTER},
+    },
     web::{Data, Json, ServiceConfig, get, post},
 };
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
-use std::{marker::PhantomData, pin::Pin};
+use std::{marker::PhantomData, net::IpAddr, pin::Pin, time::Instant};
+use tracing::{Instrument, Span, field::Empty};
+
+use uuid::Uuid;
+
+use crate::{
+    IntoPublic, ObjectId,
+    audit::{self, AuditAction, AuditContext, AuditEvent, AuditSink},
+    crypto::{hash_token, random_token},
+    error::{BoxError, as_source, fmt_with_source},
+    hooks::AuthHooks,
+    identity::IdentityError,
+    jwt::{self, JwtConfig, TokenResponse, bearer_token},
+    keys,
+    mfa::MfaProvider,
+    problem::{Problem, ToProblem},
+    telemetry::{observe_backend, record_outcome, record_user_id},
+    throttle::LoginThrottle,
+    unix_now,
+    username::UsernamePolicy,
+};
 
-use crate::{IntoPublic, ObjectId};
+/// How long a session waiting for its second factor stays valid, in seconds.
+const MFA_PENDING_LIFETIME: u64 = 5 * 60;
+/// Wrong codes after which a session waiting for its second factor is discarded.
+const MAX_MFA_ATTEMPTS: u32 = 5;
+
+/// What a stored session may be used for. Only [`SessionKind::Full`] sessions are accepted by
+/// [`SessionRes`] as cookie, the other kinds are intermediate steps of a login or belong to
+/// the stateless mode.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionKind {
+    #[default]
+    Full,
+    /// Password login that still has to be upgraded via `session/mfa`.
+    MfaPending,
+    /// Unredeemed magic link, stored under the hash of the emailed token.
+    MagicLink,
+    /// Login in stateless mode. Its id is the `sid` of the access tokens, deleting it
+    /// revokes every refresh token of the login.
+    TokenFamily,
+    /// Refresh token, stored under the hash of the token with its family in `family`.
+    RefreshToken,
+    /// Refresh token that was already exchanged. Kept until it expires to detect reuse.
+    RotatedRefreshToken,
+}
 
 #[derive(Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct Session<T> {
     pub id: String,
     pub user_id: String,
+    #[serde(default)]
+    pub kind: SessionKind,
+    /// Unix timestamp (seconds) after which the session is no longer accepted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+    /// Id of the [`SessionKind::TokenFamily`] a refresh token belongs to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub family: Option<String>,
+    /// Wrong codes sent for a [`SessionKind::MfaPending`] session.
+    #[serde(default)]
+    pub failed_attempts: u32,
     #[serde(skip_serializing_if = "Option::is_none")]
     _mapped: Option<PhantomData<T>>,
 }
@@ -26,50 +84,146 @@ impl<T> Session<T> {
         Self {
             id,
             user_id,
+            kind: SessionKind::Full,
+            expires_at: None,
+            family: None,
+            failed_attempts: 0,
+            _mapped: None,
+        }
+    }
+
+    pub fn short_lived(id: String, user_id: String, kind: SessionKind, expires_at: u64) -> Self {
+        Self {
+            id,
+            user_id,
+            kind,
+            expires_at: Some(expires_at),
+            family: None,
+            failed_attempts: 0,
             _mapped: None,
         }
     }
+
+    pub fn with_family(mut self, family: String) -> Self {
+        self.family = Some(family);
+        self
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| expires_at <= unix_now())
+    }
 }
 
 #[derive(Debug)]
 pub enum SessionError {
     InvalidOrMissingSession,
-    InternalServerError,
-    ServiceUnavailable,
+    InternalServerError(Option<BoxError>),
+    ServiceUnavailable(Option<BoxError>),
     InvalidLogin,
+    /// Rejected by throttling, `retry_after` is given in seconds.
+    TooManyRequests {
+        retry_after: u64,
+    },
 }
 
 impl std::fmt::Display for SessionError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:#?}", self)
+        match self {
+            SessionError::InvalidOrMissingSession => {
+                write!(f, "missing, invalid or expired session")
+            }
+            SessionError::InternalServerError(source) => {
+                fmt_with_source(f, "internal server error", source)
+            }
+            SessionError::ServiceUnavailable(source) => {
+                fmt_with_source(f, "service unavailable", source)
+            }
+            SessionError::InvalidLogin => write!(f, "invalid credentials"),
+            SessionError::TooManyRequests { retry_after } => {
+                write!(f, "too many attempts, retry in {retry_after} seconds")
+            }
+        }
     }
 }
 
-impl From<SessionError> for HttpResponse {
-    fn from(value: SessionError) -> Self {
+impl std::error::Error for SessionError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SessionError::InternalServerError(source)
+            | SessionError::ServiceUnavailable(source) => as_source(source),
+            _ => None,
+        }
+    }
+}
+
+impl SessionError {
+    pub fn internal(source: impl Into<BoxError>) -> Self {
+        SessionError::InternalServerError(Some(source.into()))
+    }
+
+    /// For failures that are likely to go away on retry, like a lost database connection.
+    pub fn unavailable(source: impl Into<BoxError>) -> Self {
+        SessionError::ServiceUnavailable(Some(source.into()))
+    }
+}
+
+impl From<IdentityError> for SessionError {
+    fn from(value: IdentityError) -> Self {
         match value {
-            SessionError::InternalServerError => HttpResponse::InternalServerError().finish(),
-            SessionError::InvalidOrMissingSession | SessionError::InvalidLogin => {
-                HttpResponse::Unauthorized().finish()
-            }
-            SessionError::ServiceUnavailable => HttpResponse::ServiceUnavailable().finish(),
+            IdentityError::InternalServerError(source) => SessionError::InternalServerError(source),
+            IdentityError::ServiceUnavailable(source) => SessionError::ServiceUnavailable(source),
+            // The identity of a session was deleted in the meantime.
+            IdentityError::NotFound => SessionError::InvalidOrMissingSession,
+            other => SessionError::internal(other),
         }
     }
 }
 
-impl actix_web::error::ResponseError for SessionError {
-    fn status_code(&self) -> StatusCode {
+impl ToProblem for SessionError {
+    fn to_problem(&self) -> Problem {
         match self {
-            SessionError::InternalServerError => StatusCode::INTERNAL_SERVER_ERROR,
-            SessionError::InvalidOrMissingSession | SessionError::InvalidLogin => {
-                StatusCode::UNAUTHORIZED
-            }
-            SessionError::ServiceUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+            SessionError::InternalServerError(_) => Problem::internal_server_error(),
+            SessionError::ServiceUnavailable(_) => Problem::service_unavailable(),
+            SessionError::InvalidOrMissingSession => Problem::new(
+                StatusCode::UNAUTHORIZED,
+                "invalid_session",
+                "Missing, invalid or expired session",
+            ),
+            SessionError::InvalidLogin => Problem::new(
+                StatusCode::UNAUTHORIZED,
+                "invalid_login",
+                "Invalid credentials",
+            ),
+            SessionError::TooManyRequests { retry_after } => Problem::new(
+                StatusCode::TOO_MANY_REQUESTS,
+                "too_many_requests",
+                "Too many attempts",
+            )
+            .with_detail(format!("Try again in {retry_after} seconds.")),
         }
     }
+}
+
+impl From<SessionError> for HttpResponse {
+    fn from(value: SessionError) -> Self {
+        actix_web::error::ResponseError::error_response(&value)
+    }
+}
+
+impl actix_web::error::ResponseError for SessionError {
+    fn status_code(&self) -> StatusCode {
+        self.to_problem().status_code()
+    }
 
     fn error_response(&self) -> HttpResponse<actix_web::body::BoxBody> {
-        HttpResponse::build(self.status_code()).finish()
+        let mut response = self.to_problem().into_response();
+        if let SessionError::TooManyRequests { retry_after } = self {
+            response
+                .headers_mut()
+                .insert(RETRY_AFTER, HeaderValue::from(*retry_after));
+        }
+        response
     }
 }
 
@@ -79,6 +233,28 @@ pub struct LoginRequest {
     password: String,
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct LoginResponse {
+    mfa_required: bool,
+    #[serde(flatten)]
+    tokens: Option<TokenResponse>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ValidateResponse<P> {
+    #[serde(flatten)]
+    identity: P,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    recovery_codes_remaining: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MfaRequest {
+    code: String,
+}
+
+/// Identity of a request authenticated by a session cookie or a first-party access token.
+/// API keys are only accepted by [`crate::api_key::ApiKeyRes`].
 pub struct SessionRes<T> {
     pub inner: T,
 }
@@ -97,7 +273,18 @@ where
 {
     login_path: String,
     validate_path: String,
+    mfa_path: String,
+    logout_path: String,
+    refresh_path: String,
+    jwks_path: String,
     backend: Data<Box<dyn SessionBackend<T>>>,
+    mfa_provider: Option<Data<MfaProvider<T>>>,
+    throttle: Option<LoginThrottle>,
+    username_policy: Option<UsernamePolicy>,
+    /// Also records the passkey and magic link logins.
+    pub(crate) audit_sink: Option<Data<Box<dyn AuditSink>>>,
+    hooks: Option<Data<Box<dyn AuthHooks<T>>>>,
+    jwt: Option<JwtConfig>,
 }
 
 impl<
@@ -108,54 +295,710 @@ impl<
         Self {
             login_path: String::from("session/login"),
             validate_path: String::from("session/validate"),
+            mfa_path: String::from("session/mfa"),
+            logout_path: String::from("session/logout"),
+            refresh_path: String::from("session/refresh"),
+            jwks_path: String::from(".well-known/jwks.json"),
             backend,
+            mfa_provider: None,
+            throttle: None,
+            username_policy: None,
+            audit_sink: None,
+            hooks: None,
+            jwt: None,
         }
     }
 
+    pub fn with_mfa_provider(mut self, mfa_provider: Data<MfaProvider<T>>) -> Self {
+        self.mfa_provider = Some(mfa_provider);
+        self
+    }
+
+    pub fn with_throttle(mut self, throttle: LoginThrottle) -> Self {
+        self.throttle = Some(throttle);
+        self
+    }
+
+    /// Looks up logins by the normalized username.
+    pub fn with_username_policy(mut self, policy: UsernamePolicy) -> Self {
+        self.username_policy = Some(policy);
+        self
+    }
+
+    /// Records logins, second factor verifications and logouts.
+    pub fn with_audit_sink(mut self, sink: Data<Box<dyn AuditSink>>) -> Self {
+        self.audit_sink = Some(sink);
+        self
+    }
+
+    /// Runs the login and logout hooks.
+    pub fn with_hooks(mut self, hooks: Data<Box<dyn AuthHooks<T>>>) -> Self {
+        self.hooks = Some(hooks);
+        self
+    }
+
+    /// Switches to the stateless mode: logins answer with a signed access token and a
+    /// refresh token instead of a session cookie.
+    pub fn with_jwt(mut self, jwt: JwtConfig) -> Self {
+        self.jwt = Some(jwt);
+        self
+    }
+
+    pub(crate) fn jwt(&self) -> Option<&JwtConfig> {
+        self.jwt.as_ref()
+    }
+
     pub fn configure(&self, cfg: &mut ServiceConfig) {
         let data = Data::new(self.clone());
         cfg.app_data(data.clone())
             .route(&self.login_path, post().to(login::<T>))
-            .route(&self.validate_path, get().to(validate::<T>));
+            .route(&self.validate_path, get().to(validate::<T>))
+            .route(&self.logout_path, post().to(logout::<T>));
+
+        if self.mfa_provider.is_some() {
+            cfg.route(&self.mfa_path, post().to(verify_mfa::<T>));
+        }
+
+        if let Some(jwt) = &self.jwt {
+            cfg.app_data(Data::new(jwt.key_ring().clone()))
+                .route(&self.refresh_path, post().to(jwt::refresh::<T>))
+                .route(&self.jwks_path, get().to(keys::jwks));
+        }
     }
 
+    #[tracing::instrument(
+        name = "session.validate",
+        level = "debug",
+        skip_all,
+        fields(user_id = Empty, outcome = Empty, latency_ms = Empty)
+    )]
     pub async fn validate(&self, session_id: String) -> Result<T, SessionError> {
-        self.backend.validate(session_id).await
+        let started = Instant::now();
+        let result = self
+            .active_session(session_id)
+            .await
+            .map(|(_, identity)| identity);
+
+        record_outcome("session.validate", &result, started);
+        result
     }
 
+    /// Like [`Self::validate`], but also returns the session.
+    pub(crate) async fn active_session(
+        &self,
+        session_id: String,
+    ) -> Result<(Session<T>, T), SessionError> {
+        let Some(session) =
+            observe_backend("get_session", self.backend.get_session(session_id)).await?
+        else {
+            return Err(SessionError::InvalidOrMissingSession);
+        };
+
+        if session.kind != SessionKind::Full || session.is_expired() {
+            return Err(SessionError::InvalidOrMissingSession);
+        }
+
+        record_user_id(&session.user_id);
+        let identity = observe_backend(
+            "get_identity",
+            self.backend.get_identity(session.user_id.clone()),
+        )
+        .await?;
+        Ok((session, identity))
+    }
+
+    /// `client_ip` is only used for throttling and may be `None` if unknown.
+    #[tracing::instrument(
+        name = "session.login",
+        skip_all,
+        fields(user_id = Empty, mfa_required = Empty, outcome = Empty, latency_ms = Empty)
+    )]
     pub async fn login(
         &self,
         username: String,
         password: String,
+        client_ip: Option<IpAddr>,
+    ) -> Result<Session<T>, SessionError> {
+        let started = Instant::now();
+        let result: Result<Session<T>, SessionError> = async {
+            let username = match &self.username_policy {
+                Some(policy) => policy.normalize(&username),
+                None => username,
+            };
+
+            if let Some(throttle) = &self.throttle {
+                throttle.check(&username, client_ip).await?;
+            }
+
+            let identity = match observe_backend(
+                "verify_login",
+                self.backend.verify_login(username.clone(), password),
+            )
+            .await
+            {
+                Ok(identity) => identity,
+                Err(SessionError::InvalidLogin) => {
+                    if let Some(throttle) = &self.throttle {
+                        throttle.record_failure(&username, client_ip).await?;
+                    }
+                    return Err(SessionError::InvalidLogin);
+                }
+                Err(e) => return Err(e),
+            };
+
+            if let Some(throttle) = &self.throttle {
+                throttle.record_success(&username).await?;
+            }
+
+            let Some(user_id) = identity.id() else {
+                return Err(SessionError::internal("identity without id"));
+            };
+            record_user_id(user_id);
+
+            let mfa_required = self.mfa_required(user_id.into()).await?;
+
+            Span::current().record("mfa_required", mfa_required);
+
+            let session = if mfa_required {
+                mfa_pending(user_id.into())
+            } else {
+                self.new_login(user_id.into())
+            };
+
+            observe_backend(
+                "create_session",
+                self.backend.create_session(session.clone()),
+            )
+            .await?;
+
+            if let Some(hooks) = &self.hooks
+                && !mfa_required
+            {
+                hooks.after_login(&identity, &session).await;
+            }
+            Ok(session)
+        }
+        .await;
+
+        record_outcome("session.login", &result, started);
+        result
+    }
+
+    /// Revokes a session and returns it, so callers know whose session it was.
+    pub async fn logout(&self, session_id: String) -> Result<Session<T>, SessionError> {
+        let Some(session) =
+            observe_backend("get_session", self.backend.get_session(session_id)).await?
+        else {
+            return Err(SessionError::InvalidOrMissingSession);
+        };
+
+        if !observe_backend(
+            "delete_session",
+            self.backend.delete_session(session.id.clone()),
+        )
+        .await?
+        {
+            return Err(SessionError::InvalidOrMissingSession);
+        }
+
+        if let Some(hooks) = &self.hooks {
+            hooks.after_logout(&session).await;
+        }
+        Ok(session)
+    }
+
+    /// Revokes every session and token family of the identity except `keep`, e.g. after its
+    /// password changed.
+    pub async fn logout_others(
+        &self,
+        user_id: String,
+        keep: Option<String>,
+    ) -> Result<(), SessionError> {
+        observe_backend(
+            "delete_sessions",
+            self.backend.delete_sessions(user_id, keep),
+        )
+        .await
+    }
+
+    /// Logs in an identity that authenticated with a single factor other than its password,
+    /// like a magic link or an external account. As with [`SessionProvider::login`],
+    /// identities enrolled in MFA get a session waiting for their second factor.
+    pub async fn login_without_password(
+        &self,
+        user_id: String,
+    ) -> Result<Session<T>, SessionError> {
+        if !self.mfa_required(user_id.clone()).await? {
+            return self.create_session(user_id).await;
+        }
+
+        let session = mfa_pending(user_id);
+        observe_backend(
+            "create_session",
+            self.backend.create_session(session.clone()),
+        )
+        .await?;
+        Ok(session)
+    }
+
+    async fn mfa_required(&self, user_id: String) -> Result<bool, SessionError> {
+        match &self.mfa_provider {
+            Some(mfa_provider) => Ok(mfa_provider.is_enrolled(user_id).await?),
+            None => Ok(false),
+        }
+    }
+
+    /// Issues a full session for an identity that authenticated without a password, with a
+    /// factor that needs no second one like a passkey or an MFA code.
+    pub async fn create_session(&self, user_id: String) -> Result<Session<T>, SessionError> {
+        let session = self.new_login(user_id.clone());
+        observe_backend(
+            "create_session",
+            self.backend.create_session(session.clone()),
+        )
+        .await?;
+
+        if let Some(hooks) = &self.hooks {
+            match observe_backend("get_identity", self.backend.get_identity(user_id)).await {
+                Ok(identity) => hooks.after_login(&identity, &session).await,
+                Err(e) => tracing::error!(error = %e, "failed to load identity for login hooks"),
+            }
+        }
+        Ok(session)
+    }
+
+    /// Upgrades a session waiting for its second factor into a full session.
+    /// The pending session is discarded and a fresh session id is issued. Wrong codes are
+    /// counted per user by the [`LoginThrottle`], and the pending session is discarded after
+    /// [`MAX_MFA_ATTEMPTS`] of them.
+    pub async fn verify_mfa(
+        &self,
+        session_id: String,
+        code: String,
+        client_ip: Option<IpAddr>,
     ) -> Result<Session<T>, SessionError> {
-        self.backend.login(username, password).await
+        let Some(mfa_provider) = &self.mfa_provider else {
+            return Err(SessionError::internal("MFA is not enabled"));
+        };
+
+        let Some(pending) =
+            observe_backend("get_session", self.backend.get_session(session_id)).await?
+        else {
+            return Err(SessionError::InvalidOrMissingSession);
+        };
+
+        if pending.kind != SessionKind::MfaPending || pending.is_expired() {
+            return Err(SessionError::InvalidOrMissingSession);
+        }
+
+        // Counted apart from password failures, which are keyed by username.
+        let account = format!("mfa:{}", pending.user_id);
+        if let Some(throttle) = &self.throttle {
+            throttle.check(&account, client_ip).await?;
+        }
+
+        // Taken before the code is checked, so concurrent guesses for the same session
+        // can't get past the attempt limit.
+        if !observe_backend(
+            "delete_session",
+            self.backend.delete_session(pending.id.clone()),
+        )
+        .await?
+        {
+            return Err(SessionError::InvalidOrMissingSession);
+        }
+
+        if let Err(e) = mfa_provider.verify(pending.user_id.clone(), code).await {
+            let e = SessionError::from(e);
+            let failed_attempts = match e {
+                SessionError::InvalidLogin => {
+                    if let Some(throttle) = &self.throttle {
+                        throttle.record_failure(&account, client_ip).await?;
+                    }
+                    pending.failed_attempts + 1
+                }
+                _ => pending.failed_attempts,
+            };
+            if failed_attempts < MAX_MFA_ATTEMPTS {
+                let pending = Session {
+                    failed_attempts,
+                    ..pending
+                };
+                observe_backend("create_session", self.backend.create_session(pending)).await?;
+            }
+            return Err(e);
+        }
+
+        if let Some(throttle) = &self.throttle {
+            throttle.record_success(&account).await?;
+        }
+        self.create_session(pending.user_id).await
+    }
+
+    /// Validates an access token of the stateless mode, including whether its
+    /// family was revoked, and returns the identity it was issued to.
+    #[tracing::instrument(
+        name = "session.validate_access_token",
+        level = "debug",
+        skip_all,
+        fields(user_id = Empty, outcome = Empty, latency_ms = Empty)
+    )]
+    pub async fn validate_access_token(&self, token: String) -> Result<T, SessionError> {
+        let started = Instant::now();
+        let result: Result<T, SessionError> = async {
+            let Some(jwt) = &self.jwt else {
+                return Err(SessionError::InvalidOrMissingSession);
+            };
+
+            let claims = jwt.verify::<T>(&token)?;
+            record_user_id(&claims.sub);
+            // Tokens of OAuth clients are limited to their scopes, so they don't count as login.
+            if claims.client_id.is_some() {
+                return Err(SessionError::InvalidOrMissingSession);
+            }
+
+            let family =
+                observe_backend("get_session", self.backend.get_session(claims.sid)).await?;
+            if !family.is_some_and(|family| {
+                family.kind == SessionKind::TokenFamily && !family.is_expired()
+            }) {
+                return Err(SessionError::InvalidOrMissingSession);
+            }
+
+            observe_backend("get_identity", self.backend.get_identity(claims.sub)).await
+        }
+        .await;
+
+        record_outcome("session.validate_access_token", &result, started);
+        result
+    }
+
+    /// Exchanges a refresh token for a new access and refresh token. Each refresh token
+    /// can be used once, presenting it again revokes its whole family since either the
+    /// client or an attacker holds a stolen copy. Such revocations are audited with `context`.
+    #[tracing::instrument(
+        name = "session.refresh",
+        skip_all,
+        fields(user_id = Empty, outcome = Empty, latency_ms = Empty)
+    )]
+    pub async fn refresh(
+        &self,
+        refresh_token: String,
+        context: &AuditContext,
+    ) -> Result<TokenResponse, SessionError> {
+        let started = Instant::now();
+        let result: Result<TokenResponse, SessionError> = async {
+            let Some(current) = observe_backend(
+                "get_session",
+                self.backend.get_session(hash_token(&refresh_token)),
+            )
+            .await?
+            else {
+                return Err(SessionError::InvalidOrMissingSession);
+            };
+            let Some(family_id) = current.family.clone() else {
+                return Err(SessionError::InvalidOrMissingSession);
+            };
+            record_user_id(&current.user_id);
+
+            match current.kind {
+                SessionKind::RefreshToken if !current.is_expired() => {}
+                SessionKind::RotatedRefreshToken => {
+                    tracing::warn!("refresh token reused, revoking its family");
+                    self.revoke_reused_family(family_id, current.user_id, context)
+                        .await?;
+                    return Err(SessionError::InvalidOrMissingSession);
+                }
+                _ => return Err(SessionError::InvalidOrMissingSession),
+            }
+
+            let Some(family) =
+                observe_backend("get_session", self.backend.get_session(family_id.clone())).await?
+            else {
+                return Err(SessionError::InvalidOrMissingSession);
+            };
+            if family.kind != SessionKind::TokenFamily || family.is_expired() {
+                return Err(SessionError::InvalidOrMissingSession);
+            }
+
+            if !observe_backend(
+                "delete_session",
+                self.backend.delete_session(current.id.clone()),
+            )
+            .await?
+            {
+                // A concurrent request exchanged the same token first.
+                tracing::warn!("refresh token reused concurrently, revoking its family");
+                self.revoke_reused_family(family_id, current.user_id, context)
+                    .await?;
+                return Err(SessionError::InvalidOrMissingSession);
+            }
+
+            let rotated = Session {
+                kind: SessionKind::RotatedRefreshToken,
+                ..current
+            };
+            observe_backend("create_session", self.backend.create_session(rotated)).await?;
+
+            self.issue_tokens(&family).await
+        }
+        .await;
+
+        record_outcome("session.refresh", &result, started);
+        result
+    }
+
+    /// Hands out a completed login: sessions as cookie, token families as tokens.
+    /// Sessions waiting for their second factor are answered like `session/login`.
+    pub(crate) async fn grant(&self, session: Session<T>) -> Result<HttpResponse, SessionError> {
+        if session.kind == SessionKind::TokenFamily {
+            let tokens = self.issue_tokens(&session).await?;
+            return Ok(HttpResponse::Ok().json(tokens));
+        }
+
+        if session.kind == SessionKind::MfaPending {
+            return Ok(HttpResponse::Ok()
+                .cookie(session_cookie(session))
+                .json(LoginResponse {
+                    mfa_required: true,
+                    tokens: None,
+                }));
+        }
+
+        Ok(HttpResponse::Ok().cookie(session_cookie(session)).finish())
+    }
+
+    /// The session a completed login is stored as, depending on the mode. Token families
+    /// end after the session lifetime, however often they are refreshed.
+    fn new_login(&self, user_id: String) -> Session<T> {
+        let session = Session::new(Uuid::new_v4().into(), user_id);
+        match &self.jwt {
+            Some(jwt) => Session {
+                kind: SessionKind::TokenFamily,
+                expires_at: Some(unix_now() + jwt.session_lifetime()),
+                ..session
+            },
+            None => session,
+        }
+    }
+
+    async fn issue_tokens(&self, family: &Session<T>) -> Result<TokenResponse, SessionError> {
+        let Some(jwt) = &self.jwt else {
+            return Err(SessionError::internal("stateless mode is not enabled"));
+        };
+
+        let identity = observe_backend(
+            "get_identity",
+            self.backend.get_identity(family.user_id.clone()),
+        )
+        .await?;
+
+        let refresh_token = random_token(48);
+        let expires_at = family
+            .expires_at
+            .unwrap_or(u64::MAX)
+            .min(unix_now() + jwt.refresh_token_lifetime());
+        let stored = Session::short_lived(
+            hash_token(&refresh_token),
+            family.user_id.clone(),
+            SessionKind::RefreshToken,
+            expires_at,
+        )
+        .with_family(family.id.clone());
+        observe_backend("create_session", self.backend.create_session(stored)).await?;
+
+        Ok(TokenResponse {
+            access_token: jwt.issue(&identity, family.id.clone())?,
+            token_type: String::from("Bearer"),
+            expires_in: jwt.access_token_lifetime(),
+            refresh_token: Some(refresh_token),
+            id_token: None,
+            scope: None,
+        })
+    }
+
+    async fn revoke_reused_family(
+        &self,
+        family_id: String,
+        user_id: String,
+        context: &AuditContext,
+    ) -> Result<(), SessionError> {
+        observe_backend("delete_session", self.backend.delete_session(family_id)).await?;
+        audit::record(
+            &self.audit_sink,
+            AuditEvent::new(AuditAction::SessionRevoke, context).target(user_id),
+        )
+        .await;
+        Ok(())
     }
 }
 
+pub(crate) fn session_cookie<T>(session: Session<T>) -> Cookie<'static> {
+    let expires = match session.expires_at.and_then(|expires_at| {
+        OffsetDateTime::from_unix_timestamp(i64::try_from(expires_at).ok()?).ok()
+    }) {
+        Some(expires) => Some(expires),
+        None => OffsetDateTime::now_utc().checked_add(Duration::minutes(10)),
+    };
+
+    Cookie::build("sessionId", session.id)
+        .path("/")
+        .expires(expires)
+        .finish()
+}
+
 async fn validate<
     T: IntoPublic + ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
 >(
+    session_provider: Data<SessionProvider<T>>,
     session: SessionRes<T>,
-) -> impl Responder {
-    HttpResponse::Ok().json(session.inner.clone().into_public())
+) -> Result<impl Responder, SessionError> {
+    let recovery_codes_remaining = match (&session_provider.mfa_provider, session.inner.id()) {
+        (Some(mfa_provider), Some(user_id)) => {
+            mfa_provider
+                .remaining_recovery_codes(user_id.into())
+                .await?
+        }
+        _ => None,
+    };
+
+    Ok(HttpResponse::Ok().json(ValidateResponse {
+        identity: session.inner.into_public(),
+        recovery_codes_remaining,
+    }))
+}
+
+/// A session waiting for the second factor of its identity.
+fn mfa_pending<T>(user_id: String) -> Session<T> {
+    Session::short_lived(
+        Uuid::new_v4().into(),
+        user_id,
+        SessionKind::MfaPending,
+        unix_now() + MFA_PENDING_LIFETIME,
+    )
 }
 
 async fn login<
     T: IntoPublic + ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
 >(
+    req: HttpRequest,
     session_provider: Data<SessionProvider<T>>,
     request: Json<LoginRequest>,
 ) -> Result<impl Responder, SessionError> {
+    let client_ip = session_provider
+        .throttle
+        .as_ref()
+        .and_then(|throttle| throttle.client_ip(&req));
+    let mut context = AuditContext::from_request(&req);
+    if client_ip.is_some() {
+        context.ip = client_ip;
+    }
+
     let request = request.0;
-    let session = session_provider
-        .login(request.username, request.password)
-        .await?;
+    let result = session_provider
+        .login(request.username.clone(), request.password, client_ip)
+        .await;
 
-    let session_cookie = Cookie::build("sessionId", session.id)
-        .path("/")
-        .expires(OffsetDateTime::now_utc().checked_add(Duration::minutes(10)))
-        .finish();
-    Ok(HttpResponse::Ok().cookie(session_cookie).finish())
+    let event = AuditEvent::new(AuditAction::Login, &context).outcome_of(&result);
+    let event = match &result {
+        Ok(session) => event
+            .actor(session.user_id.clone())
+            .target(session.user_id.clone()),
+        Err(_) => event.target(request.username),
+    };
+    audit::record(&session_provider.audit_sink, event).await;
+
+    let session = result?;
+
+    if session.kind == SessionKind::TokenFamily {
+        let tokens = session_provider.issue_tokens(&session).await?;
+        return Ok(HttpResponse::Ok().json(LoginResponse {
+            mfa_required: false,
+            tokens: Some(tokens),
+        }));
+    }
+
+    let mfa_required = session.kind == SessionKind::MfaPending;
+    Ok(HttpResponse::Ok()
+        .cookie(session_cookie(session))
+        .json(LoginResponse {
+            mfa_required,
+            tokens: None,
+        }))
+}
+
+async fn verify_mfa<
+    T: IntoPublic + ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
+>(
+    req: HttpRequest,
+    session_provider: Data<SessionProvider<T>>,
+    request: Json<MfaRequest>,
+) -> Result<impl Responder, SessionError> {
+    let Some(session_id) = req.cookie("sessionId") else {
+        return Err(SessionError::InvalidOrMissingSession);
+    };
+    let client_ip = session_provider
+        .throttle
+        .as_ref()
+        .and_then(|throttle| throttle.client_ip(&req));
+    let mut context = AuditContext::from_request(&req);
+    if client_ip.is_some() {
+        context.ip = client_ip;
+    }
+
+    let result = session_provider
+        .verify_mfa(session_id.value().into(), request.0.code, client_ip)
+        .await;
+
+    let event = AuditEvent::new(AuditAction::MfaVerification, &context).outcome_of(&result);
+    let event = match &result {
+        Ok(session) => event
+            .actor(session.user_id.clone())
+            .target(session.user_id.clone()),
+        Err(_) => event,
+    };
+    audit::record(&session_provider.audit_sink, event).await;
+
+    session_provider.grant(result?).await
+}
+
+/// The session a request was made with. In stateless mode the access token names its
+/// token family.
+pub(crate) fn current_session_id<
+    T: IntoPublic + ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
+>(
+    req: &HttpRequest,
+    session_provider: &SessionProvider<T>,
+) -> Option<String> {
+    match (req.cookie("sessionId"), bearer_token(req)) {
+        (Some(cookie), _) => Some(cookie.value().into()),
+        (None, Some(token)) => Some(session_provider.jwt()?.verify::<T>(&token).ok()?.sid),
+        (None, None) => None,
+    }
+}
+
+async fn logout<
+    T: IntoPublic + ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
+>(
+    req: HttpRequest,
+    session_provider: Data<SessionProvider<T>>,
+) -> Result<impl Responder, SessionError> {
+    let Some(session_id) = current_session_id(&req, &session_provider) else {
+        return Err(SessionError::InvalidOrMissingSession);
+    };
+
+    let session = session_provider.logout(session_id).await?;
+    audit::record(
+        &session_provider.audit_sink,
+        AuditEvent::new(AuditAction::Logout, &AuditContext::from_request(&req))
+            .actor(session.user_id.clone())
+            .target(session.user_id),
+    )
+    .await;
+
+    let mut removal = Cookie::build("sessionId", "").path("/").finish();
+    removal.make_removal();
+    Ok(HttpResponse::NoContent().cookie(removal).finish())
 }
 
 impl<
@@ -167,24 +1010,58 @@ impl<
 
     fn from_request(req: &actix_web::HttpRequest, _: &mut actix_web::dev::Payload) -> Self::Future {
         let req = req.clone();
-        Box::pin(async move {
-            let Some(session_id) = req.cookie("sessionId") else {
-                return Err(SessionError::InvalidOrMissingSession);
-            };
+        let span = tracing::debug_span!(
+            "session.extract",
+            user_id = Empty,
+            outcome = Empty,
+            latency_ms = Empty
+        );
+        Box::pin(
+            async move {
+                let started = Instant::now();
+                let result = async {
+                    let Some(session_provider) = req.app_data::<Data<SessionProvider<T>>>() else {
+                        return Err(SessionError::internal("SessionProvider is not configured"));
+                    };
 
-            let Some(session_provider) = req.app_data::<Data<SessionProvider<T>>>() else {
-                return Err(SessionError::InternalServerError);
-            };
+                    let res = match (req.cookie("sessionId"), bearer_token(&req)) {
+                        (Some(session_id), _) => {
+                            session_provider.validate(session_id.value().into()).await?
+                        }
+                        (None, Some(token)) => {
+                            session_provider.validate_access_token(token).await?
+                        }
+                        (None, None) => return Err(SessionError::InvalidOrMissingSession),
+                    };
+                    if let Some(user_id) = res.id() {
+                        record_user_id(user_id);
+                    }
 
-            let res = session_provider.validate(session_id.value().into()).await?;
+                    Ok(SessionRes { inner: res })
+                }
+                .await;
 
-            Ok(SessionRes { inner: res })
-        })
+                record_outcome("session.extract", &result, started);
+                result
+            }
+            .instrument(span),
+        )
     }
 }
 
 #[async_trait]
 pub trait SessionBackend<T: ObjectId + Serialize + for<'de> Deserialize<'de>>: Send + Sync {
-    async fn validate(&self, session_id: String) -> Result<T, SessionError>;
-    async fn login(&self, username: String, password: String) -> Result<Session<T>, SessionError>;
+    async fn verify_login(&self, username: String, password: String) -> Result<T, SessionError>;
+    async fn create_session(&self, session: Session<T>) -> Result<(), SessionError>;
+    async fn get_session(&self, session_id: String) -> Result<Option<Session<T>>, SessionError>;
+    /// Returns whether a session was deleted, so callers can redeem single-use sessions safely.
+    async fn delete_session(&self, session_id: String) -> Result<bool, SessionError>;
+    /// Deletes every session of the identity, except the session or token family `keep`
+    /// together with its refresh tokens.
+    async fn delete_sessions(
+        &self,
+        user_id: String,
+        keep: Option<String>,
+    ) -> Result<(), SessionError>;
+    async fn get_identity(&self, user_id: String) -> Result<T, SessionError>;
 }
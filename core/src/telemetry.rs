@@ -0,0 +1,80 @@
+use std::{fmt::Display, time::Instant};
+
+use tracing::Span;
+
+use crate::problem::ToProblem;
+
+/// Records `outcome` and `latency_ms` on the current span and emits an event for failures.
+/// Server errors are logged with their source at `error`, rejected requests at `debug`.
+/// Spans using this have to declare both fields as `tracing::field::Empty`.
+pub(crate) fn record_outcome<V, E>(operation: &'static str, result: &Result<V, E>, started: Instant)
+where
+    E: ToProblem + std::error::Error,
+{
+    let span = Span::current();
+    let elapsed = started.elapsed();
+    span.record("latency_ms", elapsed.as_millis() as u64);
+
+    let outcome = match result {
+        Ok(_) => String::from("ok"),
+        Err(e) => {
+            let problem = e.to_problem();
+            if problem.status_code().is_server_error() {
+                tracing::error!(error = %e, "request failed");
+            } else {
+                tracing::debug!(error = %e, "request rejected");
+            }
+            problem.code
+        }
+    };
+    span.record("outcome", outcome.as_str());
+
+    #[cfg(feature = "metrics")]
+    {
+        use crate::metrics::{OPERATION_DURATION_SECONDS, OPERATIONS_TOTAL};
+
+        let labels = [("operation", String::from(operation)), ("outcome", outcome)];
+        ::metrics::counter!(OPERATIONS_TOTAL, &labels).increment(1);
+        ::metrics::histogram!(OPERATION_DURATION_SECONDS, &labels).record(elapsed.as_secs_f64());
+    }
+    #[cfg(not(feature = "metrics"))]
+    let _ = operation;
+}
+
+/// Records the id of the identity a span acts on, once it is known.
+pub(crate) fn record_user_id(user_id: impl Display) {
+    Span::current().record("user_id", tracing::field::display(user_id));
+}
+
+/// Awaits a backend call, recording its latency if metrics are enabled.
+pub(crate) async fn observe_backend<V, E>(
+    operation: &'static str,
+    call: impl Future<Output = Result<V, E>>,
+) -> Result<V, E> {
+    #[cfg(feature = "metrics")]
+    {
+        let started = Instant::now();
+        let result = call.await;
+        let outcome = if result.is_ok() { "ok" } else { "error" };
+        ::metrics::histogram!(
+            crate::metrics::BACKEND_DURATION_SECONDS,
+            "operation" => operation,
+            "outcome" => outcome
+        )
+        .record(started.elapsed().as_secs_f64());
+        result
+    }
+    #[cfg(not(feature = "metrics"))]
+    {
+        let _ = operation;
+        call.await
+    }
+}
+
+/// Counts a login rejected by throttling.
+pub(crate) fn record_lockout(scope: &'static str) {
+    tracing::info!(scope, "login throttled");
+
+    #[cfg(feature = "metrics")]
+    ::metrics::counter!(crate::metrics::LOCKOUTS_TOTAL, "scope" => scope).increment(1);
+}
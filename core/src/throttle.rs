@@ -0,0 +1,304 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use actix_web::{HttpRequest, web::Data};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+use crate::{session::SessionError, telemetry::record_lockout, unix_now};
+
+/// Failed attempts counted for one key (an account or a client address).
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Attempts {
+    pub key: String,
+    pub failures: u32,
+    pub last_failure: u64,
+}
+
+/// How quickly repeated failures lock a key.
+/// The first `free_attempts` failures are not delayed, every further failure doubles the
+/// delay starting at `base_delay` up to `max_delay` seconds. Counters start over once no
+/// failure happened for `reset_after` seconds.
+#[derive(Clone, Copy)]
+pub struct ThrottlePolicy {
+    pub free_attempts: u32,
+    pub base_delay: u64,
+    pub max_delay: u64,
+    pub reset_after: u64,
+}
+
+impl ThrottlePolicy {
+    pub fn account() -> Self {
+        Self {
+            free_attempts: 5,
+            base_delay: 1,
+            max_delay: 15 * 60,
+            reset_after: 60 * 60,
+        }
+    }
+
+    pub fn client() -> Self {
+        Self {
+            free_attempts: 20,
+            base_delay: 1,
+            max_delay: 60 * 60,
+            reset_after: 60 * 60,
+        }
+    }
+
+    /// Seconds until the next attempt is allowed, if any.
+    fn retry_after(&self, attempts: &Attempts, now: u64) -> Option<u64> {
+        if attempts.last_failure + self.reset_after <= now || attempts.failures < self.free_attempts
+        {
+            return None;
+        }
+
+        let exponent = (attempts.failures - self.free_attempts).min(63);
+        let delay = self
+            .base_delay
+            .saturating_mul(1u64 << exponent)
+            .min(self.max_delay);
+        let locked_until = attempts.last_failure + delay;
+        (locked_until > now).then(|| locked_until - now)
+    }
+}
+
+/// Counts failed logins per username and per client address and rejects
+/// attempts with [`SessionError::TooManyRequests`] while a key is backed off.
+#[derive(Clone)]
+pub struct LoginThrottle {
+    store: Data<Box<dyn AttemptStore>>,
+    account_policy: ThrottlePolicy,
+    client_policy: ThrottlePolicy,
+    trusted_proxies: Vec<IpAddr>,
+}
+
+impl LoginThrottle {
+    pub fn new(store: impl AttemptStore + 'static) -> Self {
+        Self {
+            store: Data::new(Box::new(store)),
+            account_policy: ThrottlePolicy::account(),
+            client_policy: ThrottlePolicy::client(),
+            trusted_proxies: Vec::new(),
+        }
+    }
+
+    pub fn account_policy(mut self, policy: ThrottlePolicy) -> Self {
+        self.account_policy = policy;
+        self
+    }
+
+    pub fn client_policy(mut self, policy: ThrottlePolicy) -> Self {
+        self.client_policy = policy;
+        self
+    }
+
+    /// Proxies whose `Forwarded` / `X-Forwarded-For` headers are trusted to name the client.
+    pub fn trusted_proxies(mut self, trusted_proxies: Vec<IpAddr>) -> Self {
+        self.trusted_proxies = trusted_proxies;
+        self
+    }
+
+    /// Address of the client that sent the request. Forwarding headers are only
+    /// honored if the direct peer is one of the trusted proxies.
+    pub fn client_ip(&self, req: &HttpRequest) -> Option<IpAddr> {
+        let connection_info = req.connection_info();
+        let peer = parse_ip(connection_info.peer_addr()?)?;
+        if !self.trusted_proxies.contains(&peer) {
+            return Some(peer);
+        }
+
+        connection_info
+            .realip_remote_addr()
+            .and_then(parse_ip)
+            .or(Some(peer))
+    }
+
+    /// Fails while the account or client address is backed off.
+    ///
+    /// Checking and counting a failure are separate steps, so attempts sent in parallel
+    /// all pass the check before the first failure is counted. A burst can therefore try
+    /// up to its own size beyond the free attempts, later attempts are delayed as usual.
+    pub async fn check(
+        &self,
+        username: &str,
+        client_ip: Option<IpAddr>,
+    ) -> Result<(), SessionError> {
+        self.check_keys(self.keys(Some(username), client_ip)).await
+    }
+
+    /// Like [`LoginThrottle::check`] for attempts that don't name an account, like
+    /// redeeming a magic link. Only the client counter applies.
+    pub async fn check_client(&self, client_ip: Option<IpAddr>) -> Result<(), SessionError> {
+        self.check_keys(self.keys(None, client_ip)).await
+    }
+
+    pub async fn record_failure(
+        &self,
+        username: &str,
+        client_ip: Option<IpAddr>,
+    ) -> Result<(), SessionError> {
+        self.record_failures(self.keys(Some(username), client_ip))
+            .await
+    }
+
+    pub async fn record_client_failure(
+        &self,
+        client_ip: Option<IpAddr>,
+    ) -> Result<(), SessionError> {
+        self.record_failures(self.keys(None, client_ip)).await
+    }
+
+    async fn check_keys(
+        &self,
+        keys: Vec<(String, ThrottlePolicy, &'static str)>,
+    ) -> Result<(), SessionError> {
+        let now = unix_now();
+        for (key, policy, scope) in keys {
+            let Some(attempts) = self.store.get_attempts(key).await? else {
+                continue;
+            };
+
+            if let Some(retry_after) = policy.retry_after(&attempts, now) {
+                record_lockout(scope);
+                return Err(SessionError::TooManyRequests { retry_after });
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn record_failures(
+        &self,
+        keys: Vec<(String, ThrottlePolicy, &'static str)>,
+    ) -> Result<(), SessionError> {
+        let now = unix_now();
+        for (key, policy, _) in keys {
+            self.store
+                .record_failure(key, now, now.saturating_sub(policy.reset_after))
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Resets the account counter. The client counter keeps running so a single
+    /// valid account can't be used to reset the budget of an attacking address.
+    pub async fn record_success(&self, username: &str) -> Result<(), SessionError> {
+        self.store.clear_attempts(account_key(username)).await
+    }
+
+    fn keys(
+        &self,
+        username: Option<&str>,
+        client_ip: Option<IpAddr>,
+    ) -> Vec<(String, ThrottlePolicy, &'static str)> {
+        let mut keys = Vec::new();
+        if let Some(username) = username {
+            keys.push((account_key(username), self.account_policy, "account"));
+        }
+        if let Some(client_ip) = client_ip {
+            keys.push((format!("ip:{client_ip}"), self.client_policy, "client"));
+        }
+        keys
+    }
+}
+
+fn account_key(username: &str) -> String {
+    format!("user:{username}")
+}
+
+fn parse_ip(addr: &str) -> Option<IpAddr> {
+    addr.parse::<IpAddr>()
+        .ok()
+        .or_else(|| addr.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+}
+
+/// How often, in seconds, [`InMemoryAttemptStore`] drops counters that started over.
+const SWEEP_INTERVAL: u64 = 60;
+
+/// Keeps attempt counters in process memory. Counters are lost on restart and
+/// not shared between instances, use a persistent store for those setups.
+/// Counters without a failure for longer than the longest `reset_after` are dropped.
+#[derive(Default)]
+pub struct InMemoryAttemptStore {
+    attempts: Mutex<HashMap<String, Attempts>>,
+    /// Longest `reset_after` seen so far, counters of every policy are kept that long.
+    retention: AtomicU64,
+    last_sweep: AtomicU64,
+}
+
+#[async_trait]
+impl AttemptStore for InMemoryAttemptStore {
+    async fn get_attempts(&self, key: String) -> Result<Option<Attempts>, SessionError> {
+        let attempts = self
+            .attempts
+            .lock()
+            .map_err(|e| SessionError::internal(e.to_string()))?;
+        Ok(attempts.get(&key).cloned())
+    }
+
+    async fn record_failure(
+        &self,
+        key: String,
+        now: u64,
+        reset_before: u64,
+    ) -> Result<Attempts, SessionError> {
+        let mut attempts = self
+            .attempts
+            .lock()
+            .map_err(|e| SessionError::internal(e.to_string()))?;
+
+        let retention = self
+            .retention
+            .fetch_max(now.saturating_sub(reset_before), Ordering::Relaxed)
+            .max(now.saturating_sub(reset_before));
+        if self.last_sweep.load(Ordering::Relaxed) + SWEEP_INTERVAL <= now {
+            self.last_sweep.store(now, Ordering::Relaxed);
+            let expired_before = now.saturating_sub(retention);
+            attempts.retain(|_, attempts| attempts.last_failure >= expired_before);
+        }
+
+        let entry = attempts.entry(key.clone()).or_insert(Attempts {
+            key,
+            failures: 0,
+            last_failure: now,
+        });
+        if entry.last_failure < reset_before {
+            entry.failures = 0;
+        }
+        entry.failures += 1;
+        entry.last_failure = now;
+
+        Ok(entry.clone())
+    }
+
+    async fn clear_attempts(&self, key: String) -> Result<(), SessionError> {
+        let mut attempts = self
+            .attempts
+            .lock()
+            .map_err(|e| SessionError::internal(e.to_string()))?;
+        attempts.remove(&key);
+        Ok(())
+    }
+}
+
+#[async_trait]
+pub trait AttemptStore: Send + Sync {
+    async fn get_attempts(&self, key: String) -> Result<Option<Attempts>, SessionError>;
+    /// Atomically counts a failure for `key` and returns the updated counter.
+    /// Counters whose last failure happened before `reset_before` start over.
+    async fn record_failure(
+        &self,
+        key: String,
+        now: u64,
+        reset_before: u64,
+    ) -> Result<Attempts, SessionError>;
+    async fn clear_attempts(&self, key: String) -> Result<(), SessionError>;
+}
@@ -0,0 +1,131 @@
+use serde::{Deserialize, Serialize};
+use unicode_normalization::UnicodeNormalization;
+
+/// Why a username was rejected by a [`UsernamePolicy`].
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "reason", rename_all = "snake_case")]
+pub enum UsernameViolation {
+    TooShort {
+        min_length: usize,
+    },
+    TooLong {
+        max_length: usize,
+    },
+    InvalidCharacter {
+        character: char,
+    },
+    Reserved,
+    /// The identity doesn't store usernames, see [`crate::ObjectId::set_username`], and this
+    /// one isn't in normalized form.
+    NotNormalized,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum UsernameCharacters {
+    /// ASCII letters and digits.
+    Ascii,
+    /// Unicode letters and digits. Look-alikes from different scripts stay distinct accounts.
+    Unicode,
+}
+
+/// Rules usernames are normalized and validated with. Usernames are mapped with NFKC,
+/// so full-width and other compatibility forms collapse to one account, and lower-cased
+/// like the PRECIS `UsernameCaseMapped` profile if `case_fold` is set.
+#[derive(Clone)]
+pub struct UsernamePolicy {
+    pub min_length: usize,
+    pub max_length: usize,
+    pub characters: UsernameCharacters,
+    /// Symbols allowed in addition to `characters`.
+    pub allowed_symbols: Vec<char>,
+    pub case_fold: bool,
+    /// Names that can't be registered. Compared after normalization.
+    pub reserved: Vec<String>,
+}
+
+impl Default for UsernamePolicy {
+    fn default() -> Self {
+        Self {
+            min_length: 3,
+            max_length: 64,
+            characters: UsernameCharacters::Ascii,
+            allowed_symbols: vec!['.', '_', '-', '@', '+'],
+            case_fold: true,
+            reserved: [
+                "admin",
+                "administrator",
+                "root",
+                "system",
+                "support",
+                "security",
+                "postmaster",
+                "webmaster",
+            ]
+            .into_iter()
+            .map(String::from)
+            .collect(),
+        }
+    }
+}
+
+impl UsernamePolicy {
+    /// Maps a username to the form it is stored and looked up by, without validating it.
+    /// Used for login lookups so accounts created before a rule change can still sign in.
+    pub fn normalize(&self, username: &str) -> String {
+        let username = username.nfkc().collect::<String>();
+        if !self.case_fold {
+            return username;
+        }
+
+        // Lower-casing can produce sequences that aren't normalized anymore.
+        username.to_lowercase().nfkc().collect()
+    }
+
+    /// Normalizes a username for registration or renaming and checks it against the rules.
+    pub fn validate(&self, username: &str) -> Result<String, Vec<UsernameViolation>> {
+        let username = self.normalize(username);
+        let mut violations = Vec::new();
+
+        let length = username.chars().count();
+        if length < self.min_length {
+            violations.push(UsernameViolation::TooShort {
+                min_length: self.min_length,
+            });
+        }
+        if length > self.max_length {
+            violations.push(UsernameViolation::TooLong {
+                max_length: self.max_length,
+            });
+        }
+
+        let mut invalid = Vec::new();
+        for character in username.chars().filter(|c| !self.is_allowed(*c)) {
+            if !invalid.contains(&character) {
+                invalid.push(character);
+                violations.push(UsernameViolation::InvalidCharacter { character });
+            }
+        }
+
+        if self
+            .reserved
+            .iter()
+            .any(|reserved| self.normalize(reserved) == username)
+        {
+            violations.push(UsernameViolation::Reserved);
+        }
+
+        if violations.is_empty() {
+            Ok(username)
+        } else {
+            Err(violations)
+        }
+    }
+
+    fn is_allowed(&self, c: char) -> bool {
+        let in_set = match self.characters {
+            UsernameCharacters::Ascii => c.is_ascii_alphanumeric(),
+            UsernameCharacters::Unicode => c.is_alphanumeric(),
+        };
+        in_set || self.allowed_symbols.contains(&c)
+    }
+}
@@ -0,0 +1,485 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use actix_web::{HttpResponse, http::StatusCode};
+use async_trait::async_trait;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::Sha256;
+use subtle::ConstantTimeEq;
+use tokio::task::JoinHandle;
+use uuid::Uuid;
+
+use crate::{
+    error::{BoxError, as_source, fmt_with_source},
+    problem::{Problem, ToProblem},
+    unix_now,
+};
+
+/// Header carrying the event type, e.g. `identity.created`.
+pub const EVENT_HEADER: &str = "X-Toro-Event";
+/// Header carrying the delivery id. It stays the same across retries, so receivers can
+/// use it to drop duplicates.
+pub const DELIVERY_HEADER: &str = "X-Toro-Delivery";
+/// Header carrying `t=<unix timestamp>,v1=<hex HMAC-SHA256 of "<timestamp>.<body>">`.
+pub const SIGNATURE_HEADER: &str = "X-Toro-Signature";
+
+#[derive(Debug)]
+pub enum WebhookError {
+    InternalServerError(Option<BoxError>),
+    ServiceUnavailable(Option<BoxError>),
+}
+
+impl std::fmt::Display for WebhookError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WebhookError::InternalServerError(source) => {
+                fmt_with_source(f, "internal server error", source)
+            }
+            WebhookError::ServiceUnavailable(source) => {
+                fmt_with_source(f, "service unavailable", source)
+            }
+        }
+    }
+}
+
+impl std::error::Error for WebhookError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            WebhookError::InternalServerError(source)
+            | WebhookError::ServiceUnavailable(source) => as_source(source),
+        }
+    }
+}
+
+impl WebhookError {
+    pub fn internal(source: impl Into<BoxError>) -> Self {
+        WebhookError::InternalServerError(Some(source.into()))
+    }
+
+    /// For failures that are likely to go away on retry, like a lost database connection.
+    pub fn unavailable(source: impl Into<BoxError>) -> Self {
+        WebhookError::ServiceUnavailable(Some(source.into()))
+    }
+}
+
+impl ToProblem for WebhookError {
+    fn to_problem(&self) -> Problem {
+        match self {
+            WebhookError::InternalServerError(_) => Problem::internal_server_error(),
+            WebhookError::ServiceUnavailable(_) => Problem::service_unavailable(),
+        }
+    }
+}
+
+impl From<WebhookError> for HttpResponse {
+    fn from(value: WebhookError) -> Self {
+        value.to_problem().into_response()
+    }
+}
+
+impl actix_web::error::ResponseError for WebhookError {
+    fn status_code(&self) -> StatusCode {
+        self.to_problem().status_code()
+    }
+
+    fn error_response(&self) -> HttpResponse<actix_web::body::BoxBody> {
+        self.to_problem().into_response()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum WebhookEventType {
+    #[serde(rename = "identity.created")]
+    IdentityCreated,
+    #[serde(rename = "identity.deleted")]
+    IdentityDeleted,
+    #[serde(rename = "identity.password_changed")]
+    PasswordChanged,
+}
+
+impl WebhookEventType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WebhookEventType::IdentityCreated => "identity.created",
+            WebhookEventType::IdentityDeleted => "identity.deleted",
+            WebhookEventType::PasswordChanged => "identity.password_changed",
+        }
+    }
+}
+
+/// The JSON body POSTed to endpoints.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct WebhookEvent {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub event_type: WebhookEventType,
+    pub timestamp: u64,
+    pub user_id: String,
+    /// The public form of the identity, if it was still available.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub identity: Option<Value>,
+}
+
+impl WebhookEvent {
+    pub fn new(event_type: WebhookEventType, user_id: String, identity: Option<Value>) -> Self {
+        Self {
+            id: Uuid::new_v4().into(),
+            event_type,
+            timestamp: unix_now(),
+            user_id,
+            identity,
+        }
+    }
+}
+
+/// A receiver of webhook events. An empty `events` list subscribes to every event type.
+#[derive(Clone)]
+pub struct WebhookEndpoint {
+    pub url: String,
+    pub secret: String,
+    pub events: Vec<WebhookEventType>,
+}
+
+impl WebhookEndpoint {
+    pub fn new(url: String, secret: String) -> Self {
+        Self {
+            url,
+            secret,
+            events: Vec::new(),
+        }
+    }
+
+    pub fn with_events(mut self, events: Vec<WebhookEventType>) -> Self {
+        self.events = events;
+        self
+    }
+
+    fn subscribes_to(&self, event_type: WebhookEventType) -> bool {
+        self.events.is_empty() || self.events.contains(&event_type)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryStatus {
+    Pending,
+    Delivered,
+    /// Gave up after the maximum number of attempts. Kept in the outbox for inspection.
+    Failed,
+}
+
+/// One event on its way to one endpoint, as stored in the outbox.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct WebhookDelivery {
+    pub id: String,
+    pub url: String,
+    pub event: WebhookEvent,
+    pub status: DeliveryStatus,
+    pub attempts: u32,
+    /// Unix timestamp before which the delivery isn't attempted (again).
+    pub next_attempt_at: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+}
+
+/// Signs a body the way receivers should verify it, see [`SIGNATURE_HEADER`].
+pub fn sign(secret: &str, timestamp: u64, body: &[u8]) -> Result<String, WebhookError> {
+    Ok(format!(
+        "t={timestamp},v1={}",
+        signature(secret, timestamp, body)?
+    ))
+}
+
+/// Checks a [`SIGNATURE_HEADER`] value against the raw request body. Signatures older
+/// than `tolerance` seconds are rejected to limit replays.
+pub fn verify_signature(secret: &str, header: &str, body: &[u8], tolerance: u64) -> bool {
+    let mut timestamp = None;
+    let mut provided = None;
+    for part in header.split(',') {
+        match part.trim().split_once('=') {
+            Some(("t", value)) => timestamp = value.parse::<u64>().ok(),
+            Some(("v1", value)) => provided = Some(value),
+            _ => {}
+        }
+    }
+
+    let (Some(timestamp), Some(provided)) = (timestamp, provided) else {
+        return false;
+    };
+    if unix_now().abs_diff(timestamp) > tolerance {
+        return false;
+    }
+
+    let Ok(expected) = signature(secret, timestamp, body) else {
+        return false;
+    };
+    provided.as_bytes().ct_eq(expected.as_bytes()).into()
+}
+
+fn signature(secret: &str, timestamp: u64, body: &[u8]) -> Result<String, WebhookError> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).map_err(WebhookError::internal)?;
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    Ok(mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect())
+}
+
+/// Writes events to the outbox and delivers them in the background.
+///
+/// Events are only enqueued by the request, delivery happens in the task started with
+/// [`WebhookDispatcher::spawn`]. Failed deliveries are retried with exponential backoff,
+/// so receivers see every enqueued event at least once but possibly more than once.
+/// Enqueueing isn't part of the identity change: if the outbox write fails or the process
+/// stops in between, the change is kept and its event is lost.
+#[derive(Clone)]
+pub struct WebhookDispatcher {
+    endpoints: Vec<WebhookEndpoint>,
+    outbox: Arc<dyn WebhookOutbox>,
+    client: reqwest::Client,
+    max_attempts: u32,
+    base_backoff: u64,
+    max_backoff: u64,
+    poll_interval: Duration,
+    /// How long a claimed delivery stays hidden from other dispatchers, in seconds.
+    lease: u64,
+}
+
+impl WebhookDispatcher {
+    pub fn new(outbox: impl WebhookOutbox + 'static, endpoints: Vec<WebhookEndpoint>) -> Self {
+        Self {
+            endpoints,
+            outbox: Arc::new(outbox),
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(10))
+                .build()
+                .unwrap_or_default(),
+            max_attempts: 10,
+            base_backoff: 10,
+            max_backoff: 60 * 60,
+            poll_interval: Duration::from_secs(5),
+            lease: 60,
+        }
+    }
+
+    /// Attempts per delivery before it is marked as failed.
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    /// The first retry waits `base` seconds, every further one twice as long, up to `max`.
+    pub fn with_backoff(mut self, base: u64, max: u64) -> Self {
+        self.base_backoff = base;
+        self.max_backoff = max;
+        self
+    }
+
+    pub fn with_poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    /// Stores one delivery per subscribed endpoint.
+    pub async fn enqueue(&self, event: WebhookEvent) -> Result<(), WebhookError> {
+        for endpoint in self
+            .endpoints
+            .iter()
+            .filter(|endpoint| endpoint.subscribes_to(event.event_type))
+        {
+            self.outbox
+                .enqueue(WebhookDelivery {
+                    id: Uuid::new_v4().into(),
+                    url: endpoint.url.clone(),
+                    event: event.clone(),
+                    status: DeliveryStatus::Pending,
+                    attempts: 0,
+                    next_attempt_at: unix_now(),
+                    last_error: None,
+                })
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Delivers every delivery that is due and returns how many were attempted.
+    pub async fn dispatch_due(&self) -> Result<usize, WebhookError> {
+        let mut attempted = 0;
+        while let Some(delivery) = self
+            .outbox
+            .claim_due(unix_now(), unix_now() + self.lease)
+            .await?
+        {
+            self.attempt(delivery).await?;
+            attempted += 1;
+        }
+        Ok(attempted)
+    }
+
+    /// Runs [`WebhookDispatcher::dispatch_due`] every poll interval on the Tokio runtime.
+    /// Several processes may share one outbox, claimed deliveries are leased to one of them.
+    pub fn spawn(&self) -> JoinHandle<()> {
+        let dispatcher = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(dispatcher.poll_interval);
+            loop {
+                interval.tick().await;
+                if let Err(e) = dispatcher.dispatch_due().await {
+                    tracing::error!(error = %e, "failed to dispatch webhooks");
+                }
+            }
+        })
+    }
+
+    async fn attempt(&self, mut delivery: WebhookDelivery) -> Result<(), WebhookError> {
+        delivery.attempts += 1;
+
+        let result = match self
+            .endpoints
+            .iter()
+            .find(|endpoint| endpoint.url == delivery.url)
+        {
+            Some(endpoint) => self.send(endpoint, &delivery).await,
+            None => Err(String::from("endpoint is no longer configured")),
+        };
+
+        match result {
+            Ok(()) => {
+                delivery.status = DeliveryStatus::Delivered;
+                delivery.last_error = None;
+            }
+            Err(e) => {
+                tracing::warn!(
+                    url = %delivery.url,
+                    attempts = delivery.attempts,
+                    error = %e,
+                    "webhook delivery failed"
+                );
+                if delivery.attempts >= self.max_attempts {
+                    delivery.status = DeliveryStatus::Failed;
+                } else {
+                    delivery.next_attempt_at = unix_now() + self.backoff(delivery.attempts);
+                }
+                delivery.last_error = Some(e);
+            }
+        }
+
+        self.outbox.update(delivery).await
+    }
+
+    async fn send(
+        &self,
+        endpoint: &WebhookEndpoint,
+        delivery: &WebhookDelivery,
+    ) -> Result<(), String> {
+        let body = serde_json::to_vec(&delivery.event).map_err(|e| e.to_string())?;
+        let signature = sign(&endpoint.secret, unix_now(), &body).map_err(|e| e.to_string())?;
+        let response = self
+            .client
+            .post(&endpoint.url)
+            .header("Content-Type", "application/json")
+            .header(EVENT_HEADER, delivery.event.event_type.as_str())
+            .header(DELIVERY_HEADER, &delivery.id)
+            .header(SIGNATURE_HEADER, signature)
+            .body(body)
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+
+        if !response.status().is_success() {
+            return Err(format!("endpoint answered {}", response.status()));
+        }
+        Ok(())
+    }
+
+    fn backoff(&self, attempts: u32) -> u64 {
+        self.base_backoff
+            .saturating_mul(2u64.saturating_pow(attempts.saturating_sub(1)))
+            .min(self.max_backoff)
+    }
+}
+
+/// Keeps deliveries in process memory, meant for tests. Pending events are lost on restart.
+#[derive(Default)]
+pub struct InMemoryWebhookOutbox {
+    deliveries: Mutex<Vec<WebhookDelivery>>,
+}
+
+impl InMemoryWebhookOutbox {
+    pub fn deliveries(&self) -> Vec<WebhookDelivery> {
+        self.deliveries
+            .lock()
+            .map(|deliveries| deliveries.clone())
+            .unwrap_or_default()
+    }
+}
+
+#[async_trait]
+impl WebhookOutbox for InMemoryWebhookOutbox {
+    async fn enqueue(&self, delivery: WebhookDelivery) -> Result<(), WebhookError> {
+        self.deliveries
+            .lock()
+            .map_err(|e| WebhookError::internal(e.to_string()))?
+            .push(delivery);
+        Ok(())
+    }
+
+    async fn claim_due(
+        &self,
+        now: u64,
+        lease_until: u64,
+    ) -> Result<Option<WebhookDelivery>, WebhookError> {
+        let mut deliveries = self
+            .deliveries
+            .lock()
+            .map_err(|e| WebhookError::internal(e.to_string()))?;
+        Ok(deliveries
+            .iter_mut()
+            .filter(|delivery| {
+                delivery.status == DeliveryStatus::Pending && delivery.next_attempt_at <= now
+            })
+            .min_by_key(|delivery| delivery.next_attempt_at)
+            .map(|delivery| {
+                delivery.next_attempt_at = lease_until;
+                delivery.clone()
+            }))
+    }
+
+    async fn update(&self, delivery: WebhookDelivery) -> Result<(), WebhookError> {
+        let mut deliveries = self
+            .deliveries
+            .lock()
+            .map_err(|e| WebhookError::internal(e.to_string()))?;
+        if let Some(stored) = deliveries
+            .iter_mut()
+            .find(|stored| stored.id == delivery.id)
+        {
+            *stored = delivery;
+        }
+        Ok(())
+    }
+}
+
+/// Durable store of pending deliveries.
+#[async_trait]
+pub trait WebhookOutbox: Send + Sync {
+    async fn enqueue(&self, delivery: WebhookDelivery) -> Result<(), WebhookError>;
+    /// Atomically picks a pending delivery with `next_attempt_at <= now` and moves its
+    /// `next_attempt_at` to `lease_until`, so concurrent dispatchers don't pick it as well.
+    async fn claim_due(
+        &self,
+        now: u64,
+        lease_until: u64,
+    ) -> Result<Option<WebhookDelivery>, WebhookError>;
+    /// Replaces the stored delivery with the same id.
+    async fn update(&self, delivery: WebhookDelivery) -> Result<(), WebhookError>;
+}
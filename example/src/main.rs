@@ -1,22 +1,100 @@
-use actix_web::{App, HttpServer};
+use actix_web::{
+    App, HttpRequest, HttpResponse, HttpServer,
+    http::StatusCode,
+    web::{Bytes, post},
+};
+use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
-use std::str::FromStr;
-use toro_auth_core::{IntoPublic, ObjectId, provider::AuthProvider};
+use std::{str::FromStr, time::Duration};
+use toro_auth_core::{
+    IntoPublic, ObjectId,
+    hooks::{AuthHooks, HookError},
+    metrics::install_prometheus_recorder,
+    notifier::{Notification, Notifier, NotifierError},
+    password::PasswordPolicy,
+    provider::AuthProvider,
+    session::Session,
+    throttle::{InMemoryAttemptStore, LoginThrottle},
+    username::UsernamePolicy,
+    webhook::{SIGNATURE_HEADER, WebhookDispatcher, WebhookEndpoint, verify_signature},
+};
 use toro_auth_mongo::MongoBackend;
+use tracing_subscriber::EnvFilter;
 use uuid::Uuid;
 
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
-    let identity = AuthProvider::default_with_backend(
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .init();
+
+    let backend =
         MongoBackend::<DBUser>::from_url("mongodb://localhost:27017".into(), "example".into())
             .await
-            .unwrap(),
+            .unwrap();
+
+    let webhooks = WebhookDispatcher::new(
+        backend.clone(),
+        vec![WebhookEndpoint::new(
+            "http://localhost:8080/webhook-receiver".into(),
+            WEBHOOK_SECRET.into(),
+        )],
     );
+    webhooks.spawn();
+
+    let purge = backend.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(10 * 60));
+        loop {
+            interval.tick().await;
+            if let Err(e) = purge.purge_expired().await {
+                eprintln!("Failed to purge expired sessions: {e}");
+            }
+        }
+    });
 
-    HttpServer::new(move || App::new().configure(|cfg| identity.clone().configure(cfg)))
-        .bind(("127.0.0.1", 8080))?
-        .run()
-        .await
+    let identity = AuthProvider::builder(backend.clone())
+        .with_mfa("toro-auth-example".into())
+        .with_passkeys("localhost".into(), "http://localhost:8080".into())
+        .unwrap()
+        .with_magic_links(ConsoleNotifier)
+        .with_private_registration(ConsoleNotifier)
+        .with_login_throttle(LoginThrottle::new(InMemoryAttemptStore::default()))
+        .with_password_policy(PasswordPolicy::default())
+        .with_api_keys(vec!["deploy".into()])
+        .with_username_policy(UsernamePolicy::default())
+        .with_metrics(install_prometheus_recorder().unwrap())
+        // Demo only, real apps should keep roles in their identities.
+        .with_audit_log(backend, |user: &DBUser| user.username == "alice")
+        .with_hooks(ConsoleHooks)
+        .with_webhooks(webhooks)
+        .build();
+
+    HttpServer::new(move || {
+        App::new()
+            .configure(|cfg| identity.clone().configure(cfg))
+            .route("webhook-receiver", post().to(receive_webhook))
+    })
+    .bind(("127.0.0.1", 8080))?
+    .run()
+    .await
+}
+
+const WEBHOOK_SECRET: &str = "example-webhook-secret";
+
+/// Stands in for another service receiving the webhooks.
+async fn receive_webhook(req: HttpRequest, body: Bytes) -> HttpResponse {
+    let signature = req
+        .headers()
+        .get(SIGNATURE_HEADER)
+        .and_then(|signature| signature.to_str().ok())
+        .unwrap_or_default();
+    if !verify_signature(WEBHOOK_SECRET, signature, &body, 5 * 60) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    println!("Received webhook: {}", String::from_utf8_lossy(&body));
+    HttpResponse::NoContent().finish()
 }
 
 #[derive(Serialize, Clone)]
@@ -28,7 +106,6 @@ struct User {
 #[derive(Serialize, Deserialize, Clone)]
 struct DBUser {
     username: String,
-    password: String,
     id: Option<String>,
 }
 
@@ -44,6 +121,10 @@ impl ObjectId for DBUser {
     fn username(&self) -> String {
         self.username.clone()
     }
+
+    fn set_username(&mut self, username: String) {
+        self.username = username;
+    }
 }
 
 impl IntoPublic for DBUser {
@@ -55,3 +136,52 @@ impl IntoPublic for DBUser {
         }
     }
 }
+
+/// Prints notifications instead of emailing them.
+struct ConsoleNotifier;
+
+#[async_trait]
+impl Notifier<DBUser> for ConsoleNotifier {
+    async fn notify(
+        &self,
+        identity: DBUser,
+        notification: Notification,
+    ) -> Result<(), NotifierError> {
+        match notification {
+            Notification::MagicLink { token, .. } => println!(
+                "Login link for {}: http://localhost:8080/session/magic-link/{token}",
+                identity.username
+            ),
+            Notification::DuplicateRegistration => println!(
+                "Someone tried to register the existing username {}",
+                identity.username
+            ),
+        }
+        Ok(())
+    }
+}
+
+/// Logs sign ups and logins and keeps the `toro` prefix for the team.
+struct ConsoleHooks;
+
+#[async_trait]
+impl AuthHooks<DBUser> for ConsoleHooks {
+    async fn before_create(&self, identity: &DBUser) -> Result<(), HookError> {
+        if identity.username.starts_with("toro") {
+            return Err(HookError::new(
+                StatusCode::FORBIDDEN,
+                "reserved_prefix",
+                "Usernames starting with toro are reserved",
+            ));
+        }
+        Ok(())
+    }
+
+    async fn after_create(&self, identity: &DBUser) {
+        println!("Welcome, {}!", identity.username);
+    }
+
+    async fn after_login(&self, identity: &DBUser, _session: &Session<DBUser>) {
+        println!("{} logged in", identity.username);
+    }
+}
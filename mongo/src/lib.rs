@@ -1,145 +1,1649 @@
 use async_trait::async_trait;
 use futures::TryStreamExt;
-use mongodb::{Client, Collection, Database, bson::doc};
+use mongodb::{
+    Client, Collection, Database, IndexModel,
+    bson::{Document, doc, to_bson},
+    error::{
+        Error, ErrorKind, RETRYABLE_WRITE_ERROR, TRANSIENT_TRANSACTION_ERROR, WriteError,
+        WriteFailure,
+    },
+    options::{Collation, CollationStrength, IndexOptions, ReturnDocument},
+};
 use serde::{Deserialize, Serialize};
-use std::{marker::PhantomData, str::FromStr};
+use std::{
+    marker::PhantomData,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
 use toro_auth_core::{
     ObjectId,
+    api_key::{ApiKey, ApiKeyBackend, ApiKeyError},
+    audit::{AuditError, AuditEvent, AuditQuery, AuditSink},
+    credential::{Credential, CredentialBackend, CredentialError, CredentialKind},
+    crypto::{is_password_hash, verify_password},
+    external::{ExternalLoginBackend, ExternalLoginError, ExternalLoginState},
     identity::{IdentityBackend, IdentityError},
+    keys::{KeyError, KeyStore, StoredKey},
+    mfa::{MfaBackend, MfaError, TotpSecret},
+    oauth::{ClientBackend, Grant, GrantBackend, OAuthClient, OAuthError},
+    passkey::{PasskeyBackend, PasskeyCeremony, PasskeyError},
     session::{Session, SessionBackend, SessionError},
+    throttle::{AttemptStore, Attempts},
+    webhook::{DeliveryStatus, WebhookDelivery, WebhookError, WebhookOutbox},
 };
 use uuid::Uuid;
 
-#[derive(Debug)]
-pub enum MongoInitError {
-    FailedToConnect,
+#[derive(Debug)]
+pub enum MongoInitError {
+    FailedToConnect(Error),
+    FailedToCreateIndexes(Error),
+}
+
+impl std::fmt::Display for MongoInitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MongoInitError::FailedToConnect(e) => write!(f, "failed to connect: {e}"),
+            MongoInitError::FailedToCreateIndexes(e) => write!(f, "failed to create indexes: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for MongoInitError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MongoInitError::FailedToConnect(e) | MongoInitError::FailedToCreateIndexes(e) => {
+                Some(e)
+            }
+        }
+    }
+}
+
+/// Server error code for writes that violate a unique index.
+const DUPLICATE_KEY: i32 = 11000;
+
+/// Errors that may go away on retry, like lost connections or a replica set election.
+fn is_transient(e: &Error) -> bool {
+    e.contains_label(RETRYABLE_WRITE_ERROR)
+        || e.contains_label(TRANSIENT_TRANSACTION_ERROR)
+        || matches!(
+            e.kind.as_ref(),
+            ErrorKind::Io(_)
+                | ErrorKind::ServerSelection { .. }
+                | ErrorKind::ConnectionPoolCleared { .. }
+        )
+}
+
+fn identity_error(e: Error) -> IdentityError {
+    match is_transient(&e) {
+        true => IdentityError::unavailable(e),
+        false => IdentityError::internal(e),
+    }
+}
+
+fn session_error(e: Error) -> SessionError {
+    match is_transient(&e) {
+        true => SessionError::unavailable(e),
+        false => SessionError::internal(e),
+    }
+}
+
+fn mfa_error(e: Error) -> MfaError {
+    match is_transient(&e) {
+        true => MfaError::unavailable(e),
+        false => MfaError::internal(e),
+    }
+}
+
+fn passkey_error(e: Error) -> PasskeyError {
+    match is_transient(&e) {
+        true => PasskeyError::unavailable(e),
+        false => PasskeyError::internal(e),
+    }
+}
+
+fn credential_error(e: Error) -> CredentialError {
+    match is_transient(&e) {
+        true => CredentialError::unavailable(e),
+        false => CredentialError::internal(e),
+    }
+}
+
+fn api_key_error(e: Error) -> ApiKeyError {
+    match is_transient(&e) {
+        true => ApiKeyError::unavailable(e),
+        false => ApiKeyError::internal(e),
+    }
+}
+
+fn audit_error(e: Error) -> AuditError {
+    match is_transient(&e) {
+        true => AuditError::unavailable(e),
+        false => AuditError::internal(e),
+    }
+}
+
+fn webhook_error(e: Error) -> WebhookError {
+    match is_transient(&e) {
+        true => WebhookError::unavailable(e),
+        false => WebhookError::internal(e),
+    }
+}
+
+fn key_error(e: Error) -> KeyError {
+    match is_transient(&e) {
+        true => KeyError::unavailable(e),
+        false => KeyError::internal(e),
+    }
+}
+
+fn oauth_error(e: Error) -> OAuthError {
+    match is_transient(&e) {
+        true => OAuthError::unavailable(e),
+        false => OAuthError::internal(e),
+    }
+}
+
+fn external_login_error(e: Error) -> ExternalLoginError {
+    match is_transient(&e) {
+        true => ExternalLoginError::unavailable(e),
+        false => ExternalLoginError::internal(e),
+    }
+}
+
+fn is_duplicate_key(e: &Error) -> bool {
+    matches!(
+        e.kind.as_ref(),
+        ErrorKind::Write(WriteFailure::WriteError(WriteError {
+            code: DUPLICATE_KEY,
+            ..
+        }))
+    )
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct RecoveryCodes {
+    user_id: String,
+    code_hashes: Vec<String>,
+}
+
+#[derive(Clone)]
+pub struct MongoBackend<
+    T: ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
+> {
+    _mapper: PhantomData<T>,
+    identity_db: Collection<T>,
+    session_db: Collection<Session<T>>,
+    mfa_db: Collection<TotpSecret>,
+    recovery_code_db: Collection<RecoveryCodes>,
+    credential_db: Collection<Credential>,
+    api_key_db: Collection<ApiKey>,
+    passkey_ceremony_db: Collection<PasskeyCeremony>,
+    attempt_db: Collection<Attempts>,
+    audit_db: Collection<AuditEvent>,
+    webhook_outbox_db: Collection<WebhookDelivery>,
+    signing_key_db: Collection<StoredKey>,
+    oauth_client_db: Collection<OAuthClient>,
+    oauth_grant_db: Collection<Grant>,
+    external_login_state_db: Collection<ExternalLoginState>,
+}
+
+impl<T: ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static>
+    MongoBackend<T>
+{
+    pub fn new(db: Database) -> Self {
+        Self {
+            _mapper: PhantomData,
+            identity_db: db.collection("identity"),
+            session_db: db.collection("session"),
+            mfa_db: db.collection("mfa"),
+            recovery_code_db: db.collection("mfa_recovery"),
+            credential_db: db.collection("credential"),
+            api_key_db: db.collection("api_key"),
+            passkey_ceremony_db: db.collection("passkey_ceremony"),
+            attempt_db: db.collection("login_attempt"),
+            audit_db: db.collection("audit_log"),
+            webhook_outbox_db: db.collection("webhook_outbox"),
+            signing_key_db: db.collection("signing_key"),
+            oauth_client_db: db.collection("oauth_client"),
+            oauth_grant_db: db.collection("oauth_grant"),
+            external_login_state_db: db.collection("external_login_state"),
+        }
+    }
+
+    #[tracing::instrument(name = "mongo.from_url", skip_all, err(Display))]
+    pub async fn from_url(url: String, db_name: String) -> Result<Self, MongoInitError> {
+        let client = Client::with_uri_str(url)
+            .await
+            .map_err(MongoInitError::FailedToConnect)?;
+        let db = client.database(&db_name);
+        let backend = Self::new(db);
+        backend.create_indexes().await?;
+        Ok(backend)
+    }
+
+    /// Creates the indexes username lookups, session, credential and API key lookups, audit
+    /// log queries, the webhook outbox, the signing key store, OAuth lookups and
+    /// [`MongoBackend::purge_expired`] rely on.
+    /// Usernames are stored in the form produced by the configured `UsernamePolicy`, so the
+    /// unique index covers normalized names and fails if the collection already contains
+    /// duplicates.
+    #[tracing::instrument(name = "mongo.create_indexes", skip_all, err(Display))]
+    pub async fn create_indexes(&self) -> Result<(), MongoInitError> {
+        self.identity_db
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! { "username": 1 })
+                    .options(IndexOptions::builder().unique(true).build())
+                    .build(),
+            )
+            .await
+            .map_err(MongoInitError::FailedToCreateIndexes)?;
+        self.session_db
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! { "id": 1 })
+                    .options(IndexOptions::builder().unique(true).build())
+                    .build(),
+            )
+            .await
+            .map_err(MongoInitError::FailedToCreateIndexes)?;
+        self.session_db
+            .create_index(IndexModel::builder().keys(doc! { "user_id": 1 }).build())
+            .await
+            .map_err(MongoInitError::FailedToCreateIndexes)?;
+        self.session_db
+            .create_index(IndexModel::builder().keys(doc! { "family": 1 }).build())
+            .await
+            .map_err(MongoInitError::FailedToCreateIndexes)?;
+        self.credential_db
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! { "kind": 1, "issuer": 1, "identifier": 1 })
+                    .options(IndexOptions::builder().unique(true).build())
+                    .build(),
+            )
+            .await
+            .map_err(MongoInitError::FailedToCreateIndexes)?;
+        self.credential_db
+            .create_index(IndexModel::builder().keys(doc! { "user_id": 1 }).build())
+            .await
+            .map_err(MongoInitError::FailedToCreateIndexes)?;
+        self.api_key_db
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! { "key_hash": 1 })
+                    .options(IndexOptions::builder().unique(true).build())
+                    .build(),
+            )
+            .await
+            .map_err(MongoInitError::FailedToCreateIndexes)?;
+        self.api_key_db
+            .create_index(IndexModel::builder().keys(doc! { "user_id": 1 }).build())
+            .await
+            .map_err(MongoInitError::FailedToCreateIndexes)?;
+        self.audit_db
+            .create_index(IndexModel::builder().keys(doc! { "timestamp": -1 }).build())
+            .await
+            .map_err(MongoInitError::FailedToCreateIndexes)?;
+        self.webhook_outbox_db
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! { "status": 1, "next_attempt_at": 1 })
+                    .build(),
+            )
+            .await
+            .map_err(MongoInitError::FailedToCreateIndexes)?;
+        self.signing_key_db
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! { "kid": 1 })
+                    .options(IndexOptions::builder().unique(true).build())
+                    .build(),
+            )
+            .await
+            .map_err(MongoInitError::FailedToCreateIndexes)?;
+        self.oauth_client_db
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! { "client_id": 1 })
+                    .options(IndexOptions::builder().unique(true).build())
+                    .build(),
+            )
+            .await
+            .map_err(MongoInitError::FailedToCreateIndexes)?;
+        self.oauth_grant_db
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! { "id": 1 })
+                    .options(IndexOptions::builder().unique(true).build())
+                    .build(),
+            )
+            .await
+            .map_err(MongoInitError::FailedToCreateIndexes)?;
+        // Expiry timestamps are integers, which TTL indexes ignore, so expired documents are
+        // removed by `purge_expired` instead.
+        let expires_at = || IndexModel::builder().keys(doc! { "expires_at": 1 }).build();
+        self.session_db
+            .create_index(expires_at())
+            .await
+            .map_err(MongoInitError::FailedToCreateIndexes)?;
+        self.oauth_grant_db
+            .create_index(expires_at())
+            .await
+            .map_err(MongoInitError::FailedToCreateIndexes)?;
+        self.external_login_state_db
+            .create_index(expires_at())
+            .await
+            .map_err(MongoInitError::FailedToCreateIndexes)?;
+        self.passkey_ceremony_db
+            .create_index(expires_at())
+            .await
+            .map_err(MongoInitError::FailedToCreateIndexes)?;
+        Ok(())
+    }
+
+    /// Removes expired sessions, including pending second factors, magic links and rotated
+    /// refresh tokens, expired OAuth grants, external logins in progress and passkey
+    /// ceremonies. None of them are accepted once expired, but they stay stored until
+    /// purged, so call this periodically. Returns the number of removed documents.
+    #[tracing::instrument(name = "mongo.purge_expired", skip_all, err(Display))]
+    pub async fn purge_expired(&self) -> Result<u64, Error> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |now| now.as_secs());
+        let expired = doc! {
+            "expires_at": {
+                "$lte": i64::try_from(now).unwrap_or(i64::MAX)
+            }
+        };
+
+        let mut deleted = 0;
+        deleted += self
+            .session_db
+            .delete_many(expired.clone())
+            .await?
+            .deleted_count;
+        deleted += self
+            .oauth_grant_db
+            .delete_many(expired.clone())
+            .await?
+            .deleted_count;
+        deleted += self
+            .external_login_state_db
+            .delete_many(expired.clone())
+            .await?
+            .deleted_count;
+        deleted += self
+            .passkey_ceremony_db
+            .delete_many(expired)
+            .await?
+            .deleted_count;
+
+        Ok(deleted)
+    }
+
+    #[tracing::instrument(
+        name = "mongo.search_identity",
+        level = "debug",
+        skip_all,
+        err(Display, level = "debug")
+    )]
+    pub async fn search_identity(&self, username: String) -> Result<Vec<T>, IdentityError> {
+        let mut res = match self
+            .identity_db
+            .find(doc! {
+                "name": {
+                    "$regex": username,
+                    "$options": "i"
+                }
+            })
+            .await
+        {
+            Ok(res) => res,
+            Err(e) => return Err(identity_error(e)),
+        };
+
+        let mut identities = Vec::new();
+        while let Some(identity) = res.try_next().await.map_err(identity_error)? {
+            identities.push(identity);
+        }
+
+        Ok(identities)
+    }
+}
+
+impl<T: ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static>
+    MongoBackend<T>
+{
+    /// The `password` field identity documents had before passwords became credentials.
+    async fn legacy_password(&self, user_id: &str) -> Result<Option<String>, SessionError> {
+        let document = self
+            .identity_db
+            .clone_with_type::<Document>()
+            .find_one(doc! {
+                "id": {
+                    "$eq": user_id
+                }
+            })
+            .await
+            .map_err(session_error)?;
+
+        Ok(document.and_then(|document| document.get_str("password").ok().map(String::from)))
+    }
+
+    /// Stores a password that was just verified as argon2id hash in the password credential
+    /// and removes it from the identity document. Failures are logged, the password is
+    /// migrated again on the next login.
+    async fn migrate_password(
+        &self,
+        user_id: String,
+        credential: Option<Credential>,
+        password: &str,
+    ) {
+        let credential = match credential {
+            Some(credential) => credential.with_password(password),
+            None => Credential::password(user_id.clone(), password),
+        };
+        let saved = match credential {
+            Ok(credential) => self.save_credential(credential).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = saved {
+            tracing::error!(error = %e, "failed to migrate password");
+            return;
+        }
+
+        if let Err(e) = self
+            .identity_db
+            .update_one(
+                doc! {
+                    "id": {
+                        "$eq": user_id
+                    }
+                },
+                doc! {
+                    "$unset": {
+                        "password": ""
+                    }
+                },
+            )
+            .await
+        {
+            tracing::error!(error = %e, "failed to remove migrated password from identity");
+        }
+    }
+}
+
+#[async_trait]
+impl<T: ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static>
+    SessionBackend<T> for MongoBackend<T>
+{
+    #[tracing::instrument(
+        name = "mongo.verify_login",
+        level = "debug",
+        skip_all,
+        err(Display, level = "debug")
+    )]
+    async fn verify_login(&self, username: String, password: String) -> Result<T, SessionError> {
+        // Looked up by username only and checked against the argon2id hash, unknown usernames
+        // and identities without a password are checked against a dummy hash so they take as
+        // long as a wrong password.
+        let identity = self
+            .identity_db
+            .find_one(doc! {
+                "username": {
+                    "$eq": username
+                }
+            })
+            .await
+            .map_err(session_error)?;
+
+        let user_id = identity
+            .as_ref()
+            .and_then(|identity| identity.id())
+            .map(|user_id| user_id.to_string());
+        let credential = match &user_id {
+            Some(user_id) => self
+                .credential_db
+                .find_one(doc! {
+                    "kind": {
+                        "$eq": CredentialKind::Password.as_str()
+                    },
+                    "identifier": {
+                        "$eq": user_id
+                    }
+                })
+                .await
+                .map_err(session_error)?,
+            None => None,
+        };
+
+        // Identities created before credentials existed still carry their password.
+        let stored_password = match (&credential, &user_id) {
+            (Some(credential), _) => credential.secret.clone(),
+            (None, Some(user_id)) => self.legacy_password(user_id).await?,
+            (None, None) => None,
+        };
+        if !verify_password(&password, stored_password.as_deref()) {
+            return Err(SessionError::InvalidLogin);
+        }
+
+        if let (Some(user_id), Some(stored_password)) = (user_id, stored_password)
+            && (credential.is_none() || !is_password_hash(&stored_password))
+        {
+            self.migrate_password(user_id, credential, &password).await;
+        }
+
+        identity.ok_or(SessionError::InvalidLogin)
+    }
+
+    #[tracing::instrument(
+        name = "mongo.delete_sessions",
+        level = "debug",
+        skip_all,
+        err(Display, level = "debug")
+    )]
+    async fn delete_sessions(
+        &self,
+        user_id: String,
+        keep: Option<String>,
+    ) -> Result<(), SessionError> {
+        let mut filter = doc! {
+            "user_id": {
+                "$eq": user_id
+            }
+        };
+        if let Some(keep) = keep {
+            filter.insert("id", doc! { "$ne": keep.clone() });
+            filter.insert("family", doc! { "$ne": keep });
+        }
+
+        self.session_db
+            .delete_many(filter)
+            .await
+            .map_err(session_error)?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(
+        name = "mongo.create_session",
+        level = "debug",
+        skip_all,
+        err(Display, level = "debug")
+    )]
+    async fn create_session(&self, session: Session<T>) -> Result<(), SessionError> {
+        self.session_db
+            .insert_one(session)
+            .await
+            .map_err(session_error)?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(
+        name = "mongo.get_session",
+        level = "debug",
+        skip_all,
+        err(Display, level = "debug")
+    )]
+    async fn get_session(&self, session_id: String) -> Result<Option<Session<T>>, SessionError> {
+        self.session_db
+            .find_one(doc! {
+                "id": {
+                    "$eq": session_id
+                }
+            })
+            .await
+            .map_err(session_error)
+    }
+
+    #[tracing::instrument(
+        name = "mongo.delete_session",
+        level = "debug",
+        skip_all,
+        err(Display, level = "debug")
+    )]
+    async fn delete_session(&self, session_id: String) -> Result<bool, SessionError> {
+        let res = self
+            .session_db
+            .delete_one(doc! {
+                "id": {
+                    "$eq": session_id
+                }
+            })
+            .await
+            .map_err(session_error)?;
+
+        Ok(res.deleted_count > 0)
+    }
+
+    #[tracing::instrument(
+        name = "mongo.get_identity",
+        level = "debug",
+        skip_all,
+        err(Display, level = "debug")
+    )]
+    async fn get_identity(&self, user_id: String) -> Result<T, SessionError> {
+        self.get_by_id(user_id).await.map_err(SessionError::from)
+    }
+}
+
+#[async_trait]
+impl<T: ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static>
+    MfaBackend<T> for MongoBackend<T>
+{
+    #[tracing::instrument(
+        name = "mongo.get_totp",
+        level = "debug",
+        skip_all,
+        err(Display, level = "debug")
+    )]
+    async fn get_totp(&self, user_id: String) -> Result<Option<TotpSecret>, MfaError> {
+        self.mfa_db
+            .find_one(doc! {
+                "user_id": {
+                    "$eq": user_id
+                }
+            })
+            .await
+            .map_err(mfa_error)
+    }
+
+    #[tracing::instrument(
+        name = "mongo.save_totp",
+        level = "debug",
+        skip_all,
+        err(Display, level = "debug")
+    )]
+    async fn save_totp(&self, totp: TotpSecret) -> Result<(), MfaError> {
+        self.mfa_db
+            .replace_one(
+                doc! {
+                    "user_id": {
+                        "$eq": totp.user_id.clone()
+                    }
+                },
+                totp,
+            )
+            .upsert(true)
+            .await
+            .map_err(mfa_error)?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(
+        name = "mongo.delete_totp",
+        level = "debug",
+        skip_all,
+        err(Display, level = "debug")
+    )]
+    async fn delete_totp(&self, user_id: String) -> Result<(), MfaError> {
+        let res = self
+            .mfa_db
+            .delete_one(doc! {
+                "user_id": {
+                    "$eq": user_id
+                }
+            })
+            .await
+            .map_err(mfa_error)?;
+
+        match res.deleted_count {
+            0 => Err(MfaError::NotEnrolled),
+            _ => Ok(()),
+        }
+    }
+
+    #[tracing::instrument(
+        name = "mongo.get_recovery_codes",
+        level = "debug",
+        skip_all,
+        err(Display, level = "debug")
+    )]
+    async fn get_recovery_codes(&self, user_id: String) -> Result<Vec<String>, MfaError> {
+        let res = self
+            .recovery_code_db
+            .find_one(doc! {
+                "user_id": {
+                    "$eq": user_id
+                }
+            })
+            .await
+            .map_err(mfa_error)?;
+
+        Ok(res
+            .map(|recovery_codes| recovery_codes.code_hashes)
+            .unwrap_or_default())
+    }
+
+    #[tracing::instrument(
+        name = "mongo.save_recovery_codes",
+        level = "debug",
+        skip_all,
+        err(Display, level = "debug")
+    )]
+    async fn save_recovery_codes(
+        &self,
+        user_id: String,
+        code_hashes: Vec<String>,
+    ) -> Result<(), MfaError> {
+        self.recovery_code_db
+            .replace_one(
+                doc! {
+                    "user_id": {
+                        "$eq": user_id.clone()
+                    }
+                },
+                RecoveryCodes {
+                    user_id,
+                    code_hashes,
+                },
+            )
+            .upsert(true)
+            .await
+            .map_err(mfa_error)?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(
+        name = "mongo.consume_recovery_code",
+        level = "debug",
+        skip_all,
+        err(Display, level = "debug")
+    )]
+    async fn consume_recovery_code(
+        &self,
+        user_id: String,
+        code_hash: String,
+    ) -> Result<bool, MfaError> {
+        let res = self
+            .recovery_code_db
+            .update_one(
+                doc! {
+                    "user_id": {
+                        "$eq": user_id
+                    },
+                    "code_hashes": code_hash.clone()
+                },
+                doc! {
+                    "$pull": {
+                        "code_hashes": code_hash
+                    }
+                },
+            )
+            .await
+            .map_err(mfa_error)?;
+
+        Ok(res.modified_count > 0)
+    }
+
+    #[tracing::instrument(
+        name = "mongo.delete_mfa",
+        level = "debug",
+        skip_all,
+        err(Display, level = "debug")
+    )]
+    async fn delete_mfa(&self, user_id: String) -> Result<(), MfaError> {
+        self.mfa_db
+            .delete_many(doc! {
+                "user_id": {
+                    "$eq": user_id.clone()
+                }
+            })
+            .await
+            .map_err(mfa_error)?;
+        self.recovery_code_db
+            .delete_many(doc! {
+                "user_id": {
+                    "$eq": user_id
+                }
+            })
+            .await
+            .map_err(mfa_error)?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<T: ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static>
+    PasskeyBackend<T> for MongoBackend<T>
+{
+    #[tracing::instrument(
+        name = "mongo.save_ceremony",
+        level = "debug",
+        skip_all,
+        err(Display, level = "debug")
+    )]
+    async fn save_ceremony(&self, ceremony: PasskeyCeremony) -> Result<(), PasskeyError> {
+        self.passkey_ceremony_db
+            .insert_one(ceremony)
+            .await
+            .map_err(passkey_error)?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(
+        name = "mongo.take_ceremony",
+        level = "debug",
+        skip_all,
+        err(Display, level = "debug")
+    )]
+    async fn take_ceremony(
+        &self,
+        ceremony_id: String,
+    ) -> Result<Option<PasskeyCeremony>, PasskeyError> {
+        self.passkey_ceremony_db
+            .find_one_and_delete(doc! {
+                "id": {
+                    "$eq": ceremony_id
+                }
+            })
+            .await
+            .map_err(passkey_error)
+    }
+}
+
+#[async_trait]
+impl<T: ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static>
+    CredentialBackend for MongoBackend<T>
+{
+    #[tracing::instrument(
+        name = "mongo.get_credentials",
+        level = "debug",
+        skip_all,
+        err(Display, level = "debug")
+    )]
+    async fn get_credentials(&self, user_id: String) -> Result<Vec<Credential>, CredentialError> {
+        let mut res = self
+            .credential_db
+            .find(doc! {
+                "user_id": {
+                    "$eq": user_id
+                }
+            })
+            .await
+            .map_err(credential_error)?;
+
+        let mut credentials = Vec::new();
+        while let Some(credential) = res.try_next().await.map_err(credential_error)? {
+            credentials.push(credential);
+        }
+
+        Ok(credentials)
+    }
+
+    #[tracing::instrument(
+        name = "mongo.find_credential",
+        level = "debug",
+        skip_all,
+        err(Display, level = "debug")
+    )]
+    async fn find_credential(
+        &self,
+        kind: CredentialKind,
+        issuer: Option<String>,
+        identifier: String,
+    ) -> Result<Option<Credential>, CredentialError> {
+        self.credential_db
+            .find_one(doc! {
+                "kind": {
+                    "$eq": kind.as_str()
+                },
+                "issuer": {
+                    "$eq": issuer
+                },
+                "identifier": {
+                    "$eq": identifier
+                }
+            })
+            .await
+            .map_err(credential_error)
+    }
+
+    #[tracing::instrument(
+        name = "mongo.save_credential",
+        level = "debug",
+        skip_all,
+        err(Display, level = "debug")
+    )]
+    async fn save_credential(&self, credential: Credential) -> Result<(), CredentialError> {
+        self.credential_db
+            .replace_one(
+                doc! {
+                    "id": {
+                        "$eq": credential.id.clone()
+                    }
+                },
+                credential,
+            )
+            .upsert(true)
+            .await
+            .map_err(|e| match is_duplicate_key(&e) {
+                // The unique index on kind, issuer and identifier.
+                true => CredentialError::AlreadyRegistered,
+                false => credential_error(e),
+            })?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(
+        name = "mongo.delete_credential_unless_last",
+        level = "debug",
+        skip_all,
+        err(Display, level = "debug")
+    )]
+    async fn delete_credential_unless_last(
+        &self,
+        user_id: String,
+        credential_id: String,
+    ) -> Result<bool, CredentialError> {
+        // Without a transaction the check can't happen before the delete: remove the
+        // credential first and put it back if none is left. Concurrent removals of the last
+        // two credentials then restore at least one of them instead of both succeeding.
+        let Some(credential) = self
+            .credential_db
+            .find_one_and_delete(doc! {
+                "id": {
+                    "$eq": credential_id
+                },
+                "user_id": {
+                    "$eq": user_id.clone()
+                }
+            })
+            .await
+            .map_err(credential_error)?
+        else {
+            return Ok(false);
+        };
+
+        let remaining = self
+            .credential_db
+            .count_documents(doc! {
+                "user_id": {
+                    "$eq": user_id
+                }
+            })
+            .await
+            .map_err(credential_error)?;
+        if remaining == 0 {
+            self.credential_db
+                .insert_one(credential)
+                .await
+                .map_err(credential_error)?;
+            return Err(CredentialError::LastCredential);
+        }
+
+        Ok(true)
+    }
+
+    #[tracing::instrument(
+        name = "mongo.delete_credentials",
+        level = "debug",
+        skip_all,
+        err(Display, level = "debug")
+    )]
+    async fn delete_credentials(&self, user_id: String) -> Result<(), CredentialError> {
+        self.credential_db
+            .delete_many(doc! {
+                "user_id": {
+                    "$eq": user_id
+                }
+            })
+            .await
+            .map_err(credential_error)?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<T: ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static>
+    ApiKeyBackend for MongoBackend<T>
+{
+    #[tracing::instrument(
+        name = "mongo.save_api_key",
+        level = "debug",
+        skip_all,
+        err(Display, level = "debug")
+    )]
+    async fn save_api_key(&self, api_key: ApiKey) -> Result<(), ApiKeyError> {
+        self.api_key_db
+            .insert_one(api_key)
+            .await
+            .map_err(api_key_error)?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(
+        name = "mongo.get_api_keys",
+        level = "debug",
+        skip_all,
+        err(Display, level = "debug")
+    )]
+    async fn get_api_keys(&self, user_id: String) -> Result<Vec<ApiKey>, ApiKeyError> {
+        let mut res = self
+            .api_key_db
+            .find(doc! {
+                "user_id": {
+                    "$eq": user_id
+                }
+            })
+            .await
+            .map_err(api_key_error)?;
+
+        let mut api_keys = Vec::new();
+        while let Some(api_key) = res.try_next().await.map_err(api_key_error)? {
+            api_keys.push(api_key);
+        }
+
+        Ok(api_keys)
+    }
+
+    #[tracing::instrument(
+        name = "mongo.get_api_key_by_hash",
+        level = "debug",
+        skip_all,
+        err(Display, level = "debug")
+    )]
+    async fn get_api_key_by_hash(&self, key_hash: String) -> Result<Option<ApiKey>, ApiKeyError> {
+        self.api_key_db
+            .find_one(doc! {
+                "key_hash": {
+                    "$eq": key_hash
+                }
+            })
+            .await
+            .map_err(api_key_error)
+    }
+
+    #[tracing::instrument(
+        name = "mongo.touch_api_key",
+        level = "debug",
+        skip_all,
+        err(Display, level = "debug")
+    )]
+    async fn touch_api_key(&self, id: String, last_used_at: u64) -> Result<(), ApiKeyError> {
+        self.api_key_db
+            .update_one(
+                doc! {
+                    "id": {
+                        "$eq": id
+                    }
+                },
+                doc! {
+                    "$set": {
+                        "last_used_at": i64::try_from(last_used_at).unwrap_or(i64::MAX)
+                    }
+                },
+            )
+            .await
+            .map_err(api_key_error)?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(
+        name = "mongo.delete_api_key",
+        level = "debug",
+        skip_all,
+        err(Display, level = "debug")
+    )]
+    async fn delete_api_key(&self, user_id: String, id: String) -> Result<bool, ApiKeyError> {
+        let res = self
+            .api_key_db
+            .delete_one(doc! {
+                "id": {
+                    "$eq": id
+                },
+                "user_id": {
+                    "$eq": user_id
+                }
+            })
+            .await
+            .map_err(api_key_error)?;
+
+        Ok(res.deleted_count > 0)
+    }
+
+    #[tracing::instrument(
+        name = "mongo.delete_api_keys",
+        level = "debug",
+        skip_all,
+        err(Display, level = "debug")
+    )]
+    async fn delete_api_keys(&self, user_id: String) -> Result<(), ApiKeyError> {
+        self.api_key_db
+            .delete_many(doc! {
+                "user_id": {
+                    "$eq": user_id
+                }
+            })
+            .await
+            .map_err(api_key_error)?;
+
+        Ok(())
+    }
 }
 
-#[derive(Clone)]
-pub struct MongoBackend<
-    T: ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static,
-> {
-    _mapper: PhantomData<T>,
-    identity_db: Collection<T>,
-    session_db: Collection<Session<T>>,
+#[async_trait]
+impl<T: ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static>
+    AttemptStore for MongoBackend<T>
+{
+    #[tracing::instrument(
+        name = "mongo.get_attempts",
+        level = "debug",
+        skip_all,
+        err(Display, level = "debug")
+    )]
+    async fn get_attempts(&self, key: String) -> Result<Option<Attempts>, SessionError> {
+        self.attempt_db
+            .find_one(doc! {
+                "key": {
+                    "$eq": key
+                }
+            })
+            .await
+            .map_err(session_error)
+    }
+
+    #[tracing::instrument(
+        name = "mongo.record_failure",
+        level = "debug",
+        skip_all,
+        err(Display, level = "debug")
+    )]
+    async fn record_failure(
+        &self,
+        key: String,
+        now: u64,
+        reset_before: u64,
+    ) -> Result<Attempts, SessionError> {
+        // Single pipeline update, so concurrent failures can't overwrite each other.
+        let res = self
+            .attempt_db
+            .find_one_and_update(
+                doc! {
+                    "key": {
+                        "$eq": key.clone()
+                    }
+                },
+                vec![doc! {
+                    "$set": {
+                        "key": key,
+                        "failures": {
+                            "$cond": [
+                                { "$gte": ["$last_failure", reset_before as i64] },
+                                { "$add": ["$failures", 1] },
+                                1
+                            ]
+                        },
+                        "last_failure": now as i64
+                    }
+                }],
+            )
+            .upsert(true)
+            .return_document(ReturnDocument::After)
+            .await
+            .map_err(session_error)?;
+
+        res.ok_or_else(|| SessionError::internal("upsert returned no document"))
+    }
+
+    #[tracing::instrument(
+        name = "mongo.clear_attempts",
+        level = "debug",
+        skip_all,
+        err(Display, level = "debug")
+    )]
+    async fn clear_attempts(&self, key: String) -> Result<(), SessionError> {
+        self.attempt_db
+            .delete_one(doc! {
+                "key": {
+                    "$eq": key
+                }
+            })
+            .await
+            .map_err(session_error)?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<T: ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static> AuditSink
+    for MongoBackend<T>
+{
+    #[tracing::instrument(
+        name = "mongo.record_audit_event",
+        level = "debug",
+        skip_all,
+        err(Display, level = "debug")
+    )]
+    async fn record(&self, event: AuditEvent) -> Result<(), AuditError> {
+        self.audit_db.insert_one(event).await.map_err(audit_error)?;
+        Ok(())
+    }
+
+    #[tracing::instrument(
+        name = "mongo.query_audit_log",
+        level = "debug",
+        skip_all,
+        err(Display, level = "debug")
+    )]
+    async fn query(&self, query: AuditQuery) -> Result<Vec<AuditEvent>, AuditError> {
+        let mut filter = Document::new();
+        if let Some(action) = query.action {
+            filter.insert(
+                "action",
+                doc! { "$eq": to_bson(&action).map_err(AuditError::internal)? },
+            );
+        }
+        if let Some(outcome) = query.outcome {
+            filter.insert(
+                "outcome",
+                doc! { "$eq": to_bson(&outcome).map_err(AuditError::internal)? },
+            );
+        }
+        if let Some(actor) = &query.actor {
+            filter.insert("actor", doc! { "$eq": actor });
+        }
+        if let Some(target) = &query.target {
+            filter.insert("target", doc! { "$eq": target });
+        }
+        let mut timestamp = Document::new();
+        if let Some(since) = query.since {
+            timestamp.insert("$gte", i64::try_from(since).unwrap_or(i64::MAX));
+        }
+        if let Some(until) = query.until {
+            timestamp.insert("$lt", i64::try_from(until).unwrap_or(i64::MAX));
+        }
+        if !timestamp.is_empty() {
+            filter.insert("timestamp", timestamp);
+        }
+
+        let limit = i64::try_from(query.limit()).unwrap_or(i64::MAX);
+        let mut res = self
+            .audit_db
+            .find(filter)
+            .sort(doc! { "timestamp": -1 })
+            .limit(limit)
+            .await
+            .map_err(audit_error)?;
+
+        let mut events = Vec::new();
+        while let Some(event) = res.try_next().await.map_err(audit_error)? {
+            events.push(event);
+        }
+
+        Ok(events)
+    }
 }
 
+#[async_trait]
 impl<T: ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static>
-    MongoBackend<T>
+    WebhookOutbox for MongoBackend<T>
 {
-    pub fn new(db: Database) -> Self {
-        Self {
-            _mapper: PhantomData,
-            identity_db: db.collection("identity"),
-            session_db: db.collection("session"),
+    #[tracing::instrument(
+        name = "mongo.enqueue_webhook",
+        level = "debug",
+        skip_all,
+        err(Display, level = "debug")
+    )]
+    async fn enqueue(&self, delivery: WebhookDelivery) -> Result<(), WebhookError> {
+        self.webhook_outbox_db
+            .insert_one(delivery)
+            .await
+            .map_err(webhook_error)?;
+        Ok(())
+    }
+
+    #[tracing::instrument(
+        name = "mongo.claim_webhook",
+        level = "debug",
+        skip_all,
+        err(Display, level = "debug")
+    )]
+    async fn claim_due(
+        &self,
+        now: u64,
+        lease_until: u64,
+    ) -> Result<Option<WebhookDelivery>, WebhookError> {
+        self.webhook_outbox_db
+            .find_one_and_update(
+                doc! {
+                    "status": {
+                        "$eq": to_bson(&DeliveryStatus::Pending).map_err(WebhookError::internal)?
+                    },
+                    "next_attempt_at": {
+                        "$lte": i64::try_from(now).unwrap_or(i64::MAX)
+                    }
+                },
+                doc! {
+                    "$set": {
+                        "next_attempt_at": i64::try_from(lease_until).unwrap_or(i64::MAX)
+                    }
+                },
+            )
+            .sort(doc! { "next_attempt_at": 1 })
+            .return_document(ReturnDocument::After)
+            .await
+            .map_err(webhook_error)
+    }
+
+    #[tracing::instrument(
+        name = "mongo.update_webhook",
+        level = "debug",
+        skip_all,
+        err(Display, level = "debug")
+    )]
+    async fn update(&self, delivery: WebhookDelivery) -> Result<(), WebhookError> {
+        self.webhook_outbox_db
+            .replace_one(
+                doc! {
+                    "id": {
+                        "$eq": delivery.id.clone()
+                    }
+                },
+                delivery,
+            )
+            .await
+            .map_err(webhook_error)?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<T: ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static> KeyStore
+    for MongoBackend<T>
+{
+    #[tracing::instrument(
+        name = "mongo.get_signing_keys",
+        level = "debug",
+        skip_all,
+        err(Display, level = "debug")
+    )]
+    async fn get_keys(&self) -> Result<Vec<StoredKey>, KeyError> {
+        let mut res = self.signing_key_db.find(doc! {}).await.map_err(key_error)?;
+
+        let mut keys = Vec::new();
+        while let Some(key) = res.try_next().await.map_err(key_error)? {
+            keys.push(key);
         }
+
+        Ok(keys)
     }
 
-    pub async fn from_url(url: String, db_name: String) -> Result<Self, MongoInitError> {
-        let client = Client::with_uri_str(url).await.map_err(|e| {
-            eprintln!("{e:#?}");
-            MongoInitError::FailedToConnect
-        })?;
-        let db = client.database(&db_name);
-        Ok(Self::new(db))
+    #[tracing::instrument(
+        name = "mongo.insert_signing_key",
+        level = "debug",
+        skip_all,
+        err(Display, level = "debug")
+    )]
+    async fn insert_key(&self, key: StoredKey) -> Result<bool, KeyError> {
+        // The unique index on `kid` rejects a second insert.
+        match self.signing_key_db.insert_one(key).await {
+            Ok(_) => Ok(true),
+            Err(e) if is_duplicate_key(&e) => Ok(false),
+            Err(e) => Err(key_error(e)),
+        }
     }
 
-    pub async fn search_identity(&self, username: String) -> Result<Vec<T>, IdentityError> {
-        let mut res = match self
-            .identity_db
-            .find(doc! {
-                "name": {
-                    "$regex": username,
-                    "$options": "i"
+    #[tracing::instrument(
+        name = "mongo.delete_signing_key",
+        level = "debug",
+        skip_all,
+        err(Display, level = "debug")
+    )]
+    async fn delete_key(&self, kid: String) -> Result<(), KeyError> {
+        self.signing_key_db
+            .delete_one(doc! {
+                "kid": {
+                    "$eq": kid
                 }
             })
             .await
-        {
-            Ok(res) => res,
-            Err(e) => {
-                eprintln!("{e}");
-                return Err(IdentityError::InternalServerError);
-            }
-        };
+            .map_err(key_error)?;
+        Ok(())
+    }
+}
 
-        let mut identities = Vec::new();
-        while let Some(identity) = res.try_next().await.map_err(|e| {
-            eprintln!("{e:#?}");
-            IdentityError::InternalServerError
-        })? {
-            identities.push(identity);
+#[async_trait]
+impl<T: ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static>
+    ClientBackend for MongoBackend<T>
+{
+    #[tracing::instrument(
+        name = "mongo.create_oauth_client",
+        level = "debug",
+        skip_all,
+        err(Display, level = "debug")
+    )]
+    async fn create_client(&self, client: OAuthClient) -> Result<(), OAuthError> {
+        self.oauth_client_db
+            .insert_one(client)
+            .await
+            .map_err(oauth_error)?;
+        Ok(())
+    }
+
+    #[tracing::instrument(
+        name = "mongo.get_oauth_client",
+        level = "debug",
+        skip_all,
+        err(Display, level = "debug")
+    )]
+    async fn get_client(&self, client_id: String) -> Result<Option<OAuthClient>, OAuthError> {
+        self.oauth_client_db
+            .find_one(doc! {
+                "client_id": {
+                    "$eq": client_id
+                }
+            })
+            .await
+            .map_err(oauth_error)
+    }
+
+    #[tracing::instrument(
+        name = "mongo.get_oauth_clients",
+        level = "debug",
+        skip_all,
+        err(Display, level = "debug")
+    )]
+    async fn get_clients(&self) -> Result<Vec<OAuthClient>, OAuthError> {
+        let mut res = self
+            .oauth_client_db
+            .find(doc! {})
+            .await
+            .map_err(oauth_error)?;
+
+        let mut clients = Vec::new();
+        while let Some(client) = res.try_next().await.map_err(oauth_error)? {
+            clients.push(client);
         }
 
-        Ok(identities)
+        Ok(clients)
+    }
+
+    #[tracing::instrument(
+        name = "mongo.delete_oauth_client",
+        level = "debug",
+        skip_all,
+        err(Display, level = "debug")
+    )]
+    async fn delete_client(&self, client_id: String) -> Result<bool, OAuthError> {
+        let res = self
+            .oauth_client_db
+            .delete_one(doc! {
+                "client_id": {
+                    "$eq": client_id
+                }
+            })
+            .await
+            .map_err(oauth_error)?;
+
+        Ok(res.deleted_count > 0)
     }
 }
 
 #[async_trait]
 impl<T: ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static>
-    SessionBackend<T> for MongoBackend<T>
+    GrantBackend for MongoBackend<T>
 {
-    async fn login(&self, username: String, password: String) -> Result<Session<T>, SessionError> {
-        let res = match self
-            .identity_db
+    #[tracing::instrument(
+        name = "mongo.save_oauth_grant",
+        level = "debug",
+        skip_all,
+        err(Display, level = "debug")
+    )]
+    async fn save_grant(&self, grant: Grant) -> Result<(), OAuthError> {
+        self.oauth_grant_db
+            .replace_one(
+                doc! {
+                    "id": {
+                        "$eq": grant.id.clone()
+                    }
+                },
+                grant,
+            )
+            .upsert(true)
+            .await
+            .map_err(oauth_error)?;
+        Ok(())
+    }
+
+    #[tracing::instrument(
+        name = "mongo.insert_oauth_grant",
+        level = "debug",
+        skip_all,
+        err(Display, level = "debug")
+    )]
+    async fn insert_grant(&self, grant: Grant) -> Result<bool, OAuthError> {
+        // The unique index on `id` rejects a second insert.
+        match self.oauth_grant_db.insert_one(grant).await {
+            Ok(_) => Ok(true),
+            Err(e) if is_duplicate_key(&e) => Ok(false),
+            Err(e) => Err(oauth_error(e)),
+        }
+    }
+
+    #[tracing::instrument(
+        name = "mongo.get_oauth_grant",
+        level = "debug",
+        skip_all,
+        err(Display, level = "debug")
+    )]
+    async fn get_grant(&self, id: String) -> Result<Option<Grant>, OAuthError> {
+        self.oauth_grant_db
             .find_one(doc! {
-                "username": username,
-                "password": password
+                "id": {
+                    "$eq": id
+                }
             })
             .await
-        {
-            Ok(res) => res,
-            Err(e) => {
-                eprintln!("{e}");
-                return Err(SessionError::InternalServerError);
-            }
-        };
-        let Some(identity) = res else {
-            return Err(SessionError::InvalidLogin);
-        };
+            .map_err(oauth_error)
+    }
 
-        let Some(user_id) = identity.id() else {
-            return Err(SessionError::InternalServerError);
-        };
+    #[tracing::instrument(
+        name = "mongo.delete_oauth_grant",
+        level = "debug",
+        skip_all,
+        err(Display, level = "debug")
+    )]
+    async fn delete_grant(&self, id: String) -> Result<bool, OAuthError> {
+        let res = self
+            .oauth_grant_db
+            .delete_one(doc! {
+                "id": {
+                    "$eq": id
+                }
+            })
+            .await
+            .map_err(oauth_error)?;
 
-        let session = Session::new(Uuid::new_v4().into(), user_id.into());
+        Ok(res.deleted_count > 0)
+    }
+}
 
-        let _ = match self.session_db.insert_one(session.clone()).await {
-            Ok(res) => res,
-            Err(e) => {
-                eprintln!("{e}");
-                return Err(SessionError::InternalServerError);
-            }
-        };
+#[async_trait]
+impl<T: ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static>
+    ExternalLoginBackend<T> for MongoBackend<T>
+{
+    #[tracing::instrument(
+        name = "mongo.save_external_login_state",
+        level = "debug",
+        skip_all,
+        err(Display, level = "debug")
+    )]
+    async fn save_login_state(&self, state: ExternalLoginState) -> Result<(), ExternalLoginError> {
+        self.external_login_state_db
+            .insert_one(state)
+            .await
+            .map_err(external_login_error)?;
 
-        Ok(session)
+        Ok(())
     }
 
-    async fn validate(&self, session_id: String) -> Result<T, SessionError> {
-        let res = match self
-            .session_db
-            .find_one(doc! {
+    #[tracing::instrument(
+        name = "mongo.take_external_login_state",
+        level = "debug",
+        skip_all,
+        err(Display, level = "debug")
+    )]
+    async fn take_login_state(
+        &self,
+        id: String,
+    ) -> Result<Option<ExternalLoginState>, ExternalLoginError> {
+        self.external_login_state_db
+            .find_one_and_delete(doc! {
                 "id": {
-                    "$eq": session_id
+                    "$eq": id
                 }
             })
             .await
-        {
-            Ok(res) => res,
-            Err(e) => {
-                eprintln!("{e}");
-                return Err(SessionError::InternalServerError);
-            }
-        };
-        let Some(session) = res else {
-            return Err(SessionError::InvalidOrMissingSession);
-        };
+            .map_err(external_login_error)
+    }
 
-        let identity = self.get_by_id(session.user_id).await.map_err(|_| {
-            eprintln!("Couldn't find related user");
-            SessionError::InternalServerError
-        })?;
+    /// Expects identities to store their address in an `email` field and set
+    /// `email_verified` to `true` once the app has verified it.
+    #[tracing::instrument(
+        name = "mongo.get_by_verified_email",
+        level = "debug",
+        skip_all,
+        err(Display, level = "debug")
+    )]
+    async fn get_by_verified_email(&self, email: String) -> Result<Vec<T>, ExternalLoginError> {
+        let mut res = self
+            .identity_db
+            .find(doc! {
+                "email": {
+                    "$eq": email
+                },
+                "email_verified": {
+                    "$eq": true
+                }
+            })
+            // Case-insensitive, two are enough to tell the address is ambiguous.
+            .collation(
+                Collation::builder()
+                    .locale("en")
+                    .strength(CollationStrength::Secondary)
+                    .build(),
+            )
+            .limit(2)
+            .await
+            .map_err(external_login_error)?;
 
-        Ok(identity)
+        let mut identities = Vec::new();
+        while let Some(identity) = res.try_next().await.map_err(external_login_error)? {
+            identities.push(identity);
+        }
+        Ok(identities)
     }
 }
 
@@ -147,26 +1651,32 @@ impl<T: ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync +
 impl<T: ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync + 'static>
     IdentityBackend<T> for MongoBackend<T>
 {
+    #[tracing::instrument(
+        name = "mongo.get_all",
+        level = "debug",
+        skip_all,
+        err(Display, level = "debug")
+    )]
     async fn get_all(&self) -> Result<Vec<T>, IdentityError> {
         let mut res = match self.identity_db.find(doc! {}).await {
             Ok(res) => res,
-            Err(e) => {
-                eprintln!("{e}");
-                return Err(IdentityError::InternalServerError);
-            }
+            Err(e) => return Err(identity_error(e)),
         };
 
         let mut identities = Vec::new();
-        while let Some(identity) = res.try_next().await.map_err(|e| {
-            eprintln!("{e:#?}");
-            IdentityError::InternalServerError
-        })? {
+        while let Some(identity) = res.try_next().await.map_err(identity_error)? {
             identities.push(identity);
         }
 
         Ok(identities)
     }
 
+    #[tracing::instrument(
+        name = "mongo.create",
+        level = "debug",
+        skip_all,
+        err(Display, level = "debug")
+    )]
     async fn create(&self, mut identity: T) -> Result<(), IdentityError> {
         identity.set_id(Uuid::new_v4());
 
@@ -174,13 +1684,21 @@ impl<T: ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync +
             .insert_one(identity.clone())
             .await
             .map_err(|e| {
-                eprintln!("{e:#?}");
-                IdentityError::InternalServerError
+                if is_duplicate_key(&e) {
+                    return IdentityError::UsernameAlreadyInUse;
+                }
+                identity_error(e)
             })?;
 
         Ok(())
     }
 
+    #[tracing::instrument(
+        name = "mongo.get_by_username",
+        level = "debug",
+        skip_all,
+        err(Display, level = "debug")
+    )]
     async fn get_by_username(&self, username: String) -> Result<Option<T>, IdentityError> {
         self.identity_db
             .find_one(doc! {
@@ -189,12 +1707,15 @@ impl<T: ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync +
                 }
             })
             .await
-            .map_err(|e| {
-                eprintln!("{e:#?}");
-                IdentityError::InternalServerError
-            })
+            .map_err(identity_error)
     }
 
+    #[tracing::instrument(
+        name = "mongo.get_by_id",
+        level = "debug",
+        skip_all,
+        err(Display, level = "debug")
+    )]
     async fn get_by_id(&self, id: String) -> Result<T, IdentityError> {
         let res = match self
             .identity_db
@@ -206,10 +1727,7 @@ impl<T: ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync +
             .await
         {
             Ok(res) => res,
-            Err(e) => {
-                eprintln!("{e}");
-                return Err(IdentityError::InternalServerError);
-            }
+            Err(e) => return Err(identity_error(e)),
         };
         let Some(identity) = res else {
             return Err(IdentityError::NotFound);
@@ -217,6 +1735,12 @@ impl<T: ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync +
         Ok(identity)
     }
 
+    #[tracing::instrument(
+        name = "mongo.update_by_id",
+        level = "debug",
+        skip_all,
+        err(Display, level = "debug")
+    )]
     async fn update_by_id(&self, id: String, identity: T) -> Result<(), IdentityError> {
         let mut identity = identity;
         identity.set_id(Uuid::from_str(&id).map_err(|_| IdentityError::InvalidId)?);
@@ -233,19 +1757,23 @@ impl<T: ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync +
             .await
         {
             Ok(res) => res,
-            Err(e) => {
-                eprintln!("{e:#?}");
-                return Err(IdentityError::InternalServerError);
-            }
+            Err(e) if is_duplicate_key(&e) => return Err(IdentityError::UsernameAlreadyInUse),
+            Err(e) => return Err(identity_error(e)),
         };
 
-        if res.matched_count <= 0 && res.modified_count <= 0 {
+        if res.matched_count == 0 && res.modified_count == 0 {
             return Err(IdentityError::NotFound);
         }
 
         Ok(())
     }
 
+    #[tracing::instrument(
+        name = "mongo.delete_by_id",
+        level = "debug",
+        skip_all,
+        err(Display, level = "debug")
+    )]
     async fn delete_by_id(&self, id: String) -> Result<(), IdentityError> {
         let res = match self
             .identity_db
@@ -257,10 +1785,7 @@ impl<T: ObjectId + Serialize + for<'de> Deserialize<'de> + Clone + Send + Sync +
             .await
         {
             Ok(res) => res,
-            Err(e) => {
-                eprintln!("{e}");
-                return Err(IdentityError::InternalServerError);
-            }
+            Err(e) => return Err(identity_error(e)),
         };
 
         match res.deleted_count {